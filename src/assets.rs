@@ -1,46 +1,61 @@
 use anyhow::{Context, Result};
-use futures_util::StreamExt;
 use reqwest::Client;
+use sha2::{Digest, Sha256};
 use std::fs::{self, File};
 use std::io::Write;
 use std::path::Path;
 
-const ASSET_DIR: &str = "/home/reprah007.linux/firecracker-assets";
+use crate::errors::{ErrorKind, StokerError};
+
+const DEFAULT_ASSET_DIR: &str = "/home/reprah007.linux/firecracker-assets";
+/// `f_type` of NFS mounts as reported by statfs(2)
+const NFS_SUPER_MAGIC: i64 = 0x6969;
 const KERNEL_URL: &str = "https://s3.amazonaws.com/spec.ccfc.min/firecracker-ci/v1.13/aarch64/vmlinux-5.10.239";
 const ROOTFS_URL: &str = "https://s3.amazonaws.com/spec.ccfc.min/img/aarch64/ubuntu_with_ssh/fsfiles/xenial.rootfs.ext4";
 const SSH_KEY_URL: &str = "https://s3.amazonaws.com/spec.ccfc.min/img/aarch64/ubuntu_with_ssh/fsfiles/xenial.rootfs.id_rsa";
-const FIRECRACKER_URL: &str = "https://github.com/firecracker-microvm/firecracker/releases/download/v1.10.1/firecracker-v1.10.1-aarch64.tgz";
 
 // Note: To remain purely native without shelling to `mount`, we assume the user provides an SSH-enabled rootfs.
 // We will download the generic one and rely on standard credentials or pre-baked images.
 // For the scope of this CLI, we will download the assets to a shared directory.
 
-pub async fn download_all() -> Result<()> {
-    fs::create_dir_all(ASSET_DIR).context("Failed to create assets directory")?;
+pub async fn download_all(firecracker_version: &str) -> Result<()> {
+    let asset_dir = asset_dir().write_dir();
+    fs::create_dir_all(asset_dir).context("Failed to create assets directory")?;
 
     let client = Client::new();
 
+    let firecracker_url = format!(
+        "https://github.com/firecracker-microvm/firecracker/releases/download/{v}/firecracker-{v}-aarch64.tgz",
+        v = firecracker_version
+    );
+    let firecracker_tgz = get_asset_path(&format!("firecracker-{}-aarch64.tgz", firecracker_version));
+
     download_file(&client, KERNEL_URL, &get_asset_path("vmlinux.bin")).await?;
     download_file(&client, ROOTFS_URL, &get_asset_path("ubuntu-rootfs.ext4")).await?;
-    download_file(&client, FIRECRACKER_URL, &get_asset_path("firecracker-aarch64.tgz")).await?;
+    if let Some(arch) = crate::builder::image_arch("ubuntu-rootfs") {
+        println!("Image ubuntu-rootfs is built for {}.", arch);
+    }
+    download_file(&client, &firecracker_url, &firecracker_tgz).await?;
 
     let fc_binary = get_asset_path("firecracker");
     if !Path::new(&fc_binary).exists() {
         println!("Extracting Firecracker binary...");
-        let status = std::process::Command::new("tar")
+        let status = tokio::process::Command::new("tar")
             .arg("-xzf")
-            .arg(get_asset_path("firecracker-aarch64.tgz"))
+            .arg(&firecracker_tgz)
             .arg("-C")
-            .arg(ASSET_DIR)
+            .arg(asset_dir)
             .status()
+            .await
             .context("Failed to extract firecracker")?;
             
         if status.success() {
+            let release_dir = format!("{}/release-{}-aarch64", asset_dir, firecracker_version);
             std::fs::rename(
-                format!("{}/release-v1.10.1-aarch64/firecracker-v1.10.1-aarch64", ASSET_DIR),
+                format!("{}/firecracker-{}-aarch64", release_dir, firecracker_version),
                 &fc_binary
             )?;
-            let _ = std::fs::remove_dir_all(format!("{}/release-v1.10.1-aarch64", ASSET_DIR));
+            let _ = std::fs::remove_dir_all(&release_dir);
         } else {
             anyhow::bail!("Failed to extract firecracker binary");
         }
@@ -74,45 +89,494 @@ async fn download_file(client: &Client, url: &str, dest: &str) -> Result<()> {
     let mut response = client.get(url).send().await?.error_for_status()?;
 
     let mut file = File::create(dest)?;
+    let progress = response.content_length().map(crate::progress::TransferProgress::new);
     while let Some(chunk) = response.chunk().await? {
         file.write_all(&chunk)?;
+        if let Some(progress) = &progress {
+            progress.add(chunk.len() as u64);
+        }
+    }
+    if let Some(progress) = &progress {
+        progress.finish();
     }
 
     println!("Saved to {}", dest);
     Ok(())
 }
 
+/// Where images, the kernel and keys live. `dir` may be shared and read-only, such as an NFS
+/// export of prebuilt images; everything stoker creates then goes to `output_dir`, and files
+/// there take precedence over those in `dir`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssetDir {
+    pub dir: String,
+    pub output_dir: Option<String>,
+}
+
+impl AssetDir {
+    /// From `--image-dir`/`--output-dir`, falling back to `STOKER_ASSET_DIR`/`STOKER_OUTPUT_DIR`
+    /// and then the built-in directory.
+    pub fn from_env(image_dir: Option<String>, output_dir: Option<String>) -> Self {
+        let var = |name| std::env::var(name).ok().filter(|v: &String| !v.is_empty());
+        AssetDir {
+            dir: image_dir.or_else(|| var("STOKER_ASSET_DIR")).unwrap_or_else(|| DEFAULT_ASSET_DIR.to_string()),
+            output_dir: output_dir.or_else(|| var("STOKER_OUTPUT_DIR")),
+        }
+    }
+
+    /// The directory new assets are written to.
+    pub fn write_dir(&self) -> &str {
+        self.output_dir.as_deref().unwrap_or(&self.dir)
+    }
+
+    /// The directory `filename` is read from: `dir` when only it has the file, otherwise the
+    /// output directory, so that files which don't exist yet are created there.
+    pub fn dir_of(&self, filename: &str) -> &str {
+        match &self.output_dir {
+            Some(output) if Path::new(&format!("{}/{}", output, filename)).exists() => output,
+            Some(_) if Path::new(&format!("{}/{}", self.dir, filename)).exists() => &self.dir,
+            _ => self.write_dir(),
+        }
+    }
+
+    pub fn path(&self, filename: &str) -> String {
+        format!("{}/{}", self.dir_of(filename), filename)
+    }
+}
+
+static ASSET_DIR: std::sync::OnceLock<AssetDir> = std::sync::OnceLock::new();
+
+/// Sets the asset directories for this process. Only the first call has an effect, so call it
+/// before anything resolves an asset path.
+pub fn set_asset_dir(asset_dir: AssetDir) {
+    let _ = ASSET_DIR.set(asset_dir);
+}
+
+pub fn asset_dir() -> &'static AssetDir {
+    ASSET_DIR.get_or_init(|| AssetDir::from_env(None, None))
+}
+
 // Ensure the asset path exists and returns it
 pub fn get_asset_path(filename: &str) -> String {
-    format!("{}/{}", ASSET_DIR, filename)
+    asset_dir().path(filename)
+}
+
+/// Path for writing asset `filename`, which always lands in the output directory.
+pub fn get_output_path(filename: &str) -> String {
+    format!("{}/{}", asset_dir().write_dir(), filename)
+}
+
+/// Whether `path`, or its closest existing ancestor, is on an NFS mount.
+pub fn is_nfs(path: &str) -> bool {
+    let existing = Path::new(path).ancestors().find(|p| p.exists()).unwrap_or(Path::new("/"));
+    let Ok(c_path) = std::ffi::CString::new(existing.as_os_str().as_encoded_bytes()) else {
+        return false;
+    };
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    unsafe { libc::statfs(c_path.as_ptr(), &mut stat) == 0 && stat.f_type as i64 == NFS_SUPER_MAGIC }
+}
+
+/// Bails when image `path` would be written to an NFS mount, which stoker treats as a shared,
+/// read-only image directory.
+pub fn ensure_writable_image_path(path: &str) -> Result<()> {
+    if is_nfs(path) {
+        anyhow::bail!(
+            "{} is on an NFS mount, which stoker only reads images from. Pass --output-dir <local dir> to write images elsewhere.",
+            path
+        );
+    }
+    Ok(())
+}
+
+/// Picks the Firecracker binary: an explicit path, then `STOKER_FIRECRACKER_BIN`, then the asset dir.
+pub fn resolve_firecracker_binary(explicit: Option<&str>) -> String {
+    explicit
+        .map(str::to_string)
+        .or_else(|| std::env::var("STOKER_FIRECRACKER_BIN").ok().filter(|p| !p.is_empty()))
+        .unwrap_or_else(|| get_asset_path("firecracker"))
+}
+
+/// Verifies `path` is an executable Firecracker binary and returns its reported version.
+pub fn check_firecracker_binary(path: &str) -> Result<String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let meta = fs::metadata(path)
+        .with_context(|| StokerError::new(ErrorKind::AssetMissing, format!("Firecracker binary not found at {}. Run `stoker download-assets` or set --firecracker-bin.", path)))?;
+    if !meta.is_file() || meta.permissions().mode() & 0o111 == 0 {
+        anyhow::bail!("Firecracker binary at {} is not executable", path);
+    }
+
+    let output = std::process::Command::new(path)
+        .arg("--version")
+        .output()
+        .with_context(|| format!("Failed to execute {} --version", path))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = parse_firecracker_version(&stdout)
+        .with_context(|| format!("{} does not look like a Firecracker binary", path))?;
+
+    // stoker drives the v1.x API
+    if !version.starts_with("v1.") {
+        println!("Warning: Firecracker {} is untested with stoker (expected v1.x)", version);
+    }
+    Ok(version)
+}
+
+fn parse_firecracker_version(output: &str) -> Option<String> {
+    // e.g. "Firecracker v1.10.1\n\nSupported snapshot data format versions: ..."
+    output.lines()
+        .next()?
+        .strip_prefix("Firecracker ")
+        .map(|v| v.trim().to_string())
+}
+
+/// Queries filesystem statistics for the filesystem containing `path`.
+pub fn statvfs(path: &str) -> Result<libc::statvfs> {
+    let c_path = std::ffi::CString::new(path)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        anyhow::bail!("statvfs failed for {}: {}", path, std::io::Error::last_os_error());
+    }
+    Ok(stat)
+}
+
+/// Bails with a clear message when `dir` has fewer than `needed` bytes available.
+pub fn ensure_free_space(dir: &str, needed: u64) -> Result<()> {
+    let stat = statvfs(dir)?;
+    let available = stat.f_bavail * stat.f_frsize;
+    if available < needed {
+        anyhow::bail!(
+            "Not enough free space in {}: need {:.2} MB but only {:.2} MB is available",
+            dir,
+            needed as f64 / 1_048_576.0,
+            available as f64 / 1_048_576.0
+        );
+    }
+    Ok(())
+}
+
+/// Copies the image `src` to `dst`, preferring a reflink (copy-on-write) clone that is instant
+/// and shares blocks on btrfs/XFS. When the filesystem can't reflink, falls back to a full copy
+/// after checking that `dst`'s directory has room for it plus `reserve` bytes.
+/// Returns whether a reflink was used.
+pub fn clone_image(src: &str, dst: &str, reserve: u64) -> Result<bool> {
+    if reflink(src, dst).is_ok() {
+        return Ok(true);
+    }
+    let size = fs::metadata(src).with_context(|| format!("Cannot read {}", src))?.len();
+    let dir = Path::new(dst).parent().and_then(Path::to_str).filter(|d| !d.is_empty()).unwrap_or(".");
+    ensure_free_space(dir, size + reserve)?;
+    fs::copy(src, dst).with_context(|| format!("Failed to copy {} to {}", src, dst))?;
+    Ok(false)
+}
+
+fn reflink(src: &str, dst: &str) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let source = File::open(src)?;
+    let target = File::create(dst)?;
+    if unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } != 0 {
+        let err = std::io::Error::last_os_error();
+        drop(target);
+        let _ = fs::remove_file(dst);
+        return Err(err);
+    }
+    target.set_permissions(source.metadata()?.permissions())
+}
+
+/// A registry image reference such as `192.168.1.10:5000/nginx-server` or `http://host/name`.
+#[derive(Debug, PartialEq)]
+struct ImageReference {
+    scheme: Option<String>,
+    host: String,
+    name: String,
+}
+
+fn parse_image_reference(reference: &str) -> Result<ImageReference> {
+    let (scheme, rest) = match reference.split_once("://") {
+        Some((scheme, rest)) => (Some(scheme.to_string()), rest),
+        None => (None, reference),
+    };
+    if let Some(scheme) = &scheme {
+        if scheme != "http" && scheme != "https" {
+            anyhow::bail!("Unsupported registry scheme '{}'", scheme);
+        }
+    }
+    let (host, name) = rest
+        .split_once('/')
+        .filter(|(host, name)| !host.is_empty() && !name.is_empty())
+        .with_context(|| format!("Invalid image reference '{}', expected REGISTRY/NAME", reference))?;
+    Ok(ImageReference { scheme, host: host.to_string(), name: name.to_string() })
+}
+
+/// Whether `host` appears in the comma-separated `STOKER_INSECURE_REGISTRY` list.
+fn is_insecure_registry(host: &str, insecure_list: &str) -> bool {
+    insecure_list.split(',').map(str::trim).any(|entry| !entry.is_empty() && entry == host)
 }
 
-pub fn list_images() -> Result<()> {
-    println!("{:<30} {:<15}", "IMAGE", "SIZE");
-    if let Ok(entries) = fs::read_dir(ASSET_DIR) {
+/// Downloads `NAME.ext4` from a registry into the asset directory as image `NAME`.
+/// Registries given as `http://` or listed in `STOKER_INSECURE_REGISTRY` are used without
+/// TLS verification unless `verify_tls` is set.
+pub async fn pull_image(reference: &str, verify_tls: bool) -> Result<()> {
+    let reference = parse_image_reference(reference)?;
+    let insecure_list = std::env::var("STOKER_INSECURE_REGISTRY").unwrap_or_default();
+    let insecure = !verify_tls
+        && (reference.scheme.as_deref() == Some("http") || is_insecure_registry(&reference.host, &insecure_list));
+
+    let client = if insecure {
+        println!("WARNING: pulling from insecure registry {} over plain HTTP without TLS verification", reference.host);
+        Client::builder().danger_accept_invalid_certs(true).build()?
+    } else {
+        Client::new()
+    };
+    let scheme = if insecure { "http" } else { "https" };
+
+    let image = reference.name.rsplit('/').next().unwrap_or(&reference.name);
+    fs::create_dir_all(asset_dir().write_dir()).context("Failed to create assets directory")?;
+    let dest = get_output_path(&format!("{}.ext4", image));
+    let partial = format!("{}.partial", dest);
+    let _ = fs::remove_file(&partial);
+
+    let url = format!("{}://{}/{}.ext4", scheme, reference.host, reference.name);
+    download_file(&client, &url, &partial).await?;
+    fs::rename(&partial, &dest).with_context(|| format!("Failed to move pulled image into {}", dest))?;
+    // A re-pulled image may be for another architecture than the one recorded before
+    let _ = fs::remove_file(crate::builder::meta_path(image));
+    match crate::builder::image_arch(image) {
+        Some(arch) => println!("Pulled image '{}' ({})", image, arch),
+        None => println!("Pulled image '{}'", image),
+    }
+    Ok(())
+}
+
+/// An image available to `stoker run --image`.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct ImageInfo {
+    pub name: String,
+    pub size_bytes: u64,
+    /// `sha256:<hex>` of the ext4, filled in by `stoker images --digest`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+}
+
+/// The `<image>.digest` sidecar, valid while the ext4 keeps this size and modification time.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct CachedDigest {
+    size_bytes: u64,
+    mtime_ns: u64,
+    sha256: String,
+}
+
+/// SHA-256 of image `name`'s ext4 as `sha256:<hex>`. The result is cached next to the image
+/// and only recomputed once the image's size or modification time changes.
+pub fn image_digest(name: &str) -> Result<String> {
+    image_digest_in_dir(asset_dir().dir_of(&format!("{}.ext4", name)), name)
+}
+
+fn image_digest_in_dir(dir: &str, name: &str) -> Result<String> {
+    let image_path = format!("{}/{}.ext4", dir, name);
+    let cache_path = format!("{}/{}.digest", dir, name);
+    let metadata = fs::metadata(&image_path).with_context(|| format!("Image '{}' not found at {}", name, image_path))?;
+    let mtime_ns = metadata.modified()?.duration_since(std::time::UNIX_EPOCH)?.as_nanos() as u64;
+
+    let cached = fs::read_to_string(&cache_path).ok()
+        .and_then(|content| serde_json::from_str::<CachedDigest>(&content).ok());
+    if let Some(cached) = cached.filter(|c| c.size_bytes == metadata.len() && c.mtime_ns == mtime_ns) {
+        return Ok(format!("sha256:{}", cached.sha256));
+    }
+
+    eprintln!("Hashing {} ({:.2} MB)...", name, metadata.len() as f64 / 1_048_576.0);
+    let mut file = File::open(&image_path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+    loop {
+        let n = std::io::Read::read(&mut file, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    let sha256 = crate::signing::hex(&hasher.finalize());
+    let cache = CachedDigest { size_bytes: metadata.len(), mtime_ns, sha256: sha256.clone() };
+    // A read-only asset directory only costs rehashing next time
+    let _ = fs::write(&cache_path, serde_json::to_string(&cache)?);
+    Ok(format!("sha256:{}", sha256))
+}
+
+/// Images in the asset directory, ordered by name. Images in the output directory shadow
+/// those of the same name in the image directory.
+pub fn list_images() -> Vec<ImageInfo> {
+    let assets = asset_dir();
+    let mut images = list_images_in_dir(assets.write_dir());
+    if assets.output_dir.is_some() {
+        for image in list_images_in_dir(&assets.dir) {
+            if !images.iter().any(|i| i.name == image.name) {
+                images.push(image);
+            }
+        }
+        images.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+    images
+}
+
+fn list_images_in_dir(dir: &str) -> Vec<ImageInfo> {
+    let mut images = Vec::new();
+    if let Ok(entries) = fs::read_dir(dir) {
         for entry in entries.flatten() {
             let fname = entry.file_name().to_string_lossy().to_string();
-            if fname.ends_with(".ext4") {
-                let name = fname.trim_end_matches(".ext4");
-                let size_str = if let Ok(meta) = std::fs::metadata(entry.path()) {
-                    format!("{:.2} MB", meta.len() as f64 / 1_048_576.0)
-                } else {
-                    "Unknown".to_string()
-                };
-                println!("{:<30} {:<15}", name, size_str);
+            if let Some(name) = fname.strip_suffix(".ext4") {
+                let size_bytes = entry.metadata().map(|m| m.len()).unwrap_or(0);
+                images.push(ImageInfo { name: name.to_string(), size_bytes, digest: None });
             }
         }
     }
-    Ok(())
+    images.sort_by(|a, b| a.name.cmp(&b.name));
+    images
+}
+
+/// Renders images as the `stoker images` table, with a DIGEST column once digests are known.
+pub fn render_image_table(images: &[ImageInfo]) -> String {
+    let digests = images.iter().any(|image| image.digest.is_some());
+    let mut table = format!("{:<30} {:<15}", "IMAGE", "SIZE");
+    if digests {
+        table.push_str(" DIGEST");
+    }
+    table.push('\n');
+    for image in images {
+        let size_str = format!("{:.2} MB", image.size_bytes as f64 / 1_048_576.0);
+        let mut row = format!("{:<30} {:<15}", image.name, size_str);
+        if digests {
+            row.push(' ');
+            row.push_str(image.digest.as_deref().unwrap_or("-"));
+        }
+        table.push_str(row.trim_end());
+        table.push('\n');
+    }
+    table
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_clone_image() {
+        let dir = std::env::temp_dir().join(format!("stoker-clone-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("base.ext4");
+        let dst = dir.join("clone.ext4");
+        fs::write(&src, b"ext4 contents").unwrap();
+
+        // Reflink or not depends on the filesystem; either way the contents must match
+        clone_image(src.to_str().unwrap(), dst.to_str().unwrap(), 0).unwrap();
+        assert_eq!(fs::read(&dst).unwrap(), b"ext4 contents");
+        assert!(clone_image(dir.join("missing.ext4").to_str().unwrap(), dst.to_str().unwrap(), 0).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_image_reference() {
+        let plain = parse_image_reference("192.168.1.10:5000/nginx-server").unwrap();
+        assert_eq!(plain, ImageReference {
+            scheme: None,
+            host: "192.168.1.10:5000".to_string(),
+            name: "nginx-server".to_string(),
+        });
+
+        let http = parse_image_reference("http://registry.local/team/app").unwrap();
+        assert_eq!(http.scheme.as_deref(), Some("http"));
+        assert_eq!(http.name, "team/app");
+
+        assert!(parse_image_reference("nginx-server").is_err());
+        assert!(parse_image_reference("ftp://host/app").is_err());
+    }
+
+    #[test]
+    fn test_is_insecure_registry() {
+        let list = "192.168.1.10:5000, registry.local";
+        assert!(is_insecure_registry("192.168.1.10:5000", list));
+        assert!(is_insecure_registry("registry.local", list));
+        assert!(!is_insecure_registry("192.168.1.10", list));
+        assert!(!is_insecure_registry("registry.local", ""));
+    }
+
+    #[test]
+    fn test_list_images_in_dir() {
+        let tmp_dir = std::env::temp_dir().join("stoker_test_list_images");
+        let _ = fs::remove_dir_all(&tmp_dir);
+        fs::create_dir_all(&tmp_dir).unwrap();
+        fs::write(tmp_dir.join("web.ext4"), vec![0u8; 2048]).unwrap();
+        fs::write(tmp_dir.join("base.ext4"), vec![0u8; 1024]).unwrap();
+        fs::write(tmp_dir.join("web.history.jsonl"), "").unwrap();
+
+        let images = list_images_in_dir(tmp_dir.to_str().unwrap());
+        assert_eq!(images, vec![
+            ImageInfo { name: "base".to_string(), size_bytes: 1024, digest: None },
+            ImageInfo { name: "web".to_string(), size_bytes: 2048, digest: None },
+        ]);
+        assert!(render_image_table(&images).contains("web"));
+
+        let _ = fs::remove_dir_all(&tmp_dir);
+    }
+
+    #[test]
+    fn test_image_digest_in_dir() {
+        let dir = std::env::temp_dir().join(format!("stoker_test_image_digest_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+        fs::write(dir.join("web.ext4"), b"abc").unwrap();
+
+        let abc = "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(image_digest_in_dir(dir_str, "web").unwrap(), abc);
+        // An unchanged image is answered from the sidecar
+        let mut cached: CachedDigest = serde_json::from_str(&fs::read_to_string(dir.join("web.digest")).unwrap()).unwrap();
+        cached.sha256 = "cached".to_string();
+        fs::write(dir.join("web.digest"), serde_json::to_string(&cached).unwrap()).unwrap();
+        assert_eq!(image_digest_in_dir(dir_str, "web").unwrap(), "sha256:cached");
+        // A different size invalidates it
+        fs::write(dir.join("web.ext4"), b"abcd").unwrap();
+        assert_ne!(image_digest_in_dir(dir_str, "web").unwrap(), "sha256:cached");
+        assert!(image_digest_in_dir(dir_str, "missing").is_err());
+
+        let images = vec![ImageInfo { name: "web".to_string(), size_bytes: 3, digest: Some(abc.to_string()) }];
+        assert!(render_image_table(&images).lines().all(|line| line.contains("DIGEST") || line.ends_with(abc)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_firecracker_version() {
+        let output = "Firecracker v1.10.1\n\nSupported snapshot data format versions: v1.0.0\n";
+        assert_eq!(parse_firecracker_version(output), Some("v1.10.1".to_string()));
+        assert_eq!(parse_firecracker_version("bash: not firecracker"), None);
+    }
+
     #[test]
     fn test_get_asset_path() {
-        let expected = format!("{}/test.ext4", ASSET_DIR);
+        let expected = format!("{}/test.ext4", asset_dir().dir_of("test.ext4"));
         assert_eq!(get_asset_path("test.ext4"), expected);
     }
+
+    #[test]
+    fn test_asset_dir_overlay() {
+        let root = std::env::temp_dir().join(format!("stoker_test_asset_dir_{}", std::process::id()));
+        let (shared, local) = (root.join("shared"), root.join("local"));
+        fs::create_dir_all(&shared).unwrap();
+        fs::create_dir_all(&local).unwrap();
+        fs::write(shared.join("base.ext4"), b"").unwrap();
+        fs::write(shared.join("web.ext4"), b"").unwrap();
+        fs::write(local.join("web.ext4"), b"").unwrap();
+        let (shared, local) = (shared.to_str().unwrap().to_string(), local.to_str().unwrap().to_string());
+
+        let assets = AssetDir { dir: shared.clone(), output_dir: Some(local.clone()) };
+        assert_eq!(assets.path("base.ext4"), format!("{}/base.ext4", shared));
+        // Local copies shadow shared ones and new files are created locally
+        assert_eq!(assets.path("web.ext4"), format!("{}/web.ext4", local));
+        assert_eq!(assets.path("new.ext4"), format!("{}/new.ext4", local));
+
+        let plain = AssetDir { dir: shared.clone(), output_dir: None };
+        assert_eq!(plain.path("new.ext4"), format!("{}/new.ext4", shared));
+        assert_eq!(plain.write_dir(), shared);
+
+        assert_eq!(AssetDir::from_env(Some("/srv/images".to_string()), None).dir, "/srv/images");
+        assert!(!is_nfs(&root.join("missing/image.ext4").to_string_lossy()));
+        fs::remove_dir_all(&root).unwrap();
+    }
 }
@@ -1,54 +1,951 @@
 use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
 use std::process::Command;
-use crate::assets;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::progress::{Progress, ProgressMode};
+use crate::{assets, network};
+use crate::errors::{ErrorKind, StokerError};
 
-pub fn build_image(image_name: &str, script_path: &str) -> Result<()> {
-    println!("Building Firecracker image: {}...", image_name);
+/// Extra space added to every build image for the build script to work in.
+const BUILD_EXPANSION_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+
+/// Where the local build cache appears inside the build container, also exported to the
+/// script as `STOKER_CACHE`. Scripts keep downloads and other reusable artifacts there.
+const CONTAINER_CACHE_DIR: &str = "/var/cache/stoker";
+
+/// Path of the cache tarball inside an image written by `--cache-to`.
+const CACHE_IMAGE_TARBALL: &str = ".stoker-cache/cache.tar";
+
+/// One step of an image's build provenance, stored as a line of `<image>.history.jsonl`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryEntry {
+    pub directive: String,
+    pub content: String,
+    pub size_delta: i64,
+    pub created_at: u64,
+}
+
+// Standard OCI annotation keys understood in `--label`
+pub const LABEL_CREATED: &str = "org.opencontainers.image.created";
+pub const LABEL_DESCRIPTION: &str = "org.opencontainers.image.description";
+pub const LABEL_SOURCE: &str = "org.opencontainers.image.source";
+
+/// Image metadata stored in the `<image>.meta.json` sidecar next to the ext4.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ImageMeta {
+    #[serde(default)]
+    pub labels: std::collections::BTreeMap<String, String>,
+    /// CPU architecture of the image's binaries (x86_64 or aarch64), when known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+}
+
+/// Options accepted by `build_image`, mirroring the `stoker build` flags.
+#[derive(Debug, Clone, Default)]
+pub struct BuildOptions {
+    pub image_name: String,
+    pub script_path: String,
+    /// Image to build on top of (default: ubuntu-rootfs)
+    pub from: Option<String>,
+    /// Copy the final filesystem state into a fresh ext4 to drop blocks held by deleted files
+    pub squash: bool,
+    /// Extra `host:guest` bind mounts passed through to systemd-nspawn
+    pub binds: Vec<String>,
+    /// Extra capabilities granted to the build container
+    pub capabilities: Vec<String>,
+    /// Grant the build container every capability
+    pub privileged: bool,
+    /// Build container networking: `full` shares the host network, `none` isolates it and
+    /// `private` NATs it through a temporary bridge. `host` is accepted as an alias for `full`
+    pub network: String,
+    /// `KEY=VALUE` build arguments exported into the build script's environment
+    pub build_args: Vec<String>,
+    /// `key=value` labels stored in the image's metadata sidecar
+    pub labels: Vec<String>,
+    /// How build progress is reported on stdout
+    pub progress: ProgressMode,
+    /// Image written by `--cache-to` whose cache entries seed the local build cache
+    pub cache_from: Option<String>,
+    /// Image to package the local build cache into after a successful build
+    pub cache_to: Option<String>,
+    /// On script failure, open an interactive shell in the build container before cleaning up
+    pub debug: bool,
+    /// Architecture to build for (x86_64/amd64 or aarch64/arm64); defaults to the host's
+    pub arch: Option<String>,
+    /// Print the exact systemd-nspawn or chroot command line before running the build script
+    pub verbose: bool,
+    /// Host SSH agent socket forwarded into the container as `CONTAINER_SSH_AUTH_SOCK`
+    pub ssh_agent: Option<String>,
+}
+
+/// State `build_image` works out for a build itself, kept apart from the caller's `BuildOptions`.
+#[derive(Debug, Default)]
+struct BuildContext {
+    /// Host directory bound into the container as the build cache
+    cache_dir: Option<String>,
+    /// qemu-user interpreter to bind into the container for a foreign `arch` whose binfmt
+    /// handler isn't preloaded
+    binfmt_interpreter: Option<String>,
+    /// Run the script in a plain chroot because systemd-nspawn isn't installed
+    chroot_fallback: bool,
+}
+
+/// Where `--ssh` makes the host's SSH agent available inside the build container.
+pub const CONTAINER_SSH_AUTH_SOCK: &str = "/run/ssh-auth.sock";
+
+/// Host agent socket for `--ssh <spec>`, given the host's `SSH_AUTH_SOCK`. Like BuildKit,
+/// `default` forwards `$SSH_AUTH_SOCK` and `default=<path>` another socket. None when
+/// `default` is asked for but no agent is running.
+pub fn ssh_agent_socket(spec: &str, auth_sock: Option<&str>) -> Result<Option<String>> {
+    match spec.split_once('=') {
+        None if spec == "default" => Ok(auth_sock.filter(|sock| !sock.is_empty()).map(str::to_string)),
+        Some(("default", path)) if !path.is_empty() => Ok(Some(path.to_string())),
+        _ => anyhow::bail!("Unsupported --ssh '{}'. Use default or default=<agent socket>.", spec),
+    }
+}
+
+pub fn build_image(opts: &BuildOptions) -> Result<()> {
+    let mut progress = Progress::new(opts.progress);
+    // A handler registered for this build is unregistered when `_registration` drops
+    let result = cross_arch_interpreter(opts.arch.as_deref()).and_then(|(binfmt_interpreter, _registration)| {
+        let chroot_fallback = use_chroot_fallback(find_in_path("systemd-nspawn").is_some(), find_in_path("chroot").is_some(), &opts.network)?;
+        if chroot_fallback {
+            progress.message(
+                "WARNING: systemd-nspawn not found (install systemd-container). Falling back to chroot with bind-mounted \
+                 /dev, /proc and /sys; the build script runs with full root privileges on the host network.",
+            );
+        }
+        let ctx = BuildContext { cache_dir: Some(build_cache_dir()), binfmt_interpreter, chroot_fallback };
+        run_build(opts, &ctx, &mut progress)
+    });
+    match &result {
+        Ok(()) => progress.done(),
+        Err(e) => progress.fail(e),
+    }
+    result
+}
+
+fn run_build(opts: &BuildOptions, ctx: &BuildContext, progress: &mut Progress) -> Result<()> {
+    let image_name = opts.image_name.as_str();
+    let script_path = opts.script_path.as_str();
+    if !matches!(opts.network.as_str(), "full" | "host" | "none" | "private") {
+        anyhow::bail!("Unsupported build network '{}'. Use 'full', 'none' or 'private'.", opts.network);
+    }
+    for arg in &opts.build_args {
+        parse_build_arg(arg)?;
+    }
+    for label in &opts.labels {
+        parse_label(label)?;
+    }
+    progress.message(&format!("Building Firecracker image: {}...", image_name));
     
-    let base_ext4 = assets::get_asset_path("ubuntu-rootfs.ext4");
+    let base_image = opts.from.as_deref().unwrap_or("ubuntu-rootfs");
+    if base_image == image_name {
+        anyhow::bail!("Cannot build image '{}' from itself.", image_name);
+    }
+    if opts.cache_to.as_deref().is_some_and(|cache| cache == image_name || cache == base_image) {
+        anyhow::bail!("--cache-to must name an image other than the one being built and its base.");
+    }
+    let base_ext4 = assets::get_asset_path(&format!("{}.ext4", base_image));
     if !std::path::Path::new(&base_ext4).exists() {
-        anyhow::bail!("Base rootfs not found at {}. Run `stoker download-assets` first.", base_ext4);
+        if opts.from.is_some() {
+            anyhow::bail!(StokerError::new(ErrorKind::NotFound, format!("Base image '{}' not found at {}. Run `stoker images` to list available images.", base_image, base_ext4)));
+        }
+        anyhow::bail!(StokerError::new(ErrorKind::AssetMissing, format!("Base rootfs not found at {}. Run `stoker download-assets` first.", base_ext4)));
     }
     
-    let target_ext4 = assets::get_asset_path(&format!("{}.ext4", image_name));
+    let target_ext4 = assets::get_output_path(&format!("{}.ext4", image_name));
+    assets::ensure_writable_image_path(&target_ext4)?;
+
+    if let Some(cache_image) = &opts.cache_from {
+        progress.start("cache-import", &format!("Importing build cache from {}...", cache_image));
+        import_cache(cache_image, &build_cache_dir())?;
+    }
+    if let Some(cache_dir) = &ctx.cache_dir {
+        std::fs::create_dir_all(cache_dir).with_context(|| format!("Failed to create build cache {}", cache_dir))?;
+    }
+
+    // The +2G expansion lands in the asset directory even when the clone itself shares blocks
+    let base_size = std::fs::metadata(&base_ext4)?.len();
+    assets::ensure_free_space(&assets::get_output_path(""), BUILD_EXPANSION_BYTES)?;
     
     // 1. Clone the ext4 base to the new target
-    println!("Cloning base rootfs to {}...", target_ext4);
-    std::fs::copy(&base_ext4, &target_ext4).context("Failed to copy base image")?;
+    progress.start("clone", &format!("Cloning base rootfs to {}...", target_ext4));
+    if assets::clone_image(&base_ext4, &target_ext4, BUILD_EXPANSION_BYTES).context("Failed to copy base image")? {
+        progress.message("Cloned with a copy-on-write reflink.");
+    }
+    // Start from the base image's provenance so layered images show their full chain
+    if std::fs::copy(history_path(base_image), history_path(image_name)).is_err() {
+        let _ = std::fs::remove_file(history_path(image_name));
+    }
+    record_history(image_name, "FROM", base_image, base_size as i64)?;
     
     // 2. Expand the image by 2GB to ensure enough space for the build script
-    println!("Expanding image size by +2G for build space...");
-    let _ = Command::new("truncate").args(&["-s", "+2G", &target_ext4]).status();
-    let _ = Command::new("e2fsck").args(&["-f", "-y", &target_ext4]).status();
-    let _ = Command::new("resize2fs").args(&[&target_ext4]).status();
+    progress.start("resize", "Expanding image size by +2G for build space...");
+    let _ = Command::new("truncate").args(["-s", "+2G", &target_ext4]).status();
+    let _ = Command::new("e2fsck").args(["-f", "-y", &target_ext4]).stdout(progress.tool_stdout()).status();
+    let _ = Command::new("resize2fs").args([&target_ext4]).stdout(progress.tool_stdout()).status();
     
     // 3. Mount the ext4 loop device natively via system commands (most stable for nested VM overlays)
     let mount_dir = format!("/tmp/stoker-build-{}", image_name);
     let _ = std::fs::create_dir_all(&mount_dir);
     
-    println!("Mounting loop filesystem at {}...", mount_dir);
+    progress.start("mount", &format!("Mounting loop filesystem at {}...", mount_dir));
     let status = Command::new("mount")
-        .args(&["-o", "loop", &target_ext4, &mount_dir])
+        .args(["-o", "loop", &target_ext4, &mount_dir])
         .status()?;
         
     if !status.success() {
-        anyhow::bail!("Failed to loop mount the ext4 file. Are you running as root?");
+        anyhow::bail!(StokerError::new(ErrorKind::NeedsRoot, "Failed to loop mount the ext4 file. Are you running as root?"));
+    }
+    // A mount can succeed on a damaged filesystem; catch that before running the build on it
+    let checked = check_root_layout(&mount_dir, base_image).and_then(|_| match &opts.arch {
+        Some(arch) => check_image_arch(&mount_dir, base_image, normalize_arch(arch)?),
+        None => Ok(()),
+    });
+    if let Err(e) = checked {
+        let _ = Command::new("umount").arg(&mount_dir).status();
+        let _ = std::fs::remove_dir_all(&mount_dir);
+        return Err(e);
     }
     
+    // Record build arguments so the build can be reproduced from its history
+    for arg in &opts.build_args {
+        record_history(image_name, "ARG", arg, 0)?;
+    }
+
     // Ensure we unmount cleanly even if the build fails
-    let result = execute_chroot_build(&mount_dir, script_path);
+    let used_before = filesystem_used_bytes(&mount_dir);
+    let result = execute_chroot_build(&mount_dir, script_path, opts, ctx, progress);
+    let used_after = filesystem_used_bytes(&mount_dir);
+    if let Err(e) = &result {
+        // Report the failing step before unmounting starts a new one
+        progress.fail(e);
+        if opts.debug {
+            debug_shell(&mount_dir, opts, ctx, progress);
+        }
+    }
     
     // 3. Unmount
-    println!("Unmounting loop filesystem...");
+    progress.start("unmount", "Unmounting loop filesystem...");
     let _ = Command::new("umount").arg(&mount_dir).status();
     let _ = std::fs::remove_dir_all(&mount_dir);
+    progress.done();
     
     result?;
-    println!("Successfully built stoker image: {}", image_name);
+    let script_content = std::fs::read_to_string(script_path).unwrap_or_default();
+    record_history(image_name, "RUN", &script_content, used_after as i64 - used_before as i64)?;
+
+    if opts.squash {
+        let size_before = std::fs::metadata(&target_ext4)?.len();
+        squash_image(&target_ext4, image_name, progress)?;
+        let size_after = std::fs::metadata(&target_ext4)?.len();
+        record_history(image_name, "SQUASH", "", size_after as i64 - size_before as i64)?;
+    }
+
+    // Labels are inherited from the base image like Docker's, then overridden by --label
+    let mut meta = load_image_meta(base_image);
+    match &opts.arch {
+        Some(arch) => meta.arch = Some(normalize_arch(arch)?.to_string()),
+        // Without --arch the build script ran natively
+        None => { meta.arch.get_or_insert_with(|| std::env::consts::ARCH.to_string()); }
+    }
+    meta.labels.insert(LABEL_CREATED.to_string(), format_rfc3339(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()));
+    for label in &opts.labels {
+        let (key, value) = parse_label(label)?;
+        meta.labels.insert(key.to_string(), value.to_string());
+    }
+    save_image_meta(image_name, &meta)?;
+
+    if let Some(cache_image) = &opts.cache_to {
+        progress.start("cache-export", &format!("Exporting build cache to image {}...", cache_image));
+        export_cache(&build_cache_dir(), cache_image)?;
+    }
+
+    progress.done();
+    progress.message(&format!("Successfully built stoker image: {}", image_name));
+    Ok(())
+}
+
+/// Spells an architecture name the way the kernel and binfmt_misc do.
+pub fn normalize_arch(arch: &str) -> Result<&'static str> {
+    match arch {
+        "x86_64" | "amd64" => Ok("x86_64"),
+        "aarch64" | "arm64" => Ok("aarch64"),
+        other => anyhow::bail!("Unsupported architecture '{}'. Use x86_64 (amd64) or aarch64 (arm64).", other),
+    }
+}
+
+/// Spells a `--platform` such as `linux/amd64` as the architecture it names.
+pub fn platform_arch(platform: &str) -> Result<&'static str> {
+    match platform.split_once('/') {
+        Some(("linux", arch)) => normalize_arch(arch),
+        _ => anyhow::bail!("Unsupported platform '{}'. Use linux/amd64 or linux/arm64.", platform),
+    }
+}
+
+/// Whether `arch` names an architecture other than the host's.
+fn is_foreign_arch(arch: Option<&str>) -> bool {
+    arch.and_then(|arch| normalize_arch(arch).ok()).is_some_and(|arch| arch != std::env::consts::ARCH)
+}
+
+const BINFMT_REGISTER: &str = "/proc/sys/fs/binfmt_misc/register";
+
+/// binfmt_misc registration of qemu-user `interpreter` for ELF binaries of `arch`, with the
+/// magic and mask qemu's own qemu-binfmt-conf.sh uses. The F flag opens the interpreter right
+/// away, so it works inside the container without being bound in.
+fn binfmt_registration(arch: &str, interpreter: &str) -> Result<String> {
+    let (magic, mask) = match arch {
+        "x86_64" => (
+            r"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x3e\x00",
+            r"\xff\xff\xff\xff\xff\xfe\xfe\x00\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff\xff",
+        ),
+        "aarch64" => (
+            r"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\xb7\x00",
+            r"\xff\xff\xff\xff\xff\xff\xff\x00\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff\xff",
+        ),
+        other => anyhow::bail!("No binfmt pattern for {}", other),
+    };
+    Ok(format!(":qemu-{}:M::{}:{}:{}:F", arch, magic, mask, interpreter))
+}
+
+/// A qemu-user binfmt handler registered for one build, removed again when dropped.
+struct BinfmtRegistration {
+    handler: String,
+}
+
+impl BinfmtRegistration {
+    fn register(arch: &str) -> Result<Self> {
+        let interpreter = format!("/usr/bin/qemu-{}-static", arch);
+        if !std::path::Path::new(&interpreter).exists() {
+            anyhow::bail!("{} not found", interpreter);
+        }
+        std::fs::write(BINFMT_REGISTER, binfmt_registration(arch, &interpreter)?)
+            .with_context(|| format!("Failed to write {}", BINFMT_REGISTER))?;
+        Ok(BinfmtRegistration { handler: format!("/proc/sys/fs/binfmt_misc/qemu-{}", arch) })
+    }
+}
+
+impl Drop for BinfmtRegistration {
+    fn drop(&mut self) {
+        let _ = std::fs::write(&self.handler, "-1");
+    }
+}
+
+/// Fields of a binfmt_misc handler entry: whether it is enabled, its interpreter and flags.
+fn parse_binfmt(content: &str) -> (bool, Option<String>, String) {
+    let enabled = content.lines().next() == Some("enabled");
+    let interpreter = content.lines().find_map(|line| line.strip_prefix("interpreter ")).map(str::to_string);
+    let flags = content.lines().find_map(|line| line.strip_prefix("flags: ")).unwrap_or("").to_string();
+    (enabled, interpreter, flags)
+}
+
+/// For a build targeting a foreign `arch`, checks that qemu-user is registered with binfmt_misc
+/// and returns its interpreter if the container needs it bound in. Handlers registered with the
+/// F flag (as qemu-user-static's are) are opened at registration and work inside any chroot.
+/// Without a handler, one is registered for qemu-user-static's binary and returned for removal
+/// after the build.
+fn cross_arch_interpreter(arch: Option<&str>) -> Result<(Option<String>, Option<BinfmtRegistration>)> {
+    let Some(arch) = arch else { return Ok((None, None)) };
+    let arch = normalize_arch(arch)?;
+    if arch == std::env::consts::ARCH {
+        return Ok((None, None));
+    }
+
+    let handler = format!("/proc/sys/fs/binfmt_misc/qemu-{}", arch);
+    let Ok(content) = std::fs::read_to_string(&handler) else {
+        let registration = BinfmtRegistration::register(arch).map_err(|e| anyhow::anyhow!(
+            "Building {} images on a {} host needs a qemu-user binfmt handler, but {} is not registered and \
+             registering one failed: {:#}. Install qemu-user-static (e.g. `apt install qemu-user-static binfmt-support`).",
+            arch, std::env::consts::ARCH, handler, e
+        ))?;
+        return Ok((None, Some(registration)));
+    };
+    match parse_binfmt(&content) {
+        (false, _, _) => anyhow::bail!("The binfmt handler {} is disabled. Enable it with `echo 1 > {}`.", handler, handler),
+        (true, _, flags) if flags.contains('F') => Ok((None, None)),
+        (true, Some(interpreter), _) => Ok((Some(interpreter), None)),
+        (true, None, _) => anyhow::bail!("Could not read the interpreter of binfmt handler {}", handler),
+    }
+}
+
+/// Fails unless the filesystem mounted at `root` looks like a Linux root: readable, with `etc`
+/// and `usr` directories and a `bin` directory or (usrmerge) symlink.
+fn check_root_layout(root: &str, base_image: &str) -> Result<()> {
+    let corrupt = |problem: String| {
+        anyhow::anyhow!(
+            "Base image '{}' does not look like a root filesystem: {}. It may be corrupt; check it with `e2fsck -fn` \
+             or rebuild it.",
+            base_image, problem
+        )
+    };
+    std::fs::read_dir(root).map_err(|e| corrupt(format!("its root directory is unreadable ({})", e)))?;
+    for dir in ["etc", "usr", "bin"] {
+        let path = format!("{}/{}", root, dir);
+        match std::fs::symlink_metadata(&path) {
+            Ok(meta) if meta.is_dir() || (dir == "bin" && meta.file_type().is_symlink()) => {}
+            Ok(_) => return Err(corrupt(format!("/{} is not a directory", dir))),
+            Err(e) => return Err(corrupt(format!("/{} is missing ({})", dir, e))),
+        }
+    }
+    Ok(())
+}
+
+/// Architecture named by the `e_machine` of an ELF header, or None for other files.
+pub fn elf_arch(header: &[u8]) -> Option<&'static str> {
+    if header.len() < 20 || &header[..4] != b"\x7fELF" {
+        return None;
+    }
+    // e_machine, in the file's own byte order (ELFDATA2LSB is 1)
+    let machine = if header[5] == 1 { u16::from_le_bytes([header[18], header[19]]) } else { u16::from_be_bytes([header[18], header[19]]) };
+    match machine {
+        0x3e => Some("x86_64"),
+        0xb7 => Some("aarch64"),
+        _ => None,
+    }
+}
+
+/// ELF machine of the first of a few standard binaries found in the root filesystem at `root`.
+fn root_elf_arch(root: &str) -> Option<&'static str> {
+    ["usr/bin/env", "bin/ls", "bin/busybox"].iter().find_map(|binary| {
+        let mut header = [0u8; 20];
+        let mut file = std::fs::File::open(format!("{}/{}", root, binary)).ok()?;
+        std::io::Read::read_exact(&mut file, &mut header).ok()?;
+        elf_arch(&header)
+    })
+}
+
+/// Architecture of image `image_name`, from its sidecar metadata. Images from before it was
+/// recorded are mounted read-only to look at their binaries, and the result is saved.
+pub fn image_arch(image_name: &str) -> Option<String> {
+    let mut meta = load_image_meta(image_name);
+    if meta.arch.is_none() {
+        let image_ext4 = assets::get_asset_path(&format!("{}.ext4", image_name));
+        let detected = with_mounted_image(&image_ext4, "loop,ro", |mount_dir| Ok(root_elf_arch(mount_dir))).ok().flatten()?;
+        meta.arch = Some(detected.to_string());
+        let _ = save_image_meta(image_name, &meta);
+    }
+    meta.arch
+}
+
+/// Fails if the base image mounted at `root` was built for another architecture than `arch`.
+fn check_image_arch(root: &str, base_image: &str, arch: &str) -> Result<()> {
+    match root_elf_arch(root) {
+        Some(found) if found != arch => anyhow::bail!(
+            "Base image '{}' contains {} binaries, not {}. Pass --from with an {} image.",
+            base_image, found, arch, arch
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// The local build cache shared by every build on this host.
+pub fn build_cache_dir() -> String {
+    assets::get_asset_path("build-cache")
+}
+
+/// Loop mounts `image_ext4` at a scratch directory, runs `f` on it and unmounts again. Calls
+/// may be nested to work on several images at once.
+pub fn with_mounted_image<T>(image_ext4: &str, options: &str, f: impl FnOnce(&str) -> Result<T>) -> Result<T> {
+    static MOUNTS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    let seq = MOUNTS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let mount_dir = format!("/tmp/stoker-mnt-{}-{}", std::process::id(), seq);
+    std::fs::create_dir_all(&mount_dir)?;
+    let status = Command::new("mount").args(["-o", options, image_ext4, &mount_dir]).status()?;
+    if !status.success() {
+        let _ = std::fs::remove_dir(&mount_dir);
+        anyhow::bail!(StokerError::new(ErrorKind::NeedsRoot, format!("Failed to loop mount {}. Are you running as root?", image_ext4)));
+    }
+    let result = f(&mount_dir);
+    let _ = Command::new("umount").arg(&mount_dir).status();
+    let _ = std::fs::remove_dir(&mount_dir);
+    result
+}
+
+/// Extracts the cache tarball stored in `image` by `--cache-to` into `cache_dir`, on top of
+/// whatever the local cache already holds.
+fn import_cache(image: &str, cache_dir: &str) -> Result<()> {
+    let image_ext4 = assets::get_asset_path(&format!("{}.ext4", image));
+    if !std::path::Path::new(&image_ext4).exists() {
+        anyhow::bail!(StokerError::new(ErrorKind::NotFound, format!("Cache image '{}' not found at {}.", image, image_ext4)));
+    }
+    std::fs::create_dir_all(cache_dir)?;
+    with_mounted_image(&image_ext4, "loop,ro", |mount_dir| {
+        let tarball = format!("{}/{}", mount_dir, CACHE_IMAGE_TARBALL);
+        if !std::path::Path::new(&tarball).exists() {
+            anyhow::bail!("Image '{}' holds no build cache. Create one with `stoker build --cache-to {}`.", image, image);
+        }
+        let status = Command::new("tar").args(["-xf", &tarball, "-C", cache_dir]).status()
+            .context("Failed to execute tar")?;
+        if !status.success() {
+            anyhow::bail!("Failed to extract the build cache from image '{}'", image);
+        }
+        Ok(())
+    })
+}
+
+/// Packages `cache_dir` into a fresh ext4 image named `image` that `--cache-from` can import.
+fn export_cache(cache_dir: &str, image: &str) -> Result<()> {
+    std::fs::create_dir_all(cache_dir)?;
+    let tarball = format!("/tmp/stoker-cache-{}.tar", std::process::id());
+    let status = Command::new("tar").args(["-cf", &tarball, "-C", cache_dir, "."]).status()
+        .context("Failed to execute tar")?;
+    if !status.success() {
+        let _ = std::fs::remove_file(&tarball);
+        anyhow::bail!("Failed to archive the build cache at {}", cache_dir);
+    }
+
+    let result = (|| {
+        // Room for the tarball plus ext4's own metadata
+        let size = std::fs::metadata(&tarball)?.len() + 64 * 1024 * 1024;
+        let image_ext4 = assets::get_output_path(&format!("{}.ext4", image));
+        assets::ensure_writable_image_path(&image_ext4)?;
+        assets::ensure_free_space(&assets::get_output_path(""), size)?;
+        let _ = std::fs::remove_file(&image_ext4);
+        let status = Command::new("truncate").args(["-s", &size.to_string(), &image_ext4]).status()?;
+        if !status.success() {
+            anyhow::bail!("Failed to allocate {}", image_ext4);
+        }
+        let status = Command::new("mkfs.ext4").args(["-q", "-F", &image_ext4]).status()
+            .context("Failed to execute mkfs.ext4")?;
+        if !status.success() {
+            anyhow::bail!("Failed to format {}", image_ext4);
+        }
+        with_mounted_image(&image_ext4, "loop", |mount_dir| {
+            let target = format!("{}/{}", mount_dir, CACHE_IMAGE_TARBALL);
+            std::fs::create_dir_all(format!("{}/.stoker-cache", mount_dir))?;
+            std::fs::copy(&tarball, &target).with_context(|| format!("Failed to copy the build cache into {}", image_ext4))?;
+            Ok(())
+        })
+    })();
+    let _ = std::fs::remove_file(&tarball);
+    result
+}
+
+/// Space for a root filesystem extracted from a tarball of `compressed_size` bytes: gzip'd
+/// rootfs tarballs typically expand about 3x, plus headroom for ext4 metadata and later writes.
+fn rootfs_image_size(compressed_size: u64) -> u64 {
+    (compressed_size * 4).max(64 * 1024 * 1024)
+}
+
+/// Creates image `name` from a plain root filesystem tarball such as Alpine's minirootfs:
+/// formats a fresh ext4, extracts the tarball into it and verifies it with e2fsck.
+pub fn build_from_rootfs_tarball(tarball: &std::path::Path, name: &str) -> Result<()> {
+    let compressed_size = std::fs::metadata(tarball)
+        .with_context(|| format!("Could not read rootfs tarball {}", tarball.display()))?
+        .len();
+    let image_ext4 = assets::get_asset_path(&format!("{}.ext4", name));
+    if std::path::Path::new(&image_ext4).exists() {
+        anyhow::bail!(StokerError::new(ErrorKind::AlreadyExists, format!("Image '{}' already exists at {}. Remove it first or pick another --to.", name, image_ext4)));
+    }
+    let image_ext4 = assets::get_output_path(&format!("{}.ext4", name));
+    assets::ensure_writable_image_path(&image_ext4)?;
+    let size = rootfs_image_size(compressed_size);
+    assets::ensure_free_space(&assets::get_output_path(""), size)?;
+
+    let result = (|| {
+        println!("Creating a {} MiB ext4 for image {}...", size / (1024 * 1024), name);
+        let status = Command::new("truncate").args(["-s", &size.to_string(), &image_ext4]).status()?;
+        if !status.success() {
+            anyhow::bail!("Failed to allocate {}", image_ext4);
+        }
+        let status = Command::new("mkfs.ext4").args(["-q", "-F", &image_ext4]).status()
+            .context("Failed to execute mkfs.ext4. Is e2fsprogs installed?")?;
+        if !status.success() {
+            anyhow::bail!("Failed to format {}", image_ext4);
+        }
+
+        let arch = with_mounted_image(&image_ext4, "loop", |mount_dir| {
+            println!("Extracting {}...", tarball.display());
+            // GNU tar detects the compression itself; keep the tarball's owners and modes
+            let status = Command::new("tar")
+                .arg("-xpf")
+                .arg(tarball)
+                .args(["--numeric-owner", "-C", mount_dir])
+                .status()
+                .context("Failed to execute tar")?;
+            if !status.success() {
+                anyhow::bail!("Failed to extract {}. Is it a (gzip/xz/bzip2-compressed) tar archive?", tarball.display());
+            }
+            // The guest kernel mounts devtmpfs, procfs and sysfs over these
+            for dir in ["dev", "proc", "sys"] {
+                let path = format!("{}/{}", mount_dir, dir);
+                if std::fs::symlink_metadata(&path).is_ok_and(|meta| !meta.is_dir()) {
+                    anyhow::bail!("/{} in {} is not a directory", dir, tarball.display());
+                }
+                let _ = std::fs::remove_dir_all(&path);
+                std::fs::create_dir(&path)?;
+            }
+            Ok(root_elf_arch(mount_dir))
+        })?;
+
+        let status = Command::new("e2fsck").args(["-f", "-n", &image_ext4]).stdout(std::process::Stdio::null()).status()
+            .context("Failed to execute e2fsck")?;
+        if !status.success() {
+            anyhow::bail!("e2fsck found errors in {} after extraction", image_ext4);
+        }
+        // ext4 labels are limited to 16 bytes
+        let label: String = name.chars().take(16).collect();
+        let status = Command::new("tune2fs").args(["-L", &label, &image_ext4]).stdout(std::process::Stdio::null()).status()
+            .context("Failed to execute tune2fs")?;
+        if !status.success() {
+            anyhow::bail!("Failed to label {}", image_ext4);
+        }
+
+        let _ = std::fs::remove_file(history_path(name));
+        record_history(name, "CONVERT", &tarball.display().to_string(), std::fs::metadata(&image_ext4)?.len() as i64)?;
+        save_image_meta(name, &ImageMeta { arch: arch.map(str::to_string), ..Default::default() })
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&image_ext4);
+    }
+    result?;
+    println!("Image {} created at {}", name, image_ext4);
+    Ok(())
+}
+
+/// Rewrites `image_ext4` into a freshly formatted, minimally sized ext4 holding only its live files.
+fn squash_image(image_ext4: &str, image_name: &str, progress: &mut Progress) -> Result<()> {
+    progress.start("squash", &format!("Squashing image {}...", image_name));
+    let size_before = std::fs::metadata(image_ext4)?.len();
+
+    let squashed_ext4 = format!("{}.squash", image_ext4);
+    let size_arg = size_before.to_string();
+    let _ = std::fs::remove_file(&squashed_ext4);
+    let status = Command::new("truncate").args(["-s", &size_arg, &squashed_ext4]).status()?;
+    if !status.success() {
+        anyhow::bail!("Failed to allocate squashed image {}", squashed_ext4);
+    }
+    let status = Command::new("mkfs.ext4").args(["-q", "-F", &squashed_ext4]).stdout(progress.tool_stdout()).status()
+        .context("Failed to execute mkfs.ext4. Is e2fsprogs installed?")?;
+    if !status.success() {
+        let _ = std::fs::remove_file(&squashed_ext4);
+        anyhow::bail!("Failed to format squashed image {}", squashed_ext4);
+    }
+
+    let src_dir = format!("/tmp/stoker-squash-src-{}", image_name);
+    let dst_dir = format!("/tmp/stoker-squash-dst-{}", image_name);
+    let _ = std::fs::create_dir_all(&src_dir);
+    let _ = std::fs::create_dir_all(&dst_dir);
+
+    let result = mount_and_copy(image_ext4, &squashed_ext4, &src_dir, &dst_dir, progress);
+
+    let _ = Command::new("umount").arg(&src_dir).status();
+    let _ = Command::new("umount").arg(&dst_dir).status();
+    let _ = std::fs::remove_dir_all(&src_dir);
+    let _ = std::fs::remove_dir_all(&dst_dir);
+
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&squashed_ext4);
+        return Err(e);
+    }
+
+    // Shrink the filesystem and backing file down to the space actually in use
+    let _ = Command::new("e2fsck").args(["-f", "-y", &squashed_ext4]).stdout(progress.tool_stdout()).status();
+    let _ = Command::new("resize2fs").args(["-M", &squashed_ext4]).stdout(progress.tool_stdout()).status();
+    if let Some(fs_size) = ext4_filesystem_size(&squashed_ext4) {
+        std::fs::OpenOptions::new().write(true).open(&squashed_ext4)?.set_len(fs_size)?;
+    }
+
+    std::fs::rename(&squashed_ext4, image_ext4).context("Failed to replace image with squashed copy")?;
+
+    let size_after = std::fs::metadata(image_ext4)?.len();
+    progress.message(&format!(
+        "Squashed {}: {:.2} MB -> {:.2} MB (saved {:.2} MB)",
+        image_name,
+        size_before as f64 / 1_048_576.0,
+        size_after as f64 / 1_048_576.0,
+        size_before.saturating_sub(size_after) as f64 / 1_048_576.0
+    ));
+    Ok(())
+}
+
+pub fn meta_path(image_name: &str) -> String {
+    assets::get_asset_path(&format!("{}.meta.json", image_name))
+}
+
+/// Reads an image's metadata sidecar; images without one simply have no labels.
+pub fn load_image_meta(image_name: &str) -> ImageMeta {
+    std::fs::read_to_string(meta_path(image_name))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn save_image_meta(image_name: &str, meta: &ImageMeta) -> Result<()> {
+    std::fs::write(meta_path(image_name), serde_json::to_string_pretty(meta)?)
+        .with_context(|| format!("Failed to write {}", meta_path(image_name)))
+}
+
+/// Prints an image's location, size and labels as JSON.
+pub fn inspect_image(image_name: &str) -> Result<()> {
+    let image_path = assets::get_asset_path(&format!("{}.ext4", image_name));
+    let image_meta = std::fs::metadata(&image_path)
+        .with_context(|| StokerError::new(ErrorKind::NotFound, format!("Image '{}' not found at {}", image_name, image_path)))?;
+    let ImageMeta { labels, arch: image_meta_arch } = load_image_meta(image_name);
+    let info = serde_json::json!({
+        "name": image_name,
+        "path": image_path,
+        "size_bytes": image_meta.len(),
+        "created": labels.get(LABEL_CREATED),
+        "description": labels.get(LABEL_DESCRIPTION),
+        "source": labels.get(LABEL_SOURCE),
+        "architecture": image_meta_arch,
+        "labels": labels,
+    });
+    println!("{}", serde_json::to_string_pretty(&info)?);
+    Ok(())
+}
+
+/// Formats Unix seconds as an RFC 3339 UTC timestamp, as OCI's `created` label expects.
+fn format_rfc3339(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // Civil-from-days conversion (proleptic Gregorian calendar)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+/// Splits a `key=value` label. Keys may use reverse-DNS form such as `org.opencontainers.image.source`.
+fn parse_label(label: &str) -> Result<(&str, &str)> {
+    match label.split_once('=') {
+        Some((key, value)) if !key.is_empty() && !key.chars().any(char::is_whitespace) => Ok((key, value)),
+        _ => anyhow::bail!("Invalid label '{}'. Expected key=value.", label),
+    }
+}
+
+fn history_path(image_name: &str) -> String {
+    assets::get_asset_path(&format!("{}.history.jsonl", image_name))
+}
+
+fn record_history(image_name: &str, directive: &str, content: &str, size_delta: i64) -> Result<()> {
+    let entry = HistoryEntry {
+        directive: directive.to_string(),
+        content: content.to_string(),
+        size_delta,
+        created_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    };
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(history_path(image_name))?;
+    writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+    Ok(())
+}
+
+/// Bytes in use on the filesystem mounted at `path`, or 0 if it cannot be queried.
+fn filesystem_used_bytes(path: &str) -> u64 {
+    match assets::statvfs(path) {
+        Ok(stat) => (stat.f_blocks - stat.f_bfree) * stat.f_frsize,
+        Err(_) => 0,
+    }
+}
+
+pub fn show_history(image_name: &str, no_trunc: bool) -> Result<()> {
+    let image_path = assets::get_asset_path(&format!("{}.ext4", image_name));
+    let image_meta = std::fs::metadata(&image_path)
+        .with_context(|| StokerError::new(ErrorKind::NotFound, format!("Image '{}' not found at {}", image_name, image_path)))?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    println!("{:<10} {:<20} {:<15} CONTENT", "DIRECTIVE", "CREATED", "SIZE");
+
+    let history = match std::fs::read_to_string(history_path(image_name)) {
+        Ok(history) => history,
+        Err(_) => {
+            // Downloaded or hand-made images have no recorded provenance
+            let mtime = image_meta.modified()?.duration_since(UNIX_EPOCH)?.as_secs();
+            println!("{:<10} {:<20} {:<15} {}", "<missing>", format_age(now.saturating_sub(mtime)), format_size(image_meta.len() as i64), image_name);
+            return Ok(());
+        }
+    };
+
+    // Newest step first, matching `docker image history`
+    let entries: Vec<HistoryEntry> = history.lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    for entry in entries.iter().rev() {
+        let content = entry.content.split_whitespace().collect::<Vec<_>>().join(" ");
+        let content = if !no_trunc && content.chars().count() > 45 {
+            format!("{}...", content.chars().take(42).collect::<String>())
+        } else {
+            content
+        };
+        println!("{:<10} {:<20} {:<15} {}",
+            entry.directive,
+            format_age(now.saturating_sub(entry.created_at)),
+            format_size(entry.size_delta),
+            content
+        );
+    }
     Ok(())
 }
 
-fn execute_chroot_build(mount_dir: &str, script_path: &str) -> Result<()> {
+fn format_size(bytes: i64) -> String {
+    format!("{:.2} MB", bytes as f64 / 1_048_576.0)
+}
+
+fn format_age(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{} seconds ago", secs),
+        60..=3599 => format!("{} minutes ago", secs / 60),
+        3600..=86399 => format!("{} hours ago", secs / 3600),
+        _ => format!("{} days ago", secs / 86400),
+    }
+}
+
+fn find_in_path(program: &str) -> Option<std::path::PathBuf> {
+    std::env::var_os("PATH").and_then(|paths| {
+        std::env::split_paths(&paths)
+            .map(|dir| dir.join(program))
+            .find(|candidate| candidate.is_file())
+    })
+}
+
+/// Whether systemd is the host's init, as sd_booted(3) checks.
+fn host_runs_systemd() -> bool {
+    std::path::Path::new("/run/systemd/system").exists()
+}
+
+/// Decides up front whether the build must fall back to chroot, failing with what to install
+/// when neither sandbox is available or the build needs nspawn's network isolation.
+fn use_chroot_fallback(nspawn_found: bool, chroot_found: bool, network: &str) -> Result<bool> {
+    if nspawn_found {
+        return Ok(false);
+    }
+    if !chroot_found {
+        anyhow::bail!("systemd-nspawn not found; install systemd-container (e.g. `apt install systemd-container`)");
+    }
+    if !matches!(network, "full" | "host") {
+        anyhow::bail!(
+            "systemd-nspawn not found; install systemd-container. --network {} needs it: the chroot fallback can only share the host network.",
+            network
+        );
+    }
+    Ok(true)
+}
+
+/// `systemd-nspawn`, told not to register with systemd-machined when the host doesn't run systemd.
+fn nspawn_command() -> Command {
+    let mut nspawn = Command::new("systemd-nspawn");
+    if !host_runs_systemd() {
+        nspawn.arg("--register=no");
+    }
+    nspawn
+}
+
+const CHROOT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// `chroot` into `mount_dir` running `program` with the environment nspawn would give it: a
+/// clean PATH, the build args and the cache location.
+fn chroot_command(mount_dir: &str, opts: &BuildOptions, ctx: &BuildContext, program: &str) -> Command {
+    let mut chroot = Command::new("chroot");
+    chroot.args([mount_dir, program]).env_clear().env("PATH", CHROOT_PATH).env("HOME", "/root");
+    if let Ok(term) = std::env::var("TERM") {
+        chroot.env("TERM", term);
+    }
+    if ctx.cache_dir.is_some() {
+        chroot.env("STOKER_CACHE", CONTAINER_CACHE_DIR);
+    }
+    if opts.ssh_agent.is_some() {
+        chroot.env("SSH_AUTH_SOCK", CONTAINER_SSH_AUTH_SOCK);
+    }
+    for (key, value) in opts.build_args.iter().filter_map(|arg| arg.split_once('=')) {
+        chroot.env(key, value);
+    }
+    chroot
+}
+
+/// Mounts giving a chroot what systemd-nspawn would set up: /dev, /proc, /sys, the host's DNS
+/// configuration, the build cache, the binfmt interpreter, `--bind`s and the `--ssh` agent.
+/// Unmounted in reverse order on drop, which also removes the files created to mount over.
+struct ChrootMounts {
+    targets: Vec<String>,
+    placeholders: Vec<String>,
+}
+
+impl ChrootMounts {
+    fn setup(root: &str, opts: &BuildOptions, ctx: &BuildContext) -> Result<Self> {
+        let mut mounts = ChrootMounts { targets: Vec::new(), placeholders: Vec::new() };
+        mounts.rbind("/dev", &format!("{}/dev", root))?;
+        mounts.mount(&["-t", "proc", "proc"], &format!("{}/proc", root), true)?;
+        mounts.rbind("/sys", &format!("{}/sys", root))?;
+        // Like nspawn's --resolv-conf=copy-host; a symlink would resolve against the host
+        let resolv = format!("{}/etc/resolv.conf", root);
+        let _ = std::fs::remove_file(&resolv);
+        let _ = std::fs::copy("/etc/resolv.conf", &resolv);
+        if let Some(cache_dir) = &ctx.cache_dir {
+            mounts.mount(&["--bind", cache_dir], &format!("{}{}", root, CONTAINER_CACHE_DIR), true)?;
+        }
+        if let Some(interpreter) = &ctx.binfmt_interpreter {
+            mounts.mount(&["--bind", interpreter], &format!("{}{}", root, interpreter), false)?;
+        }
+        for bind in &opts.binds {
+            let (host, guest) = bind.split_once(':').unwrap_or((bind, bind));
+            let is_dir = std::path::Path::new(host).is_dir();
+            mounts.mount(&["--bind", host], &format!("{}{}", root, guest), is_dir)?;
+        }
+        if let Some(socket) = &opts.ssh_agent {
+            mounts.mount(&["--bind", socket], &format!("{}{}", root, CONTAINER_SSH_AUTH_SOCK), false)?;
+        }
+        Ok(mounts)
+    }
+
+    /// Recursively binds `source` at `target` and makes the copy a slave, so unmounting it can't
+    /// propagate back and unmount the host's own submounts (e.g. /dev/pts or /sys/fs/cgroup)
+    /// on hosts where mounts are shared, as systemd makes them.
+    fn rbind(&mut self, source: &str, target: &str) -> Result<()> {
+        self.mount(&["--rbind", source], target, true)?;
+        let status = Command::new("mount").args(["--make-rslave", target]).status().context("Failed to execute mount")?;
+        if !status.success() {
+            // Unmounting a still-shared copy is what must not happen; leave it for the user
+            self.targets.pop();
+            anyhow::bail!("`mount --make-rslave {}` failed; unmount {} by hand once nothing shares it", target, target);
+        }
+        Ok(())
+    }
+
+    /// Runs `mount <args> <target>`, creating `target` as a directory or an empty file.
+    fn mount(&mut self, args: &[&str], target: &str, is_dir: bool) -> Result<()> {
+        if is_dir {
+            std::fs::create_dir_all(target)?;
+        } else if !std::path::Path::new(target).exists() {
+            if let Some(parent) = std::path::Path::new(target).parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(target, "")?;
+            self.placeholders.push(target.to_string());
+        }
+        let status = Command::new("mount").args(args).arg(target).status().context("Failed to execute mount")?;
+        if !status.success() {
+            anyhow::bail!("`mount {} {}` failed", args.join(" "), target);
+        }
+        self.targets.push(target.to_string());
+        Ok(())
+    }
+}
+
+impl Drop for ChrootMounts {
+    fn drop(&mut self) {
+        // Lazy unmounts detach the recursive /dev and /sys binds in one go
+        for target in self.targets.iter().rev() {
+            let _ = Command::new("umount").args(["-l", target]).status();
+        }
+        for placeholder in &self.placeholders {
+            let _ = std::fs::remove_file(placeholder);
+        }
+    }
+}
+
+fn execute_chroot_build(mount_dir: &str, script_path: &str, opts: &BuildOptions, ctx: &BuildContext, progress: &mut Progress) -> Result<()> {
     // Read the script into memory
     let script_content = std::fs::read_to_string(script_path)
         .context(format!("Could not read build script: {}", script_path))?;
@@ -58,19 +955,580 @@ fn execute_chroot_build(mount_dir: &str, script_path: &str) -> Result<()> {
     std::fs::write(&guest_script_path, script_content)?;
     
     // Set executable
-    let _ = Command::new("chmod").args(&["+x", &guest_script_path]).status();
+    let _ = Command::new("chmod").args(["+x", &guest_script_path]).status();
     
-    println!("Executing build script inside systemd-nspawn container...");
+    if ctx.chroot_fallback {
+        progress.start("run", "Executing build script in a chroot...");
+        let _mounts = ChrootMounts::setup(mount_dir, opts, ctx)?;
+        let mut chroot = chroot_command(mount_dir, opts, ctx, "/stoker-build.sh");
+        // chroot_command starts from a cleared environment
+        let status = run_logged(&mut chroot, true, opts, progress).context("Failed to execute chroot")?;
+        if !status.success() {
+            anyhow::bail!("Build script failed inside the chroot.");
+        }
+        return Ok(());
+    }
+
+    let bridge = setup_build_network(&opts.network, progress)?;
+
+    progress.start("run", "Executing build script inside systemd-nspawn container...");
     
     // Use systemd-nspawn instead of raw chroot because it automatically mounts /dev, /proc, /sys correctly for networking and apt-get isolation
-    let status = Command::new("systemd-nspawn")
-        .args(&["-D", mount_dir, "--as-pid2", "/stoker-build.sh"])
-        .status()
-        .context("Failed to execute systemd-nspawn. Is it installed inside the VM?")?;
+    let mut nspawn = nspawn_command();
+    nspawn.args(nspawn_args(mount_dir, opts, ctx, bridge.as_deref()));
+    let status = run_logged(&mut nspawn, false, opts, progress).context("Failed to execute systemd-nspawn");
+    if let Some(bridge) = &bridge {
+        teardown_build_network(bridge, progress);
+    }
+    let status = status?;
         
     if !status.success() {
-        anyhow::bail!("Build script failed inside the container.");
+        if opts.network == "none" {
+            anyhow::bail!("Build script failed inside the container (built with --network none, so no network access was available).");
+        }
+        anyhow::bail!("Build script failed inside the container. If it failed resolving hostnames, check that the host's /etc/resolv.conf points at a reachable nameserver.");
     }
     
     Ok(())
 }
+
+/// Opens an interactive shell in the failed build's filesystem and waits for the user to exit it.
+fn debug_shell(mount_dir: &str, opts: &BuildOptions, ctx: &BuildContext, progress: &Progress) {
+    progress.message(&format!(
+        "--debug: opening a shell in the build container at {}. Exit it to unmount and discard the build.",
+        mount_dir
+    ));
+    let status = if ctx.chroot_fallback {
+        ChrootMounts::setup(mount_dir, opts, ctx)
+            .and_then(|_mounts| Ok(chroot_command(mount_dir, opts, ctx, "/bin/bash").status()?))
+    } else {
+        nspawn_command().args(debug_shell_args(mount_dir, opts, ctx)).status().map_err(anyhow::Error::from)
+    };
+    if let Err(e) = status {
+        progress.message(&format!("Failed to start the debug shell: {}", e));
+    }
+}
+
+/// The build's container setup with an interactive shell in place of the build script. A
+/// `private` build's bridge is gone by now, so it shares the host network instead.
+fn debug_shell_args(mount_dir: &str, opts: &BuildOptions, ctx: &BuildContext) -> Vec<String> {
+    let mut args = nspawn_args(mount_dir, opts, ctx, None);
+    args.pop();
+    args.push("/bin/bash".to_string());
+    args
+}
+
+fn build_log_path(image_name: &str) -> String {
+    assets::get_asset_path(&format!("{}.buildlog", image_name))
+}
+
+/// Quotes `word` for a POSIX shell when it contains anything beyond a safe set of characters.
+fn shell_quote(word: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !word.is_empty() && word.chars().all(safe) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+/// `command` as a line that can be pasted into a shell to rerun it: working directory, the
+/// environment variables set on it and its arguments. Variables it inherits unchanged are left out.
+/// `clears_env` says whether it was built with `env_clear()`, which `Command` doesn't expose.
+fn describe_command(command: &Command, clears_env: bool) -> String {
+    let cwd = command.get_current_dir().map(std::path::Path::to_path_buf)
+        .or_else(|| std::env::current_dir().ok())
+        .unwrap_or_default();
+    let mut words = vec![format!("cd {} &&", shell_quote(&cwd.to_string_lossy()))];
+    let envs: Vec<_> = command.get_envs().collect();
+    if !envs.is_empty() || clears_env {
+        words.push("env".to_string());
+        if clears_env {
+            words.push("-i".to_string());
+        }
+        for (key, value) in envs {
+            if let Some(value) = value {
+                words.push(shell_quote(&format!("{}={}", key.to_string_lossy(), value.to_string_lossy())));
+            }
+        }
+    }
+    words.push(shell_quote(&command.get_program().to_string_lossy()));
+    words.extend(command.get_args().map(|arg| shell_quote(&arg.to_string_lossy())));
+    words.join(" ")
+}
+
+/// Runs the build script's container command, recording the command line and its exit status in
+/// `<image>.buildlog` next to the image. When the script's output is captured (`--progress json`)
+/// it is recorded too; otherwise the script keeps the terminal as its stdio.
+fn run_logged(command: &mut Command, clears_env: bool, opts: &BuildOptions, progress: &Progress) -> Result<std::process::ExitStatus> {
+    let command_line = describe_command(command, clears_env);
+    if opts.verbose {
+        progress.message(&format!("+ {}", command_line));
+    }
+    let log_path = build_log_path(&opts.image_name);
+    let mut log = std::fs::File::create(&log_path).with_context(|| format!("Failed to create {}", log_path))?;
+    writeln!(log, "$ {}", command_line)?;
+    let status = if progress.captures_output() {
+        run_captured(command, progress, &mut log)
+    } else {
+        command.status().map_err(anyhow::Error::from)
+    };
+    match &status {
+        Ok(status) => writeln!(log, "# {}", status)?,
+        Err(e) => writeln!(log, "# failed to run: {:#}", e)?,
+    }
+    status
+}
+
+/// Runs `command`, passing each line it writes to stdout or stderr to `progress.output` and
+/// appending it to `log`.
+fn run_captured(command: &mut Command, progress: &Progress, log: &mut dyn Write) -> Result<std::process::ExitStatus> {
+    use std::io::BufRead;
+    let mut child = command.stdout(std::process::Stdio::piped()).stderr(std::process::Stdio::piped()).spawn()?;
+    let (tx, rx) = std::sync::mpsc::channel();
+    let forward = |stream: Box<dyn std::io::Read + Send>, tx: std::sync::mpsc::Sender<String>| {
+        std::thread::spawn(move || {
+            for line in std::io::BufReader::new(stream).lines().map_while(Result::ok) {
+                let _ = tx.send(line);
+            }
+        })
+    };
+    let stdout = forward(Box::new(child.stdout.take().context("nspawn stdout not captured")?), tx.clone());
+    let stderr = forward(Box::new(child.stderr.take().context("nspawn stderr not captured")?), tx);
+    // Ends once both streams close and drop their senders
+    for line in rx {
+        progress.output(&line);
+        writeln!(log, "{}", line)?;
+    }
+    let _ = stdout.join();
+    let _ = stderr.join();
+    Ok(child.wait()?)
+}
+
+/// Reads the filesystem size (block count * block size) from the ext4 superblock.
+fn ext4_filesystem_size(image_ext4: &str) -> Option<u64> {
+    let output = Command::new("dumpe2fs").args(["-h", image_ext4]).output().ok()?;
+    let header = String::from_utf8_lossy(&output.stdout);
+    let field = |key: &str| -> Option<u64> {
+        header.lines()
+            .find_map(|line| line.strip_prefix(key))
+            .and_then(|value| value.trim().parse().ok())
+    };
+    Some(field("Block count:")? * field("Block size:")?)
+}
+
+fn mount_and_copy(src_ext4: &str, dst_ext4: &str, src_dir: &str, dst_dir: &str, progress: &Progress) -> Result<()> {
+    let status = Command::new("mount").args(["-o", "loop,ro", src_ext4, src_dir]).status()?;
+    if !status.success() {
+        anyhow::bail!("Failed to loop mount {}", src_ext4);
+    }
+    let status = Command::new("mount").args(["-o", "loop", dst_ext4, dst_dir]).status()?;
+    if !status.success() {
+        anyhow::bail!("Failed to loop mount {}", dst_ext4);
+    }
+
+    progress.message("Copying final filesystem state into a clean ext4...");
+    let status = Command::new("rsync")
+        .args(["--archive", "--hard-links", "--acls", "--xattrs", "--delete"])
+        .arg(format!("{}/", src_dir))
+        .arg(format!("{}/", dst_dir))
+        .status()
+        .context("Failed to execute rsync. Is it installed?")?;
+    if !status.success() {
+        anyhow::bail!("rsync failed while squashing the image");
+    }
+    Ok(())
+}
+
+/// Splits a `KEY=VALUE` build argument, requiring `KEY` to be a valid shell variable name.
+fn parse_build_arg(arg: &str) -> Result<(&str, &str)> {
+    let (key, value) = arg.split_once('=')
+        .with_context(|| format!("Invalid build arg '{}'. Expected KEY=VALUE.", arg))?;
+    let valid = key.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        anyhow::bail!("Invalid build arg name '{}'. Use letters, digits and underscores.", key);
+    }
+    Ok((key, value))
+}
+
+/// Prefix of the /24 used by the temporary bridge of a `private` build network.
+fn bridge_subnet(bridge: &str) -> String {
+    let octet = bridge.trim_start_matches("stoker-bld");
+    format!("10.213.{}", octet)
+}
+
+/// The lowest-numbered build bridge that is not an existing interface and whose /24 none of the
+/// host's `addresses` fall in.
+fn free_build_bridge(interfaces: &[String], addresses: &[std::net::Ipv4Addr]) -> Option<String> {
+    (0..=255u8).map(|i| format!("stoker-bld{}", i)).find(|bridge| {
+        let subnet = format!("{}.", bridge_subnet(bridge));
+        !interfaces.contains(bridge) && !addresses.iter().any(|addr| addr.to_string().starts_with(&subnet))
+    })
+}
+
+/// Creates the temporary NATed bridge for a `private` build network and returns its name.
+/// Other modes need no host-side setup.
+pub fn setup_build_network(mode: &str, progress: &Progress) -> Result<Option<String>> {
+    if mode != "private" {
+        return Ok(None);
+    }
+
+    // Pick and create the bridge under a lock, so concurrent builds each get their own bridge and
+    // subnet; once created, it is visible to the next build probing for a free one
+    let bridge = {
+        let _lock = crate::firecracker::FileLock::acquire("/tmp/stoker-build-network.lock")?;
+        let addresses: Vec<_> = network::host_ipv4_addresses().into_iter().map(|(_, addr, _)| addr).collect();
+        let bridge = free_build_bridge(&network::interface_names(), &addresses)
+            .context("All 256 build bridges are in use; wait for other builds to finish")?;
+        network::run_ip_blocking(&["link", "add", "name", &bridge, "type", "bridge"])?;
+        bridge
+    };
+    let subnet = bridge_subnet(&bridge);
+    progress.message(&format!("Creating build bridge {} ({}.0/24)...", bridge, subnet));
+
+    let configured = network::run_ip_blocking(&["addr", "add", &format!("{}.1/24", subnet), "dev", &bridge])
+        .and_then(|_| network::run_ip_blocking(&["link", "set", &bridge, "up"]))
+        .and_then(|_| {
+            std::fs::write("/proc/sys/net/ipv4/ip_forward", "1").context("Failed to enable IP forwarding")?;
+            let status = Command::new("iptables")
+                .args(["-t", "nat", "-A", "POSTROUTING", "-s", &format!("{}.0/24", subnet), "!", "-o", &bridge, "-j", "MASQUERADE"])
+                .status()
+                .context("Failed to execute iptables")?;
+            if !status.success() {
+                anyhow::bail!("Failed to add NAT rule for build bridge {}", bridge);
+            }
+            Ok(())
+        });
+    if let Err(e) = configured {
+        teardown_build_network(&bridge, progress);
+        return Err(e);
+    }
+    Ok(Some(bridge))
+}
+
+/// Removes the bridge and NAT rule created by `setup_build_network`.
+fn teardown_build_network(bridge: &str, progress: &Progress) {
+    let subnet = bridge_subnet(bridge);
+    let _ = Command::new("iptables")
+        .args(["-t", "nat", "-D", "POSTROUTING", "-s", &format!("{}.0/24", subnet), "!", "-o", bridge, "-j", "MASQUERADE"])
+        .status();
+    let _ = Command::new("ip").args(["link", "del", bridge]).status();
+    progress.message(&format!("Removed build bridge {}", bridge));
+}
+
+fn nspawn_args(mount_dir: &str, opts: &BuildOptions, ctx: &BuildContext, bridge: Option<&str>) -> Vec<String> {
+    let mut args = vec!["-D".to_string(), mount_dir.to_string(), "--as-pid2".to_string()];
+    if opts.network == "none" {
+        // A veth with nothing on the host side: the container only reaches its own interfaces
+        args.push("--private-network".to_string());
+        args.push("--network-veth".to_string());
+    } else if let Some(bridge) = bridge {
+        args.push(format!("--network-bridge={}", bridge));
+        args.push("--resolv-conf=copy-host".to_string());
+    } else {
+        // Share the host network and its DNS configuration so package managers work
+        args.push("--resolv-conf=copy-host".to_string());
+    }
+    if let Some(interpreter) = &ctx.binfmt_interpreter {
+        args.push(format!("--bind-ro={}", interpreter));
+    }
+    if let Some(cache_dir) = &ctx.cache_dir {
+        args.push(format!("--bind={}:{}", cache_dir, CONTAINER_CACHE_DIR));
+        args.push(format!("--setenv=STOKER_CACHE={}", CONTAINER_CACHE_DIR));
+    }
+    for bind in &opts.binds {
+        args.push(format!("--bind={}", bind));
+    }
+    // nspawn mounts a tmpfs over /run, so nothing of the socket is left in the image
+    if let Some(socket) = &opts.ssh_agent {
+        args.push(format!("--bind={}:{}", socket, CONTAINER_SSH_AUTH_SOCK));
+        args.push(format!("--setenv=SSH_AUTH_SOCK={}", CONTAINER_SSH_AUTH_SOCK));
+    }
+    // Build args reach the script as environment variables, so `${KEY:-default}` works as usual
+    for arg in &opts.build_args {
+        args.push(format!("--setenv={}", arg));
+    }
+    if opts.privileged {
+        args.push("--capability=all".to_string());
+    } else {
+        for cap in &opts.capabilities {
+            args.push(format!("--capability={}", cap));
+        }
+        // Emulated debuggers and tracers need ptrace under qemu-user
+        if is_foreign_arch(opts.arch.as_deref()) && !opts.capabilities.iter().any(|cap| cap == "CAP_SYS_PTRACE") {
+            args.push("--capability=CAP_SYS_PTRACE".to_string());
+        }
+    }
+    match bridge {
+        // There is no network manager running as PID 2, so address the container side ourselves
+        Some(bridge) => {
+            let subnet = bridge_subnet(bridge);
+            args.push("/bin/sh".to_string());
+            args.push("-c".to_string());
+            args.push(format!(
+                "ip addr add {s}.2/24 dev host0 && ip link set host0 up && ip route add default via {s}.1 && exec /stoker-build.sh",
+                s = subnet
+            ));
+        }
+        None => args.push("/stoker-build.sh".to_string()),
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nspawn_args_passthrough() {
+        let opts = BuildOptions {
+            binds: vec!["/srv/mirror:/mnt/mirror".to_string()],
+            capabilities: vec!["CAP_SYS_ADMIN".to_string()],
+            network: "host".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(nspawn_args("/tmp/build", &opts, &BuildContext::default(), None), vec![
+            "-D", "/tmp/build", "--as-pid2",
+            "--resolv-conf=copy-host",
+            "--bind=/srv/mirror:/mnt/mirror",
+            "--capability=CAP_SYS_ADMIN",
+            "/stoker-build.sh",
+        ]);
+    }
+
+    #[test]
+    fn test_nspawn_args_cache() {
+        let ctx = BuildContext { cache_dir: Some("/var/lib/stoker/build-cache".to_string()), ..Default::default() };
+        let args = nspawn_args("/tmp/build", &BuildOptions::default(), &ctx, None);
+        assert!(args.contains(&"--bind=/var/lib/stoker/build-cache:/var/cache/stoker".to_string()));
+        assert!(args.contains(&"--setenv=STOKER_CACHE=/var/cache/stoker".to_string()));
+    }
+
+    #[test]
+    fn test_check_root_layout() {
+        let root = std::env::temp_dir().join(format!("stoker-layout-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        std::fs::create_dir_all(root.join("usr/bin")).unwrap();
+        let root_str = root.to_str().unwrap();
+        assert!(check_root_layout(root_str, "base").unwrap_err().to_string().contains("/bin is missing"));
+
+        // usrmerge layouts link /bin to usr/bin
+        std::os::unix::fs::symlink("usr/bin", root.join("bin")).unwrap();
+        assert!(check_root_layout(root_str, "base").is_ok());
+
+        std::fs::remove_dir(root.join("etc")).unwrap();
+        std::fs::write(root.join("etc"), "").unwrap();
+        assert!(check_root_layout(root_str, "base").unwrap_err().to_string().contains("/etc is not a directory"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_elf_arch() {
+        let mut header = [0u8; 20];
+        header[..4].copy_from_slice(b"\x7fELF");
+        header[5] = 1;
+        header[18] = 0xb7;
+        assert_eq!(elf_arch(&header), Some("aarch64"));
+        // Big-endian e_machine
+        header[5] = 2;
+        header[18] = 0;
+        header[19] = 0x3e;
+        assert_eq!(elf_arch(&header), Some("x86_64"));
+        assert_eq!(elf_arch(b"#!/bin/sh"), None);
+    }
+
+    #[test]
+    fn test_normalize_arch() {
+        assert_eq!(normalize_arch("amd64").unwrap(), "x86_64");
+        assert_eq!(normalize_arch("aarch64").unwrap(), "aarch64");
+        assert_eq!(normalize_arch("arm64").unwrap(), "aarch64");
+        assert!(normalize_arch("riscv64").is_err());
+    }
+
+    #[test]
+    fn test_parse_binfmt() {
+        let content = "enabled\ninterpreter /usr/bin/qemu-aarch64-static\nflags: OCF\noffset 0\nmagic 7f454c46\n";
+        assert_eq!(parse_binfmt(content), (true, Some("/usr/bin/qemu-aarch64-static".to_string()), "OCF".to_string()));
+        let (enabled, _, flags) = parse_binfmt("disabled\ninterpreter /usr/bin/qemu-x86_64\nflags: \n");
+        assert!(!enabled);
+        assert_eq!(flags, "");
+    }
+
+    #[test]
+    fn test_nspawn_args_binfmt_interpreter() {
+        let ctx = BuildContext { binfmt_interpreter: Some("/usr/bin/qemu-aarch64".to_string()), ..Default::default() };
+        assert!(nspawn_args("/tmp/build", &BuildOptions::default(), &ctx, None).contains(&"--bind-ro=/usr/bin/qemu-aarch64".to_string()));
+    }
+
+    #[test]
+    fn test_cross_platform_build() {
+        assert_eq!(platform_arch("linux/amd64").unwrap(), "x86_64");
+        assert_eq!(platform_arch("linux/arm64").unwrap(), "aarch64");
+        assert!(platform_arch("amd64").is_err());
+        assert!(platform_arch("windows/amd64").is_err());
+
+        let registration = binfmt_registration("x86_64", "/usr/bin/qemu-x86_64-static").unwrap();
+        assert!(registration.starts_with(r":qemu-x86_64:M::\x7fELF\x02"));
+        assert!(registration.ends_with(":/usr/bin/qemu-x86_64-static:F"));
+        assert!(binfmt_registration("riscv64", "/usr/bin/qemu-riscv64-static").is_err());
+
+        let foreign = if std::env::consts::ARCH == "x86_64" { "aarch64" } else { "x86_64" };
+        let ptrace = "--capability=CAP_SYS_PTRACE".to_string();
+        let opts = BuildOptions { arch: Some(foreign.to_string()), ..Default::default() };
+        assert!(nspawn_args("/tmp/build", &opts, &BuildContext::default(), None).contains(&ptrace));
+        let opts = BuildOptions { arch: Some(std::env::consts::ARCH.to_string()), ..Default::default() };
+        assert!(!nspawn_args("/tmp/build", &opts, &BuildContext::default(), None).contains(&ptrace));
+    }
+
+    #[test]
+    fn test_rootfs_image_size() {
+        assert_eq!(rootfs_image_size(3 * 1024 * 1024), 64 * 1024 * 1024);
+        assert_eq!(rootfs_image_size(100 * 1024 * 1024), 400 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_use_chroot_fallback() {
+        assert!(!use_chroot_fallback(true, true, "private").unwrap());
+        assert!(use_chroot_fallback(false, true, "full").unwrap());
+        assert!(use_chroot_fallback(false, true, "host").unwrap());
+        let missing = use_chroot_fallback(false, false, "full").unwrap_err().to_string();
+        assert!(missing.contains("install systemd-container"));
+        assert!(use_chroot_fallback(false, true, "none").is_err());
+    }
+
+    #[test]
+    fn test_describe_command() {
+        let mut nspawn = Command::new("systemd-nspawn");
+        nspawn.args(["-D", "/tmp/build dir", "--setenv=GREETING=it's"]).current_dir("/srv");
+        assert_eq!(describe_command(&nspawn, false), "cd /srv && systemd-nspawn -D '/tmp/build dir' '--setenv=GREETING=it'\\''s'");
+
+        let opts = BuildOptions { build_args: vec!["VERSION=1.2".to_string()], ..Default::default() };
+        let chroot = chroot_command("/tmp/build", &opts, &BuildContext::default(), "/stoker-build.sh");
+        let line = describe_command(&chroot, true);
+        assert!(line.contains(" && env -i "), "{}", line);
+        assert!(line.contains(" VERSION=1.2 "), "{}", line);
+        assert!(line.ends_with(" chroot /tmp/build /stoker-build.sh"), "{}", line);
+    }
+
+    #[test]
+    fn test_ssh_agent_socket() {
+        assert_eq!(ssh_agent_socket("default", Some("/tmp/ssh-x/agent.1")).unwrap().as_deref(), Some("/tmp/ssh-x/agent.1"));
+        assert_eq!(ssh_agent_socket("default", None).unwrap(), None);
+        assert_eq!(ssh_agent_socket("default=/run/agent.sock", None).unwrap().as_deref(), Some("/run/agent.sock"));
+        assert!(ssh_agent_socket("github", Some("/tmp/agent")).is_err());
+
+        let opts = BuildOptions { ssh_agent: Some("/tmp/ssh-x/agent.1".to_string()), ..Default::default() };
+        let args = nspawn_args("/tmp/build", &opts, &BuildContext::default(), None);
+        assert!(args.contains(&"--bind=/tmp/ssh-x/agent.1:/run/ssh-auth.sock".to_string()));
+        assert!(args.contains(&"--setenv=SSH_AUTH_SOCK=/run/ssh-auth.sock".to_string()));
+        let chroot = chroot_command("/tmp/build", &opts, &BuildContext::default(), "/stoker-build.sh");
+        assert!(chroot.get_envs().any(|(k, v)| k == "SSH_AUTH_SOCK" && v == Some(std::ffi::OsStr::new(CONTAINER_SSH_AUTH_SOCK))));
+    }
+
+    #[test]
+    fn test_chroot_command() {
+        let opts = BuildOptions {
+            build_args: vec!["VERSION=1.2".to_string()],
+            ..Default::default()
+        };
+        let ctx = BuildContext { cache_dir: Some("/var/lib/stoker/build-cache".to_string()), ..Default::default() };
+        let chroot = chroot_command("/tmp/build", &opts, &ctx, "/stoker-build.sh");
+        assert_eq!(chroot.get_args().collect::<Vec<_>>(), vec!["/tmp/build", "/stoker-build.sh"]);
+        let envs: Vec<_> = chroot.get_envs().filter_map(|(k, v)| Some((k.to_str()?, v?.to_str()?))).collect();
+        assert!(envs.contains(&("VERSION", "1.2")));
+        assert!(envs.contains(&("STOKER_CACHE", CONTAINER_CACHE_DIR)));
+        assert!(envs.contains(&("PATH", CHROOT_PATH)));
+    }
+
+    #[test]
+    fn test_debug_shell_args() {
+        let opts = BuildOptions {
+            network: "private".to_string(),
+            build_args: vec!["VERSION=1.2".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(debug_shell_args("/tmp/build", &opts, &BuildContext::default()), vec![
+            "-D", "/tmp/build", "--as-pid2",
+            "--resolv-conf=copy-host",
+            "--setenv=VERSION=1.2",
+            "/bin/bash",
+        ]);
+    }
+
+    #[test]
+    fn test_nspawn_args_network_none() {
+        let opts = BuildOptions { network: "none".to_string(), ..Default::default() };
+        let args = nspawn_args("/tmp/build", &opts, &BuildContext::default(), None);
+        assert!(args.contains(&"--private-network".to_string()));
+        assert!(!args.contains(&"--resolv-conf=copy-host".to_string()));
+    }
+
+    #[test]
+    fn test_nspawn_args_network_private() {
+        let opts = BuildOptions { network: "private".to_string(), ..Default::default() };
+        let args = nspawn_args("/tmp/build", &opts, &BuildContext::default(), Some("stoker-bld7"));
+        assert!(args.contains(&"--network-bridge=stoker-bld7".to_string()));
+        let command = args.last().unwrap();
+        assert!(command.contains("ip addr add 10.213.7.2/24 dev host0"));
+        assert!(command.ends_with("exec /stoker-build.sh"));
+    }
+
+    #[test]
+    fn test_free_build_bridge() {
+        assert_eq!(free_build_bridge(&[], &[]).as_deref(), Some("stoker-bld0"));
+        let interfaces = vec!["eth0".to_string(), "stoker-bld0".to_string()];
+        // 10.213.1.0/24 is taken by an address on another interface
+        let addresses = vec!["10.213.1.5".parse().unwrap(), "10.213.10.1".parse().unwrap()];
+        assert_eq!(free_build_bridge(&interfaces, &addresses).as_deref(), Some("stoker-bld2"));
+        let all: Vec<String> = (0..=255).map(|i| format!("stoker-bld{}", i)).collect();
+        assert_eq!(free_build_bridge(&all, &[]), None);
+    }
+
+    #[test]
+    fn test_parse_label() {
+        assert_eq!(parse_label("org.opencontainers.image.source=https://example.com/repo").unwrap(),
+            ("org.opencontainers.image.source", "https://example.com/repo"));
+        assert_eq!(parse_label("empty=").unwrap(), ("empty", ""));
+        assert!(parse_label("novalue").is_err());
+        assert!(parse_label("=value").is_err());
+        assert!(parse_label("bad key=value").is_err());
+    }
+
+    #[test]
+    fn test_format_rfc3339() {
+        assert_eq!(format_rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_rfc3339(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_rfc3339(1700000000), "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn test_parse_build_arg() {
+        assert_eq!(parse_build_arg("NGINX_VERSION=1.24").unwrap(), ("NGINX_VERSION", "1.24"));
+        assert_eq!(parse_build_arg("EMPTY=").unwrap(), ("EMPTY", ""));
+        assert!(parse_build_arg("NOVALUE").is_err());
+        assert!(parse_build_arg("1BAD=x").is_err());
+        assert!(parse_build_arg("BAD-NAME=x").is_err());
+    }
+
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(5), "5 seconds ago");
+        assert_eq!(format_age(120), "2 minutes ago");
+        assert_eq!(format_age(7200), "2 hours ago");
+        assert_eq!(format_age(172800), "2 days ago");
+    }
+
+    #[test]
+    fn test_nspawn_args_privileged() {
+        let opts = BuildOptions {
+            capabilities: vec!["CAP_SYS_ADMIN".to_string()],
+            privileged: true,
+            ..Default::default()
+        };
+        let args = nspawn_args("/tmp/build", &opts, &BuildContext::default(), None);
+        assert!(args.contains(&"--capability=all".to_string()));
+        assert!(!args.contains(&"--capability=CAP_SYS_ADMIN".to_string()));
+    }
+}
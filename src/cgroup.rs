@@ -0,0 +1,115 @@
+use anyhow::{Context, Result};
+use std::path::Path;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+// Host-side resource limits for Firecracker daemons. Each VM gets a `stoker-<name>` cgroup,
+// either under the unified v2 hierarchy or under the matching v1 controller mount.
+
+fn is_unified() -> bool {
+    Path::new(&format!("{}/cgroup.controllers", CGROUP_ROOT)).exists()
+}
+
+fn cgroup_dir(controller: &str, name: &str) -> String {
+    if is_unified() {
+        format!("{}/stoker-{}", CGROUP_ROOT, name)
+    } else {
+        format!("{}/{}/stoker-{}", CGROUP_ROOT, controller, name)
+    }
+}
+
+/// Creates the VM's cgroup for `controller`, writes each value into its file and moves `pid` into it.
+fn apply(controller: &str, name: &str, pid: u32, files: &[(&str, &str)]) -> Result<()> {
+    if is_unified() {
+        // Controllers must be delegated to children before their interface files appear
+        let _ = std::fs::write(format!("{}/cgroup.subtree_control", CGROUP_ROOT), format!("+{}", controller));
+    }
+
+    let dir = cgroup_dir(controller, name);
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("Failed to create cgroup {}. Are you running as root?", dir))?;
+    for (file, value) in files {
+        std::fs::write(format!("{}/{}", dir, file), value)
+            .with_context(|| format!("Failed to write {} to {}/{}", value, dir, file))?;
+    }
+    std::fs::write(format!("{}/cgroup.procs", dir), pid.to_string())
+        .with_context(|| format!("Failed to move PID {} into cgroup {}", pid, dir))?;
+    Ok(())
+}
+
+/// Caps the number of tasks the Firecracker process may spawn.
+pub fn set_pids_limit(name: &str, pid: u32, limit: u32) -> Result<()> {
+    apply("pids", name, pid, &[("pids.max", &limit.to_string())])?;
+    println!("Applied PID limit of {} to stoker-{}", limit, name);
+    Ok(())
+}
+
+/// Caps the host memory the Firecracker process (guest RAM plus VMM overhead) may use.
+pub fn set_memory_limit(name: &str, pid: u32, limit_mib: u32) -> Result<()> {
+    let bytes = (limit_mib as u64 * 1024 * 1024).to_string();
+    let file = if is_unified() { "memory.max" } else { "memory.limit_in_bytes" };
+    apply("memory", name, pid, &[(file, &bytes)])?;
+    println!("Applied memory limit of {} MiB to stoker-{}", limit_mib, name);
+    Ok(())
+}
+
+/// Confines the Firecracker process to `cpus` and the memory of their NUMA nodes. A cpuset
+/// cgroup rejects tasks until both `cpuset.cpus` and `cpuset.mems` are set.
+pub fn set_cpuset(name: &str, pid: u32, cpus: &[usize]) -> Result<()> {
+    let cpu_list = join(cpus);
+    let nodes = numa_nodes("/sys/devices/system/cpu", cpus);
+    // Machines without NUMA information expose a single node 0
+    let mems = if nodes.is_empty() { "0".to_string() } else { join(&nodes) };
+    apply("cpuset", name, pid, &[("cpuset.cpus", &cpu_list), ("cpuset.mems", &mems)])?;
+    println!("Confined stoker-{} to CPUs {} (NUMA nodes {})", name, cpu_list, mems);
+    Ok(())
+}
+
+fn join(numbers: &[usize]) -> String {
+    numbers.iter().map(usize::to_string).collect::<Vec<_>>().join(",")
+}
+
+/// NUMA nodes of `cpus`, read from the `node<N>` links in each `cpu<M>` directory under
+/// `cpu_dir`. Sorted and deduplicated.
+fn numa_nodes(cpu_dir: &str, cpus: &[usize]) -> Vec<usize> {
+    let mut nodes: Vec<usize> = cpus.iter()
+        .filter_map(|cpu| std::fs::read_dir(format!("{}/cpu{}", cpu_dir, cpu)).ok())
+        .flat_map(|entries| entries.flatten())
+        .filter_map(|entry| entry.file_name().to_str()?.strip_prefix("node")?.parse().ok())
+        .collect();
+    nodes.sort_unstable();
+    nodes.dedup();
+    nodes
+}
+
+/// Removes any cgroups created for the VM. The process must already have exited.
+pub fn remove(name: &str) {
+    for controller in ["pids", "memory", "cpuset"] {
+        let dir = cgroup_dir(controller, name);
+        if Path::new(&dir).exists() && std::fs::remove_dir(&dir).is_ok() {
+            println!("Removed cgroup {}", dir);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numa_nodes() {
+        let tmp_dir = std::env::temp_dir().join(format!("stoker_test_numa_nodes_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&tmp_dir);
+        for (cpu, node) in [(0, 0), (1, 0), (2, 1), (3, 1)] {
+            std::fs::create_dir_all(tmp_dir.join(format!("cpu{}/node{}", cpu, node))).unwrap();
+        }
+        std::fs::create_dir_all(tmp_dir.join("cpu0/cache")).unwrap();
+        let cpu_dir = tmp_dir.to_str().unwrap();
+
+        assert_eq!(numa_nodes(cpu_dir, &[0, 1]), vec![0]);
+        assert_eq!(numa_nodes(cpu_dir, &[1, 2, 3]), vec![0, 1]);
+        assert!(numa_nodes(cpu_dir, &[7]).is_empty());
+
+        let _ = std::fs::remove_dir_all(&tmp_dir);
+    }
+}
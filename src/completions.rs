@@ -0,0 +1,176 @@
+use anyhow::{bail, Result};
+use clap::Command;
+
+// Shell completion scripts. The structural part (subcommands, their aliases and flags) is
+// generated from the clap definition; VM names are completed at invocation time by asking
+// `stoker ps -q` for the VMs in the state directory, so new VMs complete without regenerating.
+
+pub const SHELLS: [&str; 3] = ["bash", "zsh", "fish"];
+
+/// Subcommands whose first argument is a VM name.
+pub const VM_NAME_COMMANDS: [&str; 6] = ["ssh", "rm", "inspect", "restart", "diff", "logs"];
+
+/// The completion script for `shell`, generated from `cmd`.
+pub fn render(cmd: &Command, shell: &str) -> Result<String> {
+    let name = cmd.get_name();
+    match shell {
+        "bash" => Ok(render_bash(cmd, name)),
+        // zsh runs the bash script through its bash compatibility layer
+        "zsh" => Ok(format!("autoload -U +X bashcompinit && bashcompinit\n{}", render_bash(cmd, name))),
+        "fish" => Ok(render_fish(cmd, name)),
+        _ => bail!("Unsupported shell '{}'. Use {}.", shell, SHELLS.join(", ")),
+    }
+}
+
+fn visible_subcommands(cmd: &Command) -> impl Iterator<Item = &Command> {
+    cmd.get_subcommands().filter(|sub| !sub.is_hide_set())
+}
+
+/// A subcommand's name followed by its visible aliases.
+fn names(cmd: &Command) -> Vec<&str> {
+    std::iter::once(cmd.get_name()).chain(cmd.get_visible_aliases()).collect()
+}
+
+/// `--long` and `-s` spellings of `cmd`'s flags.
+fn flags(cmd: &Command) -> Vec<String> {
+    let mut flags = Vec::new();
+    for arg in cmd.get_arguments().filter(|arg| !arg.is_positional() && !arg.is_hide_set()) {
+        flags.extend(arg.get_long().map(|long| format!("--{}", long)));
+        flags.extend(arg.get_short().map(|short| format!("-{}", short)));
+    }
+    flags
+}
+
+fn render_bash(cmd: &Command, name: &str) -> String {
+    let function = format!("_{}", name.replace('-', "_"));
+    let mut out = String::from("# Static completions\n");
+    out.push_str(&format!(
+        "{}() {{\n    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\" cmd=\"\" sub=\"\" i\n    for ((i = 1; i < COMP_CWORD; i++)); do\n        case \"${{COMP_WORDS[i]}}\" in\n            -*) ;;\n            *) if [[ -z $cmd ]]; then cmd=\"${{COMP_WORDS[i]}}\"; elif [[ -z $sub ]]; then sub=\"${{COMP_WORDS[i]}}\"; fi ;;\n        esac\n    done\n",
+        function
+    ));
+    let top: Vec<&str> = visible_subcommands(cmd).flat_map(names).collect();
+    out.push_str(&format!("    if [[ -z $cmd ]]; then\n        COMPREPLY=($(compgen -W \"{} {}\" -- \"$cur\"))\n        return\n    fi\n", top.join(" "), flags(cmd).join(" ")));
+
+    out.push_str("    if [[ $cur == -* ]]; then\n        case \"$cmd $sub\" in\n");
+    for sub in visible_subcommands(cmd) {
+        for nested in visible_subcommands(sub) {
+            let pattern: Vec<String> = names(sub).iter().flat_map(|s| names(nested).into_iter().map(move |n| format!("\"{} {}\"", s, n))).collect();
+            out.push_str(&format!("            {}) COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")) ;;\n", pattern.join("|"), flags(nested).join(" ")));
+        }
+        let pattern: Vec<String> = names(sub).iter().map(|s| format!("\"{} \"*", s)).collect();
+        out.push_str(&format!("            {}) COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")) ;;\n", pattern.join("|"), flags(sub).join(" ")));
+    }
+    out.push_str("        esac\n        return\n    fi\n    case \"$cmd\" in\n");
+    for sub in visible_subcommands(cmd).filter(|sub| sub.has_subcommands()) {
+        let nested: Vec<&str> = visible_subcommands(sub).flat_map(names).collect();
+        out.push_str(&format!("        {})\n            [[ -z $sub ]] && COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n            return ;;\n", names(sub).join("|"), nested.join(" ")));
+    }
+    out.push_str(&format!("        {})\n            [[ -z $sub ]] && COMPREPLY=($(compgen -W \"$(_{}_vm_names)\" -- \"$cur\"))\n            return ;;\n", VM_NAME_COMMANDS.join("|"), name.replace('-', "_")));
+    out.push_str("    esac\n    COMPREPLY=($(compgen -f -- \"$cur\"))\n}\n");
+    out.push_str(&format!("complete -F {} {}\n", function, name));
+
+    out.push_str(&format!("\n# Dynamic completions: VM names from the live state directory\n_{}_vm_names() {{ {} ps -q 2>/dev/null; }}\n", name.replace('-', "_"), name));
+    out
+}
+
+/// `text` as a single-quoted fish string.
+fn fish_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// The first line of `cmd`'s help, for completion descriptions.
+fn summary(help: Option<&clap::builder::StyledStr>) -> String {
+    help.map(|help| help.to_string().lines().next().unwrap_or_default().to_string()).unwrap_or_default()
+}
+
+fn fish_flags(out: &mut String, name: &str, condition: &str, cmd: &Command) {
+    for arg in cmd.get_arguments().filter(|arg| !arg.is_positional() && !arg.is_hide_set()) {
+        let mut line = format!("complete -c {} -n {}", name, fish_quote(condition));
+        if let Some(long) = arg.get_long() {
+            line.push_str(&format!(" -l {}", long));
+        }
+        if let Some(short) = arg.get_short() {
+            line.push_str(&format!(" -s {}", short));
+        }
+        let help = summary(arg.get_help());
+        if !help.is_empty() {
+            line.push_str(&format!(" -d {}", fish_quote(&help)));
+        }
+        out.push_str(&line);
+        out.push('\n');
+    }
+}
+
+fn render_fish(cmd: &Command, name: &str) -> String {
+    let mut out = String::from("# Static completions\n");
+    fish_flags(&mut out, name, "true", cmd);
+    let all: Vec<&str> = visible_subcommands(cmd).flat_map(names).collect();
+    for sub in visible_subcommands(cmd) {
+        for alias in names(sub) {
+            out.push_str(&format!("complete -c {} -n '__fish_use_subcommand' -f -a {} -d {}\n", name, alias, fish_quote(&summary(sub.get_about()))));
+        }
+    }
+    for sub in visible_subcommands(cmd) {
+        let seen = format!("__fish_seen_subcommand_from {}", names(sub).join(" "));
+        let nested_names: Vec<&str> = visible_subcommands(sub).flat_map(names).collect();
+        for nested in visible_subcommands(sub) {
+            let not_yet = format!("{}; and not __fish_seen_subcommand_from {}", seen, nested_names.join(" "));
+            for alias in names(nested) {
+                out.push_str(&format!("complete -c {} -n {} -f -a {} -d {}\n", name, fish_quote(&not_yet), alias, fish_quote(&summary(nested.get_about()))));
+            }
+            fish_flags(&mut out, name, &format!("{}; and __fish_seen_subcommand_from {}", seen, names(nested).join(" ")), nested);
+        }
+        fish_flags(&mut out, name, &seen, sub);
+    }
+
+    out.push_str("\n# Dynamic completions: VM names from the live state directory\n");
+    let vm_commands: Vec<&str> = all.into_iter().filter(|sub| VM_NAME_COMMANDS.contains(sub)).collect();
+    let mut condition = format!("__fish_seen_subcommand_from {}", vm_commands.join(" "));
+    // Keeps e.g. `image inspect` from offering VM names
+    let groups: Vec<&str> = visible_subcommands(cmd).filter(|sub| sub.has_subcommands()).flat_map(names).collect();
+    if !groups.is_empty() {
+        condition.push_str(&format!("; and not __fish_seen_subcommand_from {}", groups.join(" ")));
+    }
+    out.push_str(&format!("complete -c {} -n {} -f -a \"({} ps -q)\" -d 'VM'\n", name, fish_quote(&condition), name));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Arg;
+
+    fn command() -> Command {
+        Command::new("stoker")
+            .subcommand(Command::new("ssh").about("Connects to a VM\n\nMore detail").arg(Arg::new("name")).arg(Arg::new("multiplex").long("multiplex").num_args(0)))
+            .subcommand(Command::new("list").visible_alias("ps").about("Lists VMs").arg(Arg::new("quiet").long("quiet").short('q').num_args(0)))
+            .subcommand(Command::new("image").subcommand(Command::new("inspect").about("Shows an image's config")))
+    }
+
+    #[test]
+    fn test_render_fish() {
+        let script = render(&command(), "fish").unwrap();
+        let (static_part, dynamic) = script.split_once("# Dynamic completions").unwrap();
+        assert!(static_part.contains("complete -c stoker -n '__fish_use_subcommand' -f -a ps -d 'Lists VMs'"), "{}", script);
+        assert!(static_part.contains("-n '__fish_seen_subcommand_from list ps' -l quiet -s q"), "{}", script);
+        assert!(static_part.contains("-f -a inspect -d 'Shows an image\\'s config'"), "{}", script);
+        assert!(!static_part.contains("More detail"));
+        // `image inspect` takes an image, so only top-level subcommands get VM names
+        assert!(
+            dynamic.contains("complete -c stoker -n '__fish_seen_subcommand_from ssh; and not __fish_seen_subcommand_from image' -f -a \"(stoker ps -q)\""),
+            "{}",
+            dynamic
+        );
+    }
+
+    #[test]
+    fn test_render_bash() {
+        let script = render(&command(), "bash").unwrap();
+        assert!(script.contains("compgen -W \"ssh list ps image \""), "{}", script);
+        assert!(script.contains("\"image inspect\") COMPREPLY"), "{}", script);
+        assert!(script.contains("_stoker_vm_names() { stoker ps -q 2>/dev/null; }"), "{}", script);
+        assert!(script.contains("complete -F _stoker stoker"));
+        assert!(render(&command(), "zsh").unwrap().starts_with("autoload -U +X bashcompinit"));
+        assert!(render(&command(), "powershell").is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::process::Command;
+
+use crate::assets;
+use crate::firecracker::InstanceMetadata;
+
+// Host environment report for bug reports. Every check runs even if earlier ones fail, so a
+// single invocation shows everything that is wrong.
+
+#[derive(Serialize, Debug)]
+struct Check {
+    name: &'static str,
+    ok: bool,
+    detail: String,
+}
+
+fn check(name: &'static str, result: Result<String>) -> Check {
+    match result {
+        Ok(detail) => Check { name, ok: true, detail },
+        Err(e) => Check { name, ok: false, detail: format!("{:#}", e) },
+    }
+}
+
+fn command_output(program: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(program).args(args).output()
+        .map_err(|e| anyhow::anyhow!("Failed to execute {}: {}", program, e))?;
+    if !output.status.success() {
+        anyhow::bail!("{} exited with {}: {}", program, output.status, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn kernel_version() -> Result<String> {
+    Ok(std::fs::read_to_string("/proc/sys/kernel/osrelease")?.trim().to_string())
+}
+
+fn open_read_write(path: &str) -> Result<String> {
+    std::fs::OpenOptions::new().read(true).write(true).open(path)
+        .map_err(|e| anyhow::anyhow!("Cannot open {}: {}", path, e))?;
+    Ok(format!("{} is accessible", path))
+}
+
+fn tap_interfaces() -> Result<String> {
+    let mut taps: Vec<String> = std::fs::read_dir("/sys/class/net")?
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| name.starts_with("tap-inet-"))
+        .collect();
+    taps.sort();
+    Ok(if taps.is_empty() { "none".to_string() } else { taps.join(", ") })
+}
+
+fn masquerade_rules() -> Result<String> {
+    let rules = command_output("iptables", &["-t", "nat", "-S", "POSTROUTING"])?;
+    Ok(masquerade_lines(&rules).unwrap_or_else(|| "none".to_string()))
+}
+
+fn masquerade_lines(rules: &str) -> Option<String> {
+    let lines: Vec<&str> = rules.lines().filter(|line| line.contains("MASQUERADE")).collect();
+    if lines.is_empty() {
+        None
+    } else {
+        Some(lines.join("; "))
+    }
+}
+
+fn ip_forwarding() -> Result<String> {
+    let value = std::fs::read_to_string("/proc/sys/net/ipv4/ip_forward")?;
+    if value.trim() != "1" {
+        anyhow::bail!("net.ipv4.ip_forward is {} (stoker enables it on `run`)", value.trim());
+    }
+    Ok("enabled".to_string())
+}
+
+fn metadata_files() -> Result<String> {
+    let mut found = Vec::new();
+    for entry in std::fs::read_dir("/tmp")?.flatten() {
+        let fname = entry.file_name().to_string_lossy().to_string();
+        if fname.starts_with("stoker-") && fname.ends_with(".json") {
+            let parsed = std::fs::read_to_string(entry.path())
+                .ok()
+                .and_then(|content| serde_json::from_str::<InstanceMetadata>(&content).ok());
+            found.push(match parsed {
+                Some(meta) => format!("{} ({})", fname, meta.name),
+                None => format!("{} (unparseable)", fname),
+            });
+        }
+    }
+    found.sort();
+    Ok(if found.is_empty() { "none".to_string() } else { found.join(", ") })
+}
+
+/// Runs every check and prints them with ✓/✗ markers, or as a JSON array when `json` is set.
+pub fn run(json: bool) -> Result<()> {
+    let checks = vec![
+        check("Kernel version", kernel_version()),
+        check("Firecracker", {
+            let path = assets::resolve_firecracker_binary(None);
+            assets::check_firecracker_binary(&path).map(|version| format!("{} at {}", version, path))
+        }),
+        check("/dev/net/tun", open_read_write("/dev/net/tun")),
+        check("KVM", open_read_write("/dev/kvm")),
+        check("iptables", command_output("iptables", &["--version"])),
+        check("MASQUERADE rules", masquerade_rules()),
+        check("IP forwarding", ip_forwarding()),
+        check("TAP interfaces", tap_interfaces()),
+        check("VM metadata", metadata_files()),
+    ];
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&checks)?);
+        return Ok(());
+    }
+    for c in &checks {
+        println!("{} {}: {}", if c.ok { "✓" } else { "✗" }, c.name, c.detail);
+    }
+    Ok(())
+}
+
+/// Field of the uname(2) result as a string.
+fn uname_field(field: &[libc::c_char]) -> String {
+    let bytes: Vec<u8> = field.iter().take_while(|&&c| c != 0).map(|&c| c as u8).collect();
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+/// Operating system and machine architecture from uname(2).
+fn uname() -> Result<(String, String)> {
+    let mut name: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut name) } != 0 {
+        anyhow::bail!("uname failed: {}", std::io::Error::last_os_error());
+    }
+    Ok((uname_field(&name.sysname), uname_field(&name.machine)))
+}
+
+/// The `stoker version` fields in display order. Only the stoker version unless `verbose`;
+/// anything that can't be determined is reported as an error string rather than failing.
+pub fn version_fields(verbose: bool) -> Vec<(&'static str, String)> {
+    let mut fields = vec![("stoker", env!("CARGO_PKG_VERSION").to_string())];
+    if !verbose {
+        return fields;
+    }
+    let unavailable = |e: anyhow::Error| format!("unavailable ({:#})", e);
+    let path = assets::resolve_firecracker_binary(None);
+    fields.push(("firecracker", assets::check_firecracker_binary(&path).unwrap_or_else(unavailable)));
+    let kernel = std::fs::read_to_string("/proc/version").map(|v| v.trim().to_string());
+    fields.push(("kernel", kernel.map_err(anyhow::Error::from).unwrap_or_else(unavailable)));
+    let (os, arch) = match uname() {
+        Ok(name) => name,
+        Err(e) => (unavailable(e), "unknown".to_string()),
+    };
+    fields.push(("os", os));
+    fields.push(("arch", arch));
+    fields
+}
+
+/// Renders version fields as `KEY VALUE` rows, or as a JSON object when `json` is set.
+pub fn render_version(fields: &[(&'static str, String)], json: bool) -> Result<String> {
+    if json {
+        let object: serde_json::Map<String, serde_json::Value> = fields.iter()
+            .map(|(key, value)| (key.to_string(), serde_json::Value::String(value.clone())))
+            .collect();
+        return Ok(serde_json::to_string_pretty(&object)?);
+    }
+    if let [(_, version)] = fields {
+        return Ok(format!("stoker {}", version));
+    }
+    Ok(fields.iter().map(|(key, value)| format!("{:<12} {}", key.to_uppercase(), value)).collect::<Vec<_>>().join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_masquerade_lines() {
+        let rules = "-P POSTROUTING ACCEPT\n-A POSTROUTING -o eth0 -j MASQUERADE\n-A POSTROUTING -s 10.213.4.0/24 ! -o stoker-bld4 -j MASQUERADE\n";
+        assert_eq!(
+            masquerade_lines(rules).unwrap(),
+            "-A POSTROUTING -o eth0 -j MASQUERADE; -A POSTROUTING -s 10.213.4.0/24 ! -o stoker-bld4 -j MASQUERADE"
+        );
+        assert!(masquerade_lines("-P POSTROUTING ACCEPT\n").is_none());
+    }
+
+    #[test]
+    fn test_render_version() {
+        let fields = version_fields(false);
+        assert_eq!(render_version(&fields, false).unwrap(), format!("stoker {}", env!("CARGO_PKG_VERSION")));
+
+        let fields = vec![("stoker", "0.1.0".to_string()), ("firecracker", "v1.10.1".to_string()), ("arch", "aarch64".to_string())];
+        assert_eq!(render_version(&fields, false).unwrap(), "STOKER       0.1.0\nFIRECRACKER  v1.10.1\nARCH         aarch64");
+        let json: serde_json::Value = serde_json::from_str(&render_version(&fields, true).unwrap()).unwrap();
+        assert_eq!(json["firecracker"], "v1.10.1");
+
+        let (os, arch) = uname().unwrap();
+        assert_eq!(os, "Linux");
+        assert_eq!(arch, std::env::consts::ARCH);
+    }
+}
@@ -0,0 +1,157 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::{assets, builder, firecracker};
+
+// `stoker diff`: compares a VM's rootfs copy with the image it was cloned from. The clone keeps
+// every untouched file's metadata, so comparing type, size, mode and mtime finds the changes
+// without reading file contents.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChangeKind {
+    Added,
+    Changed,
+    Deleted,
+}
+
+impl ChangeKind {
+    fn marker(self) -> char {
+        match self {
+            ChangeKind::Added => 'A',
+            ChangeKind::Changed => 'C',
+            ChangeKind::Deleted => 'D',
+        }
+    }
+}
+
+/// What identifies an unchanged entry: file type bits and mode, size, mtime and link target.
+#[derive(PartialEq)]
+struct Fingerprint {
+    mode: u32,
+    size: u64,
+    mtime: (i64, i64),
+    link: Option<std::path::PathBuf>,
+}
+
+fn fingerprint(path: &Path, meta: &std::fs::Metadata) -> Fingerprint {
+    let is_dir = meta.is_dir();
+    Fingerprint {
+        mode: meta.mode(),
+        // Directory sizes depend on their history, not their contents
+        size: if is_dir { 0 } else { meta.size() },
+        mtime: (meta.mtime(), meta.mtime_nsec()),
+        link: if meta.file_type().is_symlink() { std::fs::read_link(path).ok() } else { None },
+    }
+}
+
+/// Entries of directory `dir` by name, without following symlinks.
+fn entries(dir: &Path) -> BTreeMap<std::ffi::OsString, std::fs::Metadata> {
+    let Ok(read) = std::fs::read_dir(dir) else { return BTreeMap::new() };
+    read.flatten()
+        .filter_map(|entry| Some((entry.file_name(), std::fs::symlink_metadata(entry.path()).ok()?)))
+        .collect()
+}
+
+/// Compares the tree at `changed` against `base`, returning changes by guest path (`/etc/...`).
+/// Added directories list their contents too; deleted ones only themselves, like `docker diff`.
+pub fn diff_trees(base: &Path, changed: &Path) -> Vec<(ChangeKind, String)> {
+    let mut changes = Vec::new();
+    walk(Some(base), changed, "", &mut changes);
+    changes.sort_by(|a, b| a.1.cmp(&b.1));
+    changes
+}
+
+/// `base` is None below an added directory, where there is nothing to compare with.
+fn walk(base: Option<&Path>, changed: &Path, prefix: &str, changes: &mut Vec<(ChangeKind, String)>) {
+    let base_entries = base.map(entries).unwrap_or_default();
+    let changed_entries = entries(changed);
+
+    for (name, meta) in &changed_entries {
+        let guest_path = format!("{}/{}", prefix, name.to_string_lossy());
+        let changed_path = changed.join(name);
+        match (base, base_entries.get(name)) {
+            (Some(base), Some(base_meta)) => {
+                let base_path = base.join(name);
+                if fingerprint(&changed_path, meta) != fingerprint(&base_path, base_meta) {
+                    changes.push((ChangeKind::Changed, guest_path.clone()));
+                }
+                if meta.is_dir() && base_meta.is_dir() {
+                    walk(Some(&base_path), &changed_path, &guest_path, changes);
+                }
+            }
+            _ => {
+                changes.push((ChangeKind::Added, guest_path.clone()));
+                if meta.is_dir() {
+                    walk(None, &changed_path, &guest_path, changes);
+                }
+            }
+        }
+    }
+    for name in base_entries.keys().filter(|name| !changed_entries.contains_key(*name)) {
+        changes.push((ChangeKind::Deleted, format!("{}/{}", prefix, name.to_string_lossy())));
+    }
+}
+
+/// Prints the changes VM `name` made to its root filesystem relative to its image.
+pub fn diff_vm(name: &str) -> Result<()> {
+    let meta = firecracker::load_metadata(name)?;
+    let image = meta.image.as_deref()
+        .with_context(|| format!("VM '{}' doesn't record which image it was created from", name))?;
+    let base_ext4 = assets::get_asset_path(&format!("{}.ext4", image));
+    if !Path::new(&base_ext4).exists() {
+        anyhow::bail!("Image '{}' of VM '{}' no longer exists at {}", image, name, base_ext4);
+    }
+
+    // The guest may have the rootfs mounted: skip journal replay so it can be mounted read-only
+    let changes = builder::with_mounted_image(&firecracker::rootfs_path(&meta), "loop,ro,noload", |vm_root| {
+        builder::with_mounted_image(&base_ext4, "loop,ro", |base_root| {
+            Ok(diff_trees(Path::new(base_root), Path::new(vm_root)))
+        })
+    })?;
+    for (kind, path) in changes {
+        println!("{} {}", kind.marker(), path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_trees() {
+        let root = std::env::temp_dir().join(format!("stoker_test_diff_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let (base, vm) = (root.join("base"), root.join("vm"));
+        for tree in [&base, &vm] {
+            std::fs::create_dir_all(tree.join("etc")).unwrap();
+            std::fs::create_dir_all(tree.join("var/old")).unwrap();
+            std::fs::write(tree.join("etc/hostname"), "base").unwrap();
+            std::fs::write(tree.join("etc/motd"), "hello").unwrap();
+        }
+        // Give both trees identical metadata, as a cloned image has
+        let stamp = std::fs::metadata(base.join("etc/motd")).unwrap().modified().unwrap();
+        let sync = |path: &Path| std::fs::File::options().write(true).open(path).unwrap().set_modified(stamp).unwrap();
+        for tree in [&base, &vm] {
+            sync(&tree.join("etc/hostname"));
+            sync(&tree.join("etc/motd"));
+        }
+
+        std::fs::write(vm.join("etc/hostname"), "web-1").unwrap();
+        std::fs::create_dir_all(vm.join("srv/app")).unwrap();
+        std::fs::write(vm.join("srv/app/main.py"), "print()").unwrap();
+        std::fs::remove_dir_all(vm.join("var/old")).unwrap();
+
+        let changes = diff_trees(&base, &vm);
+        let render: Vec<String> = changes.iter().map(|(kind, path)| format!("{} {}", kind.marker(), path)).collect();
+        assert!(render.contains(&"C /etc/hostname".to_string()));
+        assert!(!render.iter().any(|line| line.ends_with("/etc/motd")));
+        assert!(render.contains(&"A /srv".to_string()));
+        assert!(render.contains(&"A /srv/app/main.py".to_string()));
+        assert!(render.contains(&"D /var/old".to_string()));
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
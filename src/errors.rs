@@ -0,0 +1,121 @@
+use std::fmt;
+
+use crate::firecracker::FirecrackerApiError;
+
+// Failures scripts may want to handle differently are raised as a `StokerError` of some
+// `ErrorKind`, either directly or as the context of a lower-level error. `main` turns the kind
+// into the process exit code; everything else exits with 1.
+
+/// Class of a failure, reported as the process exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// A VM, image or snapshot that doesn't exist
+    NotFound,
+    /// A VM, image or snapshot that would be overwritten
+    AlreadyExists,
+    /// An operation that needs root, such as mounting images or creating cgroups
+    NeedsRoot,
+    /// The kernel, base rootfs, SSH key or Firecracker binary is missing
+    AssetMissing,
+    /// Firecracker rejected or failed to answer an API request
+    FirecrackerApi,
+    /// The `ssh` behind `stoker ssh` failed, or the session ended with a non-zero status
+    Ssh,
+}
+
+impl ErrorKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::NotFound => 2,
+            ErrorKind::AlreadyExists => 3,
+            ErrorKind::NeedsRoot => 4,
+            ErrorKind::AssetMissing => 5,
+            ErrorKind::FirecrackerApi => 6,
+            // ssh's own code for its failures; the remote status could be anything
+            ErrorKind::Ssh => 255,
+        }
+    }
+}
+
+/// The exit codes as listed in `stoker --help`.
+pub const EXIT_CODES_HELP: &str = "Exit codes:
+    0  success
+    1  any other error
+    2  VM, image or snapshot not found
+    3  VM, image or snapshot already exists
+    4  needs root
+    5  kernel, rootfs, SSH key or Firecracker binary missing (see `stoker download-assets`)
+    6  Firecracker API error
+  255  `stoker ssh`: ssh failed or the session exited with a non-zero status";
+
+#[derive(Debug)]
+pub struct StokerError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl StokerError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        StokerError { kind, message: message.into() }
+    }
+}
+
+impl fmt::Display for StokerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for StokerError {}
+
+/// Class of `error`: its `StokerError`, a Firecracker API failure or a permission error
+/// anywhere in its chain, in that order of precedence.
+pub fn classify(error: &anyhow::Error) -> Option<ErrorKind> {
+    if let Some(e) = error.downcast_ref::<StokerError>() {
+        return Some(e.kind);
+    }
+    if error.downcast_ref::<FirecrackerApiError>().is_some() {
+        return Some(ErrorKind::FirecrackerApi);
+    }
+    error.chain().find_map(|cause| {
+        if let Some(e) = cause.downcast_ref::<StokerError>() {
+            return Some(e.kind);
+        }
+        if cause.downcast_ref::<FirecrackerApiError>().is_some() {
+            return Some(ErrorKind::FirecrackerApi);
+        }
+        match cause.downcast_ref::<std::io::Error>() {
+            Some(e) if e.kind() == std::io::ErrorKind::PermissionDenied => Some(ErrorKind::NeedsRoot),
+            _ => None,
+        }
+    })
+}
+
+/// The process exit code for `error`.
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    classify(error).map_or(1, ErrorKind::exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_exit_code() {
+        let not_found: anyhow::Result<()> = Err(StokerError::new(ErrorKind::NotFound, "No VM named 'web'").into());
+        let wrapped = not_found.context("Failed to stop VM").context("Failed to restart VM").unwrap_err();
+        assert_eq!(exit_code(&wrapped), 2);
+        assert_eq!(format!("{:#}", wrapped), "Failed to restart VM: Failed to stop VM: No VM named 'web'");
+
+        // As the context of a lower-level error
+        let io: std::io::Result<()> = Err(std::io::Error::from(std::io::ErrorKind::NotFound));
+        let missing = io.with_context(|| StokerError::new(ErrorKind::AssetMissing, "Kernel not found")).unwrap_err();
+        assert_eq!(exit_code(&missing), 5);
+
+        let denied: std::io::Result<()> = Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        assert_eq!(exit_code(&denied.context("Failed to create cgroup").unwrap_err()), 4);
+        assert_eq!(exit_code(&anyhow::anyhow!("Something else")), 1);
+        assert_eq!(exit_code(&StokerError::new(ErrorKind::Ssh, "ssh exited with status 2").into()), 255);
+    }
+}
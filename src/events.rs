@@ -0,0 +1,87 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::Duration;
+
+// Append-only lifecycle log shared by every VM, one JSON object per line, so tooling can
+// react to state changes without polling `stoker list`.
+const EVENTS_PATH: &str = "/tmp/stoker-events.jsonl";
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Event {
+    /// Seconds since the Unix epoch
+    pub time: u64,
+    pub name: String,
+    /// One of create, start, stop, die, remove, watchdog, timeout, adopt or snapshot
+    pub action: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Appends an event for VM `name`. Failures are ignored so logging never breaks a lifecycle step.
+pub fn record(name: &str, action: &str, detail: Option<&str>) {
+    let event = Event {
+        time: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        name: name.to_string(),
+        action: action.to_string(),
+        detail: detail.map(str::to_string),
+    };
+    let Ok(line) = serde_json::to_string(&event) else { return };
+    if let Ok(mut file) = std::fs::OpenOptions::new().create(true).append(true).open(EVENTS_PATH) {
+        let _ = writeln!(file, "{}", line);
+    }
+}
+
+fn format_event(line: &str) -> Option<String> {
+    let event: Event = serde_json::from_str(line).ok()?;
+    Some(match event.detail {
+        Some(detail) => format!("{} vm {} {} ({})", event.time, event.action, event.name, detail),
+        None => format!("{} vm {} {}", event.time, event.action, event.name),
+    })
+}
+
+/// Prints the recorded events, then keeps printing new ones as they arrive when `follow` is set.
+pub async fn stream(follow: bool) -> Result<()> {
+    let mut offset = 0;
+    let mut pending = String::new();
+    loop {
+        if let Ok(mut file) = std::fs::File::open(EVENTS_PATH) {
+            file.seek(SeekFrom::Start(offset))?;
+            let mut chunk = String::new();
+            offset += file.read_to_string(&mut chunk).context("Failed to read event log")? as u64;
+            pending.push_str(&chunk);
+
+            // Only print complete lines; a writer may be mid-append
+            while let Some(end) = pending.find('\n') {
+                let line: String = pending.drain(..=end).collect();
+                if let Some(formatted) = format_event(line.trim_end()) {
+                    println!("{}", formatted);
+                }
+            }
+        }
+
+        if !follow {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_event() {
+        let line = r#"{"time":1700000000,"name":"web","action":"die","detail":"exit code 1"}"#;
+        assert_eq!(format_event(line).unwrap(), "1700000000 vm die web (exit code 1)");
+
+        let line = r#"{"time":1700000000,"name":"web","action":"start"}"#;
+        assert_eq!(format_event(line).unwrap(), "1700000000 vm start web");
+
+        assert!(format_event("not json").is_none());
+    }
+}
@@ -1,16 +1,20 @@
 use anyhow::{Context, Result};
-use hyper::{Body, Client, Request, Method};
+use hyper::{Body, Client, Request, Method, StatusCode};
 use hyperlocal::{UnixClientExt, Uri};
 use serde_json::json;
-use std::process::{Command, Stdio};
+use sha2::{Digest, Sha256};
+use std::process::Stdio;
+use std::sync::{Arc, LazyLock, Mutex};
 use std::time::Duration;
+use tokio::process::Command;
 use tokio::time::sleep;
-use crate::guest;
+use crate::{events, guest};
+use crate::errors::{ErrorKind, StokerError};
 use serde::{Serialize, Deserialize};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct InstanceMetadata {
-    pub id: u8,
+    pub id: u16,
     pub name: String,
     pub mode: String,
     pub guest_ip: String,
@@ -18,226 +22,2619 @@ pub struct InstanceMetadata {
     pub mac_address: String,
     pub tap_device: String,
     pub pid: u32,
+    /// Host interface NAT was configured on, when overridden with `--egress`
+    #[serde(default)]
+    pub egress: Option<String>,
+    /// `--allow` destinations (`<cidr>[:<port>]`); when set, all other forwarded traffic is dropped
+    #[serde(default)]
+    pub egress_allow: Vec<String>,
+    /// `--deny` destinations (CIDRs) the VM's forwarded traffic may not reach
+    #[serde(default)]
+    pub egress_deny: Vec<String>,
+    /// Firecracker binary the VM was launched with
+    #[serde(default)]
+    pub firecracker_bin: Option<String>,
+    /// Kernel start time of `pid` (field 22 of /proc/<pid>/stat), used to detect PID reuse
+    #[serde(default)]
+    pub pid_start_time: Option<u64>,
+    #[serde(default)]
+    pub pids_limit: Option<u32>,
+    #[serde(default)]
+    pub memory_limit_mib: Option<u32>,
+    #[serde(default)]
+    pub tmpfs_size_mib: Option<u32>,
+    #[serde(default)]
+    pub memory_mib: Option<u32>,
+    #[serde(default)]
+    pub initial_balloon_mib: Option<u32>,
+    #[serde(default)]
+    pub healthcheck: Option<HealthCheck>,
+    /// Latest healthcheck verdict: `starting`, `healthy` or `unhealthy`
+    #[serde(default)]
+    pub health: Option<String>,
+    #[serde(default)]
+    pub watchdog_enabled: bool,
+    #[serde(default)]
+    pub watchdog_interval_secs: Option<u32>,
+    #[serde(default)]
+    pub watchdog_grace_failures: Option<u32>,
+    /// Background `stoker supervise` process that keeps probing a detached VM
+    #[serde(default)]
+    pub supervisor_pid: Option<u32>,
+    #[serde(default)]
+    pub supervisor_start_time: Option<u64>,
+    /// Value written to the daemon's /proc/<pid>/oom_score_adj
+    #[serde(default)]
+    pub oom_score_adj: Option<i32>,
+    /// User (and group) root login shells switch to, from `--user`
+    #[serde(default)]
+    pub run_as_uid: Option<String>,
+    #[serde(default)]
+    pub run_as_gid: Option<String>,
+    /// Directory guest login shells start in, from `--working-dir`
+    #[serde(default)]
+    pub workdir: Option<String>,
+    /// Whether the guest network config was written to disk so it survives guest reboots
+    #[serde(default)]
+    pub persist_network: bool,
+    /// Prefix length of the host/guest subnet; unset means the default /30
+    #[serde(default)]
+    pub prefix_len: Option<u8>,
+    /// Resource limits applied to guest login shells, as `<type>=<soft>:<hard>`
+    #[serde(default)]
+    pub ulimits: Vec<String>,
+    /// Additional tmpfs mounts from `--tmpfs`
+    #[serde(default)]
+    pub tmpfs_mounts: Vec<TmpfsMount>,
+    /// `key=value` hardening options from `--security-opt` applied to the daemon
+    #[serde(default)]
+    pub security_opts: Vec<String>,
+    /// Host path of the `--init-file` script injected as the guest's init
+    #[serde(default)]
+    pub init_file: Option<String>,
+    /// SHA-256 of the injected init script, for auditing
+    #[serde(default)]
+    pub init_sha256: Option<String>,
+    /// Image the rootfs was cloned from
+    #[serde(default)]
+    pub image: Option<String>,
+    /// Unix time the current Firecracker daemon was started
+    #[serde(default)]
+    pub started_at: u64,
+    /// IPC namespace of the daemon from `--ipc`: `shareable` or `vm:<name>` (None is private)
+    #[serde(default)]
+    pub ipc: Option<String>,
+    /// Where the serial console goes: `file`, `syslog` or `journald` (None discards it)
+    #[serde(default)]
+    pub log_driver: Option<String>,
+    /// Host CPUs the daemon and its vCPU threads are pinned to, as given to `--cpuset`
+    #[serde(default)]
+    pub cpuset: Option<String>,
+    /// Host CPUs of the daemon's cpuset cgroup, as given to `--cpuset-cgroup`
+    #[serde(default, alias = "cpu_set")]
+    pub cpuset_cgroup: Option<String>,
+    /// Directory holding the writable rootfs copy. None for VMs from before it was recorded,
+    /// whose copy is in /tmp
+    #[serde(default)]
+    pub rootfs_dir: Option<String>,
+    /// Guest kernel parameters from `--sysctl`, as `key=value`
+    #[serde(default)]
+    pub sysctls: Vec<String>,
+    /// Network set up by `--cni-plugin` instead of a host TAP; the daemon runs in its namespace
+    #[serde(default)]
+    pub cni: Option<crate::network::CniResult>,
+    /// Host file whose bytes are mixed into the guest's /dev/urandom on every boot
+    #[serde(default)]
+    pub seed_entropy: Option<String>,
+    /// `--add-host` entries for the guest's /etc/hosts, as `name:ip` or `name:host-gateway`
+    #[serde(default)]
+    pub extra_hosts: Vec<String>,
+    /// `cache_type` of the rootfs drive, `Unsafe` or `Writeback` (Firecracker's default, Unsafe, when unset)
+    #[serde(default)]
+    pub drive_cache: Option<String>,
+    /// Shared image attached read-only as the root drive by `--read-only-root`, in place of a
+    /// per-VM rootfs copy
+    #[serde(default)]
+    pub read_only_root: Option<String>,
+    /// Guest path the writable data drive of a read-only-root VM is mounted at
+    #[serde(default)]
+    pub data_mount: Option<String>,
+}
+
+/// A tmpfs mounted in the guest at boot, sized in MiB (the kernel default of half the guest's
+/// memory when unset).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TmpfsMount {
+    pub path: String,
+    pub size_mib: Option<u32>,
+}
+
+impl InstanceMetadata {
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len.unwrap_or(DEFAULT_PREFIX_LEN)
+    }
+}
+
+/// Healthcheck settings for VMs started with `--health-cmd`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HealthCheck {
+    pub cmd: String,
+    pub interval_secs: u64,
+    pub retries: u32,
+    pub timeout_secs: u64,
+}
+
+/// Configuration accepted by `run_vm`, mirroring the `stoker run` flags.
+#[derive(Debug, Clone, Default)]
+pub struct RunConfig {
+    pub mode: String,
+    pub name: Option<String>,
+    pub image: Option<String>,
+    /// Keep stoker in the foreground until the Firecracker daemon exits
+    pub attach: bool,
+    /// Host interface to NAT guest traffic through (default: the default route's interface)
+    pub egress: Option<String>,
+    /// Only let the guest reach these `<cidr>[:<port>]` destinations through the host
+    pub allow: Vec<String>,
+    /// Block the guest from reaching these CIDRs through the host
+    pub deny: Vec<String>,
+    /// Maximum number of tasks the Firecracker process may create (pids cgroup)
+    pub pids_limit: Option<u32>,
+    /// Host-side memory cap for the Firecracker process in MiB (memory cgroup)
+    pub memory_limit_mib: Option<u32>,
+    /// Size in MiB of a tmpfs mounted over the guest's /tmp
+    pub tmpfs_size_mib: Option<u32>,
+    /// `<path>[:size_mib]` tmpfs mounts for the guest
+    pub tmpfs: Vec<String>,
+    /// Guest memory in MiB (Firecracker defaults to 128 when unset)
+    pub memory_mib: Option<u32>,
+    /// Balloon inflated at boot, leaving `memory - balloon` MiB usable by the guest
+    pub balloon_mib: Option<u32>,
+    /// Print the Firecracker API payloads instead of launching the VM
+    pub dry_run: bool,
+    /// Firecracker binary to launch instead of the downloaded one
+    pub firecracker_bin: Option<String>,
+    /// Command run periodically over SSH to determine the VM's health
+    pub healthcheck: Option<HealthCheck>,
+    /// Seconds between watchdog probes of the guest's SSH port
+    pub watchdog_interval_secs: Option<u32>,
+    /// Consecutive failed probes before the VM is considered stuck and restarted
+    pub watchdog_grace_failures: u32,
+    /// Guest TCP ports that must accept connections before `run_vm` returns
+    pub wait_ports: Vec<u16>,
+    pub wait_timeout_secs: u64,
+    /// OOM killer score adjustment for the daemon (-1000 to 1000)
+    pub oom_score_adj: Option<i32>,
+    /// `<uid>[:gid]` to run guest login shells as instead of root
+    pub user: Option<String>,
+    /// Guest MAC for the primary interface instead of the id-derived one
+    pub mac: Option<String>,
+    /// Absolute guest path login shells `cd` into
+    pub working_dir: Option<String>,
+    /// Also write the guest address, route and DNS config to disk inside the guest
+    pub persist_network: bool,
+    /// Remove the VM's state once the attached Firecracker process exits
+    pub auto_remove: bool,
+    /// Fixed `<address>/<prefix>` for the guest instead of one derived from the VM id
+    pub guest_ip: Option<String>,
+    /// Fixed `<address>/<prefix>` for the host side of the TAP; required with `guest_ip`
+    pub host_ip: Option<String>,
+    /// Limit for bringing the VM up (TAP, daemon, SSH, `wait_ports`), after which the partly
+    /// created VM is cleaned up and an error is returned
+    pub timeout_secs: Option<u64>,
+    /// `<type>=<soft>[:<hard>]` limits for guest login shells (nofile, nproc, core, fsize)
+    pub ulimits: Vec<String>,
+    /// Resume this snapshot (from `stoker snapshot create`) instead of cold-booting an image
+    pub from_snapshot: Option<String>,
+    /// Daemon hardening: `seccomp=<path>`, `no-new-privileges[=true]`, `apparmor=<profile>`
+    pub security_opts: Vec<String>,
+    /// Script run as the guest's init before it execs the real one, passed as its argument
+    pub init_file: Option<String>,
+    /// Remove an existing VM with the same `name` instead of failing
+    pub replace: bool,
+    /// `private` (default), `host`, `shareable` or `vm:<name>` to join a shareable VM's namespace
+    pub ipc: Option<String>,
+    /// Serial console sink: `none` (default), `file`, `syslog` or `journald`
+    pub log_driver: Option<String>,
+    /// Host CPU list (`0-3,6`) to pin the Firecracker process to
+    pub cpuset: Option<String>,
+    /// Host CPU list (`0,2-4`) for the cpuset cgroup the Firecracker process is moved into
+    pub cpuset_cgroup: Option<String>,
+    /// Directory for the VM's writable rootfs copy (default: `vms` in the asset directory)
+    pub rootfs_dir: Option<String>,
+    /// Guest kernel parameters as `key=value`, applied with `sysctl -w` once SSH is up
+    pub sysctls: Vec<String>,
+    /// CNI plugin in /opt/cni/bin that sets up the VM's network instead of a NATed host TAP
+    pub cni_plugin: Option<String>,
+    /// Host file (e.g. /dev/urandom) to seed the guest's /dev/urandom from once SSH is up
+    pub seed_entropy: Option<String>,
+    /// `name:ip` entries to add to the guest's /etc/hosts
+    pub add_hosts: Vec<String>,
+    /// Cache mode of the rootfs drive, one of `DRIVE_CACHE_TYPES`
+    pub drive_cache: Option<String>,
+    /// Attach the image read-only (its `.squashfs` variant when there is one) instead of copying
+    /// it, with a fresh writable ext4 data drive for the VM's state
+    pub read_only_root: bool,
+    /// Size of the data drive in MiB (`DEFAULT_DATA_SIZE_MIB` when unset)
+    pub data_size_mib: Option<u32>,
+    /// Guest path the data drive is mounted at (`DEFAULT_DATA_MOUNT` when unset)
+    pub data_mount: Option<String>,
+}
+
+/// Guest memory Firecracker assigns when no machine config is sent.
+const DEFAULT_MEMORY_MIB: u32 = 128;
+
+/// Subnet size of id-derived VM addresses, carved out of 172.16.0.0/16.
+const DEFAULT_PREFIX_LEN: u8 = 30;
+
+/// Number of /30 subnets in 172.16.0.0/16, and so of VMs with id-derived addresses.
+const MAX_VM_IDS: u16 = 1 << (DEFAULT_PREFIX_LEN - 16);
+
+/// Grace period `rm_vm` gives Firecracker to exit after SIGTERM before sending SIGKILL.
+pub const DEFAULT_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// VMs launched by `run_vm` in this process that are still owned by it: entries are added
+/// before the launch starts and dropped once a detached VM is running or an attached one is
+/// gone. `shutdown_session` tears down whatever is left when the process is interrupted.
+static SESSION_VMS: LazyLock<Arc<Mutex<Vec<InstanceMetadata>>>> = LazyLock::new(Default::default);
+
+pub fn session_vms() -> Arc<Mutex<Vec<InstanceMetadata>>> {
+    SESSION_VMS.clone()
+}
+
+fn track_session_vm(meta: &InstanceMetadata) {
+    let mut vms = SESSION_VMS.lock().unwrap_or_else(|e| e.into_inner());
+    vms.retain(|vm| vm.name != meta.name);
+    vms.push(meta.clone());
+}
+
+/// Updates a tracked VM's entry, e.g. with the PID of a freshly spawned daemon.
+fn refresh_session_vm(meta: &InstanceMetadata) {
+    let mut vms = SESSION_VMS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(vm) = vms.iter_mut().find(|vm| vm.name == meta.name) {
+        *vm = meta.clone();
+    }
+}
+
+fn untrack_session_vm(name: &str) {
+    SESSION_VMS.lock().unwrap_or_else(|e| e.into_inner()).retain(|vm| vm.name != name);
+}
+
+/// Tears down every VM still owned by this process. Meant for a signal handler, once the
+/// `run_vm` future has been dropped.
+pub async fn shutdown_session() {
+    let vms = std::mem::take(&mut *SESSION_VMS.lock().unwrap_or_else(|e| e.into_inner()));
+    for meta in vms {
+        match teardown(&meta, DEFAULT_STOP_TIMEOUT, None).await {
+            Ok(()) => {
+                events::record(&meta.name, "remove", None);
+                println!("Cleaned up all resources for stoker-{}", meta.name);
+            }
+            Err(e) => eprintln!("Failed to clean up VM '{}': {:#}", meta.name, e),
+        }
+    }
+}
+
+/// Resolves with the signal's name once SIGINT or SIGTERM is received.
+pub async fn shutdown_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => Some(sigterm),
+        Err(e) => {
+            eprintln!("Warning: failed to install SIGTERM handler: {}", e);
+            None
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        Some(_) = async { sigterm.as_mut()?.recv().await } => "SIGTERM",
+    }
 }
 
 // We will launch the firecracker binary via Command, wait for the socket, and send REST commands.
-pub async fn run_vm(mode: &str, name_opt: Option<String>, image_opt: Option<String>) -> Result<()> {
+/// Returns the VM's metadata once it is running, or once it has exited in attached mode.
+/// Signals are left to the caller: race this against `shutdown_signal` and call
+/// `shutdown_session` if the signal wins.
+pub async fn run_vm(opts: RunConfig) -> Result<InstanceMetadata> {
+    if let Some(balloon) = opts.balloon_mib {
+        validate_balloon(opts.memory_mib.unwrap_or(DEFAULT_MEMORY_MIB), balloon)?;
+    }
+    if opts.healthcheck.as_ref().is_some_and(|check| check.retries == 0) {
+        anyhow::bail!("--health-retries must be at least 1");
+    }
+    if opts.auto_remove && !opts.attach {
+        // Nothing is left to notice a detached VM exiting
+        anyhow::bail!("--rm requires --attach");
+    }
+
+    let (run_as_uid, run_as_gid) = match opts.user.as_deref() {
+        Some(spec) => {
+            let (uid, gid) = parse_user(spec)?;
+            (Some(uid), gid)
+        }
+        None => (None, None),
+    };
+
+    if let Some(dir) = &opts.working_dir {
+        validate_working_dir(dir)?;
+    }
+    let ulimits = opts.ulimits.iter().map(|spec| parse_ulimit(spec)).collect::<Result<Vec<_>>>()?;
+    let sysctls = opts.sysctls.iter().map(|spec| parse_sysctl(spec)).collect::<Result<Vec<_>>>()?;
+    let extra_hosts = opts.add_hosts.iter().map(|spec| parse_add_host(spec)).collect::<Result<Vec<_>>>()?;
+    let tmpfs_mounts = opts.tmpfs.iter().map(|spec| parse_tmpfs(spec)).collect::<Result<Vec<_>>>()?;
+    let security_opts = opts.security_opts.iter()
+        .map(|spec| crate::security::parse_security_opt(spec))
+        .collect::<Result<Vec<_>>>()?;
+    let ipc = match opts.ipc.as_deref() {
+        None | Some("private") => None,
+        Some("host") => {
+            println!(
+                "Warning: --ipc host has no effect. The guest runs its own kernel, so its processes can't share \
+                 System V or POSIX IPC with the host; use a vsock device to talk across the VM boundary."
+            );
+            None
+        }
+        Some("shareable") => Some("shareable".to_string()),
+        Some(mode) => match mode.strip_prefix("vm:") {
+            Some(target) => {
+                ipc_namespace_of(target)?;
+                Some(mode.to_string())
+            }
+            None => anyhow::bail!("Unsupported --ipc '{}'. Use private, host, shareable or vm:<name>.", mode),
+        },
+    };
+    let log_driver = match opts.log_driver.as_deref() {
+        None | Some("none") => None,
+        Some(driver) if LOG_DRIVERS.contains(&driver) => Some(driver.to_string()),
+        Some(driver) => anyhow::bail!("Unsupported --log-driver '{}'. Use none, {}.", driver, LOG_DRIVERS.join(", ")),
+    };
+    for cpus in [&opts.cpuset, &opts.cpuset_cgroup].into_iter().flatten() {
+        parse_cpu_set(cpus)?;
+    }
+    for spec in &opts.allow {
+        crate::network::parse_allow_rule(spec)?;
+    }
+    for cidr in &opts.deny {
+        crate::network::parse_cidr(cidr).with_context(|| format!("Invalid --deny '{}'", cidr))?;
+    }
+    let rootfs_dir = opts.rootfs_dir.clone().unwrap_or_else(default_rootfs_dir);
+    if !opts.dry_run {
+        std::fs::create_dir_all(&rootfs_dir)
+            .with_context(|| format!("Failed to create rootfs directory {}", rootfs_dir))?;
+    }
+    let (init_file, init_sha256) = match &opts.init_file {
+        Some(path) => {
+            let path = std::fs::canonicalize(path)
+                .with_context(|| format!("Init file {} not found", path))?
+                .to_string_lossy()
+                .into_owned();
+            let digest = file_sha256(&path)?;
+            (Some(path), Some(digest))
+        }
+        None => (None, None),
+    };
+    let seed_entropy = match &opts.seed_entropy {
+        Some(path) => Some(
+            std::fs::canonicalize(path)
+                .with_context(|| format!("Entropy seed file {} not found", path))?
+                .to_string_lossy()
+                .into_owned(),
+        ),
+        None => None,
+    };
+    let source = match &opts.from_snapshot {
+        Some(snapshot) => {
+            check_snapshot_compatible(&opts)?;
+            Some(load_snapshot_metadata(snapshot)?)
+        }
+        None => None,
+    };
+    if let Some(name) = &opts.name {
+        match check_name_unused_in_dir("/tmp", name) {
+            Err(_) if opts.replace && opts.dry_run => println!("Would replace existing VM '{}'", name),
+            Err(_) if opts.replace => {
+                println!("Replacing existing VM '{}'...", name);
+                rm_vm(name, DEFAULT_STOP_TIMEOUT, false).await?;
+            }
+            result => result?,
+        }
+    }
+    let custom_mac = match opts.mac.as_deref() {
+        Some(mac) => {
+            let mac = validate_mac(mac)?;
+            check_mac_unused_in_dir("/tmp", &mac)?;
+            Some(mac)
+        }
+        None => None,
+    };
+
+    let custom_ips = match (opts.guest_ip.as_deref(), opts.host_ip.as_deref()) {
+        (Some(guest), Some(host)) => Some(validate_custom_ips(guest, host)?),
+        (None, None) => None,
+        _ => anyhow::bail!("--ip and --host-ip must be given together"),
+    };
+
     // 1. Allocate ID and Networking Parameters
     let id = allocate_vm_id()?;
-    let name = name_opt.unwrap_or_else(|| format!("fc-{:02x}", id));
-    let base_image = image_opt.unwrap_or_else(|| "ubuntu-rootfs".to_string());
-    
-    let host_ip = format!("172.16.{}.1", id);
-    let guest_ip = format!("172.16.{}.2", id);
-    let mac_address = format!("06:00:AC:10:{:02x}:02", id);
-    let tap_device = format!("tap-inet-{}", id);
+    let (guest_ip, host_ip, prefix_len) = match custom_ips {
+        Some((guest, host, prefix)) => (guest, host, Some(prefix)),
+        None => {
+            let (guest, host) = vm_addresses(id);
+            (guest, host, None)
+        }
+    };
+    check_subnet_unused_in_dir("/tmp", &host_ip, prefix_len.unwrap_or(DEFAULT_PREFIX_LEN))?;
+    let name = opts.name.clone().unwrap_or_else(|| format!("fc-{:02x}", id));
+    if opts.name.is_none() {
+        // A VM may have been given this name explicitly; never overwrite its metadata
+        check_name_unused_in_dir("/tmp", &name)?;
+    }
+    let base_image = opts.image.clone().unwrap_or_else(|| "ubuntu-rootfs".to_string());
     
-    // 2. Setup isolated TAP interface dynamically per VM
-    crate::network::setup_vm_tap(&tap_device, &host_ip).await?;
-    let socket_path = format!("/tmp/firecracker-{}.socket", name);
-    let log_path = format!("/tmp/firecracker-{}.log", name);
+    let mut meta = InstanceMetadata {
+        id,
+        name: name.clone(),
+        mode: opts.mode.clone(),
+        guest_ip,
+        host_ip,
+        prefix_len,
+        mac_address: custom_mac.unwrap_or_else(|| vm_mac(id, 2)),
+        tap_device: crate::network::tap_name("tap-inet-", &id.to_string(), &taken_tap_names()),
+        egress: opts.egress.clone(),
+        egress_allow: opts.allow.clone(),
+        egress_deny: opts.deny.clone(),
+        pids_limit: opts.pids_limit,
+        memory_limit_mib: opts.memory_limit_mib,
+        tmpfs_size_mib: opts.tmpfs_size_mib,
+        memory_mib: opts.memory_mib,
+        initial_balloon_mib: opts.balloon_mib,
+        firecracker_bin: Some(crate::assets::resolve_firecracker_binary(opts.firecracker_bin.as_deref())),
+        health: opts.healthcheck.as_ref().map(|_| "starting".to_string()),
+        healthcheck: opts.healthcheck.clone(),
+        watchdog_enabled: opts.watchdog_interval_secs.is_some(),
+        watchdog_interval_secs: opts.watchdog_interval_secs,
+        watchdog_grace_failures: opts.watchdog_interval_secs.map(|_| opts.watchdog_grace_failures),
+        oom_score_adj: opts.oom_score_adj,
+        run_as_uid,
+        run_as_gid,
+        workdir: opts.working_dir.clone(),
+        persist_network: opts.persist_network,
+        ulimits,
+        sysctls,
+        tmpfs_mounts,
+        security_opts,
+        init_file,
+        init_sha256,
+        image: Some(base_image.clone()),
+        ipc,
+        log_driver,
+        cpuset: opts.cpuset.clone(),
+        cpuset_cgroup: opts.cpuset_cgroup.clone(),
+        rootfs_dir: Some(rootfs_dir),
+        seed_entropy,
+        extra_hosts,
+        drive_cache: opts.drive_cache.as_deref().map(drive_cache_type).transpose()?,
+        data_mount: data_mount(&opts)?,
+        ..Default::default()
+    };
     
-    // Ensure the log file exists as required by Firecracker
-    let _ = std::fs::File::create(&log_path);
+    if let Some(source) = &source {
+        inherit_snapshot_settings(&mut meta, source);
+    }
+
+    // Find either custom image or default to the baseline
+    let target_image_path = match &opts.from_snapshot {
+        Some(snapshot) => format!("{}/rootfs.ext4", snapshot_dir(snapshot)),
+        None if opts.read_only_root => read_only_root_image(&base_image),
+        None => crate::assets::get_asset_path(&format!("{}.ext4", base_image)),
+    };
+    if !std::path::Path::new(&target_image_path).exists() {
+        anyhow::bail!(StokerError::new(ErrorKind::AssetMissing, format!("Rootfs image not found at {}. Run `stoker build` or `stoker download-assets`.", target_image_path)));
+    }
+    if opts.read_only_root {
+        meta.read_only_root = Some(target_image_path.clone());
+    }
+    // A snapshot's guest already booted on a host like this one
+    if opts.from_snapshot.is_none() {
+        let kernel = crate::assets::get_asset_path("vmlinux.bin");
+        // Detecting an unrecorded arch loop-mounts the image, which a dry run must not do
+        let image_arch = if opts.dry_run {
+            crate::builder::load_image_meta(&base_image).arch
+        } else {
+            crate::builder::image_arch(&base_image)
+        };
+        check_platform(&base_image, image_arch.as_deref(), kernel_arch(&kernel), std::env::consts::ARCH)?;
+    }
+
+    let fc_binary = crate::assets::resolve_firecracker_binary(meta.firecracker_bin.as_deref());
+    let fc_version = crate::assets::check_firecracker_binary(&fc_binary)?;
+    println!("Using Firecracker {} at {}", fc_version, fc_binary);
+
+    if opts.dry_run {
+        print_dry_run(&meta)?;
+        return Ok(meta);
+    }
+
+    track_session_vm(&meta);
+    let mut child = match opts.timeout_secs {
+        Some(secs) => {
+            let launched = tokio::time::timeout(
+                Duration::from_secs(secs),
+                launch(&mut meta, &opts, &target_image_path, &base_image, source.as_ref()),
+            ).await;
+            match launched {
+                Ok(result) => result?,
+                Err(_) => {
+                    let report = timeout_report(secs, &meta);
+                    events::record(&name, "timeout", Some(&format!("after {}s", secs)));
+                    teardown(&meta, Duration::ZERO, None).await?;
+                    untrack_session_vm(&name);
+                    anyhow::bail!("{}", report);
+                }
+            }
+        }
+        None => launch(&mut meta, &opts, &target_image_path, &base_image, source.as_ref()).await?,
+    };
+
+    if !opts.attach {
+        // Report the first verdict here; a background supervisor keeps probing after that
+        if let Some(check) = meta.healthcheck.clone() {
+            monitor_health(name.clone(), check, true).await;
+        }
+        if meta.healthcheck.is_some() || meta.watchdog_enabled {
+            let (pid, start_time) = spawn_supervisor(&name)?;
+            meta = update_metadata(&name, |meta| {
+                meta.supervisor_pid = Some(pid);
+                meta.supervisor_start_time = start_time;
+            })?;
+        }
+        println!("VM is running in background. PID: {}", meta.pid);
+        untrack_session_vm(&name);
+        return Ok(meta);
+    }
+
+    if let Some(check) = meta.healthcheck.clone() {
+        tokio::spawn(monitor_health(name.clone(), check, false));
+    }
+
+    // 6. Attached mode: stay in the foreground like `docker run` without `-d`
+    println!("VM '{}' is running in foreground. PID: {}. Press Ctrl-C to stop and remove it.", name, meta.pid);
+    if let Some(interval) = meta.watchdog_interval_secs {
+        let detail = format!("enabled (interval {}s, {} grace failures)", interval, opts.watchdog_grace_failures);
+        events::record(&name, "watchdog", Some(&detail));
+    }
+    loop {
+        tokio::select! {
+            status = child.wait() => {
+                let status = status.context("Failed to wait on firecracker daemon")?;
+                let detail = match status.code() {
+                    Some(code) => format!("exit code {}", code),
+                    None => "terminated by a signal".to_string(),
+                };
+                println!("Firecracker exited: {}", detail);
+                events::record(&name, "die", Some(&detail));
+                if opts.auto_remove {
+                    println!("Removing VM '{}' (--rm)...", name);
+                    rm_vm(&name, Duration::ZERO, false).await?;
+                }
+                break;
+            }
+            _ = watchdog(&meta, opts.watchdog_grace_failures), if meta.watchdog_enabled => {
+                let (new_meta, new_child) = watchdog_restart(&name).await?;
+                meta = new_meta;
+                child = new_child;
+            }
+        }
+    }
+    untrack_session_vm(&name);
+    Ok(meta)
+}
+
+/// Steps 2-5 of `run_vm`: everything that can stall waiting on the daemon or the guest.
+/// `source` is the metadata of the snapshot being resumed, if any.
+async fn launch(
+    meta: &mut InstanceMetadata,
+    opts: &RunConfig,
+    image_path: &str,
+    base_image: &str,
+    source: Option<&InstanceMetadata>,
+) -> Result<tokio::process::Child> {
+    let name = meta.name.clone();
+
+    // 2. Setup isolated TAP interface dynamically per VM, or have a CNI plugin provide the network
+    match &opts.cni_plugin {
+        Some(plugin) => {
+            let cni = crate::network::setup_cni(plugin, &name, meta).await?;
+            let Some(gateway) = cni.gateway.clone() else {
+                crate::network::teardown_cni(&name, &cni).await;
+                anyhow::bail!("CNI plugin {} returned no gateway for the guest's default route", plugin);
+            };
+            meta.guest_ip = cni.address.clone();
+            meta.host_ip = gateway;
+            meta.prefix_len = Some(cni.prefix_len);
+            meta.cni = Some(cni);
+            // Lets a Ctrl-C during the rest of the launch release the CNI network too
+            track_session_vm(meta);
+        }
+        None => {
+            crate::network::setup_vm_tap(&meta.tap_device, &meta.host_ip, meta.prefix_len(), meta.egress.as_deref()).await?;
+            setup_egress_rules(meta).await?;
+        }
+    }
+
+    // 3. Clone the writable rootfs, or create the data drive of a read-only root
+    if meta.read_only_root.is_some() {
+        create_data_drive(&data_drive_path(meta), opts.data_size_mib.unwrap_or(DEFAULT_DATA_SIZE_MIB)).await?;
+    } else if crate::assets::clone_image(image_path, &rootfs_path(meta), 0)? {
+        println!("Cloned rootfs with a copy-on-write reflink.");
+    }
+    if let (Some(script), None) = (&meta.init_file, source) {
+        println!("Injecting init file {} (sha256 {})...", script, meta.init_sha256.as_deref().unwrap_or("?"));
+        inject_init_script(&rootfs_path(meta), script).await?;
+    }
+    // 4-5. Boot the daemon and configure the guest over SSH, or resume a snapshot whose guest
+    // is already configured and only needs moving onto this VM's subnet
+    let child = match (source, &opts.from_snapshot) {
+        (Some(source), Some(snapshot)) => {
+            events::record(&name, "create", Some(&format!("snapshot {}", snapshot)));
+            let child = restore_instance(meta, snapshot).await?;
+            guest::reconcile_restored_network(meta, source).await?;
+            child
+        }
+        _ => {
+            events::record(&name, "create", Some(base_image));
+            let child = boot_instance(meta).await?;
+            guest::setup_guest_network(meta).await?;
+            child
+        }
+    };
     
-    // Clean up old socket if it exists
-    let _ = std::fs::remove_file(&socket_path);
+    // Save state metadata implementation_plan style
+    save_metadata(meta)?;
+    events::record(&name, "start", None);
 
-    // Launch Firecracker daemon in background
-    println!("Starting Firecracker daemon...");
-    let fc_binary = crate::assets::get_asset_path("firecracker");
-    let mut child = Command::new(&fc_binary)
-        .arg("--api-sock")
-        .arg(&socket_path)
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()
-        .context("Failed to spawn firecracker daemon")?;
+    if !opts.wait_ports.is_empty() {
+        guest::wait_for_ports(&meta.guest_ip, &opts.wait_ports, Duration::from_secs(opts.wait_timeout_secs)).await?;
+    }
+    Ok(child)
+}
 
-    // Give it a moment to create the socket
-    sleep(Duration::from_millis(500)).await;
+/// Describes how far a launch got before `--timeout` expired.
+fn timeout_report(secs: u64, meta: &InstanceMetadata) -> String {
+    let daemon_alive = meta.pid != 0 && is_process_alive(meta.pid, meta.pid_start_time);
+    let socket_present = std::path::Path::new(&format!("/tmp/firecracker-{}.socket", meta.name)).exists();
+    let ssh_reachable = format!("{}:22", meta.guest_ip).parse()
+        .is_ok_and(|addr| std::net::TcpStream::connect_timeout(&addr, Duration::from_secs(1)).is_ok());
+    format_timeout_report(secs, &meta.name, daemon_alive, socket_present, ssh_reachable)
+}
 
-    // Use hyperlocal for unix socket client
-    let client = Client::unix();
+fn format_timeout_report(secs: u64, name: &str, daemon_alive: bool, socket_present: bool, ssh_reachable: bool) -> String {
+    let yes_no = |b: bool| if b { "yes" } else { "no" };
+    format!(
+        "VM '{}' did not come up within {}s (daemon alive: {}, API socket present: {}, SSH reachable: {}); its resources were cleaned up",
+        name, secs, yes_no(daemon_alive), yes_no(socket_present), yes_no(ssh_reachable)
+    )
+}
+
+/// Probes the guest's SSH port every watchdog interval and returns once `grace_failures`
+/// consecutive probes have failed.
+async fn watchdog(meta: &InstanceMetadata, grace_failures: u32) {
+    let interval = Duration::from_secs(meta.watchdog_interval_secs.unwrap_or(30) as u64);
+    let addr = format!("{}:22", meta.guest_ip);
+    let mut failures = 0;
+    loop {
+        sleep(interval).await;
+        let probe = tokio::time::timeout(Duration::from_secs(2), tokio::net::TcpStream::connect(&addr)).await;
+        if matches!(probe, Ok(Ok(_))) {
+            failures = 0;
+            continue;
+        }
+        failures += 1;
+        let detail = format!("probe of {} failed ({}/{})", addr, failures, grace_failures);
+        events::record(&meta.name, "watchdog", Some(&detail));
+        if failures >= grace_failures {
+            return;
+        }
+    }
+}
+
+/// Restarts VM `name` after the watchdog found it unresponsive. A VM that can't be restarted is
+/// torn down completely rather than left without a daemon.
+async fn watchdog_restart(name: &str) -> Result<(InstanceMetadata, tokio::process::Child)> {
+    println!("Watchdog: VM '{}' is unresponsive, restarting it...", name);
+    events::record(name, "watchdog", Some("restarting unresponsive VM"));
+    // A hung guest won't honour SIGTERM, so don't wait for it
+    match relaunch(name, Duration::ZERO).await {
+        Ok((meta, child)) => {
+            events::record(name, "watchdog", Some(&format!("restarted VM (PID: {})", meta.pid)));
+            Ok((meta, child))
+        }
+        Err(e) => {
+            events::record(name, "watchdog", Some("restart failed, removing VM"));
+            if let Ok(meta) = load_metadata(name) {
+                teardown(&meta, Duration::ZERO, None).await?;
+            }
+            untrack_session_vm(name);
+            Err(e.context(format!("Watchdog failed to restart VM '{}'; its resources were cleaned up", name)))
+        }
+    }
+}
+
+/// Watches a detached VM for the supervisor, restarting it whenever `grace_failures`
+/// consecutive probes fail. Returns once the VM is removed.
+async fn restart_when_unresponsive(name: &str, grace_failures: u32) -> Result<()> {
+    loop {
+        let Ok(meta) = load_metadata(name) else {
+            return Ok(());
+        };
+        watchdog(&meta, grace_failures).await;
+        // Removed while being probed
+        if load_metadata(name).is_err() {
+            return Ok(());
+        }
+        watchdog_restart(name).await?;
+    }
+}
+
+/// Gracefully stops a VM's daemon and boots it again with the same id, subnet and rootfs.
+pub async fn restart_vm(name: &str, grace: Duration) -> Result<()> {
+    let (meta, _child) = relaunch(name, grace).await?;
+    println!("VM is running in background. PID: {}", meta.pid);
+    Ok(())
+}
+
+/// Replaces VM `name` with the state saved in `snapshot`, a snapshot name or directory. The
+/// current daemon is stopped gracefully and a new one resumes the snapshot on the VM's own
+/// TAP, subnet and a fresh copy of the snapshot's rootfs. Until the restored guest answers on
+/// SSH, the old rootfs and metadata are kept, and put back if the rollback fails.
+pub async fn rollback_vm(name: &str, snapshot: &str, grace: Duration) -> Result<()> {
+    let old = load_metadata(name)?;
+    let source = load_snapshot_metadata(snapshot)?;
+    let snapshot_rootfs = format!("{}/rootfs.ext4", snapshot_dir(snapshot));
+    let live_rootfs = rootfs_path(&old);
+    let staged_rootfs = format!("{}.rollback", live_rootfs);
+    let backup_rootfs = format!("{}.pre-rollback", live_rootfs);
+    crate::assets::clone_image(&snapshot_rootfs, &staged_rootfs, 0)?;
+
+    stop_process(old.pid, old.pid_start_time, grace).await;
+    crate::cgroup::remove(name);
+    events::record(name, "stop", None);
+
+    let mut meta = old.clone();
+    inherit_snapshot_settings(&mut meta, &source);
+    let restored = async {
+        std::fs::rename(&live_rootfs, &backup_rootfs).with_context(|| format!("Failed to set aside {}", live_rootfs))?;
+        std::fs::rename(&staged_rootfs, &live_rootfs).with_context(|| format!("Failed to move the snapshot's rootfs to {}", live_rootfs))?;
+        // Host-side networking isn't part of the snapshot; a CNI network outlives the daemon
+        if meta.cni.is_none() {
+            crate::network::setup_vm_tap(&meta.tap_device, &meta.host_ip, meta.prefix_len(), meta.egress.as_deref()).await?;
+            setup_egress_rules(&meta).await?;
+        }
+        let child = restore_instance(&mut meta, snapshot).await?;
+        guest::reconcile_restored_network(&meta, &source).await?;
+        Ok::<_, anyhow::Error>(child)
+    }.await;
+
+    match restored {
+        Ok(_child) => {
+            save_metadata(&meta)?;
+            let _ = std::fs::remove_file(&backup_rootfs);
+            events::record(name, "start", Some(&format!("rollback to snapshot {}", snapshot)));
+            println!("VM '{}' rolled back to snapshot '{}'. PID: {}", name, snapshot, meta.pid);
+            Ok(())
+        }
+        Err(e) => {
+            if meta.pid != old.pid {
+                stop_process(meta.pid, meta.pid_start_time, Duration::ZERO).await;
+                crate::cgroup::remove(name);
+            }
+            if std::path::Path::new(&backup_rootfs).exists() {
+                let _ = std::fs::rename(&backup_rootfs, &live_rootfs);
+            }
+            let _ = std::fs::remove_file(&staged_rootfs);
+            Err(e.context(format!(
+                "Rollback of VM '{}' failed; its previous rootfs and metadata were kept. Start it again with `stoker restart {}`",
+                name, name
+            )))
+        }
+    }
+}
+
+/// Applies the VM's `--allow`/`--deny` rules on a blocking thread, as iptables is run and
+/// waited on synchronously.
+async fn setup_egress_rules(meta: &InstanceMetadata) -> Result<()> {
+    let meta = meta.clone();
+    tokio::task::spawn_blocking(move || {
+        crate::network::setup_egress_rules(&meta.name, &meta.host_ip, meta.prefix_len(), &meta.egress_allow, &meta.egress_deny)
+    }).await?
+}
+
+/// Removes the rules `setup_egress_rules` applied for the VM, on a blocking thread.
+async fn teardown_egress_rules(meta: &InstanceMetadata) {
+    let meta = meta.clone();
+    let _ = tokio::task::spawn_blocking(move || {
+        crate::network::teardown_egress_rules(&meta.name, &meta.host_ip, meta.prefix_len(), &meta.egress_allow, &meta.egress_deny)
+    }).await;
+}
+
+/// Stops the VM's current daemon and boots a new one, returning the updated metadata and child.
+/// When booting fails, a new daemon that was already started is stopped again and the
+/// metadata still describes the stopped VM.
+async fn relaunch(name: &str, grace: Duration) -> Result<(InstanceMetadata, tokio::process::Child)> {
+    let mut meta = load_metadata(name)?;
+    let old_pid = meta.pid;
+
+    stop_process(meta.pid, meta.pid_start_time, grace).await;
+    crate::cgroup::remove(name);
+    events::record(name, "stop", None);
+
+    let booted = async {
+        // A CNI network and the TAP in its namespace outlive the daemon
+        if meta.cni.is_none() {
+            crate::network::setup_vm_tap(&meta.tap_device, &meta.host_ip, meta.prefix_len(), meta.egress.as_deref()).await?;
+            setup_egress_rules(&meta).await?;
+        }
+        let child = boot_instance(&mut meta).await?;
+        guest::setup_guest_network(&meta).await?;
+        Ok::<_, anyhow::Error>(child)
+    }.await;
+    match booted {
+        Ok(child) => {
+            save_metadata(&meta)?;
+            events::record(name, "start", None);
+            Ok((meta, child))
+        }
+        Err(e) => {
+            if meta.pid != old_pid {
+                stop_process(meta.pid, meta.pid_start_time, Duration::ZERO).await;
+                crate::cgroup::remove(name);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// One configuration call in the Firecracker boot sequence.
+struct ApiRequest {
+    description: String,
+    path: String,
+    body: serde_json::Value,
+}
+
+/// Kernel command line, including the `stoker.*` parameters read by guest-side scripts.
+fn boot_args(meta: &InstanceMetadata) -> String {
+    let mut args = "console=ttyS0 reboot=k panic=1 pci=off keep_bootcon".to_string();
+    if let Some(uid) = &meta.run_as_uid {
+        args.push_str(&format!(" stoker.run_as_uid={}", uid));
+    }
+    if let Some(gid) = &meta.run_as_gid {
+        args.push_str(&format!(" stoker.run_as_gid={}", gid));
+    }
+    if let Some(dir) = &meta.workdir {
+        args.push_str(&format!(" stoker.workdir={}", dir));
+    }
+    for ulimit in &meta.ulimits {
+        args.push_str(&format!(" stoker.ulimit.{}", ulimit));
+    }
+    if meta.init_file.is_some() {
+        // The kernel hands bare words without a '.' to init as arguments, so the real init
+        // reaches the script as $1
+        args.push_str(&format!(" init={} {}", GUEST_INIT_SCRIPT, REAL_INIT));
+    }
+    args
+}
+
+/// Where `--init-file` scripts are placed in the VM's rootfs.
+const GUEST_INIT_SCRIPT: &str = "/stoker-init.sh";
+
+/// The image's own init, which an `--init-file` script should exec when it is done.
+const REAL_INIT: &str = "/sbin/init";
+
+/// Hex SHA-256 of the file at `path`.
+fn file_sha256(path: &str) -> Result<String> {
+    let content = std::fs::read(path).with_context(|| format!("Failed to read {}", path))?;
+    Ok(crate::signing::hex(&Sha256::digest(&content)))
+}
+
+/// Copies `script` into the VM's rootfs at `GUEST_INIT_SCRIPT` as an executable. The kernel
+/// execs it directly, so a `#!/bin/sh` line is added if the script lacks an interpreter line.
+async fn inject_init_script(rootfs: &str, script: &str) -> Result<()> {
+    let content = std::fs::read_to_string(script)
+        .with_context(|| format!("Failed to read init file {}", script))?;
+    let content = if content.starts_with("#!") { content } else { format!("#!/bin/sh\n{}", content) };
+
+    let mount_dir = format!("{}.init-mnt", rootfs);
+    std::fs::create_dir_all(&mount_dir)?;
+    let status = Command::new("mount").args(["-o", "loop", rootfs, &mount_dir]).status().await?;
+    if !status.success() {
+        let _ = std::fs::remove_dir(&mount_dir);
+        anyhow::bail!("Failed to loop mount {} to inject the init file", rootfs);
+    }
+    let target = format!("{}{}", mount_dir, GUEST_INIT_SCRIPT);
+    let written = std::fs::write(&target, content).and_then(|_| {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o755))
+    });
+    let _ = Command::new("umount").arg(&mount_dir).status().await;
+    let _ = std::fs::remove_dir(&mount_dir);
+    written.with_context(|| format!("Failed to write {} into the rootfs", GUEST_INIT_SCRIPT))
+}
+
+/// Parses a `--tmpfs` value of the form `<path>[:size_mib]`. The path must be absolute and is
+/// used unquoted in guest commands and fstab, so it may only contain safe characters.
+fn parse_tmpfs(spec: &str) -> Result<TmpfsMount> {
+    let (path, size) = match spec.split_once(':') {
+        Some((path, size)) => (path, Some(size)),
+        None => (spec, None),
+    };
+    let safe = |c: char| c.is_ascii_alphanumeric() || "/._-".contains(c);
+    if !path.starts_with('/') || !path.chars().all(safe) {
+        anyhow::bail!("Invalid --tmpfs path '{}'. Expected an absolute path of letters, digits, '/', '.', '_' or '-'.", path);
+    }
+    let size_mib = match size {
+        Some(size) => match size.parse::<u32>() {
+            Ok(mib) if mib > 0 => Some(mib),
+            _ => anyhow::bail!("Invalid --tmpfs size '{}'. Expected a positive number of MiB.", size),
+        },
+        None => None,
+    };
+    Ok(TmpfsMount { path: path.to_string(), size_mib })
+}
+
+/// Resource types `--ulimit` accepts.
+const ULIMIT_TYPES: [&str; 4] = ["nofile", "nproc", "core", "fsize"];
+
+/// Validates a `--ulimit` value of the form `<type>=<soft>[:<hard>]` and returns it as
+/// `<type>=<soft>:<hard>`. Limits are numbers or `unlimited`, and soft may not exceed hard.
+fn parse_ulimit(spec: &str) -> Result<String> {
+    let (kind, limits) = spec.split_once('=')
+        .with_context(|| format!("Invalid --ulimit '{}'. Expected <type>=<soft>[:<hard>], e.g. nofile=1024:4096.", spec))?;
+    if !ULIMIT_TYPES.contains(&kind) {
+        anyhow::bail!("Unsupported --ulimit type '{}'. Supported: {}", kind, ULIMIT_TYPES.join(", "));
+    }
+    let (soft, hard) = limits.split_once(':').unwrap_or((limits, limits));
+    let parse_limit = |value: &str| -> Result<Option<u64>> {
+        if value == "unlimited" {
+            return Ok(None);
+        }
+        value.parse().map(Some).map_err(|_| anyhow::anyhow!("Invalid --ulimit '{}': '{}' is not a number or 'unlimited'", spec, value))
+    };
+    // None is unlimited, which orders above every number
+    match (parse_limit(soft)?, parse_limit(hard)?) {
+        (None, Some(_)) => anyhow::bail!("Invalid --ulimit '{}': soft limit exceeds hard limit", spec),
+        (Some(s), Some(h)) if s > h => anyhow::bail!("Invalid --ulimit '{}': soft limit exceeds hard limit", spec),
+        _ => Ok(format!("{}={}:{}", kind, soft, hard)),
+    }
+}
+
+/// `--sysctl` namespaces a guest may tune.
+const SYSCTL_PREFIXES: [&str; 3] = ["net.", "vm.", "kernel."];
+/// Keys that could hang or crash the guest or hand it a debugging backdoor. A trailing `*`
+/// matches the related keys too (kernel.panic_on_oops, ...).
+const DENIED_SYSCTLS: [&str; 5] = ["kernel.sysrq", "kernel.panic*", "kernel.core_pattern", "kernel.modprobe", "kernel.modules_disabled"];
+
+/// Validates a `--sysctl` value of the form `<key>=<value>` and returns it with `/` separators in
+/// the key normalized to dots.
+fn parse_sysctl(spec: &str) -> Result<String> {
+    let (key, value) = spec.split_once('=')
+        .with_context(|| format!("Invalid --sysctl '{}'. Expected <key>=<value>, e.g. net.ipv4.tcp_fin_timeout=30.", spec))?;
+    let key = key.trim().replace('/', ".");
+    let valid_key = !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if !valid_key || !SYSCTL_PREFIXES.iter().any(|prefix| key.starts_with(prefix)) {
+        anyhow::bail!("Unsupported --sysctl key '{}'. Keys must start with {}.", key, SYSCTL_PREFIXES.join(", "));
+    }
+    let denied = DENIED_SYSCTLS.iter().any(|denied| match denied.strip_suffix('*') {
+        Some(prefix) => key.starts_with(prefix),
+        None => key == *denied,
+    });
+    if denied {
+        anyhow::bail!("--sysctl {} is not allowed: it could crash or compromise the guest", key);
+    }
+    // Values go to the guest inside single quotes
+    if value.is_empty() || value.contains(['\'', '\n']) {
+        anyhow::bail!("Invalid --sysctl '{}': the value must be non-empty and contain no quotes or newlines", spec);
+    }
+    Ok(format!("{}={}", key, value))
+}
+
+/// Validates an `--add-host` value of the form `<name>:<ip>`. The address may be an IPv6 one
+/// or `host-gateway`, Docker's name for the host end of the VM's network.
+fn parse_add_host(spec: &str) -> Result<String> {
+    let (name, ip) = spec.split_once(':')
+        .with_context(|| format!("Invalid --add-host '{}'. Expected <name>:<ip>, e.g. db.internal:172.16.0.6.", spec))?;
+    let valid_name = !name.is_empty()
+        && name.len() <= 253
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+        && !name.starts_with(['-', '.']);
+    if !valid_name {
+        anyhow::bail!("Invalid --add-host name '{}'. Use letters, digits, '.', '-' and '_'.", name);
+    }
+    if ip != HOST_GATEWAY && ip.parse::<std::net::IpAddr>().is_err() {
+        anyhow::bail!("Invalid --add-host address '{}' for {}. Use an IP address or {}.", ip, name, HOST_GATEWAY);
+    }
+    Ok(format!("{}:{}", name, ip))
+}
+
+/// `--add-host` address standing for the VM's gateway on the host.
+pub const HOST_GATEWAY: &str = "host-gateway";
+
+/// Working directories travel on the kernel command line, so they must be absolute and space-free.
+fn validate_working_dir(dir: &str) -> Result<()> {
+    if !dir.starts_with('/') {
+        anyhow::bail!("--working-dir must be an absolute path, got '{}'", dir);
+    }
+    if dir.chars().any(char::is_whitespace) {
+        anyhow::bail!("--working-dir can't contain whitespace, got '{}'", dir);
+    }
+    Ok(())
+}
+
+/// Splits a `--user` value of the form `<uid>[:gid]`. Each part must be numeric or `nobody`.
+fn parse_user(spec: &str) -> Result<(String, Option<String>)> {
+    let (uid, gid) = match spec.split_once(':') {
+        Some((uid, gid)) => (uid, Some(gid)),
+        None => (spec, None),
+    };
+    for part in std::iter::once(uid).chain(gid) {
+        let valid = part == "nobody" || (!part.is_empty() && part.chars().all(|c| c.is_ascii_digit()));
+        if !valid {
+            anyhow::bail!("Invalid --user '{}'. Expected <uid>[:gid] with numeric ids or 'nobody'.", spec);
+        }
+    }
+    Ok((uid.to_string(), gid.map(str::to_string)))
+}
+
+/// The `/logger` call pointing the daemon's log at /tmp/firecracker-<name>.log. Sent first on
+/// both boot and snapshot restore.
+fn logger_request(meta: &InstanceMetadata) -> ApiRequest {
+    ApiRequest {
+        description: "Configuring VM Logger...".to_string(),
+        path: "/logger".to_string(),
+        body: json!({
+            "log_path": format!("/tmp/firecracker-{}.log", meta.name),
+            "level": "Debug",
+            "show_level": true,
+            "show_log_origin": true
+        }),
+    }
+}
+
+/// Builds the ordered API calls that configure and start the instance described by `meta`.
+fn boot_requests(meta: &InstanceMetadata) -> Vec<ApiRequest> {
+    let mut requests = Vec::new();
+    let mut push = |description: String, path: String, body: serde_json::Value| {
+        requests.push(ApiRequest { description, path, body });
+    };
 
     // 1. Logger
-    println!("Configuring VM Logger...");
-    let logger_payload = json!({
-        "log_path": log_path,
-        "level": "Debug",
-        "show_level": true,
-        "show_log_origin": true
-    }).to_string();
-    send_request(&client, &socket_path, "/logger", logger_payload).await?;
+    let logger = logger_request(meta);
+    push(logger.description, logger.path, logger.body);
+
+    // Machine resources
+    if let Some(memory_mib) = meta.memory_mib {
+        push(format!("Configuring Machine ({} MiB)...", memory_mib), "/machine-config".to_string(), json!({
+            // Firecracker requires both fields; keep its default single vCPU
+            "vcpu_count": CONFIGURED_VCPUS,
+            "mem_size_mib": memory_mib
+        }));
+    }
 
     // 2. Boot Source
-    println!("Configuring Boot Source...");
-    let boot_payload = json!({
+    push("Configuring Boot Source...".to_string(), "/boot-source".to_string(), json!({
         "kernel_image_path": crate::assets::get_asset_path("vmlinux.bin"),
-        "boot_args": "console=ttyS0 reboot=k panic=1 pci=off keep_bootcon"
-    }).to_string();
-    send_request(&client, &socket_path, "/boot-source", boot_payload).await?;
+        "boot_args": boot_args(meta)
+    }));
 
     // 3. Drives
-    println!("Configuring Drives...");
-    let rootfs_dest = format!("/tmp/rootfs-{}.ext4", name);
-    // Find either custom image or default to the baseline
-    let target_image_path = crate::assets::get_asset_path(&format!("{}.ext4", base_image));
-    if !std::path::Path::new(&target_image_path).exists() {
-        anyhow::bail!("Rootfs image not found at {}. Run `stoker build` or `stoker download-assets`.", target_image_path);
-    }
-    
-    std::fs::copy(&target_image_path, &rootfs_dest)?;
-    
-    let drive_payload = json!({
+    // Firecracker adds `root=/dev/vda ro` to the kernel command line for a read-only root
+    let mut drive = json!({
         "drive_id": "rootfs",
-        "path_on_host": rootfs_dest,
+        "path_on_host": meta.read_only_root.clone().unwrap_or_else(|| rootfs_path(meta)),
         "is_root_device": true,
-        "is_read_only": false
-    }).to_string();
-    send_request(&client, &socket_path, "/drives/rootfs", drive_payload).await?;
+        "is_read_only": meta.read_only_root.is_some()
+    });
+    if let Some(cache_type) = &meta.drive_cache {
+        drive["cache_type"] = json!(cache_type);
+    }
+    push("Configuring Drives...".to_string(), "/drives/rootfs".to_string(), drive);
+    if meta.read_only_root.is_some() {
+        // Attached second, so the guest sees it as /dev/vdb
+        push("Configuring Data Drive...".to_string(), "/drives/data".to_string(), json!({
+            "drive_id": "data",
+            "path_on_host": data_drive_path(meta),
+            "is_root_device": false,
+            "is_read_only": false
+        }));
+    }
 
     // 4. Network Interfaces
-    println!("Configuring Network Interface...");
-    let net_payload = json!({
+    push("Configuring Network Interface...".to_string(), "/network-interfaces/net1".to_string(), json!({
         "iface_id": "net1",
-        "guest_mac": mac_address,
-        "host_dev_name": tap_device
-    }).to_string();
-    send_request(&client, &socket_path, "/network-interfaces/net1", net_payload).await?;
+        "guest_mac": meta.mac_address,
+        "host_dev_name": meta.tap_device
+    }));
+
+    // Balloon must be configured before boot to take effect from the start
+    if let Some(balloon_mib) = meta.initial_balloon_mib {
+        push(format!("Configuring Balloon ({} MiB)...", balloon_mib), "/balloon".to_string(), json!({
+            "amount_mib": balloon_mib,
+            "deflate_on_oom": true,
+            "stats_polling_interval_s": 1
+        }));
+    }
 
     // 5. Start Instance
-    println!("Sending InstanceStart action...");
-    let action_payload = json!({
+    push("Sending InstanceStart action...".to_string(), "/actions".to_string(), json!({
         "action_type": "InstanceStart"
-    }).to_string();
-    send_request(&client, &socket_path, "/actions", action_payload).await?;
+    }));
 
-    println!("MicroVM Booted successfully via Unix API.");
-    
-    // 6. Connect via Guest module
-    guest::setup_guest_network(&guest_ip, &host_ip, mode).await?;
-    
-    // Save state metadata implementation_plan style
-    let meta = InstanceMetadata {
-        id,
-        name: name.clone(),
-        mode: mode.to_string(),
-        guest_ip,
-        host_ip,
+    requests
+}
+
+/// Prints the API calls `boot_instance` would make for `meta` without touching the host.
+fn print_dry_run(meta: &InstanceMetadata) -> Result<()> {
+    for request in boot_requests(meta) {
+        println!("PUT {}", request.path);
+        println!("{}", serde_json::to_string_pretty(&request.body)?);
+    }
+    Ok(())
+}
+
+/// Probes the VM with its healthcheck command and records `healthy` after a success or
+/// `unhealthy` after `retries` consecutive failures. Returns once the VM is removed, or after
+/// the first verdict when `until_settled` is set.
+async fn monitor_health(name: String, check: HealthCheck, until_settled: bool) {
+    let mut failures = 0;
+    loop {
+        let meta = match load_metadata(&name) {
+            Ok(meta) => meta,
+            Err(_) => return,
+        };
+
+        let ip = meta.guest_ip.clone();
+        let cmd = check.cmd.clone();
+        let timeout = Duration::from_secs(check.timeout_secs);
+        let healthy = tokio::task::spawn_blocking(move || guest::run_health_check(&ip, &cmd, timeout))
+            .await
+            .map(|r| r.unwrap_or(false))
+            .unwrap_or(false);
+
+        failures = if healthy { 0 } else { failures + 1 };
+        let verdict = if healthy {
+            Some("healthy")
+        } else if failures >= check.retries {
+            Some("unhealthy")
+        } else {
+            None
+        };
+
+        if let Some(state) = verdict {
+            if meta.health.as_deref() != Some(state) {
+                println!("VM '{}' is {}", name, state);
+                // Re-read under the lock so a concurrent update isn't overwritten
+                if update_metadata(&name, |meta| meta.health = Some(state.to_string())).is_err() {
+                    return;
+                }
+            }
+            if until_settled {
+                return;
+            }
+        }
+        sleep(Duration::from_secs(check.interval_secs)).await;
+    }
+}
+
+/// Starts `stoker supervise <name>` in its own session, so it outlives this process and the
+/// terminal, and returns its PID and start time. Its output goes to
+/// /tmp/stoker-supervisor-<name>.log.
+fn spawn_supervisor(name: &str) -> Result<(u32, Option<u64>)> {
+    use std::os::unix::process::CommandExt;
+    let exe = std::env::current_exe().context("Failed to locate the stoker binary")?;
+    let log_path = format!("/tmp/stoker-supervisor-{}.log", name);
+    let log = std::fs::File::create(&log_path).with_context(|| format!("Failed to create {}", log_path))?;
+    let assets = crate::assets::asset_dir();
+    let mut cmd = std::process::Command::new(exe);
+    cmd.arg("supervise").arg(name)
+        .env("STOKER_ASSET_DIR", &assets.dir)
+        .env("STOKER_OUTPUT_DIR", assets.write_dir())
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::setsid() < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = cmd.spawn().context("Failed to start the VM supervisor")?;
+    let pid = child.id();
+    // Reap it should this process still be around when the supervisor exits
+    std::thread::spawn(move || child.wait());
+    Ok((pid, process_start_time(pid)))
+}
+
+/// Keeps probing a detached VM's healthcheck and runs its watchdog until the VM is removed.
+/// Run by the hidden `stoker supervise` command that `run_vm` starts in the background.
+pub async fn supervise(name: &str) -> Result<()> {
+    let meta = load_metadata(name)?;
+    let health = async {
+        if let Some(check) = meta.healthcheck.clone() {
+            monitor_health(name.to_string(), check, false).await;
+        }
+        Ok(())
+    };
+    let watchdog = async {
+        match meta.watchdog_enabled {
+            true => restart_when_unresponsive(name, meta.watchdog_grace_failures.unwrap_or(3)).await,
+            false => Ok(()),
+        }
+    };
+    tokio::try_join!(health, watchdog)?;
+    Ok(())
+}
+
+/// Where new VMs keep their writable rootfs copy: on disk next to the images they are cloned
+/// from, rather than in /tmp, which is often RAM-backed and cleared on reboot.
+pub fn default_rootfs_dir() -> String {
+    crate::assets::get_asset_path("vms")
+}
+
+/// Path of the VM's writable rootfs copy.
+pub fn rootfs_path(meta: &InstanceMetadata) -> String {
+    format!("{}/rootfs-{}.ext4", meta.rootfs_dir.as_deref().unwrap_or("/tmp"), meta.name)
+}
+
+/// Path of the writable data drive of a `--read-only-root` VM.
+pub fn data_drive_path(meta: &InstanceMetadata) -> String {
+    format!("{}/data-{}.ext4", meta.rootfs_dir.as_deref().unwrap_or("/tmp"), meta.name)
+}
+
+pub const DEFAULT_DATA_SIZE_MIB: u32 = 1024;
+pub const DEFAULT_DATA_MOUNT: &str = "/data";
+
+/// The image a `--read-only-root` VM boots: `<image>.squashfs` when there is one, since
+/// squashfs is compact and read-only by design, otherwise the ext4 itself.
+fn read_only_root_image(image: &str) -> String {
+    let squashfs = crate::assets::get_asset_path(&format!("{}.squashfs", image));
+    if std::path::Path::new(&squashfs).exists() {
+        squashfs
+    } else {
+        crate::assets::get_asset_path(&format!("{}.ext4", image))
+    }
+}
+
+/// Guest mount point of the data drive for `opts`, after checking that nothing else in `opts`
+/// needs to write to a read-only root.
+fn data_mount(opts: &RunConfig) -> Result<Option<String>> {
+    if !opts.read_only_root {
+        if opts.data_mount.is_some() || opts.data_size_mib.is_some() {
+            anyhow::bail!("--data-mount and --data-size only apply with --read-only-root");
+        }
+        return Ok(None);
+    }
+    let writes_root = [
+        ("--working-dir", opts.working_dir.is_some()),
+        ("--ulimit", !opts.ulimits.is_empty()),
+        ("--user", opts.user.is_some()),
+        ("--add-host", !opts.add_hosts.is_empty()),
+        ("--persist-network", opts.persist_network),
+        ("--init-file", opts.init_file.is_some()),
+        ("--from-snapshot", opts.from_snapshot.is_some()),
+    ];
+    if let Some((flag, _)) = writes_root.iter().find(|(_, set)| *set) {
+        anyhow::bail!("{} can't be combined with --read-only-root: it needs to write to the guest's root filesystem", flag);
+    }
+    let path = opts.data_mount.as_deref().unwrap_or(DEFAULT_DATA_MOUNT);
+    let safe = |c: char| c.is_ascii_alphanumeric() || "/._-".contains(c);
+    if !path.starts_with('/') || path == "/" || !path.chars().all(safe) {
+        anyhow::bail!("Invalid --data-mount '{}'. Expected an absolute path of letters, digits, '/', '.', '_' or '-'.", path);
+    }
+    Ok(Some(path.to_string()))
+}
+
+/// Creates an empty, sparse ext4 of `size_mib` MiB at `path`.
+async fn create_data_drive(path: &str, size_mib: u32) -> Result<()> {
+    let file = std::fs::File::create(path).with_context(|| format!("Failed to create data drive {}", path))?;
+    file.set_len(u64::from(size_mib) * 1024 * 1024)?;
+    let status = Command::new("mkfs.ext4").args(["-q", "-F", path]).status().await
+        .context("Failed to run mkfs.ext4 for the data drive")?;
+    if !status.success() {
+        let _ = std::fs::remove_file(path);
+        anyhow::bail!("mkfs.ext4 failed to format data drive {}", path);
+    }
+    println!("Created a {} MiB data drive at {}.", size_mib, path);
+    Ok(())
+}
+
+/// Where `rm --keep-rootfs` leaves the rootfs: beside the live copy, so the move is a rename on
+/// the same filesystem, with the removal time telling repeated runs of one name apart.
+pub fn kept_rootfs_path(meta: &InstanceMetadata, removed_at: u64) -> String {
+    format!("{}/rootfs-{}-kept-{}.ext4", meta.rootfs_dir.as_deref().unwrap_or("/tmp"), meta.name, removed_at)
+}
+
+/// Spawns a Firecracker daemon for `meta`, configures it over its API socket and starts the
+/// instance. The rootfs and TAP devices must already exist. Updates `meta.pid` on success.
+async fn boot_instance(meta: &mut InstanceMetadata) -> Result<tokio::process::Child> {
+    let mut child = spawn_daemon(meta).await?;
+    let socket_path = format!("/tmp/firecracker-{}.socket", meta.name);
+
+    let configured = async {
+        // Use hyperlocal for unix socket client
+        let client = Client::unix();
+        for request in boot_requests(meta) {
+            println!("{}", request.description);
+            send_when_ready(&client, &socket_path, &request.path, request.body.to_string()).await?;
+        }
+        Ok::<_, anyhow::Error>(())
+    }.await;
+    if let Err(e) = configured {
+        // Never became ready or rejected its configuration; don't leave it running
+        let _ = child.kill().await;
+        return Err(e);
+    }
+
+    println!("MicroVM Booted successfully via Unix API.");
+    Ok(child)
+}
+
+/// Launches an unconfigured Firecracker daemon for `meta` under its cgroup limits and waits
+/// for its API socket. Updates `meta.pid`.
+async fn spawn_daemon(meta: &mut InstanceMetadata) -> Result<tokio::process::Child> {
+    let name = meta.name.clone();
+    let socket_path = format!("/tmp/firecracker-{}.socket", name);
+    let log_path = format!("/tmp/firecracker-{}.log", name);
+    
+    // Ensure the log file exists as required by Firecracker
+    let _ = std::fs::File::create(&log_path);
+    
+    // A socket left by an earlier daemon of this name would make the new one fail to bind
+    clear_stale_daemon(&name, &socket_path).await?;
+
+    // Launch Firecracker daemon in background
+    println!("Starting Firecracker daemon...");
+    let fc_binary = crate::assets::resolve_firecracker_binary(meta.firecracker_bin.as_deref());
+    let (stdout, stderr) = configure_log_driver(meta.log_driver.as_deref().unwrap_or("none"), &name)?;
+    let mut cmd = Command::new(&fc_binary);
+    cmd.arg("--api-sock")
+        .arg(&socket_path)
+        .stdout(stdout)
+        .stderr(stderr)
+        // Run in its own process group so a terminal Ctrl-C reaches stoker, not the daemon
+        .process_group(0);
+    crate::security::apply(&mut cmd, &name, &meta.security_opts)?;
+    set_ipc_namespace(&mut cmd, meta.ipc.as_deref())?;
+    if let Some(cni) = &meta.cni {
+        set_net_namespace(&mut cmd, &cni.netns)?;
+    }
+    let child = cmd.spawn().context("Failed to spawn firecracker daemon")?;
+    let pid = child.id().context("Firecracker daemon exited immediately after spawn")?;
+    meta.pid = pid;
+    meta.pid_start_time = process_start_time(pid);
+    meta.started_at = unix_now();
+    refresh_session_vm(meta);
+
+    // Confine the daemon with host cgroup limits before the guest starts running
+    if let Some(limit) = meta.pids_limit {
+        crate::cgroup::set_pids_limit(&name, pid, limit)?;
+    }
+    if let Some(limit) = meta.memory_limit_mib {
+        crate::cgroup::set_memory_limit(&name, pid, limit)?;
+    }
+    if let Some(cpus) = &meta.cpuset_cgroup {
+        crate::cgroup::set_cpuset(&name, pid, &parse_cpu_set(cpus)?)?;
+    }
+    if let Some(score) = meta.oom_score_adj {
+        set_oom_score_adj(pid, score)?;
+    }
+    // vCPU threads are created at InstanceStart and inherit the affinity set here
+    if let Some(cpuset) = &meta.cpuset {
+        set_cpu_affinity(pid, &parse_cpu_set(cpuset)?)?;
+    }
+
+    // Give it a moment to create the socket
+    sleep(Duration::from_millis(500)).await;
+    Ok(child)
+}
+
+/// Frees `socket` for a new daemon of VM `name`. A socket nobody answers on was left by a
+/// crashed daemon and is removed. A daemon that answers but whose instance never started is
+/// left over from an interrupted launch and is killed, so the boot sequence can start over.
+/// One whose guest is running or paused is reported instead.
+async fn clear_stale_daemon(name: &str, socket: &str) -> Result<()> {
+    if std::fs::symlink_metadata(socket).is_err() {
+        return Ok(());
+    }
+    if let Ok(Ok(info)) = tokio::time::timeout(Duration::from_secs(2), query_socket(socket, "/")).await {
+        let pids = daemons_on_socket(socket);
+        let state = info["state"].as_str().unwrap_or("unknown");
+        if state != "Not started" {
+            let owner = pids.first().map(|pid| format!(" (PID {})", pid)).unwrap_or_default();
+            anyhow::bail!(
+                "A Firecracker daemon{} is still serving VM '{}' on {} with its instance {}. Remove the VM with `stoker rm {}` first.",
+                owner, name, socket, state.to_lowercase(), name
+            );
+        }
+        for pid in pids {
+            println!("Stopping Firecracker daemon (PID {}) left unconfigured by an interrupted launch...", pid);
+            stop_process(pid, process_start_time(pid), Duration::ZERO).await;
+        }
+    }
+    let _ = std::fs::remove_file(socket);
+    Ok(())
+}
+
+/// PIDs of the processes started with `--api-sock <socket>`.
+fn daemons_on_socket(socket: &str) -> Vec<u32> {
+    let Ok(entries) = std::fs::read_dir("/proc") else { return Vec::new() };
+    entries.flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok())
+        .filter(|pid| std::fs::read(format!("/proc/{}/cmdline", pid)).is_ok_and(|cmdline| cmdline_uses_socket(&cmdline, socket)))
+        .collect()
+}
+
+/// Whether a NUL-separated /proc/<pid>/cmdline passes `--api-sock <socket>`.
+fn cmdline_uses_socket(cmdline: &[u8], socket: &str) -> bool {
+    let args: Vec<&[u8]> = cmdline.split(|b| *b == 0).collect();
+    args.windows(2).any(|pair| pair[0] == b"--api-sock" && pair[1] == socket.as_bytes())
+}
+
+/// `--drive-cache` modes. With writeback, the guest's flushes reach the host disk; with unsafe
+/// they are ignored, which is faster but can lose data on a host crash.
+pub const DRIVE_CACHE_TYPES: [&str; 2] = ["unsafe", "writeback"];
+
+/// Spells a `--drive-cache` mode as Firecracker's `cache_type`.
+fn drive_cache_type(mode: &str) -> Result<String> {
+    match mode {
+        "unsafe" => Ok("Unsafe".to_string()),
+        "writeback" => Ok("Writeback".to_string()),
+        other => anyhow::bail!("Unsupported --drive-cache '{}'. Use {}.", other, DRIVE_CACHE_TYPES.join(" or ")),
+    }
+}
+
+/// `--log-driver` sinks besides the default `none`.
+const LOG_DRIVERS: [&str; 3] = ["file", "syslog", "journald"];
+
+/// Directory for per-VM state that doesn't fit in the metadata file, like the serial log.
+pub fn state_dir(name: &str) -> String {
+    format!("/tmp/stoker-{}", name)
+}
+
+/// Returns the daemon's stdout and stderr for log driver `mode`. Firecracker writes the guest's
+/// serial console to stdout, so this is where `console=ttyS0` output ends up. The syslog and
+/// journald drivers pipe into `logger` and `systemd-cat`, which outlive a detached `stoker run`
+/// and exit once the daemon closes the pipe.
+pub fn configure_log_driver(mode: &str, name: &str) -> Result<(Stdio, Stdio)> {
+    use std::os::fd::OwnedFd;
+    use std::os::unix::process::CommandExt;
+    let forwarder = match mode {
+        "none" => return Ok((Stdio::null(), Stdio::null())),
+        "file" => {
+            std::fs::create_dir_all(state_dir(name))?;
+            let path = format!("{}/serial.log", state_dir(name));
+            let file = std::fs::OpenOptions::new().create(true).append(true).open(&path)
+                .with_context(|| format!("Failed to open {}", path))?;
+            return Ok((Stdio::from(file.try_clone()?), Stdio::from(file)));
+        }
+        "syslog" => ("logger", format!("stoker[{}]", name)),
+        "journald" => ("systemd-cat", format!("stoker-{}", name)),
+        other => anyhow::bail!("Unsupported log driver '{}'", other),
+    };
+    let (program, tag) = forwarder;
+    // Its own process group keeps the forwarder out of Ctrl-C aimed at `stoker run`
+    let mut forwarder = std::process::Command::new(program)
+        .args(["-t", &tag])
+        .stdin(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .with_context(|| format!("Failed to start {} for --log-driver {}", program, mode))?;
+    let pipe = OwnedFd::from(forwarder.stdin.take().context("Log forwarder has no stdin")?);
+    Ok((Stdio::from(pipe.try_clone()?), Stdio::from(pipe)))
+}
+
+/// Path of the IPC namespace of VM `name`'s daemon, which must have been run with `--ipc shareable`.
+fn ipc_namespace_of(name: &str) -> Result<String> {
+    let target = load_metadata(name).with_context(|| format!("--ipc vm:{}: no such VM", name))?;
+    if target.ipc.as_deref() != Some("shareable") {
+        anyhow::bail!("VM '{}' doesn't share its IPC namespace. Start it with --ipc shareable.", name);
+    }
+    if !is_process_alive(target.pid, target.pid_start_time) {
+        anyhow::bail!("VM '{}' is not running, so its IPC namespace is gone", name);
+    }
+    Ok(format!("/proc/{}/ns/ipc", target.pid))
+}
+
+/// Gives the daemon `cmd` a fresh IPC namespace for `shareable`, or joins the namespace of the
+/// VM named by `vm:<name>`. The namespace lives as long as the shareable VM's daemon.
+fn set_ipc_namespace(cmd: &mut Command, ipc: Option<&str>) -> Result<()> {
+    let join = match ipc {
+        None => return Ok(()),
+        Some("shareable") => None,
+        Some(mode) => {
+            let target = mode.strip_prefix("vm:").context("Invalid IPC mode in VM metadata")?;
+            let path = ipc_namespace_of(target)?;
+            Some(std::fs::File::open(&path).with_context(|| format!("Failed to open {}", path))?)
+        }
+    };
+    // The namespace file stays open in the closure until the child has joined it
+    unsafe {
+        cmd.pre_exec(move || {
+            let result = match &join {
+                Some(ns) => libc::setns(std::os::unix::io::AsRawFd::as_raw_fd(ns), libc::CLONE_NEWIPC),
+                None => libc::unshare(libc::CLONE_NEWIPC),
+            };
+            if result != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// Starts the daemon `cmd` in the network namespace at `netns`, where its TAP lives.
+fn set_net_namespace(cmd: &mut Command, netns: &str) -> Result<()> {
+    let ns = std::fs::File::open(netns).with_context(|| format!("Failed to open network namespace {}", netns))?;
+    unsafe {
+        cmd.pre_exec(move || {
+            if libc::setns(std::os::unix::io::AsRawFd::as_raw_fd(&ns), libc::CLONE_NEWNET) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// Directory of snapshot `name`: Firecracker's `vmstate` and `memory` files, a copy of the
+/// source VM's rootfs and its metadata. A `name` containing a `/` is the directory itself.
+fn snapshot_dir(name: &str) -> String {
+    if name.contains('/') {
+        return name.trim_end_matches('/').to_string();
+    }
+    crate::assets::get_asset_path(&format!("snapshots/{}", name))
+}
+
+/// Metadata of the VM snapshot `name` was taken from.
+fn load_snapshot_metadata(name: &str) -> Result<InstanceMetadata> {
+    let path = format!("{}/meta.json", snapshot_dir(name));
+    let content = std::fs::read_to_string(&path)
+        .with_context(|| StokerError::new(ErrorKind::NotFound, format!("Snapshot '{}' not found at {}. Create it with `stoker snapshot create`.", name, path)))?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Architecture of the guest kernel at `path`: an ELF vmlinux, an arm64 `Image` or an x86
+/// bzImage.
+fn kernel_arch(path: &str) -> Option<&'static str> {
+    let mut header = [0u8; 0x210];
+    let mut file = std::fs::File::open(path).ok()?;
+    std::io::Read::read_exact(&mut file, &mut header).ok()?;
+    if let Some(arch) = crate::builder::elf_arch(&header) {
+        return Some(arch);
+    }
+    if &header[0x38..0x3c] == b"ARM\x64" {
+        return Some("aarch64");
+    }
+    if &header[0x202..0x206] == b"HdrS" {
+        return Some("x86_64");
+    }
+    None
+}
+
+/// Fails unless image `image`, built for `image_arch`, and the kernel can boot under KVM on a
+/// `host` machine. Unknown architectures are let through.
+fn check_platform(image: &str, image_arch: Option<&str>, kernel_arch: Option<&str>, host: &str) -> Result<()> {
+    if let Some(kernel) = kernel_arch.filter(|kernel| *kernel != host) {
+        anyhow::bail!("The guest kernel {} is built for {}, but this host is {}.", crate::assets::get_asset_path("vmlinux.bin"), kernel, host);
+    }
+    match image_arch {
+        Some(arch) if arch != host => anyhow::bail!(
+            "Image '{}' is built for {}, but this host is {}. Build it with `stoker build --arch {}` or pick another --image.",
+            image, arch, host, host
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Takes the settings baked into the guest's memory and kernel command line from the metadata
+/// of the VM a snapshot was taken from.
+fn inherit_snapshot_settings(meta: &mut InstanceMetadata, source: &InstanceMetadata) {
+    meta.mac_address = source.mac_address.clone();
+    meta.memory_mib = source.memory_mib;
+    meta.initial_balloon_mib = source.initial_balloon_mib;
+    meta.tmpfs_size_mib = source.tmpfs_size_mib;
+    meta.tmpfs_mounts = source.tmpfs_mounts.clone();
+    meta.run_as_uid = source.run_as_uid.clone();
+    meta.run_as_gid = source.run_as_gid.clone();
+    meta.workdir = source.workdir.clone();
+    meta.ulimits = source.ulimits.clone();
+    meta.sysctls = source.sysctls.clone();
+    meta.persist_network = source.persist_network;
+    meta.init_file = source.init_file.clone();
+    meta.init_sha256 = source.init_sha256.clone();
+    meta.image = source.image.clone();
+}
+
+/// Rejects `run` flags that a resumed snapshot can't honour because they are fixed at boot.
+fn check_snapshot_compatible(opts: &RunConfig) -> Result<()> {
+    let conflicts: Vec<&str> = [
+        ("--image", opts.image.is_some()),
+        ("--memory", opts.memory_mib.is_some()),
+        ("--memory-balloon", opts.balloon_mib.is_some()),
+        ("--tmpfs-size", opts.tmpfs_size_mib.is_some()),
+        ("--tmpfs", !opts.tmpfs.is_empty()),
+        ("--user", opts.user.is_some()),
+        ("--working-dir", opts.working_dir.is_some()),
+        ("--ulimit", !opts.ulimits.is_empty()),
+        ("--sysctl", !opts.sysctls.is_empty()),
+        ("--cni-plugin", opts.cni_plugin.is_some()),
+        ("--mac", opts.mac.is_some()),
+        ("--persist-network", opts.persist_network),
+        ("--init-file", opts.init_file.is_some()),
+        ("--dry-run", opts.dry_run),
+    ]
+    .into_iter()
+    .filter_map(|(flag, set)| set.then_some(flag))
+    .collect();
+    if !conflicts.is_empty() {
+        anyhow::bail!("{} can't be combined with --from-snapshot; the snapshot's settings are used", conflicts.join(", "));
+    }
+    Ok(())
+}
+
+async fn set_drive_path(client: &Client<hyperlocal::UnixConnector>, socket: &str, path: &str) -> Result<()> {
+    let body = json!({ "drive_id": "rootfs", "path_on_host": path }).to_string();
+    send_api_request(client, socket, Method::PATCH, "/drives/rootfs", body).await
+        .with_context(|| format!("Failed to point the rootfs drive at {}", path))
+}
+
+async fn set_vm_state(client: &Client<hyperlocal::UnixConnector>, socket: &str, state: &str) -> Result<()> {
+    send_api_request(client, socket, Method::PATCH, "/vm", json!({ "state": state }).to_string()).await
+        .with_context(|| format!("Failed to set the VM state to {}", state))
+}
+
+/// Pauses VM `vm`, saves its memory and device state plus a copy of its rootfs as snapshot
+/// `name`, then resumes it. The saved state references the snapshot's own rootfs copy, so the
+/// snapshot stays loadable after the source VM is removed.
+pub async fn create_snapshot(vm: &str, name: &str) -> Result<()> {
+    let meta = load_metadata(vm)?;
+    if meta.read_only_root.is_some() {
+        anyhow::bail!("VM '{}' boots from a read-only root with a data drive, which snapshots don't support", vm);
+    }
+    let dir = snapshot_dir(name);
+    if std::path::Path::new(&dir).exists() {
+        anyhow::bail!(StokerError::new(ErrorKind::AlreadyExists, format!("Snapshot '{}' already exists at {}", name, dir)));
+    }
+    std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir))?;
+
+    let client = Client::unix();
+    let socket = format!("/tmp/firecracker-{}.socket", vm);
+    set_vm_state(&client, &socket, "Paused").await?;
+    let captured = capture_snapshot(&client, &socket, &meta, &dir).await;
+    let resumed = set_vm_state(&client, &socket, "Resumed").await;
+    if let Err(e) = captured {
+        let _ = std::fs::remove_dir_all(&dir);
+        return Err(e);
+    }
+    resumed.context("The snapshot was saved but the VM could not be resumed")?;
+
+    events::record(vm, "snapshot", Some(name));
+    println!("Saved snapshot '{}' of VM '{}' to {}", name, vm, dir);
+    Ok(())
+}
+
+async fn capture_snapshot(client: &Client<hyperlocal::UnixConnector>, socket: &str, meta: &InstanceMetadata, dir: &str) -> Result<()> {
+    // The guest is paused, so its disk and memory are consistent with each other
+    let snapshot_rootfs = format!("{}/rootfs.ext4", dir);
+    crate::assets::clone_image(&rootfs_path(meta), &snapshot_rootfs, 0)?;
+
+    set_drive_path(client, socket, &snapshot_rootfs).await?;
+    let body = json!({
+        "snapshot_type": "Full",
+        "snapshot_path": format!("{}/vmstate", dir),
+        "mem_file_path": format!("{}/memory", dir)
+    }).to_string();
+    let created = send_api_request(client, socket, Method::PUT, "/snapshot/create", body).await;
+    set_drive_path(client, socket, &rootfs_path(meta)).await?;
+    created.context("Firecracker failed to create the snapshot")?;
+
+    std::fs::write(format!("{}/meta.json", dir), serde_json::to_string(meta)?)?;
+    Ok(())
+}
+
+/// Body of the `PUT /snapshot/load` request that restores snapshot `dir` onto `tap_device`.
+fn snapshot_load_body(dir: &str, tap_device: &str) -> serde_json::Value {
+    json!({
+        "snapshot_path": format!("{}/vmstate", dir),
+        "mem_backend": { "backend_type": "File", "backend_path": format!("{}/memory", dir) },
+        "resume_vm": false,
+        "network_overrides": [{ "iface_id": "net1", "host_dev_name": tap_device }]
+    })
+}
+
+/// Starts `meta` from snapshot `snapshot` instead of booting a kernel: loads the saved state
+/// paused, swaps in this VM's own TAP and rootfs, and resumes. The rootfs and TAP must exist.
+async fn restore_instance(meta: &mut InstanceMetadata, snapshot: &str) -> Result<tokio::process::Child> {
+    let mut child = spawn_daemon(meta).await?;
+    let socket = format!("/tmp/firecracker-{}.socket", meta.name);
+    let client = Client::unix();
+
+    let restored = async {
+        let logger = logger_request(meta);
+        send_when_ready(&client, &socket, &logger.path, logger.body.to_string()).await?;
+
+        println!("Loading snapshot '{}'...", snapshot);
+        let body = snapshot_load_body(&snapshot_dir(snapshot), &meta.tap_device).to_string();
+        send_request(&client, &socket, "/snapshot/load", body).await.context(
+            "Firecracker failed to load the snapshot. The host's kernel, CPU model and Firecracker version must match \
+             the ones it was taken with, and network_overrides needs Firecracker v1.12 or newer",
+        )?;
+        set_drive_path(&client, &socket, &rootfs_path(meta)).await?;
+        set_vm_state(&client, &socket, "Resumed").await?;
+        Ok::<_, anyhow::Error>(())
+    }.await;
+    if let Err(e) = restored {
+        let _ = child.kill().await;
+        return Err(e);
+    }
+
+    println!("MicroVM resumed from snapshot.");
+    Ok(child)
+}
+
+fn validate_balloon(memory_mib: u32, balloon_mib: u32) -> Result<()> {
+    if balloon_mib >= memory_mib {
+        anyhow::bail!("Balloon size ({} MiB) must be smaller than guest memory ({} MiB)", balloon_mib, memory_mib);
+    }
+    Ok(())
+}
+
+/// Guest and host addresses of VM `id`'s /30 in 172.16.0.0/16 (id 0 is 172.16.0.2 and .1).
+fn vm_addresses(id: u16) -> (String, String) {
+    let network = u32::from(std::net::Ipv4Addr::new(172, 16, 0, 0)) + ((id as u32) << 2);
+    (std::net::Ipv4Addr::from(network + 2).to_string(), std::net::Ipv4Addr::from(network + 1).to_string())
+}
+
+/// Locally administered MAC for interface `index` of VM `id` (the primary interface is 2).
+fn vm_mac(id: u16, index: u8) -> String {
+    format!("06:00:AC:{:02x}:{:02x}:{:02x}", id >> 8, id & 0xff, index)
+}
+
+fn allocate_vm_id() -> Result<u16> {
+    allocate_vm_id_in_dir("/tmp")
+}
+
+/// Reads the metadata of every VM recorded in `tmp_dir`, skipping unreadable files.
+fn read_all_metadata_in_dir(tmp_dir: &str) -> Vec<InstanceMetadata> {
+    let mut vms = Vec::new();
+    if let Ok(entries) = std::fs::read_dir(tmp_dir) {
+        for entry in entries.flatten() {
+            let fname = entry.file_name().to_string_lossy().to_string();
+            if fname.starts_with("stoker-") && fname.ends_with(".json") {
+                if let Ok(content) = std::fs::read_to_string(entry.path()) {
+                    if let Ok(meta) = serde_json::from_str::<InstanceMetadata>(&content) {
+                        vms.push(meta);
+                    }
+                }
+            }
+        }
+    }
+    vms
+}
+
+/// Returns the lowest id that is unused and whose /30 doesn't overlap a recorded VM's subnet
+/// (VMs with `--ip`, or created before ids were widened, may sit anywhere in 172.16.0.0/16).
+fn allocate_vm_id_in_dir(tmp_dir: &str) -> Result<u16> {
+    let vms = read_all_metadata_in_dir(tmp_dir);
+    let used_ids: std::collections::HashSet<u16> = vms.iter().map(|m| m.id).collect();
+    let used_subnets: Vec<(std::net::Ipv4Addr, u8)> = vms.iter()
+        .filter_map(|m| m.host_ip.parse().ok().map(|ip| (ip, m.prefix_len())))
+        .collect();
+
+    for id in 0..MAX_VM_IDS {
+        if used_ids.contains(&id) {
+            continue;
+        }
+        let (_, host) = vm_addresses(id);
+        let host: std::net::Ipv4Addr = host.parse()?;
+        if !used_subnets.iter().any(|(ip, prefix)| crate::network::subnets_overlap(host, DEFAULT_PREFIX_LEN, *ip, *prefix)) {
+            return Ok(id);
+        }
+    }
+    anyhow::bail!(
+        "No available VM IDs: all {} /30 subnets in 172.16.0.0/16 are in use. Remove unused VMs with `stoker rm`, \
+         or give new VMs addresses outside that range with --ip/--host-ip",
+        MAX_VM_IDS
+    );
+}
+
+/// Checks that `mac` is a unicast `xx:xx:xx:xx:xx:xx` address and returns it normalised to uppercase.
+fn validate_mac(mac: &str) -> Result<String> {
+    let octets: Vec<&str> = mac.split(':').collect();
+    let well_formed = octets.len() == 6
+        && octets.iter().all(|o| o.len() == 2 && o.chars().all(|c| c.is_ascii_hexdigit()));
+    if !well_formed {
+        anyhow::bail!("Invalid MAC address '{}'. Expected six colon-separated hex octets, e.g. 06:00:AC:10:00:02.", mac);
+    }
+    let first = u8::from_str_radix(octets[0], 16)?;
+    if first & 0x01 != 0 {
+        anyhow::bail!("MAC address '{}' is a multicast address and can't be assigned to an interface.", mac);
+    }
+    Ok(mac.to_ascii_uppercase())
+}
+
+fn parse_cidr(cidr: &str) -> Result<(std::net::Ipv4Addr, u8)> {
+    let (addr, prefix) = cidr.split_once('/')
+        .with_context(|| format!("Invalid address '{}'. Expected <address>/<prefix>.", cidr))?;
+    let addr = addr.parse().with_context(|| format!("Invalid IPv4 address in '{}'", cidr))?;
+    let prefix = prefix.parse().with_context(|| format!("Invalid prefix length in '{}'", cidr))?;
+    Ok((addr, prefix))
+}
+
+/// Validates `--ip`/`--host-ip`: private (RFC 1918) addresses sharing one /29 or /30 subnet,
+/// distinct and neither the network nor broadcast address. Returns (guest, host, prefix).
+fn validate_custom_ips(guest: &str, host: &str) -> Result<(String, String, u8)> {
+    let (guest_addr, guest_prefix) = parse_cidr(guest)?;
+    let (host_addr, host_prefix) = parse_cidr(host)?;
+    if guest_prefix != host_prefix {
+        anyhow::bail!("--ip and --host-ip must use the same prefix length");
+    }
+    if !matches!(guest_prefix, 29 | 30) {
+        anyhow::bail!("Prefix length must be 29 or 30, got /{}", guest_prefix);
+    }
+    for addr in [guest_addr, host_addr] {
+        if !addr.is_private() {
+            anyhow::bail!("{} is not in a private (RFC 1918) range", addr);
+        }
+    }
+
+    let mask = u32::MAX << (32 - guest_prefix);
+    let network = u32::from(host_addr) & mask;
+    if u32::from(guest_addr) & mask != network {
+        anyhow::bail!("{} and {} are not in the same /{} subnet", guest_addr, host_addr, guest_prefix);
+    }
+    if guest_addr == host_addr {
+        anyhow::bail!("--ip and --host-ip must differ");
+    }
+    for addr in [guest_addr, host_addr] {
+        let host_bits = u32::from(addr) & !mask;
+        if host_bits == 0 || host_bits == !mask {
+            anyhow::bail!("{} is the network or broadcast address of its /{} subnet", addr, guest_prefix);
+        }
+    }
+    Ok((guest_addr.to_string(), host_addr.to_string(), guest_prefix))
+}
+
+/// Fails if a VM recorded in `tmp_dir` already uses a subnet overlapping `host_ip/prefix`.
+fn check_subnet_unused_in_dir(tmp_dir: &str, host_ip: &str, prefix: u8) -> Result<()> {
+    let addr: std::net::Ipv4Addr = host_ip.parse()?;
+    for vm in read_all_metadata_in_dir(tmp_dir) {
+        let Ok(other) = vm.host_ip.parse() else { continue };
+        if crate::network::subnets_overlap(addr, prefix, other, vm.prefix_len()) {
+            anyhow::bail!("Subnet {}/{} is already used by VM '{}' ({}/{})", host_ip, prefix, vm.name, vm.host_ip, vm.prefix_len());
+        }
+    }
+    Ok(())
+}
+
+/// Fails if a VM named `name` is already recorded in `tmp_dir`.
+fn check_name_unused_in_dir(tmp_dir: &str, name: &str) -> Result<()> {
+    if std::path::Path::new(tmp_dir).join(format!("stoker-{}.json", name)).exists() {
+        anyhow::bail!(StokerError::new(ErrorKind::AlreadyExists, format!("A VM named '{}' already exists. Remove it with `stoker rm {}` or pass --replace.", name, name)));
+    }
+    Ok(())
+}
+
+/// Fails if a VM recorded in `tmp_dir` already uses `mac`.
+fn check_mac_unused_in_dir(tmp_dir: &str, mac: &str) -> Result<()> {
+    for vm in read_all_metadata_in_dir(tmp_dir) {
+        if vm.mac_address.eq_ignore_ascii_case(mac) {
+            anyhow::bail!("MAC address {} is already used by VM '{}'", mac, vm.name);
+        }
+    }
+    Ok(())
+}
+
+/// Removes a VM, giving Firecracker `grace` to exit on SIGTERM before it is SIGKILLed.
+/// A zero grace period kills the daemon immediately.
+pub async fn rm_vm(name: &str, grace: Duration, keep_rootfs: bool) -> Result<()> {
+    let meta = load_metadata(name)?;
+    if keep_rootfs && meta.read_only_root.is_some() {
+        anyhow::bail!(
+            "VM '{}' has a shared read-only root; its own state is on the data drive {}. Copy that instead of using --keep-rootfs.",
+            name, data_drive_path(&meta)
+        );
+    }
+    let kept = keep_rootfs.then(|| kept_rootfs_path(&meta, unix_now()));
+    teardown(&meta, grace, kept.as_deref()).await?;
+    events::record(name, "remove", None);
+    println!("Cleaned up all resources for stoker-{}", name);
+    if let Some(kept) = kept.filter(|kept| std::path::Path::new(kept).exists()) {
+        println!("Kept the rootfs at {}. Inspect it with `mount -o loop,ro {} <dir>`.", kept, kept);
+    }
+    Ok(())
+}
+
+/// Stops the daemon and releases everything a VM may hold. Tolerates resources that were
+/// never created, so it also cleans up launches that failed part-way. With `keep_rootfs`, the
+/// rootfs is moved there once the daemon has exited instead of being deleted.
+async fn teardown(meta: &InstanceMetadata, grace: Duration, keep_rootfs: Option<&str>) -> Result<()> {
+    let name = meta.name.as_str();
+
+    // 1. Stop the Firecracker Hypervisor Native PID
+    if let Some(pid) = meta.supervisor_pid.filter(|pid| *pid != std::process::id()) {
+        if is_process_alive(pid, meta.supervisor_start_time) {
+            unsafe { libc::kill(pid as i32, libc::SIGTERM) };
+        }
+    }
+    crate::guest::close_control_master(name).await;
+    if meta.pid != 0 {
+        stop_process(meta.pid, meta.pid_start_time, grace).await;
+    }
+    crate::cgroup::remove(name);
+    crate::security::cleanup(name);
+    events::record(name, "stop", None);
+
+    // The daemon has exited, so the file holds every write the guest made
+    if let Some(kept) = keep_rootfs {
+        match std::fs::rename(rootfs_path(meta), kept) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                println!("Warning: {} does not exist, so there is no rootfs to keep", rootfs_path(meta));
+            }
+            Err(e) => {
+                return Err(anyhow::Error::new(e).context(format!("Failed to move {} aside for --keep-rootfs", rootfs_path(meta))));
+            }
+        }
+    }
+    
+    // 2. Teardown Network Interfaces
+    match &meta.cni {
+        // Deleting the namespace removes the TAP inside it
+        Some(cni) => crate::network::teardown_cni(name, cni).await,
+        None => {
+            teardown_egress_rules(meta).await;
+            crate::network::teardown_vm_tap(&meta.tap_device).await?;
+        }
+    }
+    
+    // 3. Remove /tmp state footprints to cleanly release IDs
+    let _ = std::fs::remove_file(metadata_path(name));
+    let _ = std::fs::remove_file(metadata_lock_path(name));
+    let _ = std::fs::remove_file(format!("/tmp/stoker-supervisor-{}.log", name));
+    let _ = std::fs::remove_file(format!("/tmp/firecracker-{}.socket", name));
+    let _ = std::fs::remove_file(format!("/tmp/firecracker-{}.log", name));
+    let _ = std::fs::remove_file(rootfs_path(meta));
+    let _ = std::fs::remove_file(data_drive_path(meta));
+    let _ = std::fs::remove_dir_all(state_dir(name));
+    Ok(())
+}
+
+/// Takes over a Firecracker VM that stoker did not launch. The daemon's configuration is read
+/// from its API socket, which is linked to the usual state path so every other command finds
+/// it. The guest is assumed to use the address following the TAP's host address, as stoker's
+/// own VMs do, since Firecracker has no notion of guest IPs.
+pub async fn adopt_vm(pid: u32, socket: &str, name: &str) -> Result<InstanceMetadata> {
+    if std::path::Path::new(&metadata_path(name)).exists() {
+        anyhow::bail!("A VM named '{}' is already managed by stoker", name);
+    }
+    let exe = std::fs::read_link(format!("/proc/{}/exe", pid))
+        .with_context(|| format!("Cannot read /proc/{}/exe. Is the process running and are you root?", pid))?;
+    if !exe.file_name().is_some_and(|f| f.to_string_lossy().contains("firecracker")) {
+        anyhow::bail!("PID {} is {}, not a Firecracker binary", pid, exe.display());
+    }
+
+    let config = query_socket(socket, "/vm/config").await
+        .with_context(|| format!("Failed to query the Firecracker API at {}", socket))?;
+    let (tap_device, mac_address) = primary_interface(&config)?;
+    let (host_addr, prefix_len) = crate::network::interface_ipv4_address(&tap_device)
+        .with_context(|| format!("TAP device {} has no IPv4 address to derive the guest address from", tap_device))?;
+    let host_ip = host_addr.to_string();
+    let guest_ip = std::net::Ipv4Addr::from(u32::from(host_addr) + 1).to_string();
+
+    let id = allocate_vm_id()?;
+    check_subnet_unused_in_dir("/tmp", &host_ip, prefix_len)?;
+
+    let socket_path = format!("/tmp/firecracker-{}.socket", name);
+    if std::path::Path::new(socket) != std::path::Path::new(&socket_path) {
+        let _ = std::fs::remove_file(&socket_path);
+        std::os::unix::fs::symlink(socket, &socket_path)
+            .with_context(|| format!("Failed to link {} to {}", socket_path, socket))?;
+    }
+
+    let meta = InstanceMetadata {
+        id,
+        name: name.to_string(),
+        mode: "adopted".to_string(),
+        guest_ip,
+        host_ip,
+        prefix_len: Some(prefix_len),
         mac_address,
         tap_device,
-        pid: child.id(),
+        pid,
+        pid_start_time: process_start_time(pid),
+        started_at: process_started_at(pid).unwrap_or_else(unix_now),
+        firecracker_bin: Some(exe.to_string_lossy().to_string()),
+        memory_mib: config["machine-config"]["mem_size_mib"].as_u64().map(|m| m as u32),
+        ..Default::default()
     };
-    
-    let meta_json = serde_json::to_string(&meta)?;
-    std::fs::write(format!("/tmp/stoker-{}.json", name), meta_json)?;
+    save_metadata(&meta)?;
+    events::record(name, "adopt", Some(socket));
+    println!("Adopted Firecracker PID {} as '{}' ({})", pid, name, meta.guest_ip);
+    Ok(meta)
+}
+
+/// Returns the TAP device and guest MAC of the first interface in a `GET /vm/config` response.
+fn primary_interface(config: &serde_json::Value) -> Result<(String, String)> {
+    let iface = config["network-interfaces"].as_array()
+        .and_then(|ifaces| ifaces.first())
+        .context("The VM has no network interface, so stoker cannot reach it")?;
+    let tap = iface["host_dev_name"].as_str().context("Network interface has no host_dev_name")?;
+    let mac = iface["guest_mac"].as_str().context("Network interface has no guest_mac")?;
+    Ok((tap.to_string(), mac.to_string()))
+}
+
+/// Adjusts how readily the kernel OOM killer picks the daemon. Lowering the score below its
+/// current value requires CAP_SYS_RESOURCE.
+fn set_oom_score_adj(pid: u32, score: i32) -> Result<()> {
+    let path = format!("/proc/{}/oom_score_adj", pid);
+    std::fs::write(&path, score.to_string()).with_context(|| {
+        format!("Failed to write OOM score adjustment {} to {}. Are you running as root?", score, path)
+    })?;
+    println!("Set OOM score adjustment of PID {} to {}", pid, score);
+    Ok(())
+}
+
+/// Parses a cpuset list like `0-3,6` into sorted, deduplicated CPU numbers.
+pub fn parse_cpu_set(spec: &str) -> Result<Vec<usize>> {
+    let invalid = || format!("Invalid CPU list '{}'. Expected CPU numbers and ranges like 0-3,6.", spec);
+    let mut cpus = Vec::new();
+    for part in spec.split(',') {
+        let (start, end) = match part.split_once('-') {
+            Some((start, end)) => (start.trim().parse::<usize>(), end.trim().parse::<usize>()),
+            None => (part.trim().parse::<usize>(), part.trim().parse::<usize>()),
+        };
+        let (start, end) = (start.with_context(invalid)?, end.with_context(invalid)?);
+        if start > end || end >= libc::CPU_SETSIZE as usize {
+            anyhow::bail!(invalid());
+        }
+        cpus.extend(start..=end);
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+/// Pins every thread of process `pid` to `cpus`. Threads it creates later inherit the mask.
+fn set_cpu_affinity(pid: u32, cpus: &[usize]) -> Result<()> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for &cpu in cpus {
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+    let mut tids: Vec<libc::pid_t> = std::fs::read_dir(format!("/proc/{}/task", pid))
+        .map(|tasks| tasks.flatten().filter_map(|task| task.file_name().to_str()?.parse().ok()).collect())
+        .unwrap_or_default();
+    if tids.is_empty() {
+        tids.push(pid as libc::pid_t);
+    }
+    for tid in tids {
+        if unsafe { libc::sched_setaffinity(tid, std::mem::size_of::<libc::cpu_set_t>(), &set) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Failed to set CPU affinity of thread {} of PID {}", tid, pid));
+        }
+    }
+    println!("Pinned PID {} to CPUs {:?}", pid, cpus);
+    Ok(())
+}
+
+/// Sends SIGTERM and waits up to `grace` for the process to exit, escalating to SIGKILL.
+async fn stop_process(pid: u32, start_time: Option<u64>, grace: Duration) {
+    if !is_process_alive(pid, start_time) {
+        println!("Warning: PID {} is not running (it may have already exited)", pid);
+        return;
+    }
+
+    if !grace.is_zero() {
+        unsafe { libc::kill(pid as i32, libc::SIGTERM) };
+        let deadline = tokio::time::Instant::now() + grace;
+        while tokio::time::Instant::now() < deadline {
+            if !is_process_alive(pid, start_time) {
+                println!("Stopped Firecracker daemon gracefully (PID: {})", pid);
+                return;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        println!("Firecracker daemon did not exit within {}s, killing it", grace.as_secs());
+        if !is_process_alive(pid, start_time) {
+            return;
+        }
+    }
+
+    unsafe {
+        if libc::kill(pid as i32, libc::SIGKILL) == 0 {
+            println!("Terminated Firecracker daemon (PID: {})", pid);
+        } else {
+            println!("Warning: Could not kill PID {} (it may have already exited)", pid);
+        }
+    }
+}
+
+/// Whether `pid` still refers to the process we launched. Zombies count as exited, and when
+/// `start_time` is known a mismatch means the PID was recycled by an unrelated process.
+pub fn is_process_alive(pid: u32, start_time: Option<u64>) -> bool {
+    if unsafe { libc::kill(pid as i32, 0) } != 0 {
+        return false;
+    }
+    let stat = match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => stat,
+        Err(_) => return false,
+    };
+    match parse_proc_stat(&stat) {
+        Some((state, _)) if state == "Z" => false,
+        Some((_, actual)) => start_time.is_none_or(|expected| expected == actual),
+        None => false,
+    }
+}
+
+pub fn unix_now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Unix time `pid` was started, from its start time in clock ticks after boot.
+fn process_started_at(pid: u32) -> Option<u64> {
+    let ticks = process_start_time(pid)?;
+    let stat = std::fs::read_to_string("/proc/stat").ok()?;
+    let boot_time: u64 = stat.lines().find_map(|line| line.strip_prefix("btime "))?.trim().parse().ok()?;
+    let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    (ticks_per_sec > 0).then(|| boot_time + ticks / ticks_per_sec as u64)
+}
+
+fn process_start_time(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    parse_proc_stat(&stat).map(|(_, start_time)| start_time)
+}
+
+/// Extracts the state (field 3) and start time (field 22) from a /proc/<pid>/stat line.
+fn parse_proc_stat(stat: &str) -> Option<(String, u64)> {
+    // Fields resume after the parenthesised command name, which may itself contain spaces
+    let (_, rest) = stat.rsplit_once(')')?;
+    let fields: Vec<&str> = rest.split_whitespace().collect();
+    let state = fields.first()?.to_string();
+    let start_time = fields.get(19)?.parse().ok()?;
+    Some((state, start_time))
+}
+
+fn metadata_path(name: &str) -> String {
+    format!("/tmp/stoker-{}.json", name)
+}
+
+/// Loads the state metadata recorded for the VM `name`.
+pub fn load_metadata(name: &str) -> Result<InstanceMetadata> {
+    let meta_path = metadata_path(name);
+    if !std::path::Path::new(&meta_path).exists() {
+        anyhow::bail!(StokerError::new(ErrorKind::NotFound, format!("No running Firecracker VM found with name '{}'", name)));
+    }
+    let meta_json = std::fs::read_to_string(&meta_path)?;
+    Ok(serde_json::from_str(&meta_json)?)
+}
+
+pub fn save_metadata(meta: &InstanceMetadata) -> Result<()> {
+    let meta_json = serde_json::to_string(meta)?;
+    // Written aside and renamed over, so readers never see a half-written file
+    let path = metadata_path(&meta.name);
+    let staged = format!("{}.tmp-{}", path, std::process::id());
+    std::fs::write(&staged, meta_json)?;
+    std::fs::rename(&staged, &path)?;
+    Ok(())
+}
+
+fn metadata_lock_path(name: &str) -> String {
+    format!("/tmp/stoker-{}.lock", name)
+}
+
+/// Holds an exclusive `flock` on `path`, creating the file if needed, until dropped.
+pub struct FileLock {
+    _file: std::fs::File,
+}
+
+impl FileLock {
+    pub fn acquire(path: &str) -> Result<FileLock> {
+        let file = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(path)
+            .with_context(|| format!("Failed to open lock file {}", path))?;
+        if unsafe { libc::flock(std::os::unix::io::AsRawFd::as_raw_fd(&file), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error()).with_context(|| format!("Failed to lock {}", path));
+        }
+        Ok(FileLock { _file: file })
+    }
+}
+
+/// Loads VM `name`'s metadata, applies `update` and saves it, all under the VM's metadata lock
+/// so that concurrent updaters, such as a health probe and `stoker run` recording its
+/// supervisor, don't overwrite each other's changes. Returns the saved metadata.
+pub fn update_metadata(name: &str, update: impl FnOnce(&mut InstanceMetadata)) -> Result<InstanceMetadata> {
+    let _lock = FileLock::acquire(&metadata_lock_path(name))?;
+    let mut meta = load_metadata(name)?;
+    update(&mut meta);
+    save_metadata(&meta)?;
+    Ok(meta)
+}
+
+/// Failure of a request to a Firecracker API socket.
+#[derive(Debug)]
+pub enum FirecrackerApiError {
+    /// The request could not be built from the given path and body
+    InvalidRequest(hyper::http::Error),
+    /// The socket could not be reached or the connection dropped, e.g. because the daemon
+    /// hasn't created its socket yet
+    Connection(hyper::Error),
+    /// Firecracker answered with a non-2xx status; `fault_message` comes from its JSON error body
+    Fault { status: StatusCode, fault_message: String },
+    /// The response body wasn't the JSON expected
+    Serde(serde_json::Error),
+}
+
+impl std::fmt::Display for FirecrackerApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FirecrackerApiError::InvalidRequest(e) => write!(f, "Invalid Firecracker API request: {}", e),
+            FirecrackerApiError::Connection(e) => write!(f, "Failed to reach the Firecracker API socket: {}", e),
+            FirecrackerApiError::Fault { status, fault_message } => {
+                write!(f, "Firecracker API returned {}: {}", status, fault_message)
+            }
+            FirecrackerApiError::Serde(e) => write!(f, "Unexpected Firecracker API response: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FirecrackerApiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FirecrackerApiError::InvalidRequest(e) => Some(e),
+            FirecrackerApiError::Connection(e) => Some(e),
+            FirecrackerApiError::Fault { .. } => None,
+            FirecrackerApiError::Serde(e) => Some(e),
+        }
+    }
+}
+
+impl From<hyper::http::Error> for FirecrackerApiError {
+    fn from(e: hyper::http::Error) -> Self {
+        FirecrackerApiError::InvalidRequest(e)
+    }
+}
+
+impl From<hyper::Error> for FirecrackerApiError {
+    fn from(e: hyper::Error) -> Self {
+        FirecrackerApiError::Connection(e)
+    }
+}
+
+impl From<serde_json::Error> for FirecrackerApiError {
+    fn from(e: serde_json::Error) -> Self {
+        FirecrackerApiError::Serde(e)
+    }
+}
+
+/// Extracts `fault_message` from a Firecracker error body, falling back to the raw text.
+fn fault_message(body: &[u8]) -> String {
+    serde_json::from_slice::<serde_json::Value>(body).ok()
+        .and_then(|v| v["fault_message"].as_str().map(str::to_string))
+        .unwrap_or_else(|| String::from_utf8_lossy(body).trim().to_string())
+}
+
+/// Sends `request`, returning the response body of a successful call.
+async fn api_call(client: &Client<hyperlocal::UnixConnector>, request: Request<Body>) -> Result<hyper::body::Bytes, FirecrackerApiError> {
+    let resp = client.request(request).await?;
+    let status = resp.status();
+    let bytes = hyper::body::to_bytes(resp.into_body()).await?;
+    if !status.is_success() {
+        return Err(FirecrackerApiError::Fault { status, fault_message: fault_message(&bytes) });
+    }
+    Ok(bytes)
+}
+
+async fn send_request(client: &Client<hyperlocal::UnixConnector>, socket: &str, path: &str, body: String) -> Result<(), FirecrackerApiError> {
+    send_api_request(client, socket, Method::PUT, path, body).await
+}
+
+/// Like `send_request`, but retries while the freshly spawned daemon's socket isn't accepting
+/// connections yet.
+async fn send_when_ready(client: &Client<hyperlocal::UnixConnector>, socket: &str, path: &str, body: String) -> Result<(), FirecrackerApiError> {
+    let mut attempts = 0;
+    loop {
+        match send_request(client, socket, path, body.clone()).await {
+            Err(FirecrackerApiError::Connection(_)) if attempts < 20 => {
+                attempts += 1;
+                sleep(Duration::from_millis(100)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn send_api_request(client: &Client<hyperlocal::UnixConnector>, socket: &str, method: Method, path: &str, body: String) -> Result<(), FirecrackerApiError> {
+    let url = Uri::new(socket, path);
+    let req = Request::builder()
+        .method(method)
+        .uri(url)
+        .header("Accept", "application/json")
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))?;
+
+    api_call(client, req).await?;
+    Ok(())
+}
+
+/// Issues a GET against VM `name`'s Firecracker API and returns the JSON response.
+pub async fn query_api(name: &str, path: &str) -> Result<serde_json::Value, FirecrackerApiError> {
+    query_socket(&format!("/tmp/firecracker-{}.socket", name), path).await
+}
+
+async fn query_socket(socket: &str, path: &str) -> Result<serde_json::Value, FirecrackerApiError> {
+    let client = Client::unix();
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(Uri::new(socket, path))
+        .header("Accept", "application/json")
+        .body(Body::empty())?;
+
+    let bytes = api_call(&client, req).await?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+/// A running VM's machine configuration as Firecracker reports it, including values it
+/// defaulted.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MachineConfig {
+    pub vcpu_count: u8,
+    pub mem_size_mib: u32,
+    #[serde(default)]
+    pub smt: bool,
+    #[serde(default)]
+    pub track_dirty_pages: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_template: Option<String>,
+}
+
+/// Asks VM `name`'s daemon for its machine configuration with `GET /machine-config`.
+pub async fn get_machine_config(name: &str) -> Result<MachineConfig> {
+    machine_config_at(&format!("/tmp/firecracker-{}.socket", name)).await
+        .with_context(|| format!("Failed to query the machine configuration of VM '{}'", name))
+}
+
+async fn machine_config_at(socket: &str) -> Result<MachineConfig> {
+    Ok(serde_json::from_value(query_socket(socket, "/machine-config").await?)?)
+}
+
+/// Machine configurations of the running VMs among `vms`, by name. VMs whose API doesn't
+/// answer within a second are left out.
+pub async fn machine_configs(vms: &[InstanceMetadata]) -> std::collections::HashMap<String, MachineConfig> {
+    let mut configs = std::collections::HashMap::new();
+    for meta in vms.iter().filter(|meta| is_process_alive(meta.pid, meta.pid_start_time)) {
+        if let Ok(Ok(config)) = tokio::time::timeout(Duration::from_secs(1), get_machine_config(&meta.name)).await {
+            configs.insert(meta.name.clone(), config);
+        }
+    }
+    configs
+}
+
+/// vCPUs stoker asks Firecracker for at boot.
+const CONFIGURED_VCPUS: u8 = 1;
 
-    println!("VM is running in background. PID: {}", child.id());
+/// Prints VM `name`'s metadata with the resources it was configured with and those its daemon
+/// actually reports, as JSON.
+pub async fn inspect_vm(name: &str) -> Result<()> {
+    let meta = load_metadata(name)?;
+    let actual = if is_process_alive(meta.pid, meta.pid_start_time) { Some(get_machine_config(name).await?) } else { None };
+    let info = json!({
+        "metadata": meta,
+        "configured": { "vcpu_count": CONFIGURED_VCPUS, "mem_size_mib": meta.memory_mib.unwrap_or(DEFAULT_MEMORY_MIB) },
+        "machine_config": actual,
+    });
+    println!("{}", serde_json::to_string_pretty(&info)?);
     Ok(())
 }
 
-fn allocate_vm_id() -> Result<u8> {
-    allocate_vm_id_in_dir("/tmp")
+/// Metadata of every VM recorded in the state directory, ordered by id.
+pub fn list_vms() -> Vec<InstanceMetadata> {
+    let mut vms = read_all_metadata_in_dir("/tmp");
+    vms.sort_by_key(|vm| vm.id);
+    vms
 }
 
-fn allocate_vm_id_in_dir(tmp_dir: &str) -> Result<u8> {
-    let mut used_ids = std::collections::HashSet::new();
-    if let Ok(entries) = std::fs::read_dir(tmp_dir) {
-        for entry in entries.flatten() {
-            let fname = entry.file_name().to_string_lossy().to_string();
-            if fname.starts_with("stoker-") && fname.ends_with(".json") {
-                if let Ok(content) = std::fs::read_to_string(entry.path()) {
-                    if let Ok(meta) = serde_json::from_str::<InstanceMetadata>(&content) {
-                        used_ids.insert(meta.id);
-                    }
-                }
-            }
-        }
+/// Interface names a new TAP must avoid: those of every VM's TAPs and of host interfaces
+/// stoker didn't create. A leftover stoker TAP of the same name is reused instead.
+fn taken_tap_names() -> Vec<String> {
+    let mut taken: Vec<String> = list_vms().into_iter().map(|vm| vm.tap_device).collect();
+    taken.extend(crate::network::interface_names().into_iter().filter(|name| !crate::network::is_stoker_interface(name)));
+    taken
+}
+
+/// Fields `stoker list --sort` orders VMs by.
+pub const SORT_FIELDS: [&str; 5] = ["name", "id", "ip", "uptime", "status"];
+
+/// Orders VMs by one of `SORT_FIELDS`, breaking ties by name. Running VMs sort by uptime
+/// ahead of stopped ones.
+pub fn sort_vms(vms: &mut [InstanceMetadata], field: &str, reverse: bool) {
+    sort_by_field(vms, field, reverse, |meta| is_process_alive(meta.pid, meta.pid_start_time), unix_now())
+}
+
+fn sort_by_field(vms: &mut [InstanceMetadata], field: &str, reverse: bool, alive: impl Fn(&InstanceMetadata) -> bool, now: u64) {
+    let mut keyed: Vec<(bool, u64, String, InstanceMetadata)> = vms.iter().map(|meta| {
+        let up = alive(meta);
+        let uptime = if up { now.saturating_sub(meta.started_at) } else { 0 };
+        (up, uptime, vm_status(meta, up), meta.clone())
+    }).collect();
+    keyed.sort_by(|(a_up, a_uptime, a_status, a), (b_up, b_uptime, b_status, b)| {
+        let order = match field {
+            "id" => a.id.cmp(&b.id),
+            "ip" => match (a.guest_ip.parse::<std::net::Ipv4Addr>(), b.guest_ip.parse::<std::net::Ipv4Addr>()) {
+                (Ok(a_ip), Ok(b_ip)) => a_ip.cmp(&b_ip),
+                _ => a.guest_ip.cmp(&b.guest_ip),
+            },
+            "uptime" => b_up.cmp(a_up).then(a_uptime.cmp(b_uptime)),
+            "status" => a_status.cmp(b_status),
+            _ => std::cmp::Ordering::Equal,
+        };
+        order.then_with(|| a.name.cmp(&b.name))
+    });
+    if reverse {
+        keyed.reverse();
     }
-    
-    for id in 0..=254 {
-        if !used_ids.contains(&id) {
-            return Ok(id);
-        }
+    for (slot, (.., meta)) in vms.iter_mut().zip(keyed) {
+        *slot = meta;
     }
-    anyhow::bail!("No available VM IDs");
 }
 
-pub async fn rm_vm(name: &str) -> Result<()> {
-    let meta_path = format!("/tmp/stoker-{}.json", name);
-    if !std::path::Path::new(&meta_path).exists() {
-        anyhow::bail!("No running Firecracker VM found with name '{}'", name);
+/// Renders VMs as the `stoker list` table, with the resources reported in `configs`.
+pub fn render_vm_table(vms: &[InstanceMetadata], configs: &std::collections::HashMap<String, MachineConfig>) -> String {
+    render_table(vms, configs, |meta| is_process_alive(meta.pid, meta.pid_start_time), unix_now())
+}
+
+fn render_table(
+    vms: &[InstanceMetadata],
+    configs: &std::collections::HashMap<String, MachineConfig>,
+    alive: impl Fn(&InstanceMetadata) -> bool,
+    now: u64,
+) -> String {
+    let mut rows = vec![["CONTAINER ID", "IMAGE", "STATUS", "UPTIME", "VCPUS", "MEMORY", "NAMES", "IP"].map(String::from)];
+    for meta in vms {
+        let up = alive(meta);
+        let config = configs.get(&meta.name).filter(|_| up);
+        rows.push([
+            format!("fc_{:02x}", meta.id),
+            meta.image.clone().unwrap_or_else(|| "-".to_string()),
+            vm_status(meta, up),
+            vm_uptime(meta, up, now),
+            config.map(|c| c.vcpu_count.to_string()).unwrap_or_else(|| "-".to_string()),
+            vm_memory(meta, config),
+            meta.name.clone(),
+            meta.guest_ip.clone(),
+        ]);
     }
-    
-    let meta_json = std::fs::read_to_string(&meta_path)?;
-    let meta: InstanceMetadata = serde_json::from_str(&meta_json)?;
-    
-    // 1. Kill the Firecracker Hypervisor Native PID
-    unsafe {
-        if libc::kill(meta.pid as i32, libc::SIGKILL) == 0 {
-            println!("Terminated Firecracker daemon (PID: {})", meta.pid);
-        } else {
-            println!("Warning: Could not kill PID {} (it may have already exited)", meta.pid);
-        }
+
+    // Size each column to its longest value, like `docker ps`
+    let widths: Vec<usize> = (0..8).map(|col| rows.iter().map(|row| row[col].chars().count()).max().unwrap_or(0)).collect();
+    let mut table = String::new();
+    for row in &rows {
+        let cells: Vec<String> = row.iter().zip(&widths).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect();
+        table.push_str(cells.join("   ").trim_end());
+        table.push('\n');
     }
-    
-    // 2. Teardown Network Interfaces
-    crate::network::teardown_vm_tap(&meta.tap_device).await?;
-    
-    // 3. Remove /tmp state footprints to cleanly release IDs
-    let _ = std::fs::remove_file(&meta_path);
-    let _ = std::fs::remove_file(format!("/tmp/firecracker-{}.socket", name));
-    let _ = std::fs::remove_file(format!("/tmp/firecracker-{}.log", name));
-    let _ = std::fs::remove_file(format!("/tmp/rootfs-{}.ext4", name));
-    
-    println!("Cleaned up all resources for stoker-{}", name);
-    Ok(())
+    table
 }
 
-async fn send_request(client: &Client<hyperlocal::UnixConnector>, socket: &str, path: &str, body: String) -> Result<()> {
-    let url = Uri::new(socket, path);
-    let req = Request::builder()
-        .method(Method::PUT)
-        .uri(url)
-        .header("Accept", "application/json")
-        .header("Content-Type", "application/json")
-        .body(Body::from(body))?;
+fn vm_status(meta: &InstanceMetadata, up: bool) -> String {
+    match (&meta.health, up) {
+        (_, false) => "Exited".to_string(),
+        (Some(health), true) => format!("Up ({})", health),
+        (None, true) => "Up".to_string(),
+    }
+}
 
-    let resp = client.request(req).await?;
-    if !resp.status().is_success() {
-        let status = resp.status();
-        let bytes = hyper::body::to_bytes(resp.into_body()).await?;
-        anyhow::bail!("API Request failed: {} - {:?}", status, bytes);
+/// Guest memory Firecracker reports, noting the configured size when the two differ.
+fn vm_memory(meta: &InstanceMetadata, config: Option<&MachineConfig>) -> String {
+    let configured = meta.memory_mib.unwrap_or(DEFAULT_MEMORY_MIB);
+    match config {
+        Some(config) if config.mem_size_mib == configured => format!("{} MiB", configured),
+        Some(config) => format!("{} MiB ({} configured)", config.mem_size_mib, configured),
+        None => "-".to_string(),
     }
-    Ok(())
 }
 
-pub fn list_vms() -> Result<()> {
-    println!("{:<20} {:<15} {:<15} {:<20} {:<15}", "CONTAINER ID", "IMAGE", "STATUS", "NAMES", "IP");
-    
-    // Natively scan /tmp for stoker metadata jsons
-    if let Ok(entries) = std::fs::read_dir("/tmp") {
-        for entry in entries.flatten() {
-            let fname = entry.file_name().to_string_lossy().to_string();
-            if fname.starts_with("stoker-") && fname.ends_with(".json") {
-                if let Ok(content) = std::fs::read_to_string(entry.path()) {
-                    if let Ok(meta) = serde_json::from_str::<InstanceMetadata>(&content) {
-                        let id_str = format!("fc_{:02x}", meta.id);
-                        println!("{:<20} {:<15} {:<15} {:<20} {:<15}", 
-                            id_str, 
-                            "ubuntu:24.04", 
-                            "Up", 
-                            meta.name,
-                            meta.guest_ip
-                        );
-                    }
-                }
+fn vm_uptime(meta: &InstanceMetadata, up: bool, now: u64) -> String {
+    if up && meta.started_at > 0 { format_uptime(now.saturating_sub(meta.started_at)) } else { "-".to_string() }
+}
+
+/// Fields `stoker list --format` templates can use as `{{.field}}`.
+pub const FORMAT_FIELDS: [&str; 16] = [
+    "id", "name", "mode", "image", "status", "uptime", "guest_ip", "host_ip", "mac_address",
+    "tap_device", "pid", "memory_mib", "health", "started_at", "cpuset", "rootfs_dir",
+];
+
+#[derive(Debug, PartialEq)]
+enum FormatSegment {
+    Text(String),
+    Field(String),
+}
+
+/// Splits a `--format` template into literal text and `{{.field}}` placeholders. `\t` and
+/// `\n` in the text become a tab and a newline, as with `docker ps --format`.
+fn parse_format(template: &str) -> Result<Vec<FormatSegment>> {
+    let unescape = |text: &str| text.replace("\\t", "\t").replace("\\n", "\n");
+    let mut segments = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..].find("}}").map(|end| start + end)
+            .with_context(|| format!("Unclosed '{{{{' in --format '{}'", template))?;
+        if start > 0 {
+            segments.push(FormatSegment::Text(unescape(&rest[..start])));
+        }
+        let placeholder = rest[start + 2..end].trim();
+        let field = placeholder.strip_prefix('.').filter(|field| FORMAT_FIELDS.contains(field)).with_context(|| {
+            format!("Unknown placeholder '{{{{{}}}}}' in --format. Fields: {}", placeholder, FORMAT_FIELDS.join(", "))
+        })?;
+        segments.push(FormatSegment::Field(field.to_string()));
+        rest = &rest[end + 2..];
+    }
+    if !rest.is_empty() {
+        segments.push(FormatSegment::Text(unescape(rest)));
+    }
+    Ok(segments)
+}
+
+fn format_field(meta: &InstanceMetadata, field: &str, up: bool, now: u64) -> String {
+    let optional = |value: Option<String>| value.unwrap_or_default();
+    match field {
+        "id" => format!("fc_{:02x}", meta.id),
+        "name" => meta.name.clone(),
+        "mode" => meta.mode.clone(),
+        "image" => optional(meta.image.clone()),
+        "status" => vm_status(meta, up),
+        "uptime" => vm_uptime(meta, up, now),
+        "guest_ip" => meta.guest_ip.clone(),
+        "host_ip" => meta.host_ip.clone(),
+        "mac_address" => meta.mac_address.clone(),
+        "tap_device" => meta.tap_device.clone(),
+        "pid" => meta.pid.to_string(),
+        "memory_mib" => optional(meta.memory_mib.map(|mib| mib.to_string())),
+        "health" => optional(meta.health.clone()),
+        "started_at" => meta.started_at.to_string(),
+        "cpuset" => optional(meta.cpuset.clone()),
+        "rootfs_dir" => optional(meta.rootfs_dir.clone()),
+        _ => String::new(),
+    }
+}
+
+/// Renders one line per VM from a `--format` template such as `{{.name}} {{.guest_ip}}`.
+pub fn render_vm_format(vms: &[InstanceMetadata], template: &str) -> Result<String> {
+    let segments = parse_format(template)?;
+    Ok(render_format(vms, &segments, |meta| is_process_alive(meta.pid, meta.pid_start_time), unix_now()))
+}
+
+fn render_format(vms: &[InstanceMetadata], segments: &[FormatSegment], alive: impl Fn(&InstanceMetadata) -> bool, now: u64) -> String {
+    let mut output = String::new();
+    for meta in vms {
+        let up = alive(meta);
+        for segment in segments {
+            match segment {
+                FormatSegment::Text(text) => output.push_str(text),
+                FormatSegment::Field(field) => output.push_str(&format_field(meta, field, up, now)),
             }
         }
+        output.push('\n');
+    }
+    output
+}
+
+fn format_uptime(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{} seconds", secs),
+        60..=3599 => format!("{} minutes", secs / 60),
+        3600..=86399 => format!("{} hours", secs / 3600),
+        _ => format!("{} days", secs / 86400),
     }
-    
-    Ok(())
 }
 
 #[cfg(test)]
@@ -267,6 +2664,7 @@ mod tests {
             mac_address: "00:00:00:00".to_string(),
             tap_device: "tap-inet-0".to_string(),
             pid: 1234,
+            ..Default::default()
         };
         let mut file = File::create(format!("{}/stoker-test-0.json", test_dir))?;
         file.write_all(serde_json::to_string(&meta_0)?.as_bytes())?;
@@ -278,4 +2676,612 @@ mod tests {
         fs::remove_dir_all(&test_dir)?;
         Ok(())
     }
+
+    fn allocation_test_dir(label: &str) -> Result<String> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+        let test_dir = format!("/tmp/stoker-test-{}-{}", label, timestamp);
+        fs::create_dir_all(&test_dir)?;
+        Ok(test_dir)
+    }
+
+    fn write_vm_with_id(dir: &str, id: u16) -> Result<()> {
+        let meta = InstanceMetadata { id, name: format!("vm-{}", id), ..Default::default() };
+        fs::write(format!("{}/stoker-vm-{}.json", dir, id), serde_json::to_string(&meta)?)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_allocate_vm_id_first_gap() -> Result<()> {
+        let test_dir = allocation_test_dir("gap")?;
+        for id in [0, 1, 2, 4] {
+            write_vm_with_id(&test_dir, id)?;
+        }
+        assert_eq!(allocate_vm_id_in_dir(&test_dir)?, 3);
+        fs::remove_dir_all(&test_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_allocate_vm_id_exhausted() -> Result<()> {
+        let test_dir = allocation_test_dir("full")?;
+        for id in 0..MAX_VM_IDS {
+            write_vm_with_id(&test_dir, id)?;
+        }
+        let err = allocate_vm_id_in_dir(&test_dir).unwrap_err();
+        assert!(err.to_string().contains("No available VM IDs"));
+        fs::remove_dir_all(&test_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_tap_names_fit() {
+        for id in 0..MAX_VM_IDS {
+            let name = crate::network::tap_name("tap-inet-", &id.to_string(), &[]);
+            assert!(name.len() <= crate::network::MAX_IFNAME_LEN, "{}", name);
+            for index in [2, 255] {
+                let name = crate::network::tap_name("tap-x", &format!("{}-{}", id, index), &[]);
+                assert!(name.len() <= crate::network::MAX_IFNAME_LEN, "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_allocate_vm_id_skips_malformed_metadata() -> Result<()> {
+        let test_dir = allocation_test_dir("malformed")?;
+        fs::write(format!("{}/stoker-broken.json", test_dir), "{ not json")?;
+        fs::write(format!("{}/stoker-empty.json", test_dir), "")?;
+        write_vm_with_id(&test_dir, 0)?;
+        // Files that aren't stoker metadata are ignored even if they parse
+        fs::write(format!("{}/other-1.json", test_dir), serde_json::to_string(&InstanceMetadata { id: 1, ..Default::default() })?)?;
+        assert_eq!(allocate_vm_id_in_dir(&test_dir)?, 1);
+        fs::remove_dir_all(&test_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_allocate_vm_id_skips_overlapping_subnets() -> Result<()> {
+        let test_dir = allocation_test_dir("overlap")?;
+        // A VM from the old 172.16.<id>.0/30 scheme occupies the subnet of new id 0
+        let legacy = InstanceMetadata { id: 7, name: "legacy".to_string(), host_ip: "172.16.0.1".to_string(), ..Default::default() };
+        fs::write(format!("{}/stoker-legacy.json", test_dir), serde_json::to_string(&legacy)?)?;
+        assert_eq!(allocate_vm_id_in_dir(&test_dir)?, 1);
+        fs::remove_dir_all(&test_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_vm_addresses_and_mac() {
+        assert_eq!(vm_addresses(0), ("172.16.0.2".to_string(), "172.16.0.1".to_string()));
+        assert_eq!(vm_addresses(1), ("172.16.0.6".to_string(), "172.16.0.5".to_string()));
+        assert_eq!(vm_addresses(MAX_VM_IDS - 1), ("172.16.255.254".to_string(), "172.16.255.253".to_string()));
+        assert_eq!(vm_mac(0x1234, 2), "06:00:AC:12:34:02");
+    }
+
+    #[test]
+    fn test_boot_requests_order() {
+        let meta = InstanceMetadata {
+            name: "web".to_string(),
+            memory_mib: Some(512),
+            initial_balloon_mib: Some(128),
+            ..Default::default()
+        };
+        let paths: Vec<String> = boot_requests(&meta).into_iter().map(|r| r.path).collect();
+        assert_eq!(paths, vec![
+            "/logger", "/machine-config", "/boot-source", "/drives/rootfs",
+            "/network-interfaces/net1", "/balloon", "/actions",
+        ]);
+    }
+
+    #[test]
+    fn test_read_only_root() {
+        let meta = InstanceMetadata {
+            name: "web".to_string(),
+            rootfs_dir: Some("/srv/vms".to_string()),
+            read_only_root: Some("/assets/web.squashfs".to_string()),
+            data_mount: Some("/data".to_string()),
+            ..Default::default()
+        };
+        let requests = boot_requests(&meta);
+        let root = requests.iter().find(|r| r.path == "/drives/rootfs").unwrap();
+        assert_eq!(root.body["path_on_host"], "/assets/web.squashfs");
+        assert_eq!(root.body["is_read_only"], true);
+        let data = requests.iter().find(|r| r.path == "/drives/data").unwrap();
+        assert_eq!(data.body["path_on_host"], "/srv/vms/data-web.ext4");
+        assert_eq!(data.body["is_read_only"], false);
+        assert!(boot_requests(&InstanceMetadata::default()).iter().all(|r| r.path != "/drives/data"));
+
+        let opts = RunConfig { read_only_root: true, ..Default::default() };
+        assert_eq!(data_mount(&opts).unwrap().as_deref(), Some(DEFAULT_DATA_MOUNT));
+        let opts = RunConfig { read_only_root: true, data_mount: Some("/var/lib/app".to_string()), ..Default::default() };
+        assert_eq!(data_mount(&opts).unwrap().as_deref(), Some("/var/lib/app"));
+        assert!(data_mount(&RunConfig { read_only_root: true, data_mount: Some("/".to_string()), ..Default::default() }).is_err());
+        let err = data_mount(&RunConfig { read_only_root: true, persist_network: true, ..Default::default() }).unwrap_err();
+        assert!(err.to_string().contains("--persist-network"), "{}", err);
+        assert!(data_mount(&RunConfig { data_size_mib: Some(512), ..Default::default() }).is_err());
+        assert_eq!(data_mount(&RunConfig::default()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_drive_cache() {
+        let drive = |meta: &InstanceMetadata| boot_requests(meta).into_iter().find(|r| r.path == "/drives/rootfs").unwrap().body;
+        // Firecracker's default is left implicit
+        assert!(drive(&InstanceMetadata::default()).get("cache_type").is_none());
+        let meta = InstanceMetadata { drive_cache: Some(drive_cache_type("writeback").unwrap()), ..Default::default() };
+        assert_eq!(drive(&meta)["cache_type"], "Writeback");
+        assert!(drive_cache_type("none").is_err());
+    }
+
+    #[test]
+    fn test_parse_user() {
+        assert_eq!(parse_user("1000").unwrap(), ("1000".to_string(), None));
+        assert_eq!(parse_user("1000:100").unwrap(), ("1000".to_string(), Some("100".to_string())));
+        assert_eq!(parse_user("nobody:nobody").unwrap(), ("nobody".to_string(), Some("nobody".to_string())));
+        assert!(parse_user("alice").is_err());
+        assert!(parse_user("1000:").is_err());
+        assert!(parse_user("").is_err());
+    }
+
+    #[test]
+    fn test_boot_args_user() {
+        let meta = InstanceMetadata {
+            run_as_uid: Some("1000".to_string()),
+            run_as_gid: Some("100".to_string()),
+            ..Default::default()
+        };
+        assert!(boot_args(&meta).ends_with("keep_bootcon stoker.run_as_uid=1000 stoker.run_as_gid=100"));
+        assert!(!boot_args(&InstanceMetadata::default()).contains("stoker."));
+
+        let meta = InstanceMetadata { workdir: Some("/srv/app".to_string()), ..Default::default() };
+        assert!(boot_args(&meta).ends_with(" stoker.workdir=/srv/app"));
+    }
+
+    #[test]
+    fn test_parse_sysctl() {
+        assert_eq!(parse_sysctl("net.ipv4.tcp_fin_timeout=30").unwrap(), "net.ipv4.tcp_fin_timeout=30");
+        assert_eq!(parse_sysctl("net/core/somaxconn=4096").unwrap(), "net.core.somaxconn=4096");
+        assert_eq!(parse_sysctl("net.ipv4.tcp_rmem=4096 87380 6291456").unwrap(), "net.ipv4.tcp_rmem=4096 87380 6291456");
+        assert!(parse_sysctl("fs.file-max=100000").is_err());
+        assert!(parse_sysctl("kernel.sysrq=1").is_err());
+        assert!(parse_sysctl("kernel.panic=10").is_err());
+        assert!(parse_sysctl("kernel.panic_on_oops=1").is_err());
+        assert!(parse_sysctl("net.core.somaxconn").is_err());
+        assert!(parse_sysctl("net.core.somaxconn=").is_err());
+        assert!(parse_sysctl("vm.swappiness=1'; reboot '").is_err());
+        assert!(parse_sysctl("net.core.$(reboot)=1").is_err());
+    }
+
+    #[test]
+    fn test_parse_add_host() {
+        assert_eq!(parse_add_host("db.internal:172.16.0.6").unwrap(), "db.internal:172.16.0.6");
+        assert_eq!(parse_add_host("ipv6-peer:fd00::1").unwrap(), "ipv6-peer:fd00::1");
+        assert_eq!(parse_add_host("host.stoker.internal:host-gateway").unwrap(), "host.stoker.internal:host-gateway");
+        assert!(parse_add_host("db.internal").is_err());
+        assert!(parse_add_host("db:not-an-ip").is_err());
+        assert!(parse_add_host(":10.0.0.1").is_err());
+        assert!(parse_add_host("db;reboot:10.0.0.1").is_err());
+    }
+
+    #[test]
+    fn test_parse_ulimit() {
+        assert_eq!(parse_ulimit("nofile=1024:4096").unwrap(), "nofile=1024:4096");
+        assert_eq!(parse_ulimit("nproc=512").unwrap(), "nproc=512:512");
+        assert_eq!(parse_ulimit("core=0:unlimited").unwrap(), "core=0:unlimited");
+        assert!(parse_ulimit("nofile=4096:1024").is_err());
+        assert!(parse_ulimit("fsize=unlimited:100").is_err());
+        assert!(parse_ulimit("stack=8192").is_err());
+        assert!(parse_ulimit("nofile").is_err());
+        assert!(parse_ulimit("nofile=lots").is_err());
+
+        let meta = InstanceMetadata { ulimits: vec!["nofile=1024:4096".to_string()], ..Default::default() };
+        assert!(boot_args(&meta).ends_with(" stoker.ulimit.nofile=1024:4096"));
+    }
+
+    #[test]
+    fn test_boot_args_init_file() {
+        let meta = InstanceMetadata { init_file: Some("./preflight.sh".to_string()), ..Default::default() };
+        assert!(boot_args(&meta).ends_with(" init=/stoker-init.sh /sbin/init"));
+        assert!(!boot_args(&InstanceMetadata::default()).contains("init="));
+    }
+
+    #[test]
+    fn test_file_sha256() {
+        let path = std::env::temp_dir().join(format!("stoker-sha-{}", std::process::id()));
+        std::fs::write(&path, "abc").unwrap();
+        assert_eq!(
+            file_sha256(path.to_str().unwrap()).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_tmpfs() {
+        assert_eq!(parse_tmpfs("/scratch:512").unwrap(), TmpfsMount { path: "/scratch".to_string(), size_mib: Some(512) });
+        assert_eq!(parse_tmpfs("/var/cache/app").unwrap(), TmpfsMount { path: "/var/cache/app".to_string(), size_mib: None });
+        assert!(parse_tmpfs("scratch:512").is_err());
+        assert!(parse_tmpfs("/scratch:0").is_err());
+        assert!(parse_tmpfs("/scratch:1g").is_err());
+        assert!(parse_tmpfs("/my dir").is_err());
+        assert!(parse_tmpfs("/x';reboot'").is_err());
+    }
+
+    #[test]
+    fn test_validate_working_dir() {
+        assert!(validate_working_dir("/srv/app").is_ok());
+        assert!(validate_working_dir("srv/app").is_err());
+        assert!(validate_working_dir("/srv/my app").is_err());
+    }
+
+    #[test]
+    fn test_validate_mac() {
+        assert_eq!(validate_mac("02:ab:cd:ef:00:01").unwrap(), "02:AB:CD:EF:00:01");
+        assert!(validate_mac("02:ab:cd:ef:00").is_err());
+        assert!(validate_mac("02-ab-cd-ef-00-01").is_err());
+        assert!(validate_mac("02:ab:cd:ef:00:0g").is_err());
+        // Multicast bit set
+        assert!(validate_mac("01:00:5e:00:00:01").is_err());
+    }
+
+    #[test]
+    fn test_check_name_unused_in_dir() {
+        let tmp_dir = std::env::temp_dir().join("stoker_test_name_unused");
+        let _ = std::fs::remove_dir_all(&tmp_dir);
+        std::fs::create_dir_all(&tmp_dir).unwrap();
+        let dir = tmp_dir.to_str().unwrap();
+
+        let meta = InstanceMetadata { name: "web".to_string(), ..Default::default() };
+        std::fs::write(tmp_dir.join("stoker-web.json"), serde_json::to_string(&meta).unwrap()).unwrap();
+
+        let err = check_name_unused_in_dir(dir, "web").unwrap_err().to_string();
+        assert!(err.contains("--replace"));
+        assert!(check_name_unused_in_dir(dir, "db").is_ok());
+
+        let _ = std::fs::remove_dir_all(&tmp_dir);
+    }
+
+    #[test]
+    fn test_check_mac_unused_in_dir() {
+        let tmp_dir = std::env::temp_dir().join(format!("stoker_test_mac_unused_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&tmp_dir);
+        std::fs::create_dir_all(&tmp_dir).unwrap();
+        let dir = tmp_dir.to_str().unwrap();
+
+        let meta = InstanceMetadata {
+            name: "web".to_string(),
+            mac_address: "06:00:AC:10:00:02".to_string(),
+            ..Default::default()
+        };
+        std::fs::write(tmp_dir.join("stoker-web.json"), serde_json::to_string(&meta).unwrap()).unwrap();
+
+        assert!(check_mac_unused_in_dir(dir, "06:00:ac:10:00:02").is_err());
+        assert!(check_mac_unused_in_dir(dir, "06:00:AC:10:00:03").is_ok());
+
+        let _ = std::fs::remove_dir_all(&tmp_dir);
+    }
+
+    #[test]
+    fn test_validate_custom_ips() {
+        assert_eq!(validate_custom_ips("10.0.5.2/30", "10.0.5.1/30").unwrap(),
+            ("10.0.5.2".to_string(), "10.0.5.1".to_string(), 30));
+        assert!(validate_custom_ips("192.168.7.6/29", "192.168.7.1/29").is_ok());
+        // Public address
+        assert!(validate_custom_ips("8.8.8.2/30", "8.8.8.1/30").is_err());
+        // Prefix outside 29-30
+        assert!(validate_custom_ips("10.0.5.2/24", "10.0.5.1/24").is_err());
+        // Different subnets
+        assert!(validate_custom_ips("10.0.5.6/30", "10.0.5.1/30").is_err());
+        // Broadcast address
+        assert!(validate_custom_ips("10.0.5.3/30", "10.0.5.1/30").is_err());
+        assert!(validate_custom_ips("10.0.5.2", "10.0.5.1/30").is_err());
+    }
+
+    #[test]
+    fn test_check_subnet_unused_in_dir() {
+        let tmp_dir = std::env::temp_dir().join("stoker_test_subnet_unused");
+        let _ = std::fs::remove_dir_all(&tmp_dir);
+        std::fs::create_dir_all(&tmp_dir).unwrap();
+        let dir = tmp_dir.to_str().unwrap();
+
+        let meta = InstanceMetadata {
+            name: "db".to_string(),
+            host_ip: "10.0.5.1".to_string(),
+            prefix_len: Some(29),
+            ..Default::default()
+        };
+        std::fs::write(tmp_dir.join("stoker-db.json"), serde_json::to_string(&meta).unwrap()).unwrap();
+
+        assert!(check_subnet_unused_in_dir(dir, "10.0.5.5", 30).is_err());
+        assert!(check_subnet_unused_in_dir(dir, "10.0.5.9", 30).is_ok());
+
+        let _ = std::fs::remove_dir_all(&tmp_dir);
+    }
+
+    #[test]
+    fn test_render_vm_table() {
+        let vms = vec![
+            InstanceMetadata {
+                id: 3,
+                name: "web".to_string(),
+                guest_ip: "172.16.3.2".to_string(),
+                health: Some("healthy".to_string()),
+                image: Some("nginx-server-with-tls".to_string()),
+                started_at: 1_000,
+                pid: 42,
+                ..Default::default()
+            },
+            InstanceMetadata { id: 4, name: "db".to_string(), guest_ip: "172.16.4.2".to_string(), ..Default::default() },
+        ];
+        let config = MachineConfig { vcpu_count: 2, mem_size_mib: 256, smt: false, track_dirty_pages: false, cpu_template: None };
+        let configs = std::collections::HashMap::from([("web".to_string(), config)]);
+        let table = render_table(&vms, &configs, |meta| meta.pid == 42, 1_000 + 7_200);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("CONTAINER ID"));
+        let row: Vec<&str> = lines[1].split_whitespace().collect();
+        assert_eq!(row, vec![
+            "fc_03", "nginx-server-with-tls", "Up", "(healthy)", "2", "hours", "2", "256", "MiB", "(128", "configured)", "web", "172.16.3.2",
+        ]);
+        let row: Vec<&str> = lines[2].split_whitespace().collect();
+        assert_eq!(row, vec!["fc_04", "-", "Exited", "-", "-", "-", "db", "172.16.4.2"]);
+
+        // Columns line up however long the values are
+        let name_col = lines[0].find("NAMES").unwrap();
+        assert_eq!(lines[1].find("web"), Some(name_col));
+        assert_eq!(lines[2].find("db"), Some(name_col));
+    }
+
+    #[test]
+    fn test_sort_by_field() {
+        let vm = |id: u16, name: &str, ip: &str, started_at: u64| InstanceMetadata {
+            id,
+            name: name.to_string(),
+            guest_ip: ip.to_string(),
+            started_at,
+            pid: if started_at > 0 { 42 } else { 0 },
+            ..Default::default()
+        };
+        let mut vms = vec![vm(3, "web", "172.16.10.2", 500), vm(1, "db", "172.16.9.2", 900), vm(2, "cache", "172.16.2.2", 0)];
+        let names = |vms: &[InstanceMetadata]| vms.iter().map(|vm| vm.name.clone()).collect::<Vec<_>>();
+        let alive = |meta: &InstanceMetadata| meta.pid == 42;
+
+        sort_by_field(&mut vms, "name", false, alive, 1_000);
+        assert_eq!(names(&vms), vec!["cache", "db", "web"]);
+        sort_by_field(&mut vms, "id", false, alive, 1_000);
+        assert_eq!(names(&vms), vec!["db", "cache", "web"]);
+        // Addresses compare numerically, not as strings
+        sort_by_field(&mut vms, "ip", false, alive, 1_000);
+        assert_eq!(names(&vms), vec!["cache", "db", "web"]);
+        // Running VMs first, shortest uptime first
+        sort_by_field(&mut vms, "uptime", false, alive, 1_000);
+        assert_eq!(names(&vms), vec!["db", "web", "cache"]);
+        sort_by_field(&mut vms, "status", true, alive, 1_000);
+        assert_eq!(names(&vms), vec!["web", "db", "cache"]);
+    }
+
+    #[test]
+    fn test_render_format() {
+        let vms = vec![
+            InstanceMetadata { id: 3, name: "web".to_string(), guest_ip: "172.16.3.2".to_string(), pid: 42, ..Default::default() },
+            InstanceMetadata { id: 4, name: "db".to_string(), guest_ip: "172.16.4.2".to_string(), ..Default::default() },
+        ];
+        let segments = parse_format("{{.name}}\\t{{ .guest_ip }} {{.status}} {{.image}}").unwrap();
+        assert_eq!(
+            render_format(&vms, &segments, |meta| meta.pid == 42, 0),
+            "web\t172.16.3.2 Up \ndb\t172.16.4.2 Exited \n"
+        );
+
+        assert!(parse_format("{{.names}}").is_err());
+        assert!(parse_format("{{name}}").is_err());
+        assert!(parse_format("{{.name").is_err());
+        assert_eq!(parse_format("plain").unwrap(), vec![FormatSegment::Text("plain".to_string())]);
+    }
+
+    #[test]
+    fn test_fault_message() {
+        let body = br#"{"fault_message":"The requested operation is not supported after starting the microVM."}"#;
+        assert_eq!(fault_message(body), "The requested operation is not supported after starting the microVM.");
+        assert_eq!(fault_message(b"Bad gateway\n"), "Bad gateway");
+
+        let err = FirecrackerApiError::Fault { status: StatusCode::BAD_REQUEST, fault_message: fault_message(body) };
+        assert_eq!(
+            err.to_string(),
+            "Firecracker API returned 400 Bad Request: The requested operation is not supported after starting the microVM."
+        );
+    }
+
+    #[test]
+    fn test_rootfs_path() {
+        let mut meta = InstanceMetadata { name: "web".to_string(), ..Default::default() };
+        // Metadata written before rootfs_dir existed points at the old /tmp copy
+        assert_eq!(rootfs_path(&meta), "/tmp/rootfs-web.ext4");
+        meta.rootfs_dir = Some("/var/lib/stoker/vms".to_string());
+        assert_eq!(rootfs_path(&meta), "/var/lib/stoker/vms/rootfs-web.ext4");
+        assert_eq!(kept_rootfs_path(&meta, 1700000000), "/var/lib/stoker/vms/rootfs-web-kept-1700000000.ext4");
+    }
+
+    #[test]
+    fn test_parse_cpu_set() {
+        assert_eq!(parse_cpu_set("0-3,6").unwrap(), vec![0, 1, 2, 3, 6]);
+        assert_eq!(parse_cpu_set("2,1,2").unwrap(), vec![1, 2]);
+        assert!(parse_cpu_set("3-1").is_err());
+        assert!(parse_cpu_set("a").is_err());
+        assert!(parse_cpu_set("0,").is_err());
+        assert!(parse_cpu_set("4096").is_err());
+    }
+
+    #[test]
+    fn test_configure_log_driver() {
+        assert!(configure_log_driver("none", "log-test").is_ok());
+        assert!(configure_log_driver("fluentd", "log-test").is_err());
+
+        let name = format!("log-test-{}", std::process::id());
+        assert!(configure_log_driver("file", &name).is_ok());
+        assert!(std::path::Path::new(&format!("{}/serial.log", state_dir(&name))).exists());
+        let _ = std::fs::remove_dir_all(state_dir(&name));
+    }
+
+    #[test]
+    fn test_session_registry() {
+        let mut meta = InstanceMetadata { name: "session-test".to_string(), ..Default::default() };
+        track_session_vm(&meta);
+        meta.pid = 4242;
+        refresh_session_vm(&meta);
+        let tracked = |name: &str| session_vms().lock().unwrap().iter().find(|vm| vm.name == name).map(|vm| vm.pid);
+        assert_eq!(tracked("session-test"), Some(4242));
+
+        // Refreshing never adds VMs this process didn't launch
+        refresh_session_vm(&InstanceMetadata { name: "session-other".to_string(), ..Default::default() });
+        assert_eq!(tracked("session-other"), None);
+
+        untrack_session_vm("session-test");
+        assert_eq!(tracked("session-test"), None);
+    }
+
+    #[test]
+    fn test_format_timeout_report() {
+        let report = format_timeout_report(45, "web", true, true, false);
+        assert!(report.starts_with("VM 'web' did not come up within 45s"));
+        assert!(report.contains("daemon alive: yes, API socket present: yes, SSH reachable: no"));
+    }
+
+    #[test]
+    fn test_snapshot_load_body() {
+        let body = snapshot_load_body("/assets/snapshots/warm", "tap-inet-9");
+        assert_eq!(body["snapshot_path"], "/assets/snapshots/warm/vmstate");
+        assert_eq!(body["mem_backend"]["backend_path"], "/assets/snapshots/warm/memory");
+        assert_eq!(body["resume_vm"], false);
+        assert_eq!(body["network_overrides"][0]["host_dev_name"], "tap-inet-9");
+    }
+
+    #[test]
+    fn test_cmdline_uses_socket() {
+        let cmdline = b"/opt/firecracker\0--api-sock\0/tmp/firecracker-web.socket\0";
+        assert!(cmdline_uses_socket(cmdline, "/tmp/firecracker-web.socket"));
+        assert!(!cmdline_uses_socket(cmdline, "/tmp/firecracker-we.socket"));
+        assert!(!cmdline_uses_socket(b"/bin/cat\0/tmp/firecracker-web.socket\0", "/tmp/firecracker-web.socket"));
+    }
+
+    #[test]
+    fn test_update_metadata_concurrent() {
+        let name = format!("test-update-{}", std::process::id());
+        save_metadata(&InstanceMetadata { name: name.clone(), ..Default::default() }).unwrap();
+        let writers: Vec<_> = (0..8)
+            .map(|i| {
+                let name = name.clone();
+                std::thread::spawn(move || update_metadata(&name, |meta| meta.ulimits.push(format!("nofile={}", i))).unwrap())
+            })
+            .collect();
+        writers.into_iter().for_each(|w| { w.join().unwrap(); });
+        assert_eq!(load_metadata(&name).unwrap().ulimits.len(), 8);
+        assert!(update_metadata("test-update-missing", |_| {}).is_err());
+        std::fs::remove_file(metadata_path(&name)).unwrap();
+        let _ = std::fs::remove_file(metadata_lock_path(&name));
+        let _ = std::fs::remove_file(metadata_lock_path("test-update-missing"));
+    }
+
+    #[tokio::test]
+    async fn test_health_retries_at_least_one() {
+        let check = HealthCheck { cmd: "true".to_string(), interval_secs: 1, retries: 0, timeout_secs: 1 };
+        let err = run_vm(RunConfig { healthcheck: Some(check), dry_run: true, ..Default::default() }).await.unwrap_err();
+        assert!(err.to_string().contains("--health-retries"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_machine_config_at() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let socket = std::env::temp_dir().join(format!("stoker_test_machine_config_{}.socket", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            let body = r#"{"vcpu_count":2,"mem_size_mib":1024,"smt":false,"track_dirty_pages":true,"huge_pages":"None"}"#;
+            let response = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let config = machine_config_at(socket.to_str().unwrap()).await.unwrap();
+        assert_eq!(config, MachineConfig { vcpu_count: 2, mem_size_mib: 1024, smt: false, track_dirty_pages: true, cpu_template: None });
+        assert!(server.await.unwrap().starts_with("GET /machine-config HTTP/1.1"));
+        std::fs::remove_file(&socket).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_dir() {
+        assert_eq!(snapshot_dir("warm"), crate::assets::get_asset_path("snapshots/warm"));
+        assert_eq!(snapshot_dir("/srv/snapshots/warm/"), "/srv/snapshots/warm");
+        assert_eq!(snapshot_dir("./warm"), "./warm");
+    }
+
+    #[test]
+    fn test_check_snapshot_compatible() {
+        let opts = RunConfig { from_snapshot: Some("warm".to_string()), ..Default::default() };
+        assert!(check_snapshot_compatible(&opts).is_ok());
+
+        let opts = RunConfig { image: Some("nginx".to_string()), memory_mib: Some(512), ..opts };
+        let err = check_snapshot_compatible(&opts).unwrap_err().to_string();
+        assert!(err.starts_with("--image, --memory can't be combined with --from-snapshot"));
+    }
+
+    #[test]
+    fn test_check_platform() {
+        assert!(check_platform("web", Some("aarch64"), Some("aarch64"), "aarch64").is_ok());
+        // Images from before the architecture was recorded are let through
+        assert!(check_platform("web", None, None, "x86_64").is_ok());
+        let err = check_platform("web", Some("x86_64"), Some("aarch64"), "aarch64").unwrap_err().to_string();
+        assert!(err.contains("Image 'web' is built for x86_64, but this host is aarch64"), "{}", err);
+        assert!(check_platform("web", Some("aarch64"), Some("x86_64"), "aarch64").is_err());
+    }
+
+    #[test]
+    fn test_kernel_arch() {
+        let path = std::env::temp_dir().join(format!("stoker_test_kernel_arch_{}", std::process::id()));
+        let mut image = vec![0u8; 0x400];
+        image[0x38..0x3c].copy_from_slice(b"ARM\x64");
+        std::fs::write(&path, &image).unwrap();
+        assert_eq!(kernel_arch(path.to_str().unwrap()), Some("aarch64"));
+
+        let mut bzimage = vec![0u8; 0x400];
+        bzimage[0x202..0x206].copy_from_slice(b"HdrS");
+        std::fs::write(&path, &bzimage).unwrap();
+        assert_eq!(kernel_arch(path.to_str().unwrap()), Some("x86_64"));
+
+        std::fs::write(&path, b"short").unwrap();
+        assert_eq!(kernel_arch(path.to_str().unwrap()), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_primary_interface() {
+        let config = json!({
+            "machine-config": { "vcpu_count": 2, "mem_size_mib": 256 },
+            "network-interfaces": [
+                { "iface_id": "eth0", "guest_mac": "AA:FC:00:00:00:01", "host_dev_name": "tap0" },
+                { "iface_id": "eth1", "guest_mac": "AA:FC:00:00:00:02", "host_dev_name": "tap1" }
+            ]
+        });
+        assert_eq!(primary_interface(&config).unwrap(), ("tap0".to_string(), "AA:FC:00:00:00:01".to_string()));
+        assert!(primary_interface(&json!({ "network-interfaces": [] })).is_err());
+        assert!(primary_interface(&json!({ "network-interfaces": [{ "iface_id": "eth0" }] })).is_err());
+    }
+
+    #[test]
+    fn test_validate_balloon() {
+        assert!(validate_balloon(1024, 256).is_ok());
+        assert!(validate_balloon(1024, 1024).is_err());
+        assert!(validate_balloon(DEFAULT_MEMORY_MIB, 512).is_err());
+    }
+
+    #[test]
+    fn test_parse_proc_stat() {
+        let stat = "4242 (fire cracker) S 1 4242 4242 0 -1 4194560 1043 0 0 0 12 9 0 0 20 0 3 0 987654 1097728 512 18446744073709551615";
+        assert_eq!(parse_proc_stat(stat), Some(("S".to_string(), 987654)));
+        assert_eq!(parse_proc_stat("4242 (truncated) S 1"), None);
+    }
 }
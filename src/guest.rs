@@ -1,63 +1,403 @@
 use anyhow::{Context, Result};
-use std::process::Command;
+use std::cell::RefCell;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
 use crate::assets;
-use crate::firecracker::InstanceMetadata;
+use crate::errors::{ErrorKind, StokerError};
+use crate::progress::TransferProgress;
+use crate::firecracker::{self, InstanceMetadata, TmpfsMount};
 
-pub fn interactive_ssh(name: &str) -> Result<()> {
-    // 1. We must find the IP mapping from the state JSON
-    let meta_path = format!("/tmp/stoker-{}.json", name);
-    if !std::path::Path::new(&meta_path).exists() {
-        anyhow::bail!("No running Firecracker VM found with name: {}", name);
+/// How long an idle `stoker ssh --multiplex` master connection stays up.
+const CONTROL_PERSIST: &str = "10m";
+
+/// OpenSSH control socket shared by `stoker ssh --multiplex` sessions to VM `name`.
+pub fn control_socket_path(name: &str) -> String {
+    format!("{}/ssh-control.sock", firecracker::state_dir(name))
+}
+
+/// Asks the master connection behind VM `name`'s control socket to exit, if there is one.
+pub async fn close_control_master(name: &str) {
+    let socket = control_socket_path(name);
+    if Path::new(&socket).exists() {
+        let _ = Command::new("ssh")
+            .args(["-o", &format!("ControlPath={}", socket), "-O", "exit", "stoker"])
+            .stderr(std::process::Stdio::null())
+            .status()
+            .await;
+        let _ = std::fs::remove_file(&socket);
     }
-    
-    let meta_json = std::fs::read_to_string(&meta_path)?;
-    let meta: InstanceMetadata = serde_json::from_str(&meta_json)?;
+}
+
+pub async fn interactive_ssh(name: &str, agent_forward: bool, multiplex: bool, ssh_opts: &[String]) -> Result<()> {
+    // 1. We must find the IP mapping from the state JSON
+    let meta = firecracker::load_metadata(name)?;
     let guest_ip = meta.guest_ip;
 
     let key_path = assets::get_asset_path("ubuntu-24.04.id_rsa");
 
     if !std::path::Path::new(&key_path).exists() {
-        anyhow::bail!("SSH Key not found at {}. Is the VM provisioned?", key_path);
+        anyhow::bail!(StokerError::new(ErrorKind::AssetMissing, format!("SSH Key not found at {}. Is the VM provisioned?", key_path)));
     }
 
-    // 2. We use native std::process::Command to take over the TTY natively.
+    // 2. We use the native ssh binary to take over the TTY natively.
     // SSH2 crate is designed for background programmatic execution without a PTY.
     // For a real `docker exec`-like interactive shell, chaining the native `ssh` binary is the cleanest TTY handoff in Rust.
     
     println!("Connecting to stoker-{m} at {ip}...", m=name, ip=guest_ip);
     
+    let control_path = if multiplex {
+        std::fs::create_dir_all(firecracker::state_dir(name))?;
+        Some(control_socket_path(name))
+    } else {
+        None
+    };
     let mut child = Command::new("ssh")
-        .arg("-i")
-        .arg(&key_path)
-        .arg("-o")
-        .arg("StrictHostKeyChecking=no")
-        .arg("-o")
-        .arg("UserKnownHostsFile=/dev/null")
-        .arg("-o")
-        .arg("LogLevel=ERROR")
-        .arg(format!("root@{}", guest_ip))
+        .args(ssh_args(&key_path, &guest_ip, agent_forward, control_path.as_deref(), ssh_opts))
         .spawn()
         .context("Failed to spawn interactive SSH session")?;
         
-    let status = child.wait()?;
+    let status = child.wait().await?;
     if !status.success() {
-        std::process::exit(status.code().unwrap_or(1));
+        // Passing the code through could pass for one of stoker's own exit codes
+        anyhow::bail!(StokerError::new(ErrorKind::Ssh, format!("SSH session to VM '{}' ended with {}", name, status)));
+    }
+    Ok(())
+}
+
+/// Arguments for the `ssh` behind `stoker ssh`. Options from `--ssh-opt` come first, since ssh
+/// keeps the first value it sees, so they can override the defaults. With `control_path`, the
+/// first connection becomes a master that later ones multiplex over, skipping the handshake.
+fn ssh_args(key_path: &str, guest_ip: &str, agent_forward: bool, control_path: Option<&str>, ssh_opts: &[String]) -> Vec<String> {
+    let mut args = Vec::new();
+    for opt in ssh_opts {
+        args.push("-o".to_string());
+        args.push(opt.clone());
+    }
+    if agent_forward {
+        args.push("-A".to_string());
+    }
+    if let Some(control_path) = control_path {
+        for opt in ["ControlMaster=auto".to_string(), format!("ControlPath={}", control_path), format!("ControlPersist={}", CONTROL_PERSIST)] {
+            args.push("-o".to_string());
+            args.push(opt);
+        }
+    }
+    args.extend(["-i", key_path, "-o", "StrictHostKeyChecking=no", "-o", "UserKnownHostsFile=/dev/null", "-o", "LogLevel=ERROR"].map(String::from));
+    args.push(format!("root@{}", guest_ip));
+    args
+}
+
+/// Switches root login shells to the `stoker.run_as_uid`/`stoker.run_as_gid` kernel parameters.
+const USER_PROFILE_SCRIPT: &str = r#"# Installed by `stoker run --user`
+uid=$(sed -n 's/.*stoker\.run_as_uid=\([^ ]*\).*/\1/p' /proc/cmdline)
+gid=$(sed -n 's/.*stoker\.run_as_gid=\([^ ]*\).*/\1/p' /proc/cmdline)
+if [ -n "$uid" ] && [ "$(id -u)" = 0 ]; then
+    user=$(getent passwd "$uid" | cut -d: -f1)
+    if [ -n "$gid" ]; then
+        group=$(getent group "$gid" | cut -d: -f1)
+        exec su -s /bin/sh -g "${group:-$gid}" "${user:-$uid}"
+    fi
+    exec su -s /bin/sh "${user:-$uid}"
+fi
+"#;
+
+/// Writes the runtime network setup to disk so the guest re-applies it on reboot: a
+/// systemd-networkd unit when networkd is enabled, otherwise an ifupdown drop-in. resolv.conf
+/// becomes a plain file so nothing regenerates it.
+fn persistent_network_script(guest_ip: &str, host_ip: &str, prefix_len: u8) -> String {
+    format!(
+        r#"if [ -d /etc/systemd/network ] && systemctl is-enabled systemd-networkd >/dev/null 2>&1; then
+cat > /etc/systemd/network/10-stoker-eth0.network <<'EOF'
+[Match]
+Name=eth0
+
+[Network]
+Address={ip}/{prefix}
+Gateway={gw}
+DNS=8.8.8.8
+EOF
+else
+mkdir -p /etc/network/interfaces.d
+cat > /etc/network/interfaces.d/stoker-eth0 <<'EOF'
+auto eth0
+iface eth0 inet static
+    address {ip}/{prefix}
+    gateway {gw}
+EOF
+fi
+rm -f /etc/resolv.conf && echo 'nameserver 8.8.8.8' > /etc/resolv.conf"#,
+        ip = guest_ip,
+        gw = host_ip,
+        prefix = prefix_len
+    )
+}
+
+/// Mounts `mount` in the guest, and with `persist` also adds it to /etc/fstab (once) so it
+/// comes back after a guest reboot.
+fn tmpfs_mount_command(mount: &TmpfsMount, persist: bool) -> String {
+    let options = match mount.size_mib {
+        Some(mib) => format!("size={}m", mib),
+        None => "defaults".to_string(),
+    };
+    let mut cmd = format!("mkdir -p {p} && mount -t tmpfs -o {o} tmpfs {p}", p = mount.path, o = options);
+    if persist {
+        cmd.push_str(&format!(
+            " && (grep -q '^tmpfs {p} ' /etc/fstab || echo 'tmpfs {p} tmpfs {o} 0 0' >> /etc/fstab)",
+            p = mount.path,
+            o = options
+        ));
+    }
+    cmd
+}
+
+/// Applies `key=value` sysctls with `sysctl -w` and persists them in /etc/sysctl.d so they
+/// survive a guest reboot. Values were validated to be free of quotes and newlines.
+fn sysctl_command(sysctls: &[String]) -> String {
+    let apply: Vec<String> = sysctls.iter().map(|setting| format!("sysctl -w '{}'", setting)).collect();
+    format!(
+        "{} && cat > /etc/sysctl.d/99-stoker.conf <<'EOF'\n# Installed by `stoker run --sysctl`\n{}\nEOF",
+        apply.join(" && "),
+        sysctls.join("\n")
+    )
+}
+
+/// Marks the /etc/hosts lines added for `--add-host`, so reapplying them replaces the old ones.
+const ADD_HOST_MARKER: &str = "# stoker --add-host";
+
+/// Replaces the guest's `--add-host` lines in /etc/hosts with `extra_hosts`, resolving
+/// `host-gateway` to `host_ip`. Names and addresses were validated to be shell-safe.
+fn hosts_command(extra_hosts: &[String], host_ip: &str) -> String {
+    let lines: Vec<String> = extra_hosts.iter()
+        .filter_map(|entry| entry.split_once(':'))
+        .map(|(name, ip)| {
+            let ip = if ip == firecracker::HOST_GATEWAY { host_ip } else { ip };
+            format!("{}\t{} {}", ip, name, ADD_HOST_MARKER)
+        })
+        .collect();
+    format!(
+        "sed -i '/ {}$/d' /etc/hosts && cat >> /etc/hosts <<'EOF'\n{}\nEOF",
+        ADD_HOST_MARKER,
+        lines.join("\n")
+    )
+}
+
+/// Changes login shells into the `stoker.workdir` kernel parameter and applies the
+/// `stoker.ulimit.<type>=<soft>:<hard>` limits. Sorts before stoker-user.sh so both carry over
+/// when switching users. Setting the soft limit first, then hard and soft again, works whether
+/// the new limits are above or below the current ones.
+const ENV_PROFILE_SCRIPT: &str = r#"# Installed by `stoker run --working-dir/--ulimit`
+workdir=$(sed -n 's/.*stoker\.workdir=\([^ ]*\).*/\1/p' /proc/cmdline)
+if [ -n "$workdir" ]; then
+    cd "$workdir" || echo "stoker: working directory $workdir does not exist" >&2
+fi
+for param in $(cat /proc/cmdline); do
+    case "$param" in
+        stoker.ulimit.*=*) ;;
+        *) continue ;;
+    esac
+    setting=${param#stoker.ulimit.}
+    type=${setting%%=*}
+    limits=${setting#*=}
+    case "$type" in
+        nofile) flag=-n ;;
+        nproc) flag=-u ;;
+        core) flag=-c ;;
+        fsize) flag=-f ;;
+        *) continue ;;
+    esac
+    ulimit -S $flag "${limits%%:*}" 2>/dev/null
+    ulimit -H $flag "${limits#*:}" && ulimit -S $flag "${limits%%:*}" || echo "stoker: failed to apply ulimit $setting" >&2
+done
+"#;
+
+pub async fn setup_guest_network(meta: &InstanceMetadata) -> Result<()> {
+    let guest_ip = meta.guest_ip.as_str();
+    let host_ip = meta.host_ip.as_str();
+    let sess = open_session(guest_ip).await?;
+
+    println!("SSH connected! Applying nested IP routes...");
+
+    // Inject dynamic routing idempotently. A read-only root gets its resolv.conf bound over
+    // the image's from /run.
+    let resolv_conf = if meta.read_only_root.is_some() {
+        "echo 'nameserver 8.8.8.8' > /run/stoker-resolv.conf && mount --bind /run/stoker-resolv.conf /etc/resolv.conf"
+    } else {
+        "echo 'nameserver 8.8.8.8' > /etc/resolv.conf"
+    };
+    let cmds = format!(
+        "ip addr replace {}/{} dev eth0 && ip link set eth0 up && ip route replace default via {} && {}",
+        guest_ip, meta.prefix_len(), host_ip, resolv_conf
+    );
+    exec_command(&sess, &cmds).context("Guest IP configuration failed")?;
+    println!("Guest network configured via native SSH.");
+
+    if let Some(path) = &meta.data_mount {
+        // The mount point must already exist in the image unless it is under a writable mount
+        exec_command(&sess, &format!("mkdir -p {p} 2>/dev/null; mount /dev/vdb {p}", p = path))
+            .with_context(|| format!("Failed to mount the data drive at {}. Does {} exist in the image?", path, path))?;
+        println!("Mounted the data drive at {} in guest.", path);
+    }
+
+    if meta.persist_network {
+        exec_command(&sess, &persistent_network_script(guest_ip, host_ip, meta.prefix_len()))
+            .context("Failed to persist guest network configuration")?;
+        println!("Guest network configuration persisted for reboots.");
+    }
+
+    if let Some(size_mib) = meta.tmpfs_size_mib {
+        // Note: a guest systemd `tmp.mount` unit may later remount /tmp over this
+        exec_command(&sess, &format!("mount -t tmpfs -o size={}m tmpfs /tmp", size_mib))
+            .context("Failed to mount tmpfs at /tmp")?;
+        println!("Mounted {} MiB tmpfs at /tmp in guest.", size_mib);
+    }
+
+    for mount in &meta.tmpfs_mounts {
+        exec_command(&sess, &tmpfs_mount_command(mount, meta.persist_network))
+            .with_context(|| format!("Failed to mount tmpfs at {}", mount.path))?;
+        println!("Mounted tmpfs at {} in guest.", mount.path);
+    }
+
+    if meta.workdir.is_some() || !meta.ulimits.is_empty() {
+        exec_command(&sess, &format!("cat > /etc/profile.d/stoker-env.sh <<'EOF'\n{}EOF", ENV_PROFILE_SCRIPT))
+            .context("Failed to install /etc/profile.d/stoker-env.sh")?;
+        if let Some(dir) = &meta.workdir {
+            println!("Guest login shells will start in {}.", dir);
+        }
+        if !meta.ulimits.is_empty() {
+            println!("Guest login shells will apply ulimits {}.", meta.ulimits.join(", "));
+        }
+    }
+
+    if !meta.sysctls.is_empty() {
+        exec_command(&sess, &sysctl_command(&meta.sysctls)).context("Failed to apply --sysctl settings")?;
+        println!("Applied guest sysctls {}.", meta.sysctls.join(", "));
     }
+
+    if meta.run_as_uid.is_some() {
+        exec_command(&sess, &format!("cat > /etc/profile.d/stoker-user.sh <<'EOF'\n{}EOF", USER_PROFILE_SCRIPT))
+            .context("Failed to install /etc/profile.d/stoker-user.sh")?;
+        println!("Guest login shells will run as user {}.", meta.run_as_uid.as_deref().unwrap_or_default());
+    }
+
+    if !meta.extra_hosts.is_empty() {
+        exec_command(&sess, &hosts_command(&meta.extra_hosts, host_ip)).context("Failed to update /etc/hosts")?;
+        println!("Added {} to the guest's /etc/hosts.", meta.extra_hosts.join(", "));
+    }
+
+    if let Some(path) = &meta.seed_entropy {
+        seed_entropy(&sess, path)?;
+    }
+
     Ok(())
 }
 
-pub async fn setup_guest_network(guest_ip: &str, host_ip: &str, mode: &str) -> Result<()> {
+/// Bytes taken from a `--seed-entropy` device such as /dev/urandom, which never ends.
+const ENTROPY_DEVICE_BYTES: u64 = 512;
+
+/// Upper bound on what is sent from a regular `--seed-entropy` file.
+const ENTROPY_FILE_MAX_BYTES: u64 = 64 * 1024;
+
+/// Mixes the bytes of host file `path` into the guest's /dev/urandom, for kernels without a
+/// virtio-rng device that would otherwise start out short on entropy. Writing to /dev/urandom
+/// stirs the pool without crediting entropy, so it is safe to repeat with any input.
+fn seed_entropy(sess: &ssh2::Session, path: &str) -> Result<()> {
+    let file = std::fs::File::open(path).with_context(|| format!("Cannot read entropy seed {}", path))?;
+    let limit = if file.metadata()?.is_file() { ENTROPY_FILE_MAX_BYTES } else { ENTROPY_DEVICE_BYTES };
+    let mut seed = Vec::new();
+    std::io::Read::read_to_end(&mut std::io::Read::take(file, limit), &mut seed)
+        .with_context(|| format!("Cannot read entropy seed {}", path))?;
+
+    let mut channel = sess.channel_session()?;
+    channel.exec("dd if=/dev/stdin of=/dev/urandom 2>/dev/null")?;
+    std::io::Write::write_all(&mut channel, &seed)?;
+    channel.send_eof()?;
+    let mut err = String::new();
+    std::io::Read::read_to_string(&mut channel.stderr(), &mut err)?;
+    channel.wait_close()?;
+    if channel.exit_status()? != 0 {
+        anyhow::bail!("Failed to seed guest entropy: {}", err);
+    }
+    println!("Injected {} bytes of entropy from {} into the guest.", seed.len(), path);
+    Ok(())
+}
+
+/// Moves a guest resumed from a snapshot onto `meta`'s subnet. The guest still holds the
+/// snapshot source's address, so the host briefly takes the old gateway address on the new TAP
+/// to reach it, re-addresses it in the background, then reconnects at the new address.
+pub async fn reconcile_restored_network(meta: &InstanceMetadata, source: &InstanceMetadata) -> Result<()> {
+    let unchanged = meta.guest_ip == source.guest_ip && meta.host_ip == source.host_ip && meta.prefix_len() == source.prefix_len();
+    if !unchanged {
+        crate::network::add_restore_route(&meta.tap_device, &source.host_ip, &source.guest_ip).await?;
+        let moved = readdress_guest(meta, source).await;
+        crate::network::remove_restore_route(&meta.tap_device, &source.host_ip, &source.guest_ip).await;
+        moved.context("Failed to move the restored guest onto its new subnet")?;
+    }
+
+    let sess = open_session(&meta.guest_ip).await?;
+    if meta.persist_network {
+        exec_command(&sess, &persistent_network_script(&meta.guest_ip, &meta.host_ip, meta.prefix_len()))
+            .context("Failed to persist guest network configuration")?;
+    }
+    if !meta.extra_hosts.is_empty() {
+        exec_command(&sess, &hosts_command(&meta.extra_hosts, &meta.host_ip)).context("Failed to update /etc/hosts")?;
+    }
+    // Every guest resumed from a snapshot starts with the same random pool
+    if let Some(path) = &meta.seed_entropy {
+        seed_entropy(&sess, path)?;
+    }
+    println!("Restored guest reachable at {}.", meta.guest_ip);
+    Ok(())
+}
+
+async fn readdress_guest(meta: &InstanceMetadata, source: &InstanceMetadata) -> Result<()> {
+    let old_host: std::net::Ipv4Addr = source.host_ip.parse()?;
+    let target: std::net::SocketAddr = format!("{}:22", source.guest_ip).parse()?;
+    println!("Reaching restored guest at its snapshot address {}...", source.guest_ip);
+
+    // Bind to the old gateway address so the guest's replies route back through this TAP
+    let tcp = loop {
+        let socket = tokio::net::TcpSocket::new_v4()?;
+        socket.bind(std::net::SocketAddr::new(old_host.into(), 0))?;
+        match socket.connect(target).await {
+            Ok(stream) => break stream,
+            Err(_) => tokio::time::sleep(tokio::time::Duration::from_millis(200)).await,
+        }
+    };
+    let tcp = tcp.into_std()?;
+    tcp.set_nonblocking(false)?;
+    let sess = start_session(tcp)?;
+
+    // Detached and delayed, since flushing eth0 cuts this very connection
+    let cmd = format!(
+        "nohup sh -c 'sleep 1; ip addr flush dev eth0; ip addr add {}/{} dev eth0; ip link set eth0 up; ip route replace default via {}' >/dev/null 2>&1 &",
+        meta.guest_ip, meta.prefix_len(), meta.host_ip
+    );
+    exec_command(&sess, &cmd)?;
+    Ok(())
+}
+
+/// Waits for the guest's SSH port and returns an authenticated root session.
+async fn open_session(guest_ip: &str) -> Result<ssh2::Session> {
     println!("Waiting for SSH on {}...", guest_ip);
     
+    // Connect through tokio so a `run --timeout` can interrupt the wait
     let tcp = loop {
-        match std::net::TcpStream::connect(format!("{}:22", guest_ip)) {
+        match tokio::net::TcpStream::connect(format!("{}:22", guest_ip)).await {
             Ok(stream) => break stream,
             Err(_) => {
                 tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
             }
         }
     };
+    let tcp = tcp.into_std()?;
+    tcp.set_nonblocking(false)?;
     
+    start_session(tcp)
+}
+
+/// Performs the SSH handshake and root key authentication over an established connection.
+fn start_session(tcp: std::net::TcpStream) -> Result<ssh2::Session> {
     let mut sess = ssh2::Session::new()?;
     sess.set_tcp_stream(tcp);
     sess.handshake().context("SSH handshake failed")?;
@@ -66,17 +406,368 @@ pub async fn setup_guest_network(guest_ip: &str, host_ip: &str, mode: &str) -> R
     sess.userauth_pubkey_file("root", None, std::path::Path::new(&key_path), None)
         .context("SSH auth failed")?;
 
-    println!("SSH connected! Applying nested IP routes...");
+    Ok(sess)
+}
+
+/// Polls each of `ports` on the guest until all accept TCP connections, failing after `timeout`.
+pub async fn wait_for_ports(guest_ip: &str, ports: &[u16], timeout: std::time::Duration) -> Result<()> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    for port in ports {
+        let addr = format!("{}:{}", guest_ip, port);
+        println!("Waiting for {} to accept connections...", addr);
+        loop {
+            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+            if remaining.is_zero() {
+                anyhow::bail!("Timed out after {}s waiting for port {} on the guest (the VM is still running)", timeout.as_secs(), port);
+            }
+            let attempt = tokio::time::timeout(remaining, tokio::net::TcpStream::connect(&addr)).await;
+            if let Ok(Ok(_)) = attempt {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        }
+    }
+    println!("All requested ports are open");
+    Ok(())
+}
+
+/// Runs a healthcheck command in the guest, returning whether it exited 0 within `timeout`.
+pub fn run_health_check(guest_ip: &str, cmd: &str, timeout: std::time::Duration) -> Result<bool> {
+    let addr = format!("{}:22", guest_ip).parse()?;
+    let tcp = std::net::TcpStream::connect_timeout(&addr, timeout)?;
+    let sess = start_session(tcp)?;
+    sess.set_timeout(timeout.as_millis() as u32);
 
     let mut channel = sess.channel_session()?;
-    
-    // Inject dynamic routing idempotently
-    let cmds = format!(
-        "ip addr replace {}/30 dev eth0 && ip link set eth0 up && ip route replace default via {} && echo 'nameserver 8.8.8.8' > /etc/resolv.conf",
-        guest_ip, host_ip
+    channel.exec(cmd)?;
+    let mut sink = String::new();
+    std::io::Read::read_to_string(&mut channel, &mut sink)?;
+    channel.wait_close()?;
+    Ok(channel.exit_status()? == 0)
+}
+
+/// Recursively uploads `local_dir` to `remote_dir` in the guest. Directories are created over
+/// SFTP and files are streamed with SCP, keeping their permission bits. Symlinks are recreated
+/// as symlinks, or copied as what they point to with `follow_links`.
+pub fn scp_directory(sess: &ssh2::Session, local_dir: &Path, remote_dir: &str, follow_links: bool) -> Result<()> {
+    let mode = std::fs::metadata(local_dir)
+        .with_context(|| format!("Cannot read {}", local_dir.display()))?
+        .permissions().mode();
+    let (_, bytes) = local_tree_size(local_dir, follow_links, &mut Vec::new())?;
+    let copy = TreeCopy::new(sess, follow_links, bytes)?;
+    let result = copy.upload_tree(local_dir, remote_dir, mode);
+    copy.progress.finish();
+    result
+}
+
+/// Recursively downloads `remote_dir` from the guest into `local_dir`, listing directories
+/// over SFTP and fetching files with SCP. Symlinks are recreated as symlinks, or copied as
+/// what they point to with `follow_links`. Special files are skipped.
+pub fn scp_directory_from(sess: &ssh2::Session, remote_dir: &str, local_dir: &Path, follow_links: bool) -> Result<()> {
+    let sftp = sess.sftp().context("Failed to start SFTP subsystem")?;
+    let stat = sftp.stat(Path::new(remote_dir))
+        .with_context(|| format!("Cannot read {} in the guest", remote_dir))?;
+    let (_, bytes) = remote_tree_size(&sftp, Path::new(remote_dir), &stat, follow_links, &mut Vec::new())?;
+    let copy = TreeCopy::new(sess, follow_links, bytes)?;
+    let result = copy.download_tree(Path::new(remote_dir), local_dir, &stat);
+    copy.progress.finish();
+    result
+}
+
+/// Records `id` as a directory being copied, failing if one of the directories it is inside of
+/// already is `id`: a followed symlink leads back up the tree, which would be copied forever.
+fn enter_dir<T: PartialEq>(open_dirs: &mut Vec<T>, id: T, path: &Path) -> Result<()> {
+    if open_dirs.contains(&id) {
+        anyhow::bail!("Symlink loop: {} leads back to a directory containing it; copy without --follow-links", path.display());
+    }
+    open_dirs.push(id);
+    Ok(())
+}
+
+/// The canonical path of `path` in the guest, which identifies a directory there since SFTP
+/// doesn't report inode numbers.
+fn remote_dir_id(sftp: &ssh2::Sftp, path: &Path) -> PathBuf {
+    sftp.realpath(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Regular files and their total size in the host tree at `path`. `open_dirs` holds the
+/// (device, inode) of the directories it is inside of.
+fn local_tree_size(path: &Path, follow_links: bool, open_dirs: &mut Vec<(u64, u64)>) -> Result<(u64, u64)> {
+    let metadata = if follow_links { std::fs::metadata(path) } else { std::fs::symlink_metadata(path) };
+    match metadata {
+        Ok(metadata) if metadata.is_dir() => {
+            enter_dir(open_dirs, (metadata.dev(), metadata.ino()), path)?;
+            let mut total = (0, 0);
+            for entry in std::fs::read_dir(path).into_iter().flatten().flatten() {
+                let (files, bytes) = local_tree_size(&entry.path(), follow_links, open_dirs)?;
+                total = (total.0 + files, total.1 + bytes);
+            }
+            open_dirs.pop();
+            Ok(total)
+        }
+        Ok(metadata) if metadata.is_file() => Ok((1, metadata.len())),
+        _ => Ok((0, 0)),
+    }
+}
+
+/// Regular files and their total size in the guest tree at `path`, whose lstat is `stat`.
+/// `open_dirs` holds the canonical paths of the directories it is inside of.
+fn remote_tree_size(sftp: &ssh2::Sftp, path: &Path, stat: &ssh2::FileStat, follow_links: bool, open_dirs: &mut Vec<PathBuf>) -> Result<(u64, u64)> {
+    let resolved;
+    let stat = if follow_links && stat.file_type().is_symlink() {
+        match sftp.stat(path) {
+            Ok(target) => { resolved = target; &resolved }
+            Err(_) => return Ok((0, 0)),
+        }
+    } else {
+        stat
+    };
+    if stat.is_dir() {
+        enter_dir(open_dirs, remote_dir_id(sftp, path), path)?;
+        let mut total = (0, 0);
+        for (entry, stat) in sftp.readdir(path).into_iter().flatten() {
+            let (files, bytes) = remote_tree_size(sftp, &entry, &stat, follow_links, open_dirs)?;
+            total = (total.0 + files, total.1 + bytes);
+        }
+        open_dirs.pop();
+        Ok(total)
+    } else if stat.is_file() {
+        Ok((1, stat.size.unwrap_or(0)))
+    } else {
+        Ok((0, 0))
+    }
+}
+
+/// A recursive copy over one SSH session, with a progress bar across all of its files.
+struct TreeCopy<'a> {
+    sess: &'a ssh2::Session,
+    sftp: ssh2::Sftp,
+    follow_links: bool,
+    progress: TransferProgress,
+    /// (device, inode) of the host directories being uploaded, outermost first
+    local_dirs: RefCell<Vec<(u64, u64)>>,
+    /// Canonical paths of the guest directories being downloaded, outermost first
+    remote_dirs: RefCell<Vec<PathBuf>>,
+}
+
+impl<'a> TreeCopy<'a> {
+    fn new(sess: &'a ssh2::Session, follow_links: bool, total_bytes: u64) -> Result<Self> {
+        let sftp = sess.sftp().context("Failed to start SFTP subsystem")?;
+        Ok(TreeCopy {
+            sess,
+            sftp,
+            follow_links,
+            progress: TransferProgress::new(total_bytes),
+            local_dirs: RefCell::new(Vec::new()),
+            remote_dirs: RefCell::new(Vec::new()),
+        })
+    }
+
+    /// Copies `reader` to `writer` in chunks, advancing the progress bar as it goes.
+    fn stream(&self, reader: &mut impl std::io::Read, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            writer.write_all(&buf[..n])?;
+            self.progress.add(n as u64);
+        }
+    }
+
+    fn upload_tree(&self, local_dir: &Path, remote_dir: &str, mode: u32) -> Result<()> {
+        let metadata = std::fs::metadata(local_dir)
+            .with_context(|| format!("Cannot read {}", local_dir.display()))?;
+        enter_dir(&mut self.local_dirs.borrow_mut(), (metadata.dev(), metadata.ino()), local_dir)?;
+        let result = self.upload_dir_entries(local_dir, remote_dir, mode);
+        self.local_dirs.borrow_mut().pop();
+        result
+    }
+
+    fn upload_dir_entries(&self, local_dir: &Path, remote_dir: &str, mode: u32) -> Result<()> {
+        if self.sftp.stat(Path::new(remote_dir)).is_err() {
+            self.sftp.mkdir(Path::new(remote_dir), (mode & 0o7777) as i32)
+                .with_context(|| format!("Failed to create {} in the guest", remote_dir))?;
+        }
+
+        let mut entries: Vec<_> = std::fs::read_dir(local_dir)
+            .with_context(|| format!("Cannot read {}", local_dir.display()))?
+            .collect::<std::io::Result<_>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let remote_path = format!("{}/{}", remote_dir.trim_end_matches('/'), entry.file_name().to_string_lossy());
+            self.upload_entry(&entry.path(), &remote_path)?;
+        }
+        Ok(())
+    }
+
+    /// Uploads one file, directory or symlink at `local_path` to `remote_path`.
+    fn upload_entry(&self, local_path: &Path, remote_path: &str) -> Result<()> {
+        let metadata = if self.follow_links { std::fs::metadata(local_path) } else { std::fs::symlink_metadata(local_path) }
+            .with_context(|| format!("Cannot read {}", local_path.display()))?;
+        let mode = metadata.permissions().mode();
+        if metadata.is_dir() {
+            self.upload_tree(local_path, remote_path, mode)?;
+        } else if metadata.is_file() {
+            let mut file = std::fs::File::open(local_path)?;
+            let mut channel = self.sess.scp_send(Path::new(remote_path), (mode & 0o777) as i32, metadata.len(), None)
+                .with_context(|| format!("Failed to start upload of {}", remote_path))?;
+            self.stream(&mut file, &mut channel)
+                .with_context(|| format!("Failed to upload {}", local_path.display()))?;
+            channel.send_eof()?;
+            channel.wait_eof()?;
+            channel.close()?;
+            channel.wait_close()?;
+        } else if metadata.file_type().is_symlink() {
+            let target = std::fs::read_link(local_path)?;
+            let _ = self.sftp.unlink(Path::new(remote_path));
+            // ssh2 takes the link's target first and the path of the new link second
+            self.sftp.symlink(&target, Path::new(remote_path))
+                .with_context(|| format!("Failed to create symlink {} in the guest", remote_path))?;
+        } else {
+            println!("Skipping {} (not a regular file, directory or symlink)", local_path.display());
+        }
+        Ok(())
+    }
+
+    fn download_tree(&self, remote_dir: &Path, local_dir: &Path, stat: &ssh2::FileStat) -> Result<()> {
+        enter_dir(&mut self.remote_dirs.borrow_mut(), remote_dir_id(&self.sftp, remote_dir), remote_dir)?;
+        let result = self.download_dir_entries(remote_dir, local_dir, stat);
+        self.remote_dirs.borrow_mut().pop();
+        result
+    }
+
+    fn download_dir_entries(&self, remote_dir: &Path, local_dir: &Path, stat: &ssh2::FileStat) -> Result<()> {
+        std::fs::create_dir_all(local_dir)
+            .with_context(|| format!("Failed to create {}", local_dir.display()))?;
+
+        let mut entries = self.sftp.readdir(remote_dir)
+            .with_context(|| format!("Cannot list {} in the guest", remote_dir.display()))?;
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (remote_path, stat) in entries {
+            let file_name = match remote_path.file_name() {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+            self.download_entry(&remote_path, &local_dir.join(&file_name), stat)?;
+        }
+        // Set last, so a read-only directory can still be filled
+        if let Some(perm) = stat.perm {
+            std::fs::set_permissions(local_dir, std::fs::Permissions::from_mode(perm & 0o7777))?;
+        }
+        Ok(())
+    }
+
+    /// Downloads one file, directory or symlink at `remote_path`, whose lstat is `stat`.
+    fn download_entry(&self, remote_path: &Path, local_path: &Path, stat: ssh2::FileStat) -> Result<()> {
+        let stat = if self.follow_links && stat.file_type().is_symlink() {
+            self.sftp.stat(remote_path)
+                .with_context(|| format!("Cannot follow symlink {} in the guest", remote_path.display()))?
+        } else {
+            stat
+        };
+        if stat.is_dir() {
+            self.download_tree(remote_path, local_path, &stat)?;
+        } else if stat.is_file() {
+            let (mut channel, scp_stat) = self.sess.scp_recv(remote_path)
+                .with_context(|| format!("Failed to start download of {}", remote_path.display()))?;
+            let mut file = std::fs::File::create(local_path)
+                .with_context(|| format!("Failed to create {}", local_path.display()))?;
+            self.stream(&mut channel, &mut file)
+                .with_context(|| format!("Failed to download {}", remote_path.display()))?;
+            channel.send_eof()?;
+            channel.wait_eof()?;
+            channel.close()?;
+            channel.wait_close()?;
+            file.set_permissions(std::fs::Permissions::from_mode((scp_stat.mode() as u32) & 0o777))?;
+        } else if stat.file_type().is_symlink() {
+            let target = self.sftp.readlink(remote_path)
+                .with_context(|| format!("Cannot read symlink {} in the guest", remote_path.display()))?;
+            let _ = std::fs::remove_file(local_path);
+            std::os::unix::fs::symlink(&target, local_path)
+                .with_context(|| format!("Failed to create symlink {}", local_path.display()))?;
+        } else {
+            println!("Skipping {} (not a regular file, directory or symlink)", remote_path.display());
+        }
+        Ok(())
+    }
+}
+
+/// Splits a `stoker cp` operand into the VM name and path for `<vm>:<path>`, or None and the
+/// path for a host path. Anything with a `/` before its first `:` is a host path.
+pub fn parse_cp_operand(operand: &str) -> (Option<&str>, &str) {
+    match operand.split_once(':') {
+        Some((vm, path)) if !vm.is_empty() && !vm.contains('/') => (Some(vm), path),
+        _ => (None, operand),
+    }
+}
+
+/// Where copying a source named `file_name` to `dest` puts it: inside `dest` when that is an
+/// existing directory, as with cp(1), otherwise at `dest` itself.
+fn copy_destination(dest: &str, dest_is_dir: bool, file_name: &str) -> String {
+    if dest_is_dir {
+        format!("{}/{}", dest.trim_end_matches('/'), file_name)
+    } else {
+        dest.to_string()
+    }
+}
+
+/// Copies a file or directory tree between the host and a VM, as `stoker cp`. Exactly one of
+/// `src` and `dest` must be a `<vm>:<path>` operand. Files are streamed one at a time.
+pub async fn copy(src: &str, dest: &str, follow_links: bool) -> Result<()> {
+    let (vm, upload) = match (parse_cp_operand(src), parse_cp_operand(dest)) {
+        ((None, _), (Some(vm), _)) => (vm, true),
+        ((Some(vm), _), (None, _)) => (vm, false),
+        ((Some(_), _), (Some(_), _)) => anyhow::bail!("Copying between two VMs is not supported; copy through the host"),
+        ((None, _), (None, _)) => anyhow::bail!("One of the paths must be in a VM, as <vm>:<path>"),
+    };
+    let (src_path, dest_path) = (parse_cp_operand(src).1, parse_cp_operand(dest).1);
+    let meta = firecracker::load_metadata(vm)?;
+    let sess = open_session(&meta.guest_ip).await?;
+    let sftp = sess.sftp().context("Failed to start SFTP subsystem")?;
+
+    let file_name = Path::new(src_path).file_name()
+        .with_context(|| format!("Cannot copy '{}': it has no file name", src_path))?
+        .to_string_lossy()
+        .to_string();
+    let (files, result, source, target) = if upload {
+        let dest_is_dir = sftp.stat(Path::new(dest_path)).is_ok_and(|stat| stat.is_dir());
+        let target = copy_destination(dest_path, dest_is_dir, &file_name);
+        let (files, bytes) = local_tree_size(Path::new(src_path), follow_links, &mut Vec::new())?;
+        let copy = TreeCopy::new(&sess, follow_links, bytes)?;
+        let result = copy.upload_entry(Path::new(src_path), &target).map(|_| copy);
+        (files, result, src_path.to_string(), format!("{}:{}", vm, target))
+    } else {
+        let stat = sftp.lstat(Path::new(src_path))
+            .with_context(|| StokerError::new(ErrorKind::NotFound, format!("{} not found in VM '{}'", src_path, vm)))?;
+        let target = copy_destination(dest_path, Path::new(dest_path).is_dir(), &file_name);
+        let (files, bytes) = remote_tree_size(&sftp, Path::new(src_path), &stat, follow_links, &mut Vec::new())?;
+        let copy = TreeCopy::new(&sess, follow_links, bytes)?;
+        let result = copy.download_entry(Path::new(src_path), Path::new(&target), stat).map(|_| copy);
+        (files, result, format!("{}:{}", vm, src_path), target)
+    };
+    let copy = result?;
+    copy.progress.finish();
+    println!(
+        "Copied {} ({} file{}, {:.1} MiB) to {} in {:.1}s ({})",
+        source,
+        files,
+        if files == 1 { "" } else { "s" },
+        copy.progress.sent() as f64 / 1_048_576.0,
+        target,
+        copy.progress.elapsed().as_secs_f64(),
+        crate::progress::format_rate(copy.progress.sent(), copy.progress.elapsed())
     );
-    
-    channel.exec(&cmds)?;
+    Ok(())
+}
+
+/// Runs `cmd` in the guest, returning its stdout or an error carrying stdout/stderr on failure.
+fn exec_command(sess: &ssh2::Session, cmd: &str) -> Result<String> {
+    let mut channel = sess.channel_session()?;
+    channel.exec(cmd)?;
     
     let mut s = String::new();
     let mut err = String::new();
@@ -85,9 +776,86 @@ pub async fn setup_guest_network(guest_ip: &str, host_ip: &str, mode: &str) -> R
     channel.wait_close()?;
     
     if channel.exit_status()? != 0 {
-        anyhow::bail!("Guest IP configuration failed: stdout: {}, stderr: {}", s, err);
+        anyhow::bail!("Guest command failed: stdout: {}, stderr: {}", s, err);
+    }
+    Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cp_operand() {
+        assert_eq!(parse_cp_operand("web:/srv/app"), (Some("web"), "/srv/app"));
+        assert_eq!(parse_cp_operand("./build"), (None, "./build"));
+        assert_eq!(parse_cp_operand("./a:b"), (None, "./a:b"));
+        assert_eq!(parse_cp_operand(":/srv"), (None, ":/srv"));
+    }
+
+    #[test]
+    fn test_hosts_command() {
+        let hosts = vec!["db.internal:172.16.0.6".to_string(), "host.internal:host-gateway".to_string()];
+        let cmd = hosts_command(&hosts, "172.16.0.1");
+        assert!(cmd.starts_with("sed -i '/ # stoker --add-host$/d' /etc/hosts && "));
+        assert!(cmd.contains("\n172.16.0.6\tdb.internal # stoker --add-host\n"));
+        assert!(cmd.contains("\n172.16.0.1\thost.internal # stoker --add-host\n"));
+    }
+
+    #[test]
+    fn test_ssh_args() {
+        let args = ssh_args("/assets/id_rsa", "172.16.0.2", false, None, &[]);
+        assert_eq!(args.first().map(String::as_str), Some("-i"));
+        assert_eq!(args.last().map(String::as_str), Some("root@172.16.0.2"));
+        assert!(!args.contains(&"-A".to_string()));
+
+        let args = ssh_args("/assets/id_rsa", "172.16.0.2", true, None, &["ProxyJump=bastion".to_string()]);
+        assert_eq!(&args[..3], ["-o", "ProxyJump=bastion", "-A"]);
+
+        // A user's ControlPersist wins over the default
+        let args = ssh_args("/assets/id_rsa", "172.16.0.2", false, Some("/tmp/stoker-web/ssh-control.sock"), &["ControlPersist=60".to_string()]);
+        assert_eq!(&args[..6], ["-o", "ControlPersist=60", "-o", "ControlMaster=auto", "-o", "ControlPath=/tmp/stoker-web/ssh-control.sock"]);
+        assert_eq!(control_socket_path("web"), format!("{}/ssh-control.sock", firecracker::state_dir("web")));
+    }
+
+    #[test]
+    fn test_copy_destination() {
+        assert_eq!(copy_destination("/srv/", true, "app"), "/srv/app");
+        assert_eq!(copy_destination("/srv/app2", false, "app"), "/srv/app2");
+    }
+
+    #[test]
+    fn test_local_tree_size() {
+        let root = std::env::temp_dir().join(format!("stoker_test_tree_size_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("a"), b"hello").unwrap();
+        std::fs::write(root.join("sub/b"), vec![0u8; 1000]).unwrap();
+        std::os::unix::fs::symlink(root.join("sub/b"), root.join("link")).unwrap();
+
+        assert_eq!(local_tree_size(&root, false, &mut Vec::new()).unwrap(), (2, 1005));
+        // Following links counts what they point to
+        assert_eq!(local_tree_size(&root, true, &mut Vec::new()).unwrap(), (3, 2005));
+        assert_eq!(local_tree_size(&root.join("a"), false, &mut Vec::new()).unwrap(), (1, 5));
+        assert_eq!(local_tree_size(&root.join("missing"), false, &mut Vec::new()).unwrap(), (0, 0));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_local_tree_size_symlink_loop() {
+        let root = std::env::temp_dir().join(format!("stoker_test_tree_loop_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("a/shared")).unwrap();
+        std::fs::write(root.join("a/shared/file"), "12345").unwrap();
+        // Two links to the same directory aren't a loop
+        std::os::unix::fs::symlink("shared", root.join("a/again")).unwrap();
+        assert_eq!(local_tree_size(&root, true, &mut Vec::new()).unwrap(), (2, 10));
+
+        std::os::unix::fs::symlink("..", root.join("a/shared/up")).unwrap();
+        assert_eq!(local_tree_size(&root, false, &mut Vec::new()).unwrap(), (1, 5));
+        let err = local_tree_size(&root, true, &mut Vec::new()).unwrap_err().to_string();
+        assert!(err.contains("Symlink loop"), "{}", err);
+
+        let _ = std::fs::remove_dir_all(&root);
     }
-    
-    println!("Guest network configured via native SSH.");
-    Ok(())
 }
@@ -0,0 +1,60 @@
+//! Embeddable API for managing Firecracker microVMs. The `stoker` binary is a thin CLI over
+//! these modules; other tools can call them directly instead of shelling out.
+
+#[cfg(target_os = "linux")]
+pub mod network;
+#[cfg(target_os = "linux")]
+pub mod firecracker;
+#[cfg(target_os = "linux")]
+pub mod guest;
+#[cfg(target_os = "linux")]
+pub mod assets;
+#[cfg(target_os = "linux")]
+pub mod builder;
+#[cfg(target_os = "linux")]
+pub mod cgroup;
+#[cfg(target_os = "linux")]
+pub mod events;
+#[cfg(target_os = "linux")]
+pub mod diagnose;
+#[cfg(target_os = "linux")]
+pub mod metrics;
+#[cfg(target_os = "linux")]
+pub mod progress;
+#[cfg(target_os = "linux")]
+pub mod security;
+#[cfg(target_os = "linux")]
+pub mod diff;
+#[cfg(target_os = "linux")]
+pub mod stokerfile;
+#[cfg(target_os = "linux")]
+pub mod signing;
+#[cfg(target_os = "linux")]
+pub mod push;
+#[cfg(target_os = "linux")]
+pub mod errors;
+#[cfg(target_os = "linux")]
+pub mod logs;
+#[cfg(target_os = "linux")]
+pub mod completions;
+
+#[cfg(target_os = "linux")]
+pub use firecracker::{HealthCheck, InstanceMetadata, RunConfig};
+
+/// Boots a VM as described by `config`, returning its metadata once it is running.
+#[cfg(target_os = "linux")]
+pub async fn run_vm(config: RunConfig) -> anyhow::Result<InstanceMetadata> {
+    firecracker::run_vm(config).await
+}
+
+/// Lists the VMs stoker is currently tracking.
+#[cfg(target_os = "linux")]
+pub fn list() -> Vec<InstanceMetadata> {
+    firecracker::list_vms()
+}
+
+/// Stops a VM and releases its TAP, socket, rootfs and metadata, like `stoker rm`.
+#[cfg(target_os = "linux")]
+pub async fn remove(name: &str) -> anyhow::Result<()> {
+    firecracker::rm_vm(name, firecracker::DEFAULT_STOP_TIMEOUT, false).await
+}
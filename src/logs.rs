@@ -0,0 +1,128 @@
+use anyhow::{Context, Result};
+use std::collections::VecDeque;
+use std::io::BufRead;
+use std::time::Duration;
+
+use crate::firecracker;
+
+// Firecracker's own log, configured through /logger at boot. Every entry starts with the
+// daemon's local time, e.g.
+//
+//     2024-05-02T14:03:11.482913137 [anonymous-instance:main:INFO:src/firecracker/src/main.rs:94] Running Firecracker v1.10.1
+//
+// Entries can span several lines; continuation lines have no timestamp and belong to the entry
+// before them.
+
+fn log_path(name: &str) -> String {
+    format!("/tmp/firecracker-{}.log", name)
+}
+
+/// Parses a `--since` duration such as `90s`, `5m`, `2h` or `1d`. A bare number is seconds.
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "s"),
+    };
+    let invalid = || format!("Invalid duration '{}'. Use e.g. 30s, 5m, 2h or 1d.", value);
+    let number: u64 = number.parse().with_context(invalid)?;
+    let unit_secs: u64 = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => anyhow::bail!("Invalid duration unit in '{}'. Use s, m, h or d.", value),
+    };
+    Ok(Duration::from_secs(number.checked_mul(unit_secs).with_context(invalid)?))
+}
+
+/// Unix time of the timestamp a Firecracker log entry starts with, read as local time like
+/// Firecracker writes it. None for continuation lines.
+fn parse_log_timestamp(line: &str) -> Option<libc::time_t> {
+    let stamp = line.get(..19)?;
+    let bytes = stamp.as_bytes();
+    if !stamp.is_ascii() || bytes[4] != b'-' || bytes[7] != b'-' || bytes[10] != b'T' || bytes[13] != b':' || bytes[16] != b':' {
+        return None;
+    }
+    let field = |range: std::ops::Range<usize>| stamp[range].parse::<libc::c_int>().ok();
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    tm.tm_year = field(0..4)? - 1900;
+    tm.tm_mon = field(5..7)? - 1;
+    tm.tm_mday = field(8..10)?;
+    tm.tm_hour = field(11..13)?;
+    tm.tm_min = field(14..16)?;
+    tm.tm_sec = field(17..19)?;
+    // Let mktime work out whether daylight saving time applies
+    tm.tm_isdst = -1;
+    match unsafe { libc::mktime(&mut tm) } {
+        -1 => None,
+        time => Some(time),
+    }
+}
+
+/// The log lines from entries at or after `since` (Unix time), limited to the last `tail`.
+fn filter_lines(lines: impl Iterator<Item = String>, since: Option<libc::time_t>, tail: Option<usize>) -> Vec<String> {
+    let mut kept = VecDeque::new();
+    let mut include = since.is_none();
+    for line in lines {
+        if let (Some(since), Some(time)) = (since, parse_log_timestamp(&line)) {
+            include = time >= since;
+        }
+        if !include {
+            continue;
+        }
+        kept.push_back(line);
+        if tail.is_some_and(|tail| kept.len() > tail) {
+            kept.pop_front();
+        }
+    }
+    kept.into()
+}
+
+/// Prints VM `name`'s Firecracker log, optionally only the entries from the last `since` and
+/// only the last `tail` lines of those.
+pub fn show(name: &str, tail: Option<usize>, since: Option<Duration>) -> Result<()> {
+    firecracker::load_metadata(name)?;
+    let path = log_path(name);
+    let file = std::fs::File::open(&path).with_context(|| format!("Failed to open {}", path))?;
+    let since = since.map(|since| firecracker::unix_now().saturating_sub(since.as_secs()) as libc::time_t);
+    let lines = std::io::BufReader::new(file).lines().map_while(Result::ok);
+    for line in filter_lines(lines, since, tail) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+        assert_eq!(parse_duration("45").unwrap(), Duration::from_secs(45));
+        assert!(parse_duration("5w").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("999999999999999999d").is_err());
+    }
+
+    #[test]
+    fn test_filter_lines() {
+        let log = "2024-05-02T14:00:00.000000001 [fc:main:INFO] Running Firecracker v1.10.1\n\
+                   2024-05-02T14:05:00.000000001 [fc:main:ERROR] Failed to start\n\
+                   backtrace line\n\
+                   2024-05-02T14:06:30.500000000 [fc:vcpu:INFO] Received KVM_EXIT_SHUTDOWN";
+        let lines = || log.lines().map(str::to_string);
+        let first = parse_log_timestamp(log).unwrap();
+        assert_eq!(parse_log_timestamp(log.lines().nth(3).unwrap()), Some(first + 390));
+        assert_eq!(parse_log_timestamp("backtrace line"), None);
+
+        // Continuation lines stay with their entry
+        let recent = filter_lines(lines(), Some(first + 300), None);
+        assert_eq!(recent.len(), 3);
+        assert_eq!(recent[1], "backtrace line");
+        assert_eq!(filter_lines(lines(), None, Some(2)), vec!["backtrace line", log.lines().nth(3).unwrap()]);
+        assert_eq!(filter_lines(lines(), Some(first + 1000), None).len(), 0);
+        assert_eq!(filter_lines(lines(), None, None).len(), 4);
+    }
+}
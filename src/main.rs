@@ -1,29 +1,41 @@
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
 use anyhow::Result;
 
 #[cfg(target_os = "linux")]
-mod network;
-#[cfg(target_os = "linux")]
-mod firecracker;
-#[cfg(target_os = "linux")]
-mod guest;
-#[cfg(target_os = "linux")]
-mod assets;
+use stoker::{assets, builder, completions, diagnose, diff, events, firecracker, guest, logs, metrics, push, stokerfile};
+
 #[cfg(target_os = "linux")]
-mod builder;
+use stoker::errors::EXIT_CODES_HELP;
+#[cfg(not(target_os = "linux"))]
+const EXIT_CODES_HELP: &str = "";
 
 #[derive(Parser, Debug)]
 #[command(name = "stoker")]
 #[command(about = "A docker-like CLI for managing Firecracker microVMs natively in Rust", long_about = None)]
+#[command(version, after_help = EXIT_CODES_HELP)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Directory holding images, the kernel and SSH keys (default: $STOKER_ASSET_DIR or the built-in path). May be a read-only NFS mount
+    #[arg(long, global = true)]
+    image_dir: Option<String>,
+
+    /// Directory for everything stoker writes, such as built and pulled images and VM disks (default: $STOKER_OUTPUT_DIR or --image-dir). Images here take precedence over those in --image-dir
+    #[arg(long, global = true)]
+    output_dir: Option<String>,
 }
 
+// Parsed once per invocation, so Run's many flags making it the largest variant is harmless
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Downloads necessary kernel, rootfs, and ssh keys
-    DownloadAssets,
+    DownloadAssets {
+        /// Firecracker release to download
+        #[arg(long, default_value = "v1.10.1")]
+        firecracker_version: String,
+    },
     /// Starts a microVM instance
     Run {
         /// Mode of network (internet or local)
@@ -35,6 +47,175 @@ enum Commands {
         /// Target image name to boot (default: ubuntu-rootfs)
         #[arg(long)]
         image: Option<String>,
+        /// Keep the VM in the foreground and remove it on Ctrl-C
+        #[arg(long)]
+        attach: bool,
+        /// Host interface used for outbound NAT (default: auto-detected from the default route)
+        #[arg(long, conflicts_with = "cni_plugin")]
+        egress: Option<String>,
+        /// Only let the guest reach this destination through the host, as <cidr>[:<port>]
+        /// (repeatable). Everything else it sends out is dropped, so allow a DNS server too
+        #[arg(long, conflicts_with = "cni_plugin")]
+        allow: Vec<String>,
+        /// Block the guest from reaching this CIDR through the host (repeatable)
+        #[arg(long, conflicts_with = "cni_plugin")]
+        deny: Vec<String>,
+        /// Network the VM through the CNI plugin /opt/cni/bin/<name>, configured by the file in
+        /// /etc/cni/net.d with that "type" (or a .conflist whose chain starts with it), instead
+        /// of a NATed TAP on 172.16.0.0/16
+        #[arg(long, conflicts_with_all = ["ip", "host_ip"])]
+        cni_plugin: Option<String>,
+        /// Maximum number of processes the Firecracker daemon may create
+        #[arg(long)]
+        pid_limit: Option<u32>,
+        /// Host-side memory limit for the Firecracker daemon in MiB
+        #[arg(long)]
+        memory_limit: Option<u32>,
+        /// Mount a tmpfs of this many MiB at /tmp in the guest. A guest `tmp.mount`
+        /// unit may remount /tmp; disable it in a custom image if so
+        #[arg(long)]
+        tmpfs_size: Option<u32>,
+        /// Mount a tmpfs in the guest as <path>[:size_mib] (repeatable). Also added to the
+        /// guest's /etc/fstab with --persist-network
+        #[arg(long)]
+        tmpfs: Vec<String>,
+        /// Guest memory in MiB (default: 128)
+        #[arg(long)]
+        memory: Option<u32>,
+        /// Balloon size in MiB inflated at boot; must be smaller than --memory
+        #[arg(long)]
+        memory_balloon: Option<u32>,
+        /// Print the Firecracker API payloads without launching the VM
+        #[arg(long)]
+        dry_run: bool,
+        /// Path to the Firecracker binary (default: $STOKER_FIRECRACKER_BIN or the downloaded one)
+        #[arg(long)]
+        firecracker_bin: Option<String>,
+        /// Command run in the guest over SSH to check health (exit 0 = healthy)
+        #[arg(long)]
+        health_cmd: Option<String>,
+        /// Seconds between healthchecks
+        #[arg(long, default_value_t = 30)]
+        health_interval: u64,
+        /// Consecutive failures before the VM is marked unhealthy
+        #[arg(long, default_value_t = 3)]
+        health_retries: u32,
+        /// Seconds a single healthcheck may take
+        #[arg(long, default_value_t = 30)]
+        health_timeout: u64,
+        /// Probe the guest's SSH port every N seconds and restart the VM if it stops answering. A
+        /// detached VM is watched by a background `stoker supervise` process
+        #[arg(long)]
+        watchdog_interval: Option<u32>,
+        /// Consecutive failed watchdog probes before the VM is restarted
+        #[arg(long, default_value_t = 3)]
+        watchdog_grace_failures: u32,
+        /// Block until this guest TCP port accepts connections (repeatable)
+        #[arg(long = "wait-port")]
+        wait_ports: Vec<u16>,
+        /// Seconds to wait for all --wait-port ports before failing
+        #[arg(long, default_value_t = 60)]
+        wait_timeout: u64,
+        /// OOM killer score adjustment for the Firecracker daemon (-1000 to 1000)
+        #[arg(long, default_value_t = 200, allow_negative_numbers = true,
+              value_parser = clap::value_parser!(i32).range(-1000..=1000))]
+        oom_score_adj: i32,
+        /// Shorthand for --oom-score-adj -1000
+        #[arg(long, conflicts_with = "oom_score_adj")]
+        protect_from_oom: bool,
+        /// Run guest login shells as <uid>[:gid] instead of root (numeric ids or `nobody`)
+        #[arg(long)]
+        user: Option<String>,
+        /// Static MAC address for the guest's primary interface
+        #[arg(long)]
+        mac: Option<String>,
+        /// Absolute guest directory login shells start in. There is no entrypoint yet, so
+        /// this only affects shells opened with `stoker ssh`
+        #[arg(long)]
+        working_dir: Option<String>,
+        /// Write the guest network configuration to disk so it survives reboots inside the guest
+        #[arg(long)]
+        persist_network: bool,
+        /// Remove the VM (TAP, socket, rootfs, metadata) when its Firecracker process exits (requires --attach)
+        #[arg(long = "rm", requires = "attach")]
+        auto_remove: bool,
+        /// Fixed guest address as <address>/<prefix> (private range, /29 or /30; requires --host-ip)
+        #[arg(long, requires = "host_ip")]
+        ip: Option<String>,
+        /// Fixed host-side TAP address as <address>/<prefix> (requires --ip)
+        #[arg(long, requires = "ip")]
+        host_ip: Option<String>,
+        /// Give up and clean up if the VM isn't up (SSH configured, --wait-port ports open)
+        /// within this many seconds
+        #[arg(long)]
+        timeout: Option<u64>,
+        /// Resource limit for guest login shells as <type>=<soft>[:<hard>], where type is
+        /// nofile, nproc, core or fsize (core and fsize in the shell's `ulimit` blocks; repeatable)
+        #[arg(long = "ulimit")]
+        ulimits: Vec<String>,
+        /// Guest kernel parameter as <key>=<value> under net., vm. or kernel. (repeatable).
+        /// Applied once the guest is up and persisted in /etc/sysctl.d/99-stoker.conf
+        #[arg(long = "sysctl")]
+        sysctls: Vec<String>,
+        /// Resume a snapshot from `stoker snapshot create` instead of booting an image. The
+        /// host's kernel, CPU model and Firecracker version must match the snapshot's
+        #[arg(long)]
+        from_snapshot: Option<String>,
+        /// Harden the Firecracker process: seccomp=<profile>, no-new-privileges[=true] or
+        /// apparmor=<profile> (repeatable)
+        #[arg(long = "security-opt")]
+        security_opts: Vec<String>,
+        /// Script to run as the guest's init before the image's own; it receives the real init
+        /// (/sbin/init) as its argument and should `exec "$1"` when done
+        #[arg(long)]
+        init_file: Option<String>,
+        /// Remove an existing VM with the same --name before starting this one
+        #[arg(long)]
+        replace: bool,
+        /// IPC namespace of the Firecracker process: private (default), host (no effect, see
+        /// vsock), shareable, or vm:<name> to join a shareable VM's namespace
+        #[arg(long)]
+        ipc: Option<String>,
+        /// Where the guest's serial console goes: none (default), file
+        /// (/tmp/stoker-<name>/serial.log), syslog or journald
+        #[arg(long, value_parser = ["none", "file", "syslog", "journald"])]
+        log_driver: Option<String>,
+        /// Pin the Firecracker process and its vCPU threads to these host CPUs (e.g. 0-3,6)
+        #[arg(long)]
+        cpuset: Option<String>,
+        /// Confine the Firecracker process to these host CPUs (e.g. 0,2-4) through a cpuset
+        /// cgroup, along with the memory of their NUMA nodes
+        #[arg(long, conflicts_with = "cpuset")]
+        cpuset_cgroup: Option<String>,
+        /// Directory for the VM's writable rootfs copy (default: vms/ in the asset directory).
+        /// Pointing it at a tmpfs gives a RAM-backed disk that does not survive a reboot
+        #[arg(long)]
+        rootfs_dir: Option<String>,
+        /// Mix this host file's bytes (up to 64 KiB, or 512 bytes of a device) into the guest's
+        /// /dev/urandom on boot. Without a file, 512 bytes of the host's /dev/urandom are used
+        #[arg(long, num_args = 0..=1, default_missing_value = "/dev/urandom")]
+        seed_entropy: Option<String>,
+        /// Add a <name>:<ip> line to the guest's /etc/hosts (repeatable). host-gateway as the
+        /// address stands for the host end of the VM's network
+        #[arg(long = "add-host")]
+        add_hosts: Vec<String>,
+        /// Cache mode of the rootfs drive. writeback passes the guest's flushes through to the
+        /// host disk; unsafe (Firecracker's default) ignores them, which is faster but can lose
+        /// writes if the host crashes
+        #[arg(long, value_parser = firecracker::DRIVE_CACHE_TYPES)]
+        drive_cache: Option<String>,
+        /// Boot the image read-only (<image>.squashfs when present) instead of copying it, and
+        /// give the VM a fresh writable ext4 data drive. Flags that write to the guest's root,
+        /// such as --user or --persist-network, are not allowed
+        #[arg(long)]
+        read_only_root: bool,
+        /// Size of the --read-only-root data drive in MiB [default: 1024]
+        #[arg(long, requires = "read_only_root")]
+        data_size: Option<u32>,
+        /// Guest path the --read-only-root data drive is mounted at; it must exist in the image
+        /// [default: /data]
+        #[arg(long, requires = "read_only_root")]
+        data_mount: Option<String>,
     },
     /// Builds a custom microVM filesystem image using a bash script
     Build {
@@ -42,34 +223,317 @@ enum Commands {
         #[arg(long)]
         image_name: String,
         /// Path to the bash script to execute inside the build container
+        #[arg(long, required_unless_present = "file", conflicts_with_all = ["file", "target"])]
+        script_path: Option<String>,
+        /// Build from a Stokerfile (FROM, STAGE and RUN directives) instead of a single script
+        #[arg(long)]
+        file: Option<String>,
+        /// Build the Stokerfile only up to this stage, saving it as <image-name>-<stage>
+        #[arg(long, requires = "file")]
+        target: Option<String>,
+        /// Existing image to build on top of (default: ubuntu-rootfs)
+        #[arg(long)]
+        from: Option<String>,
+        /// Squash the result into a fresh, minimally sized ext4
         #[arg(long)]
-        script_path: String,
+        squash: bool,
+        /// Extra bind mount for the build container as host_path:container_path (repeatable)
+        #[arg(long = "bind")]
+        binds: Vec<String>,
+        /// Extra capability for the build container, e.g. CAP_SYS_ADMIN (repeatable)
+        #[arg(long = "capability")]
+        capabilities: Vec<String>,
+        /// Grant the build container all capabilities
+        #[arg(long)]
+        privileged: bool,
+        /// Build container networking: full (host network), none, or private (NAT via a temporary bridge)
+        #[arg(long, default_value = "full")]
+        network: String,
+        /// Build argument exported to the build script as KEY=VALUE (repeatable)
+        #[arg(long = "build-arg")]
+        build_args: Vec<String>,
+        /// Metadata label stored with the image as key=value, e.g.
+        /// org.opencontainers.image.description=... (repeatable)
+        #[arg(long = "label")]
+        labels: Vec<String>,
+        /// Progress output: plain (messages), tty (animated steps) or json (one event per line)
+        #[arg(long, default_value = "plain", value_parser = ["plain", "tty", "json"])]
+        progress: String,
+        /// Seed the local build cache from an image written by --cache-to
+        #[arg(long)]
+        cache_from: Option<String>,
+        /// After a successful build, package the local build cache into this image
+        #[arg(long)]
+        cache_to: Option<String>,
+        /// If the build script fails, open a shell in the build container before cleaning up
+        #[arg(long)]
+        debug: bool,
+        /// Build for this architecture (x86_64/amd64 or aarch64/arm64) using a registered
+        /// qemu-user binfmt handler when it differs from the host's. The base image must match
+        #[arg(long)]
+        arch: Option<String>,
+        /// Build for this platform, e.g. linux/amd64 or linux/arm64. The same as --arch
+        #[arg(long, conflicts_with = "arch")]
+        platform: Option<String>,
+        /// Print the exact container command line (systemd-nspawn, or chroot when falling back to
+        /// it) before running the build script. It is always recorded in <image-name>.buildlog in
+        /// the asset directory, along with the script's output under --progress json
+        #[arg(long, short)]
+        verbose: bool,
+        /// Forward an SSH agent into the build container as $SSH_AUTH_SOCK, e.g. to clone
+        /// private Git repositories: default (the host's $SSH_AUTH_SOCK) or default=<socket>
+        #[arg(long)]
+        ssh: Option<String>,
     },
     /// Connects interactively to an active microVM
     Ssh {
         /// Custom name or ID of the VM to connect to
         name: String,
+        /// Forward the local SSH agent into the VM (ssh -A)
+        #[arg(long, short = 'A')]
+        agent_forward: bool,
+        /// Extra ssh option as <key>=<value>, e.g. ProxyJump=bastion to reach the host through
+        /// a jump host (repeatable). Takes precedence over stoker's defaults
+        #[arg(long = "ssh-opt")]
+        ssh_opts: Vec<String>,
+        /// Share one SSH connection between sessions through a control socket, so repeated
+        /// `stoker ssh` calls skip the handshake. The connection stays up 10 minutes after the
+        /// last session and is closed when the VM stops
+        #[arg(long)]
+        multiplex: bool,
+    },
+    /// Copies a file or directory between the host and a VM, e.g. `stoker cp ./app web:/srv`
+    Cp {
+        /// Source: a host path or <vm>:<path>
+        src: String,
+        /// Destination: a host path or <vm>:<path>. An existing directory receives the source
+        /// inside it
+        dest: String,
+        /// Copy what symlinks point to instead of recreating them as symlinks
+        #[arg(long, short = 'L')]
+        follow_links: bool,
     },
     /// Removes a microVM and releases its IP subnet
     Rm {
         /// Name of the VM to remove
         name: String,
+        /// Kill the Firecracker daemon immediately instead of stopping it gracefully
+        #[arg(long, short)]
+        force: bool,
+        /// Seconds to wait for a graceful stop before killing the daemon
+        #[arg(long, short, default_value_t = 10)]
+        time: u64,
+        /// Tear the VM down but keep its rootfs, renamed to rootfs-<name>-kept-<time>.ext4 next
+        /// to where it was, for inspection with a loop mount
+        #[arg(long)]
+        keep_rootfs: bool,
+    },
+    /// Gracefully stops a microVM and boots it again with the same configuration
+    Restart {
+        /// Name of the VM to restart
+        name: String,
+        /// Seconds to wait for a graceful stop before killing the daemon
+        #[arg(long, short, default_value_t = 10)]
+        time: u64,
+    },
+    /// Stops a VM and resumes it from a snapshot, keeping its name, network and subnet
+    Rollback {
+        /// Name of the VM to roll back
+        name: String,
+        /// Snapshot from `stoker snapshot create`, by name or directory
+        snapshot: String,
+        /// Seconds to wait for a graceful stop before killing the daemon
+        #[arg(long, short, default_value_t = 10)]
+        time: u64,
     },
     /// Lists active microVMs
-    List,
+    #[command(visible_alias = "ps")]
+    List {
+        /// Print the VMs' metadata as JSON
+        #[arg(long, conflicts_with = "format")]
+        json: bool,
+        /// Print only the VMs' names, one per line
+        #[arg(long, short, conflicts_with_all = ["json", "format"])]
+        quiet: bool,
+        /// Output format: table (default), json, or a template like '{{.name}}\t{{.guest_ip}}'
+        /// using the fields id, name, mode, image, status, uptime, guest_ip, host_ip,
+        /// mac_address, tap_device, pid, memory_mib, health, started_at, cpuset and rootfs_dir
+        #[arg(long)]
+        format: Option<String>,
+        /// Order the VMs by name (default), id, ip, uptime or status
+        #[arg(long, default_value = "name", value_parser = firecracker::SORT_FIELDS)]
+        sort: String,
+        /// Reverse the sort order
+        #[arg(long)]
+        reverse: bool,
+    },
+    /// Shows a VM's metadata with its configured and actual vCPUs and memory as JSON
+    Inspect {
+        /// Name of the VM to inspect
+        name: String,
+    },
+    /// Shows files a VM added (A), changed (C) or deleted (D) relative to its image
+    Diff {
+        /// Name of the VM to inspect
+        name: String,
+    },
+    /// Shows a VM's Firecracker log
+    Logs {
+        /// Name of the VM
+        name: String,
+        /// Only show the last N lines
+        #[arg(long, short = 'n')]
+        tail: Option<usize>,
+        /// Only show entries from the last DURATION, e.g. 30s, 5m, 2h or 1d
+        #[arg(long, value_parser = logs::parse_duration)]
+        since: Option<std::time::Duration>,
+    },
+    /// Shows VM lifecycle events (create, start, stop, die, remove, watchdog, timeout, adopt,
+    /// snapshot)
+    Events {
+        /// Keep printing new events as they are recorded
+        #[arg(long, short)]
+        follow: bool,
+    },
     /// Lists available microVM images
-    Images,
+    Images {
+        /// Print the images as JSON
+        #[arg(long)]
+        json: bool,
+        /// Show each image's SHA-256, cached in a <image>.digest file until the image changes
+        #[arg(long)]
+        digest: bool,
+    },
+    /// Manages microVM images
+    Image {
+        #[command(subcommand)]
+        command: ImageCommands,
+    },
+    /// Manages microVM memory snapshots for fast starts
+    Snapshot {
+        #[command(subcommand)]
+        command: SnapshotCommands,
+    },
+    /// Prints the stoker version, and with --verbose the Firecracker, kernel and OS versions
+    Version {
+        /// Also report Firecracker, host kernel, OS and architecture
+        #[arg(short, long)]
+        verbose: bool,
+        /// Print the versions as a JSON object
+        #[arg(long)]
+        json: bool,
+    },
+    /// Prints a shell completion script. Install with e.g.
+    /// `stoker completions fish > ~/.config/fish/completions/stoker.fish`
+    Completions {
+        /// Shell to generate the script for
+        #[arg(value_parser = completions::SHELLS)]
+        shell: String,
+    },
+    /// Collects host diagnostics (KVM, TUN, iptables, Firecracker, VM state) for bug reports
+    Diagnose {
+        /// Print the checks as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Brings a Firecracker VM started outside stoker under stoker management
+    Adopt {
+        /// PID of the running Firecracker process
+        #[arg(long)]
+        pid: u32,
+        /// Path of the VM's Firecracker API socket
+        #[arg(long)]
+        socket: String,
+        /// Name to manage the VM under
+        #[arg(long)]
+        name: String,
+    },
+    /// Serves Prometheus metrics for all VMs at /metrics
+    MetricsServer {
+        /// Port to listen on
+        #[arg(long, default_value_t = 9464)]
+        port: u16,
+    },
     /// Provisions the Lima virtual machine environment end-to-end from macOS
     Setup,
+    /// Keeps probing a detached VM until it is removed; started by `stoker run`
+    #[command(hide = true)]
+    Supervise {
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SnapshotCommands {
+    /// Pauses a running VM, saves its memory, device state and rootfs, and resumes it
+    Create {
+        /// Name of the VM to snapshot
+        vm: String,
+        /// Name to save the snapshot under
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ImageCommands {
+    /// Shows the build steps that produced an image
+    History {
+        /// Name of the image
+        name: String,
+        /// Do not truncate the step contents
+        #[arg(long)]
+        no_trunc: bool,
+    },
+    /// Shows an image's location, size and labels as JSON
+    Inspect {
+        /// Name of the image
+        name: String,
+    },
+    /// Creates an image from a plain root filesystem tarball, e.g. Alpine's minirootfs
+    Convert {
+        /// Root filesystem tarball (optionally gzip/xz/bzip2-compressed)
+        #[arg(long)]
+        from_rootfs: String,
+        /// Name of the image to create
+        #[arg(long)]
+        to: String,
+    },
+    /// Downloads an image from a registry, e.g. 192.168.1.10:5000/nginx-server
+    Pull {
+        /// Registry reference as [http(s)://]REGISTRY/NAME
+        reference: String,
+        /// Require TLS even for registries listed in STOKER_INSECURE_REGISTRY
+        #[arg(long)]
+        verify_tls: bool,
+    },
+    /// Uploads an image to object storage as s3://BUCKET/KEY or gs://BUCKET/KEY. S3 uses
+    /// AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY; GCS uses GOOGLE_OAUTH_ACCESS_TOKEN or gcloud
+    Push {
+        /// Name of the image
+        name: String,
+        /// Destination URL; a key ending in / gets NAME.ext4 appended
+        destination: String,
+    },
 }
 
+
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() {
     let cli = Cli::parse();
+    if let Err(e) = run(cli).await {
+        eprintln!("Error: {:?}", e);
+        #[cfg(target_os = "linux")]
+        std::process::exit(stoker::errors::exit_code(&e));
+        #[cfg(not(target_os = "linux"))]
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
 
     #[cfg(target_os = "macos")]
     {
-        use std::process::Command;
+        use tokio::process::Command;
         use std::env;
 
         let args: Vec<String> = env::args().collect();
@@ -101,7 +565,7 @@ async fn main() -> Result<()> {
             .args(&["shell", "firecracker-vm", "bash", "-l", "-c", &cmd_str])
             .spawn()?;
         
-        let status = child.wait()?;
+        let status = child.wait().await?;
         if !status.success() {
             std::process::exit(status.code().unwrap_or(1));
         }
@@ -110,33 +574,231 @@ async fn main() -> Result<()> {
 
     #[cfg(target_os = "linux")]
     {
+        assets::set_asset_dir(assets::AssetDir::from_env(cli.image_dir, cli.output_dir));
         match cli.command {
-            Commands::DownloadAssets => {
+            Commands::DownloadAssets { firecracker_version } => {
                 println!("Downloading Firecracker assets natively...");
-                assets::download_all().await?;
+                assets::download_all(&firecracker_version).await?;
                 println!("Assets downloaded successfully.");
             }
-            Commands::Run { mode, name, image } => {
+            Commands::Run {
+                mode, name, image, attach, egress, allow, deny, pid_limit, memory_limit, tmpfs_size, tmpfs,
+                memory, memory_balloon, dry_run, firecracker_bin,
+                health_cmd, health_interval, health_retries, health_timeout,
+                watchdog_interval, watchdog_grace_failures, wait_ports, wait_timeout,
+                oom_score_adj, protect_from_oom, user, mac, working_dir, persist_network,
+                auto_remove, ip, host_ip, timeout, ulimits, sysctls, from_snapshot, security_opts,
+                init_file, replace, ipc, log_driver, cpuset, cpuset_cgroup, rootfs_dir, cni_plugin,
+                seed_entropy, add_hosts, drive_cache, read_only_root, data_size, data_mount,
+            } => {
                 println!("Starting stoker {} VM...", mode);
-                firecracker::run_vm(&mode, name, image).await?;
+                let run = stoker::run_vm(stoker::RunConfig {
+                    mode,
+                    name,
+                    image,
+                    attach,
+                    egress,
+                    allow,
+                    deny,
+                    pids_limit: pid_limit,
+                    memory_limit_mib: memory_limit,
+                    tmpfs_size_mib: tmpfs_size,
+                    tmpfs,
+                    memory_mib: memory,
+                    balloon_mib: memory_balloon,
+                    dry_run,
+                    firecracker_bin,
+                    healthcheck: health_cmd.map(|cmd| firecracker::HealthCheck {
+                        cmd,
+                        interval_secs: health_interval,
+                        retries: health_retries,
+                        timeout_secs: health_timeout,
+                    }),
+                    watchdog_interval_secs: watchdog_interval,
+                    watchdog_grace_failures,
+                    wait_ports,
+                    wait_timeout_secs: wait_timeout,
+                    oom_score_adj: Some(if protect_from_oom { -1000 } else { oom_score_adj }),
+                    user,
+                    mac,
+                    working_dir,
+                    persist_network,
+                    auto_remove,
+                    guest_ip: ip,
+                    host_ip,
+                    timeout_secs: timeout,
+                    ulimits,
+                    sysctls,
+                    from_snapshot,
+                    security_opts,
+                    init_file,
+                    replace,
+                    ipc,
+                    log_driver,
+                    cpuset,
+                    cpuset_cgroup,
+                    rootfs_dir,
+                    cni_plugin,
+                    seed_entropy,
+                    add_hosts,
+                    drive_cache,
+                    read_only_root,
+                    data_size_mib: data_size,
+                    data_mount,
+                });
+                tokio::select! {
+                    result = run => { result?; }
+                    signal = firecracker::shutdown_signal() => {
+                        println!("Caught {}, shutting down VMs...", signal);
+                        firecracker::shutdown_session().await;
+                        std::process::exit(if signal == "SIGINT" { 130 } else { 143 });
+                    }
+                }
+            }
+            Commands::Build {
+                image_name, script_path, from, squash, binds, capabilities, privileged, network,
+                build_args, labels, progress, cache_from, cache_to, debug, arch, platform, file, target, verbose,
+                ssh,
+            } => {
+                let arch = match &platform {
+                    Some(platform) => Some(builder::platform_arch(platform)?.to_string()),
+                    None => arch,
+                };
+                let ssh_agent = match &ssh {
+                    Some(spec) => {
+                        let socket = builder::ssh_agent_socket(spec, std::env::var("SSH_AUTH_SOCK").ok().as_deref())?;
+                        if socket.is_none() {
+                            println!("Warning: --ssh default given but SSH_AUTH_SOCK is not set; building without an SSH agent.");
+                        }
+                        socket
+                    }
+                    None => None,
+                };
+                let opts = builder::BuildOptions {
+                    image_name,
+                    script_path: script_path.unwrap_or_default(),
+                    from,
+                    squash,
+                    binds,
+                    capabilities,
+                    privileged,
+                    network,
+                    build_args,
+                    labels,
+                    progress: progress.parse()?,
+                    cache_from,
+                    cache_to,
+                    debug,
+                    arch,
+                    verbose,
+                    ssh_agent,
+                };
+                // Builds mount, loop-copy and run the script synchronously; keep them off the runtime
+                tokio::task::spawn_blocking(move || match file {
+                    Some(file) => stokerfile::build(&opts, &file, target.as_deref()),
+                    None => builder::build_image(&opts),
+                })
+                .await??;
             }
-            Commands::Build { image_name, script_path } => {
-                builder::build_image(&image_name, &script_path)?;
+            Commands::Ssh { name, agent_forward, ssh_opts, multiplex } => {
+                guest::interactive_ssh(&name, agent_forward, multiplex, &ssh_opts).await?;
             }
-            Commands::Ssh { name } => {
-                guest::interactive_ssh(&name)?;
+            Commands::Cp { src, dest, follow_links } => {
+                guest::copy(&src, &dest, follow_links).await?;
             }
-            Commands::Rm { name } => {
+            Commands::Rm { name, force, time, keep_rootfs } => {
                 println!("Removing VM '{}'...", name);
-                firecracker::rm_vm(&name).await?;
+                let grace = if force { std::time::Duration::ZERO } else { std::time::Duration::from_secs(time) };
+                firecracker::rm_vm(&name, grace, keep_rootfs).await?;
                 println!("VM '{}' successfully removed.", name);
             }
-            Commands::List => {
-                firecracker::list_vms()?;
+            Commands::Restart { name, time } => {
+                println!("Restarting VM '{}'...", name);
+                firecracker::restart_vm(&name, std::time::Duration::from_secs(time)).await?;
+                println!("VM '{}' successfully restarted.", name);
+            }
+            Commands::Rollback { name, snapshot, time } => {
+                println!("Rolling back VM '{}' to snapshot '{}'...", name, snapshot);
+                firecracker::rollback_vm(&name, &snapshot, std::time::Duration::from_secs(time)).await?;
+            }
+            Commands::List { json, quiet, format, sort, reverse } => {
+                let mut vms = firecracker::list_vms();
+                firecracker::sort_vms(&mut vms, &sort, reverse);
+                match (json, format.as_deref()) {
+                    _ if quiet => vms.iter().for_each(|vm| println!("{}", vm.name)),
+                    (true, _) | (false, Some("json")) => println!("{}", serde_json::to_string_pretty(&vms)?),
+                    (false, None | Some("table")) => {
+                        let configs = firecracker::machine_configs(&vms).await;
+                        print!("{}", firecracker::render_vm_table(&vms, &configs));
+                    }
+                    (false, Some(template)) => print!("{}", firecracker::render_vm_format(&vms, template)?),
+                }
+            }
+            Commands::Inspect { name } => {
+                firecracker::inspect_vm(&name).await?;
+            }
+            Commands::Diff { name } => {
+                diff::diff_vm(&name)?;
+            }
+            Commands::Logs { name, tail, since } => {
+                logs::show(&name, tail, since)?;
+            }
+            Commands::Events { follow } => {
+                events::stream(follow).await?;
             }
-            Commands::Images => {
-                assets::list_images()?;
+            Commands::Diagnose { json } => {
+                diagnose::run(json)?;
             }
+            Commands::Completions { shell } => {
+                print!("{}", completions::render(&Cli::command(), &shell)?);
+            }
+            Commands::Version { verbose, json } => {
+                println!("{}", diagnose::render_version(&diagnose::version_fields(verbose), json)?);
+            }
+            Commands::Adopt { pid, socket, name } => {
+                firecracker::adopt_vm(pid, &socket, &name).await?;
+            }
+            Commands::MetricsServer { port } => {
+                metrics::serve(port).await?;
+            }
+            Commands::Supervise { name } => {
+                firecracker::supervise(&name).await?;
+            }
+            Commands::Images { json, digest } => {
+                let mut images = assets::list_images();
+                if digest {
+                    for image in &mut images {
+                        image.digest = Some(assets::image_digest(&image.name)?);
+                    }
+                }
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&images)?);
+                } else {
+                    print!("{}", assets::render_image_table(&images));
+                }
+            }
+            Commands::Image { command } => match command {
+                ImageCommands::History { name, no_trunc } => {
+                    builder::show_history(&name, no_trunc)?;
+                }
+                ImageCommands::Inspect { name } => {
+                    builder::inspect_image(&name)?;
+                }
+                ImageCommands::Convert { from_rootfs, to } => {
+                    builder::build_from_rootfs_tarball(std::path::Path::new(&from_rootfs), &to)?;
+                }
+                ImageCommands::Pull { reference, verify_tls } => {
+                    assets::pull_image(&reference, verify_tls).await?;
+                }
+                ImageCommands::Push { name, destination } => {
+                    push::push_image(&name, &destination).await?;
+                }
+            },
+            Commands::Snapshot { command } => match command {
+                SnapshotCommands::Create { vm, name } => {
+                    firecracker::create_snapshot(&vm, &name).await?;
+                }
+            },
             Commands::Setup => {
                 // Setup is exclusively a macOS proxy command to build the Lima VM.
                 println!("The `setup` command is only available on macOS to build the host VM.");
@@ -152,11 +814,35 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// Deletes the wrapped path when dropped, so temp files go away on every exit path.
+#[cfg(target_os = "macos")]
+struct RemoveOnDrop(std::path::PathBuf);
+
+#[cfg(target_os = "macos")]
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn find_in_path(program: &str) -> Option<std::path::PathBuf> {
+    std::env::var_os("PATH").and_then(|paths| {
+        std::env::split_paths(&paths)
+            .map(|dir| dir.join(program))
+            .find(|candidate| candidate.is_file())
+    })
+}
+
 #[cfg(target_os = "macos")]
 async fn macos_setup() -> Result<()> {
     use std::io::Write;
-    use std::process::Command;
+    use tokio::process::Command;
     
+    if find_in_path("limactl").is_none() {
+        anyhow::bail!("limactl not found in PATH. Install Lima first, e.g. `brew install lima`, then re-run `stoker setup`.");
+    }
+
     println!("Setting up Lima VM for Firecracker...");
     
     let yaml = r#"
@@ -184,16 +870,23 @@ provision:
     apt-get install -y iptables build-essential curl pkg-config libssl-dev
 "#;
 
-    let yaml_path = "/tmp/firecracker-vm.yaml";
-    let mut file = std::fs::File::create(yaml_path)?;
+    // A per-invocation name keeps concurrent setups from clobbering each other's config
+    let suffix = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let yaml_path = std::env::temp_dir().join(format!("stoker-lima-{}-{}.yaml", std::process::id(), suffix));
+    let _cleanup = RemoveOnDrop(yaml_path.clone());
+    let mut file = std::fs::File::create(&yaml_path)?;
     file.write_all(yaml.as_bytes())?;
     
     println!("Creating Lima VM (this may take a few minutes)...");
     let mut child = Command::new("limactl")
-        .args(&["start", "--name=firecracker-vm", "--tty=false", yaml_path])
+        .args(["start", "--name=firecracker-vm", "--tty=false"])
+        .arg(&yaml_path)
         .spawn()?;
         
-    let status = child.wait()?;
+    let status = child.wait().await?;
     if !status.success() {
         anyhow::bail!("Failed to create Lima VM");
     }
@@ -209,7 +902,7 @@ provision:
         .args(&["shell", "firecracker-vm", "bash", "-l", "-c", &compile_cmd])
         .spawn()?;
         
-    let status2 = child2.wait()?;
+    let status2 = child2.wait().await?;
     if !status2.success() {
         anyhow::bail!("Failed to compile stoker inside Lima VM");
     }
@@ -229,10 +922,12 @@ mod tests {
         let args = vec!["stoker", "run"];
         let cli = Cli::try_parse_from(args).unwrap();
         match cli.command {
-            Commands::Run { mode, name, image } => {
+            Commands::Run { mode, name, image, attach, egress, .. } => {
                 assert_eq!(mode, "internet");
                 assert_eq!(name, None);
                 assert_eq!(image, None);
+                assert!(!attach);
+                assert_eq!(egress, None);
             }
             _ => panic!("Expected Run command"),
         }
@@ -243,7 +938,7 @@ mod tests {
         let args = vec!["stoker", "run", "--name", "my-server", "--image", "nginx-image", "--mode", "local"];
         let cli = Cli::try_parse_from(args).unwrap();
         match cli.command {
-            Commands::Run { mode, name, image } => {
+            Commands::Run { mode, name, image, .. } => {
                 assert_eq!(mode, "local");
                 assert_eq!(name, Some("my-server".to_string()));
                 assert_eq!(image, Some("nginx-image".to_string()));
@@ -252,28 +947,735 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_cli_run_auto_remove() {
+        let cli = Cli::try_parse_from(vec!["stoker", "run", "--attach", "--rm"]).unwrap();
+        match cli.command {
+            Commands::Run { attach, auto_remove, .. } => {
+                assert!(attach);
+                assert!(auto_remove);
+            }
+            _ => panic!("Expected Run command"),
+        }
+        assert!(Cli::try_parse_from(vec!["stoker", "run", "--rm"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_run_attach() {
+        let args = vec!["stoker", "run", "--attach"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Run { attach, .. } => assert!(attach),
+            _ => panic!("Expected Run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_run_limits() {
+        let args = vec!["stoker", "run", "--pid-limit", "512", "--memory-limit", "1024"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Run { pid_limit, memory_limit, .. } => {
+                assert_eq!(pid_limit, Some(512));
+                assert_eq!(memory_limit, Some(1024));
+            }
+            _ => panic!("Expected Run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_run_healthcheck() {
+        let args = vec!["stoker", "run", "--health-cmd", "systemctl is-active nginx", "--health-retries", "5"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Run { health_cmd, health_interval, health_retries, health_timeout, .. } => {
+                assert_eq!(health_cmd.as_deref(), Some("systemctl is-active nginx"));
+                assert_eq!(health_interval, 30);
+                assert_eq!(health_retries, 5);
+                assert_eq!(health_timeout, 30);
+            }
+            _ => panic!("Expected Run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_run_watchdog() {
+        let args = vec!["stoker", "run", "--attach", "--watchdog-interval", "10"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Run { watchdog_interval, watchdog_grace_failures, .. } => {
+                assert_eq!(watchdog_interval, Some(10));
+                assert_eq!(watchdog_grace_failures, 3);
+            }
+            _ => panic!("Expected Run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_run_wait_ports() {
+        let args = vec!["stoker", "run", "--wait-port", "80", "--wait-port", "443", "--wait-timeout", "120"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Run { wait_ports, wait_timeout, .. } => {
+                assert_eq!(wait_ports, vec![80, 443]);
+                assert_eq!(wait_timeout, 120);
+            }
+            _ => panic!("Expected Run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_run_oom_score_adj() {
+        let cli = Cli::try_parse_from(vec!["stoker", "run"]).unwrap();
+        match cli.command {
+            Commands::Run { oom_score_adj, protect_from_oom, .. } => {
+                assert_eq!(oom_score_adj, 200);
+                assert!(!protect_from_oom);
+            }
+            _ => panic!("Expected Run command"),
+        }
+
+        let cli = Cli::try_parse_from(vec!["stoker", "run", "--oom-score-adj", "-500"]).unwrap();
+        match cli.command {
+            Commands::Run { oom_score_adj, .. } => assert_eq!(oom_score_adj, -500),
+            _ => panic!("Expected Run command"),
+        }
+
+        assert!(Cli::try_parse_from(vec!["stoker", "run", "--oom-score-adj", "1001"]).is_err());
+        assert!(Cli::try_parse_from(vec!["stoker", "run", "--oom-score-adj", "5", "--protect-from-oom"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_run_static_ip() {
+        let args = vec!["stoker", "run", "--ip", "10.0.5.2/30", "--host-ip", "10.0.5.1/30"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Run { ip, host_ip, .. } => {
+                assert_eq!(ip.as_deref(), Some("10.0.5.2/30"));
+                assert_eq!(host_ip.as_deref(), Some("10.0.5.1/30"));
+            }
+            _ => panic!("Expected Run command"),
+        }
+        assert!(Cli::try_parse_from(vec!["stoker", "run", "--ip", "10.0.5.2/30"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_run_ulimit() {
+        let args = vec!["stoker", "run", "--ulimit", "nofile=1024:4096", "--ulimit", "core=0"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Run { ulimits, .. } => assert_eq!(ulimits, vec!["nofile=1024:4096", "core=0"]),
+            _ => panic!("Expected Run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_run_sysctl() {
+        let args = vec!["stoker", "run", "--sysctl", "net.ipv4.tcp_fin_timeout=30", "--sysctl", "vm.swappiness=10"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Run { sysctls, .. } => assert_eq!(sysctls, vec!["net.ipv4.tcp_fin_timeout=30", "vm.swappiness=10"]),
+            _ => panic!("Expected Run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_snapshot() {
+        let cli = Cli::try_parse_from(vec!["stoker", "snapshot", "create", "web", "warm"]).unwrap();
+        match cli.command {
+            Commands::Snapshot { command: SnapshotCommands::Create { vm, name } } => {
+                assert_eq!(vm, "web");
+                assert_eq!(name, "warm");
+            }
+            _ => panic!("Expected Snapshot Create command"),
+        }
+
+        let cli = Cli::try_parse_from(vec!["stoker", "run", "--from-snapshot", "warm"]).unwrap();
+        match cli.command {
+            Commands::Run { from_snapshot, .. } => assert_eq!(from_snapshot.as_deref(), Some("warm")),
+            _ => panic!("Expected Run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_run_tmpfs() {
+        let args = vec!["stoker", "run", "--tmpfs", "/scratch:512", "--tmpfs", "/var/cache/app"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Run { tmpfs, .. } => assert_eq!(tmpfs, vec!["/scratch:512", "/var/cache/app"]),
+            _ => panic!("Expected Run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_run_security_opt() {
+        let args = vec!["stoker", "run", "--security-opt", "no-new-privileges", "--security-opt", "apparmor=stoker-fc"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Run { security_opts, .. } => assert_eq!(security_opts, vec!["no-new-privileges", "apparmor=stoker-fc"]),
+            _ => panic!("Expected Run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_run_init_file() {
+        let cli = Cli::try_parse_from(vec!["stoker", "run", "--init-file", "./preflight.sh"]).unwrap();
+        match cli.command {
+            Commands::Run { init_file, .. } => assert_eq!(init_file.as_deref(), Some("./preflight.sh")),
+            _ => panic!("Expected Run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_run_replace() {
+        let cli = Cli::try_parse_from(vec!["stoker", "run", "--name", "web", "--replace"]).unwrap();
+        match cli.command {
+            Commands::Run { name, replace, .. } => {
+                assert_eq!(name.as_deref(), Some("web"));
+                assert!(replace);
+            }
+            _ => panic!("Expected Run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_run_ipc() {
+        let cli = Cli::try_parse_from(vec!["stoker", "run", "--ipc", "vm:web"]).unwrap();
+        match cli.command {
+            Commands::Run { ipc, .. } => assert_eq!(ipc.as_deref(), Some("vm:web")),
+            _ => panic!("Expected Run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_run_seed_entropy() {
+        let cli = Cli::try_parse_from(vec!["stoker", "run", "--seed-entropy", "/var/lib/seed"]).unwrap();
+        match cli.command {
+            Commands::Run { seed_entropy, .. } => assert_eq!(seed_entropy.as_deref(), Some("/var/lib/seed")),
+            _ => panic!("Expected Run command"),
+        }
+        let cli = Cli::try_parse_from(vec!["stoker", "run", "--seed-entropy"]).unwrap();
+        match cli.command {
+            Commands::Run { seed_entropy, .. } => assert_eq!(seed_entropy.as_deref(), Some("/dev/urandom")),
+            _ => panic!("Expected Run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_run_add_host() {
+        let args = vec!["stoker", "run", "--add-host", "db.internal:172.16.0.6", "--add-host", "host.internal:host-gateway"];
+        match Cli::try_parse_from(args).unwrap().command {
+            Commands::Run { add_hosts, .. } => assert_eq!(add_hosts, vec!["db.internal:172.16.0.6", "host.internal:host-gateway"]),
+            _ => panic!("Expected Run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_run_cni_plugin() {
+        let cli = Cli::try_parse_from(vec!["stoker", "run", "--cni-plugin", "ptp"]).unwrap();
+        match cli.command {
+            Commands::Run { cni_plugin, .. } => assert_eq!(cni_plugin.as_deref(), Some("ptp")),
+            _ => panic!("Expected Run command"),
+        }
+        assert!(Cli::try_parse_from(vec!["stoker", "run", "--cni-plugin", "ptp", "--egress", "eth0"]).is_err());
+        assert!(Cli::try_parse_from(vec!["stoker", "run", "--cni-plugin", "ptp", "--allow", "1.1.1.1:53"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_run_rootfs_dir() {
+        let cli = Cli::try_parse_from(vec!["stoker", "run", "--rootfs-dir", "/dev/shm/stoker"]).unwrap();
+        match cli.command {
+            Commands::Run { rootfs_dir, .. } => assert_eq!(rootfs_dir.as_deref(), Some("/dev/shm/stoker")),
+            _ => panic!("Expected Run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_run_cpuset() {
+        let cli = Cli::try_parse_from(vec!["stoker", "run", "--cpuset", "0-3,6"]).unwrap();
+        match cli.command {
+            Commands::Run { cpuset, .. } => assert_eq!(cpuset.as_deref(), Some("0-3,6")),
+            _ => panic!("Expected Run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_run_allow_deny() {
+        let args = vec!["stoker", "run", "--allow", "10.0.0.0/8:443", "--allow", "1.1.1.1:53", "--deny", "10.1.0.0/16"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Run { allow, deny, .. } => {
+                assert_eq!(allow, vec!["10.0.0.0/8:443", "1.1.1.1:53"]);
+                assert_eq!(deny, vec!["10.1.0.0/16"]);
+            }
+            _ => panic!("Expected Run command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_run_cpuset_cgroup() {
+        let cli = Cli::try_parse_from(vec!["stoker", "run", "--cpuset-cgroup", "0,2-4"]).unwrap();
+        match cli.command {
+            Commands::Run { cpuset_cgroup, .. } => assert_eq!(cpuset_cgroup.as_deref(), Some("0,2-4")),
+            _ => panic!("Expected Run command"),
+        }
+        assert!(Cli::try_parse_from(vec!["stoker", "run", "--cpuset-cgroup", "0", "--cpuset", "1"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_run_log_driver() {
+        let cli = Cli::try_parse_from(vec!["stoker", "run", "--log-driver", "journald"]).unwrap();
+        match cli.command {
+            Commands::Run { log_driver, .. } => assert_eq!(log_driver.as_deref(), Some("journald")),
+            _ => panic!("Expected Run command"),
+        }
+        assert!(Cli::try_parse_from(vec!["stoker", "run", "--log-driver", "fluentd"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_run_timeout() {
+        let cli = Cli::try_parse_from(vec!["stoker", "run", "--timeout", "90"]).unwrap();
+        match cli.command {
+            Commands::Run { timeout, .. } => assert_eq!(timeout, Some(90)),
+            _ => panic!("Expected Run command"),
+        }
+    }
+
     #[test]
     fn test_cli_build() {
         let args = vec!["stoker", "build", "--image-name", "custom-build", "--script-path", "/path/to/script.sh"];
         let cli = Cli::try_parse_from(args).unwrap();
         match cli.command {
-            Commands::Build { image_name, script_path } => {
+            Commands::Build { image_name, script_path, from, squash, binds, network, .. } => {
                 assert_eq!(image_name, "custom-build");
-                assert_eq!(script_path, "/path/to/script.sh");
+                assert_eq!(script_path.as_deref(), Some("/path/to/script.sh"));
+                assert_eq!(from, None);
+                assert!(!squash);
+                assert!(binds.is_empty());
+                assert_eq!(network, "full");
+            }
+            _ => panic!("Expected Build command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_rm() {
+        let args = vec!["stoker", "rm", "my-server", "--time", "3"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Rm { name, force, time, keep_rootfs } => {
+                assert_eq!(name, "my-server");
+                assert!(!force);
+                assert_eq!(time, 3);
+                assert!(!keep_rootfs);
+            }
+            _ => panic!("Expected Rm command"),
+        }
+
+        let cli = Cli::try_parse_from(vec!["stoker", "rm", "my-server", "--keep-rootfs"]).unwrap();
+        match cli.command {
+            Commands::Rm { keep_rootfs, .. } => assert!(keep_rootfs),
+            _ => panic!("Expected Rm command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_list_json() {
+        let cli = Cli::try_parse_from(vec!["stoker", "list", "--json"]).unwrap();
+        match cli.command {
+            Commands::List { json, .. } => assert!(json),
+            _ => panic!("Expected List command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_images_digest() {
+        let cli = Cli::try_parse_from(vec!["stoker", "images", "--digest", "--json"]).unwrap();
+        match cli.command {
+            Commands::Images { json, digest } => assert!(json && digest),
+            _ => panic!("Expected Images command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_run_drive_cache() {
+        let cli = Cli::try_parse_from(vec!["stoker", "run", "--drive-cache", "writeback"]).unwrap();
+        match cli.command {
+            Commands::Run { drive_cache, .. } => assert_eq!(drive_cache.as_deref(), Some("writeback")),
+            _ => panic!("Expected Run command"),
+        }
+        assert!(Cli::try_parse_from(vec!["stoker", "run", "--drive-cache", "none"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_run_read_only_root() {
+        let cli = Cli::try_parse_from(vec!["stoker", "run", "--read-only-root", "--data-size", "2048", "--data-mount", "/var/lib/app"]).unwrap();
+        match cli.command {
+            Commands::Run { read_only_root, data_size, data_mount, .. } => {
+                assert!(read_only_root);
+                assert_eq!(data_size, Some(2048));
+                assert_eq!(data_mount.as_deref(), Some("/var/lib/app"));
+            }
+            _ => panic!("Expected Run command"),
+        }
+        assert!(Cli::try_parse_from(vec!["stoker", "run", "--data-size", "2048"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_version() {
+        let cli = Cli::try_parse_from(vec!["stoker", "version", "--verbose", "--json"]).unwrap();
+        match cli.command {
+            Commands::Version { verbose, json } => assert!(verbose && json),
+            _ => panic!("Expected Version command"),
+        }
+        let err = Cli::try_parse_from(vec!["stoker", "--version"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::DisplayVersion);
+    }
+
+    #[test]
+    fn test_cli_completions() {
+        let cli = Cli::try_parse_from(vec!["stoker", "completions", "fish"]).unwrap();
+        match cli.command {
+            Commands::Completions { shell } => assert_eq!(shell, "fish"),
+            _ => panic!("Expected Completions command"),
+        }
+        assert!(Cli::try_parse_from(vec!["stoker", "completions", "tcsh"]).is_err());
+        let script = completions::render(&Cli::command(), "fish").unwrap();
+        assert!(script.contains("-n '__fish_seen_subcommand_from ssh rm restart inspect diff logs; and not __fish_seen_subcommand_from image snapshot' -f -a \"(stoker ps -q)\""), "{}", script);
+
+        let cli = Cli::try_parse_from(vec!["stoker", "ps", "-q"]).unwrap();
+        match cli.command {
+            Commands::List { quiet, .. } => assert!(quiet),
+            _ => panic!("Expected List command"),
+        }
+        assert!(Cli::try_parse_from(vec!["stoker", "ps", "-q", "--json"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_image_dir() {
+        let cli = Cli::try_parse_from(vec!["stoker", "run", "--image", "web", "--image-dir", "/mnt/images", "--output-dir", "/var/lib/stoker"]).unwrap();
+        assert_eq!(cli.image_dir.as_deref(), Some("/mnt/images"));
+        assert_eq!(cli.output_dir.as_deref(), Some("/var/lib/stoker"));
+        let cli = Cli::try_parse_from(vec!["stoker", "--image-dir", "/mnt/images", "images"]).unwrap();
+        assert_eq!(cli.image_dir.as_deref(), Some("/mnt/images"));
+        assert_eq!(cli.output_dir, None);
+    }
+
+    #[test]
+    fn test_cli_rollback() {
+        let cli = Cli::try_parse_from(vec!["stoker", "rollback", "web", "/srv/snapshots/before-upgrade", "-t", "3"]).unwrap();
+        match cli.command {
+            Commands::Rollback { name, snapshot, time } => {
+                assert_eq!(name, "web");
+                assert_eq!(snapshot, "/srv/snapshots/before-upgrade");
+                assert_eq!(time, 3);
             }
+            _ => panic!("Expected Rollback command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_build_ssh() {
+        let args = vec!["stoker", "build", "--image-name", "web", "--script-path", "build.sh", "--ssh", "default"];
+        match Cli::try_parse_from(args).unwrap().command {
+            Commands::Build { ssh, .. } => assert_eq!(ssh.as_deref(), Some("default")),
             _ => panic!("Expected Build command"),
         }
     }
 
+    #[test]
+    fn test_cli_inspect() {
+        match Cli::try_parse_from(vec!["stoker", "inspect", "web"]).unwrap().command {
+            Commands::Inspect { name } => assert_eq!(name, "web"),
+            _ => panic!("Expected Inspect command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_list_sort() {
+        let cli = Cli::try_parse_from(vec!["stoker", "ps", "--sort", "uptime", "--reverse"]).unwrap();
+        match cli.command {
+            Commands::List { sort, reverse, .. } => {
+                assert_eq!(sort, "uptime");
+                assert!(reverse);
+            }
+            _ => panic!("Expected List command"),
+        }
+        match Cli::try_parse_from(vec!["stoker", "list"]).unwrap().command {
+            Commands::List { sort, reverse, .. } => assert_eq!((sort.as_str(), reverse), ("name", false)),
+            _ => panic!("Expected List command"),
+        }
+        assert!(Cli::try_parse_from(vec!["stoker", "list", "--sort", "memory"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_list_format() {
+        let cli = Cli::try_parse_from(vec!["stoker", "list", "--format", "{{.name}} {{.pid}}"]).unwrap();
+        match cli.command {
+            Commands::List { json, format, .. } => {
+                assert!(!json);
+                assert_eq!(format.as_deref(), Some("{{.name}} {{.pid}}"));
+            }
+            _ => panic!("Expected List command"),
+        }
+        assert!(Cli::try_parse_from(vec!["stoker", "list", "--json", "--format", "table"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_logs() {
+        match Cli::try_parse_from(vec!["stoker", "logs", "web", "--since", "5m", "-n", "100"]).unwrap().command {
+            Commands::Logs { name, tail, since } => {
+                assert_eq!(name, "web");
+                assert_eq!(tail, Some(100));
+                assert_eq!(since, Some(std::time::Duration::from_secs(300)));
+            }
+            _ => panic!("Expected Logs command"),
+        }
+        assert!(Cli::try_parse_from(vec!["stoker", "logs", "web", "--since", "5 minutes"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_events() {
+        let cli = Cli::try_parse_from(vec!["stoker", "events", "-f"]).unwrap();
+        match cli.command {
+            Commands::Events { follow } => assert!(follow),
+            _ => panic!("Expected Events command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_diff() {
+        let cli = Cli::try_parse_from(vec!["stoker", "diff", "web"]).unwrap();
+        match cli.command {
+            Commands::Diff { name } => assert_eq!(name, "web"),
+            _ => panic!("Expected Diff command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_diagnose() {
+        let cli = Cli::try_parse_from(vec!["stoker", "diagnose", "--json"]).unwrap();
+        match cli.command {
+            Commands::Diagnose { json } => assert!(json),
+            _ => panic!("Expected Diagnose command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_adopt() {
+        let args = vec!["stoker", "adopt", "--pid", "4242", "--socket", "/run/fc.sock", "--name", "legacy"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Adopt { pid, socket, name } => {
+                assert_eq!(pid, 4242);
+                assert_eq!(socket, "/run/fc.sock");
+                assert_eq!(name, "legacy");
+            }
+            _ => panic!("Expected Adopt command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_metrics_server() {
+        let cli = Cli::try_parse_from(vec!["stoker", "metrics-server", "--port", "9100"]).unwrap();
+        match cli.command {
+            Commands::MetricsServer { port } => assert_eq!(port, 9100),
+            _ => panic!("Expected MetricsServer command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_image_history() {
+        let args = vec!["stoker", "image", "history", "nginx-server", "--no-trunc"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Image { command: ImageCommands::History { name, no_trunc } } => {
+                assert_eq!(name, "nginx-server");
+                assert!(no_trunc);
+            }
+            _ => panic!("Expected Image History command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_build_labels() {
+        let args = vec!["stoker", "build", "--image-name", "web", "--script-path", "build.sh",
+            "--label", "org.opencontainers.image.source=https://example.com/web", "--label", "team=infra"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Build { labels, .. } => {
+                assert_eq!(labels, vec!["org.opencontainers.image.source=https://example.com/web", "team=infra"]);
+            }
+            _ => panic!("Expected Build command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_build_progress() {
+        let args = vec!["stoker", "build", "--image-name", "web", "--script-path", "build.sh", "--progress", "json"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Build { progress, .. } => assert_eq!(progress, "json"),
+            _ => panic!("Expected Build command"),
+        }
+        let args = vec!["stoker", "build", "--image-name", "web", "--script-path", "build.sh", "--progress", "fancy"];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_cli_build_cache() {
+        let args = vec![
+            "stoker", "build", "--image-name", "web", "--script-path", "build.sh",
+            "--cache-from", "web-cache", "--cache-to", "web-cache-next",
+        ];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Build { cache_from, cache_to, .. } => {
+                assert_eq!(cache_from.as_deref(), Some("web-cache"));
+                assert_eq!(cache_to.as_deref(), Some("web-cache-next"));
+            }
+            _ => panic!("Expected Build command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_build_stokerfile_target() {
+        let args = vec!["stoker", "build", "--image-name", "web", "--file", "Stokerfile", "--target", "deps"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Build { script_path, file, target, .. } => {
+                assert_eq!(script_path, None);
+                assert_eq!(file.as_deref(), Some("Stokerfile"));
+                assert_eq!(target.as_deref(), Some("deps"));
+            }
+            _ => panic!("Expected Build command"),
+        }
+        // A build needs either a script or a Stokerfile, and --target only applies to the latter
+        assert!(Cli::try_parse_from(vec!["stoker", "build", "--image-name", "web"]).is_err());
+        let args = vec!["stoker", "build", "--image-name", "web", "--script-path", "build.sh", "--target", "deps"];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_cli_build_debug() {
+        let args = vec!["stoker", "build", "--image-name", "web", "--script-path", "build.sh", "--debug"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Build { debug, .. } => assert!(debug),
+            _ => panic!("Expected Build command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_build_verbose() {
+        let args = vec!["stoker", "build", "--image-name", "web", "--script-path", "build.sh", "--verbose"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Build { verbose, .. } => assert!(verbose),
+            _ => panic!("Expected Build command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_build_arch() {
+        let args = vec!["stoker", "build", "--image-name", "web", "--script-path", "build.sh", "--arch", "arm64"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Build { arch, .. } => assert_eq!(arch.as_deref(), Some("arm64")),
+            _ => panic!("Expected Build command"),
+        }
+        let args = vec!["stoker", "build", "--image-name", "web", "--script-path", "build.sh", "--platform", "linux/amd64"];
+        match Cli::try_parse_from(args).unwrap().command {
+            Commands::Build { platform, arch, .. } => {
+                assert_eq!(platform.as_deref(), Some("linux/amd64"));
+                assert_eq!(arch, None);
+            }
+            _ => panic!("Expected Build command"),
+        }
+        let args = vec!["stoker", "build", "--image-name", "web", "--arch", "arm64", "--platform", "linux/amd64"];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_cli_image_convert() {
+        let args = vec!["stoker", "image", "convert", "--from-rootfs", "alpine-minirootfs.tar.gz", "--to", "alpine"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Image { command: ImageCommands::Convert { from_rootfs, to } } => {
+                assert_eq!(from_rootfs, "alpine-minirootfs.tar.gz");
+                assert_eq!(to, "alpine");
+            }
+            _ => panic!("Expected Image Convert command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_image_pull() {
+        let args = vec!["stoker", "image", "pull", "192.168.1.10:5000/nginx-server", "--verify-tls"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Image { command: ImageCommands::Pull { reference, verify_tls } } => {
+                assert_eq!(reference, "192.168.1.10:5000/nginx-server");
+                assert!(verify_tls);
+            }
+            _ => panic!("Expected Image Pull command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_image_push() {
+        let cli = Cli::try_parse_from(vec!["stoker", "image", "push", "nginx-server", "s3://team-images/web/"]).unwrap();
+        match cli.command {
+            Commands::Image { command: ImageCommands::Push { name, destination } } => {
+                assert_eq!(name, "nginx-server");
+                assert_eq!(destination, "s3://team-images/web/");
+            }
+            _ => panic!("Expected Image Push command"),
+        }
+    }
+
     #[test]
     fn test_cli_ssh() {
         let args = vec!["stoker", "ssh", "my-server"];
         let cli = Cli::try_parse_from(args).unwrap();
         match cli.command {
-            Commands::Ssh { name } => {
+            Commands::Ssh { name, .. } => {
                 assert_eq!(name, "my-server");
             }
             _ => panic!("Expected Ssh command"),
         }
     }
+
+    #[test]
+    fn test_cli_ssh_forwarding() {
+        let args = vec!["stoker", "ssh", "web", "--agent-forward", "--ssh-opt", "ProxyJump=bastion", "--ssh-opt", "ServerAliveInterval=30"];
+        match Cli::try_parse_from(args).unwrap().command {
+            Commands::Ssh { agent_forward, ssh_opts, .. } => {
+                assert!(agent_forward);
+                assert_eq!(ssh_opts, vec!["ProxyJump=bastion", "ServerAliveInterval=30"]);
+            }
+            _ => panic!("Expected Ssh command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_ssh_multiplex() {
+        match Cli::try_parse_from(vec!["stoker", "ssh", "web", "--multiplex"]).unwrap().command {
+            Commands::Ssh { multiplex, agent_forward, .. } => assert!(multiplex && !agent_forward),
+            _ => panic!("Expected Ssh command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_cp() {
+        let cli = Cli::try_parse_from(vec!["stoker", "cp", "-L", "./app", "web:/srv"]).unwrap();
+        match cli.command {
+            Commands::Cp { src, dest, follow_links } => {
+                assert_eq!(src, "./app");
+                assert_eq!(dest, "web:/srv");
+                assert!(follow_links);
+            }
+            _ => panic!("Expected Cp command"),
+        }
+    }
 }
@@ -0,0 +1,117 @@
+use anyhow::Result;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::time::Duration;
+
+use crate::firecracker;
+
+// Prometheus exporter for `stoker metrics-server`. The text exposition format is simple enough
+// to render directly, so every scrape reads fresh state instead of maintaining registries.
+
+/// One VM's values for a scrape. API-derived values are absent for VMs that are down or
+/// whose API didn't answer.
+#[derive(Debug, Default)]
+struct VmSample {
+    name: String,
+    up: bool,
+    memory_mib: Option<u64>,
+    vcpus: Option<u64>,
+    balloon_mib: Option<u64>,
+}
+
+/// How long a scrape waits on each VM's API, as `stoker list` does, so one hung daemon can't
+/// stall the whole response.
+const API_TIMEOUT: Duration = Duration::from_secs(1);
+
+async fn collect() -> Vec<VmSample> {
+    let mut samples = Vec::new();
+    for meta in firecracker::list_vms() {
+        let up = firecracker::is_process_alive(meta.pid, meta.pid_start_time);
+        let mut sample = VmSample { name: meta.name.clone(), up, ..Default::default() };
+        if up {
+            if let Ok(Ok(config)) = tokio::time::timeout(API_TIMEOUT, firecracker::get_machine_config(&meta.name)).await {
+                sample.memory_mib = Some(config.mem_size_mib as u64);
+                sample.vcpus = Some(config.vcpu_count as u64);
+            }
+            if meta.initial_balloon_mib.is_some() {
+                if let Ok(Ok(balloon)) = tokio::time::timeout(API_TIMEOUT, firecracker::query_api(&meta.name, "/balloon")).await {
+                    sample.balloon_mib = balloon["amount_mib"].as_u64();
+                }
+            }
+        }
+        samples.push(sample);
+    }
+    samples
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Metric name, HELP text and the sample's value (None omits the series).
+type Gauge = (&'static str, &'static str, fn(&VmSample) -> Option<u64>);
+
+fn render(samples: &[VmSample]) -> String {
+    let gauges: [Gauge; 4] = [
+        ("stoker_vm_up", "Whether the VM's Firecracker process is running", |s| Some(s.up as u64)),
+        ("stoker_vm_memory_mib", "Guest memory configured in Firecracker, in MiB", |s| s.memory_mib),
+        ("stoker_vm_vcpus", "vCPUs configured in Firecracker", |s| s.vcpus),
+        ("stoker_vm_balloon_mib", "Current balloon target, in MiB", |s| s.balloon_mib),
+    ];
+
+    let mut out = String::new();
+    for (metric, help, value) in gauges {
+        out.push_str(&format!("# HELP {} {}\n# TYPE {} gauge\n", metric, help, metric));
+        for sample in samples {
+            if let Some(v) = value(sample) {
+                out.push_str(&format!("{}{{name=\"{}\"}} {}\n", metric, escape_label(&sample.name), v));
+            }
+        }
+    }
+    out
+}
+
+async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = if req.method() == Method::GET && req.uri().path() == "/metrics" {
+        Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Body::from(render(&collect().await)))
+    } else {
+        Response::builder().status(StatusCode::NOT_FOUND).body(Body::from("Not Found\n"))
+    };
+    Ok(response.unwrap_or_else(|_| Response::new(Body::empty())))
+}
+
+/// Serves `GET /metrics` on all interfaces until the process is stopped.
+pub async fn serve(port: u16) -> Result<()> {
+    let addr = std::net::SocketAddr::from(([0, 0, 0, 0], port));
+    let make_svc = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
+    let server = Server::try_bind(&addr)
+        .map_err(|e| anyhow::anyhow!("Failed to bind metrics server to {}: {}", addr, e))?
+        .serve(make_svc);
+    println!("Serving Prometheus metrics on http://{}/metrics", addr);
+    server.await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let samples = vec![
+            VmSample { name: "web".to_string(), up: true, memory_mib: Some(512), vcpus: Some(1), balloon_mib: Some(128) },
+            VmSample { name: "db\"1".to_string(), up: false, ..Default::default() },
+        ];
+        let out = render(&samples);
+        assert!(out.contains("# TYPE stoker_vm_up gauge\n"));
+        assert!(out.contains("stoker_vm_up{name=\"web\"} 1\n"));
+        assert!(out.contains("stoker_vm_up{name=\"db\\\"1\"} 0\n"));
+        assert!(out.contains("stoker_vm_memory_mib{name=\"web\"} 512\n"));
+        assert!(out.contains("stoker_vm_balloon_mib{name=\"web\"} 128\n"));
+        // Down VMs only report stoker_vm_up
+        assert!(!out.contains("stoker_vm_vcpus{name=\"db"));
+    }
+}
@@ -1,17 +1,19 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use futures_util::stream::TryStreamExt;
 use rtnetlink::{new_connection, Handle};
+use sha2::{Digest, Sha256};
 use std::net::Ipv4Addr;
 
-pub async fn setup_vm_tap(tap_name: &str, host_ip_str: &str) -> Result<()> {
+pub async fn setup_vm_tap(tap_name: &str, host_ip_str: &str, prefix_len: u8, egress: Option<&str>) -> Result<()> {
     let host_ip: Ipv4Addr = host_ip_str.parse()?;
-    let prefix_len = 30;
+
+    check_address_conflict(&host_ipv4_addresses(), host_ip, prefix_len)?;
 
     let (connection, handle, _) = new_connection()?;
     tokio::spawn(connection);
 
     // 1. Create or ensure Tap device exists
-    create_or_reset_tap(&handle, tap_name).await?;
+    ensure_tap(&handle, tap_name).await?;
 
     // 2. Set IP Address (e.g., 172.16.X.1/30)
     set_ip_address(&handle, tap_name, host_ip, prefix_len).await?;
@@ -19,13 +21,49 @@ pub async fn setup_vm_tap(tap_name: &str, host_ip_str: &str) -> Result<()> {
     // 3. Set device UP
     set_link_up(&handle, tap_name).await?;
 
-    // 4. Configure iptables MASQUERADE (idempotent for all instances on eth0)
+    // 4. Configure iptables MASQUERADE (idempotent for all instances on the egress interface)
+    let out_iface = match egress {
+        Some(iface) => iface.to_string(),
+        None => detect_egress_interface()?,
+    };
     enable_ip_forwarding()?;
-    setup_nat("eth0")?;
+    // The iptables crate runs and waits on the iptables binary; keep that off the runtime
+    tokio::task::spawn_blocking(move || setup_nat(&out_iface)).await??;
+
+    Ok(())
+}
+
+/// Runs `ip` with `args`, failing if it exits unsuccessfully.
+pub async fn run_ip(args: &[&str]) -> Result<()> {
+    let status = tokio::process::Command::new("ip").args(args).status().await.context("Failed to execute ip")?;
+    if !status.success() {
+        bail!("`ip {}` failed", args.join(" "));
+    }
+    Ok(())
+}
 
+/// `run_ip` for synchronous callers such as the image builder.
+pub fn run_ip_blocking(args: &[&str]) -> Result<()> {
+    let status = std::process::Command::new("ip").args(args).status().context("Failed to execute ip")?;
+    if !status.success() {
+        bail!("`ip {}` failed", args.join(" "));
+    }
     Ok(())
 }
 
+/// Temporarily gives `tap_name` a snapshot source's gateway address and a host route to its
+/// guest, so a guest resumed from that snapshot can be reached before it is re-addressed.
+pub async fn add_restore_route(tap_name: &str, old_host_ip: &str, old_guest_ip: &str) -> Result<()> {
+    run_ip(&["addr", "add", &format!("{}/32", old_host_ip), "dev", tap_name]).await?;
+    run_ip(&["route", "replace", &format!("{}/32", old_guest_ip), "dev", tap_name, "src", old_host_ip]).await
+}
+
+/// Undoes `add_restore_route`, ignoring parts that are already gone.
+pub async fn remove_restore_route(tap_name: &str, old_host_ip: &str, old_guest_ip: &str) {
+    let _ = run_ip(&["route", "del", &format!("{}/32", old_guest_ip), "dev", tap_name]).await;
+    let _ = run_ip(&["addr", "del", &format!("{}/32", old_host_ip), "dev", tap_name]).await;
+}
+
 pub async fn teardown_vm_tap(tap_name: &str) -> Result<()> {
     let (connection, handle, _) = new_connection()?;
     tokio::spawn(connection);
@@ -41,7 +79,261 @@ pub async fn teardown_vm_tap(tap_name: &str) -> Result<()> {
     Ok(())
 }
 
-async fn create_or_reset_tap(handle: &Handle, name: &str) -> Result<()> {
+const CNI_BIN_DIR: &str = "/opt/cni/bin";
+const CNI_CONF_DIR: &str = "/etc/cni/net.d";
+/// Interface the CNI plugin creates in the VM's network namespace
+const CNI_IFNAME: &str = "eth0";
+
+/// What `--cni-plugin` set up for a VM, kept so the same plugins can release it.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct CniResult {
+    pub plugin: String,
+    /// The network config file: one plugin's config, or a `.conflist` with a `plugins` chain
+    pub config: String,
+    /// What ADD returned, handed back to the plugins as `prevResult` on DEL
+    #[serde(default)]
+    pub add_result: Option<serde_json::Value>,
+    /// Path of the network namespace the daemon runs in
+    pub netns: String,
+    pub address: String,
+    pub prefix_len: u8,
+    pub gateway: Option<String>,
+}
+
+fn cni_netns_name(vm_name: &str) -> String {
+    format!("stoker-{}", vm_name)
+}
+
+/// Finds the network config in `dir` whose main plugin (the first of a `.conflist` chain) has
+/// `type` `plugin`, trying files in name order as CNI runtimes do.
+fn find_cni_config(dir: &str, plugin: &str) -> Result<String> {
+    let mut paths: Vec<std::path::PathBuf> = std::fs::read_dir(dir)
+        .map(|entries| entries.flatten().map(|entry| entry.path()).collect())
+        .unwrap_or_default();
+    paths.sort();
+    for path in paths.iter().filter(|p| matches!(p.extension().and_then(|e| e.to_str()), Some("conf" | "conflist" | "json"))) {
+        let Ok(content) = std::fs::read_to_string(path) else { continue };
+        let Ok(chain) = cni_plugin_chain(&content) else { continue };
+        if chain[0]["type"] == plugin {
+            return Ok(content);
+        }
+    }
+    bail!("No CNI network config with \"type\": \"{}\" found in {}", plugin, dir)
+}
+
+/// The plugins a network config runs, in order. Each plugin of a `.conflist` gets the list's
+/// `cniVersion` and `name`; a single-plugin config is a chain of one.
+fn cni_plugin_chain(config: &str) -> Result<Vec<serde_json::Value>> {
+    let config: serde_json::Value = serde_json::from_str(config).context("Invalid CNI network config")?;
+    let Some(plugins) = config["plugins"].as_array() else {
+        cni_plugin_type(&config)?;
+        return Ok(vec![config]);
+    };
+    if plugins.is_empty() {
+        bail!("CNI network config {} has no plugins", config["name"]);
+    }
+    plugins.iter()
+        .map(|plugin| {
+            cni_plugin_type(plugin)?;
+            let mut plugin = plugin.clone();
+            plugin["cniVersion"] = config["cniVersion"].clone();
+            plugin["name"] = config["name"].clone();
+            Ok(plugin)
+        })
+        .collect()
+}
+
+/// A plugin config's `type`, the name of its binary in `CNI_BIN_DIR`.
+fn cni_plugin_type(plugin: &serde_json::Value) -> Result<&str> {
+    match plugin["type"].as_str() {
+        Some(name) if !name.is_empty() && !name.contains('/') => Ok(name),
+        _ => bail!("Invalid CNI plugin type in {}", plugin),
+    }
+}
+
+/// The first IPv4 address, its prefix length and gateway in a CNI ADD result. Handles both
+/// the `ips` list of CNI 0.3+ and the `ip4` object of 0.2.
+fn parse_cni_result(result: &serde_json::Value) -> Result<(Ipv4Addr, u8, Option<String>)> {
+    let candidates: Vec<&serde_json::Value> = match result["ips"].as_array() {
+        Some(ips) => ips.iter().collect(),
+        None => vec![&result["ip4"]],
+    };
+    for entry in candidates {
+        let cidr = entry["address"].as_str().or_else(|| entry["ip"].as_str()).unwrap_or_default();
+        let Some((ip, prefix)) = cidr.split_once('/') else { continue };
+        if let (Ok(ip), Ok(prefix)) = (ip.parse::<Ipv4Addr>(), prefix.parse::<u8>()) {
+            return Ok((ip, prefix, entry["gateway"].as_str().map(str::to_string)));
+        }
+    }
+    bail!("The CNI plugin did not assign an IPv4 address: {}", result)
+}
+
+/// Runs ADD through the plugin chain of `config` in order, each plugin getting the previous
+/// one's result as `prevResult`, and returns the last result.
+async fn cni_add(vm_name: &str, netns: &str, config: &str) -> Result<serde_json::Value> {
+    let mut result = serde_json::Value::Null;
+    for (index, mut plugin) in cni_plugin_chain(config)?.into_iter().enumerate() {
+        if index > 0 {
+            plugin["prevResult"] = result;
+        }
+        let stdout = invoke_cni("ADD", cni_plugin_type(&plugin)?, vm_name, netns, &plugin.to_string()).await?;
+        result = serde_json::from_slice(&stdout)?;
+    }
+    Ok(result)
+}
+
+/// Runs DEL through the plugin chain of `config` in reverse, passing `add_result` as
+/// `prevResult` when ADD got that far. Every plugin is run; the first failure is returned.
+async fn cni_del(vm_name: &str, netns: &str, config: &str, add_result: Option<&serde_json::Value>) -> Result<()> {
+    let mut outcome = Ok(());
+    for mut plugin in cni_plugin_chain(config)?.into_iter().rev() {
+        if let Some(add_result) = add_result {
+            plugin["prevResult"] = add_result.clone();
+        }
+        let deleted = invoke_cni("DEL", cni_plugin_type(&plugin)?, vm_name, netns, &plugin.to_string()).await;
+        if outcome.is_ok() {
+            outcome = deleted.map(|_| ());
+        }
+    }
+    outcome
+}
+
+/// Runs `plugin` with CNI_COMMAND `command` for VM `vm_name`, returning its stdout.
+async fn invoke_cni(command: &str, plugin: &str, vm_name: &str, netns: &str, config: &str) -> Result<Vec<u8>> {
+    use tokio::io::AsyncWriteExt;
+
+    let binary = format!("{}/{}", CNI_BIN_DIR, plugin);
+    let mut child = tokio::process::Command::new(&binary)
+        .env("CNI_COMMAND", command)
+        .env("CNI_CONTAINERID", format!("stoker-{}", vm_name))
+        .env("CNI_NETNS", netns)
+        .env("CNI_IFNAME", CNI_IFNAME)
+        .env("CNI_PATH", CNI_BIN_DIR)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run CNI plugin {}", binary))?;
+    // Dropping stdin once written closes it, so the plugin sees the end of its config
+    child.stdin.take().context("CNI plugin has no stdin")?.write_all(config.as_bytes()).await?;
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        // Plugins report failures as {"code": ..., "msg": ...} on stdout
+        let error: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap_or_default();
+        let message = error["msg"].as_str().map(str::to_string)
+            .unwrap_or_else(|| String::from_utf8_lossy(&output.stderr).trim().to_string());
+        bail!("CNI plugin {} {} failed: {}", plugin, command, message);
+    }
+    Ok(output.stdout)
+}
+
+/// Sets up VM networking through a CNI plugin instead of `setup_vm_tap`, running the rest of a
+/// `.conflist` chain after it. The plugin attaches a fresh network namespace, where the VM's TAP is created and bridged to the plugin's
+/// interface with tc redirects, so the daemon must be started inside that namespace. The
+/// plugin interface takes the guest's MAC so the plugin's side of the link reaches the guest.
+pub async fn setup_cni(plugin: &str, vm_name: &str, metadata: &crate::firecracker::InstanceMetadata) -> Result<CniResult> {
+    if plugin.is_empty() || plugin.contains('/') {
+        bail!("Invalid CNI plugin name '{}'", plugin);
+    }
+    if !std::path::Path::new(&format!("{}/{}", CNI_BIN_DIR, plugin)).exists() {
+        bail!("CNI plugin '{}' not found in {}", plugin, CNI_BIN_DIR);
+    }
+    let config = find_cni_config(CNI_CONF_DIR, plugin)?;
+    for chained in cni_plugin_chain(&config)?.iter().skip(1) {
+        let chained = cni_plugin_type(chained)?;
+        if !std::path::Path::new(&format!("{}/{}", CNI_BIN_DIR, chained)).exists() {
+            bail!("CNI plugin '{}', chained after '{}', not found in {}", chained, plugin, CNI_BIN_DIR);
+        }
+    }
+
+    let ns_name = cni_netns_name(vm_name);
+    let netns = format!("/var/run/netns/{}", ns_name);
+    run_ip(&["netns", "add", &ns_name]).await.context("Failed to create the VM's network namespace")?;
+    let (add_result, added) = match cni_add(vm_name, &netns, &config).await {
+        Ok(result) => {
+            let parsed = parse_cni_result(&result);
+            (Some(result), parsed)
+        }
+        Err(e) => (None, Err(e)),
+    };
+    let (address, prefix_len, gateway) = match added {
+        Ok(added) => added,
+        Err(e) => {
+            // Lets the plugins release what they allocated before the failure, such as an IPAM lease
+            let _ = cni_del(vm_name, &netns, &config, add_result.as_ref()).await;
+            let _ = run_ip(&["netns", "del", &ns_name]).await;
+            return Err(e);
+        }
+    };
+    let result = CniResult {
+        plugin: plugin.to_string(),
+        config,
+        add_result,
+        netns,
+        address: address.to_string(),
+        prefix_len,
+        gateway,
+    };
+
+    let tap = metadata.tap_device.as_str();
+    let steps: [&[&str]; 7] = [
+        &["ip", "tuntap", "add", "dev", tap, "mode", "tap"],
+        &["ip", "link", "set", tap, "up"],
+        &["ip", "link", "set", CNI_IFNAME, "address", &metadata.mac_address],
+        &["tc", "qdisc", "add", "dev", CNI_IFNAME, "ingress"],
+        &["tc", "qdisc", "add", "dev", tap, "ingress"],
+        &["tc", "filter", "add", "dev", CNI_IFNAME, "parent", "ffff:", "protocol", "all", "u32", "match", "u8", "0", "0",
+          "action", "mirred", "egress", "redirect", "dev", tap],
+        &["tc", "filter", "add", "dev", tap, "parent", "ffff:", "protocol", "all", "u32", "match", "u8", "0", "0",
+          "action", "mirred", "egress", "redirect", "dev", CNI_IFNAME],
+    ];
+    for step in steps {
+        let mut args = vec!["netns", "exec", &ns_name];
+        args.extend_from_slice(step);
+        if let Err(e) = run_ip(&args).await {
+            teardown_cni(vm_name, &result).await;
+            return Err(e.context("Failed to connect the VM's TAP to the CNI interface"));
+        }
+    }
+    println!("CNI plugin {} assigned {}/{} in network namespace {}", plugin, result.address, prefix_len, ns_name);
+    Ok(result)
+}
+
+/// Releases what `setup_cni` set up, ignoring parts that are already gone.
+pub async fn teardown_cni(vm_name: &str, result: &CniResult) {
+    if let Err(e) = cni_del(vm_name, &result.netns, &result.config, result.add_result.as_ref()).await {
+        println!("Warning: {}", e);
+    }
+    if run_ip(&["netns", "del", &cni_netns_name(vm_name)]).await.is_ok() {
+        println!("Deleted network namespace {}", cni_netns_name(vm_name));
+    }
+}
+
+// TUNSETIFF flag bits as reported in /sys/class/net/<name>/tun_flags
+const IFF_TUN: u32 = 0x0001;
+const IFF_TAP: u32 = 0x0002;
+const IFF_PERSIST: u32 = 0x0800;
+const IFF_NO_PI: u32 = 0x1000;
+
+/// Whether `tun_flags` describe a persistent TAP without packet info, as `ensure_tap` creates.
+fn is_reusable_tap(tun_flags: &str) -> bool {
+    let hex = tun_flags.trim().trim_start_matches("0x");
+    match u32::from_str_radix(hex, 16) {
+        Ok(flags) => flags & (IFF_TUN | IFF_TAP | IFF_PERSIST | IFF_NO_PI) == IFF_TAP | IFF_PERSIST | IFF_NO_PI,
+        Err(_) => false,
+    }
+}
+
+/// Creates the persistent TAP `name`, reusing a matching one left by an earlier run so that
+/// restarts don't make the interface briefly disappear. Anything else by that name is replaced.
+async fn ensure_tap(handle: &Handle, name: &str) -> Result<()> {
+    if let Ok(flags) = std::fs::read_to_string(format!("/sys/class/net/{}/tun_flags", name)) {
+        if is_reusable_tap(&flags) {
+            println!("Reusing existing TAP interface: {}", name);
+            return Ok(());
+        }
+    }
+
     // Delete natively via netlink if it exists
     let mut links = handle.link().get().match_name(name.to_string()).execute();
     if let Ok(Some(link)) = links.try_next().await {
@@ -67,9 +359,8 @@ async fn create_or_reset_tap(handle: &Handle, name: &str) -> Result<()> {
         ifr_flags: (0x0002 | 0x1000) as std::os::raw::c_short,
     };
     
-    let bytes = name.as_bytes();
-    for i in 0..std::cmp::min(15, bytes.len()) {
-        ifr.ifr_name[i] = bytes[i] as std::os::raw::c_char;
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(name.as_bytes().iter().take(15)) {
+        *dst = *src as std::os::raw::c_char;
     }
 
     const TUNSETIFF: libc::c_ulong = 1074025674; // _IOW('T', 202, int) on generic linux
@@ -89,6 +380,11 @@ async fn create_or_reset_tap(handle: &Handle, name: &str) -> Result<()> {
 }
 
 async fn set_ip_address(handle: &Handle, name: &str, ip: Ipv4Addr, prefix: u8) -> Result<()> {
+    if has_ipv4_address(name, ip) {
+        println!("IP {} already configured on {}", ip, name);
+        return Ok(());
+    }
+
     let mut links = handle.link().get().match_name(name.to_string()).execute();
     if let Ok(Some(link)) = links.try_next().await {
         let index = link.header.index;
@@ -114,6 +410,27 @@ async fn set_link_up(handle: &Handle, name: &str) -> Result<()> {
     Ok(())
 }
 
+/// Finds the interface carrying the host's IPv4 default route.
+pub fn detect_egress_interface() -> Result<String> {
+    let routes = std::fs::read_to_string("/proc/net/route")?;
+    match default_route_interface(&routes) {
+        Some(iface) => Ok(iface),
+        None => bail!("Could not detect the default route interface. Pass --egress <iface> explicitly."),
+    }
+}
+
+fn default_route_interface(routes: &str) -> Option<String> {
+    // Columns: Iface Destination Gateway Flags ... Mask; the default route has a zero destination and mask
+    routes.lines().skip(1).find_map(|line| {
+        let cols: Vec<&str> = line.split_whitespace().collect();
+        if cols.len() > 7 && cols[1] == "00000000" && cols[7] == "00000000" {
+            Some(cols[0].to_string())
+        } else {
+            None
+        }
+    })
+}
+
 fn enable_ip_forwarding() -> Result<()> {
     std::fs::write("/proc/sys/net/ipv4/ip_forward", b"1")?;
     println!("Enabled IP Forwarding");
@@ -132,3 +449,327 @@ fn setup_nat(out_iface: &str) -> Result<()> {
     println!("Configured MASQUERADE NAT on {}", out_iface);
     Ok(())
 }
+
+/// Parses an `--allow` destination, `<cidr>[:<port>]`, into the CIDR and optional port.
+pub fn parse_allow_rule(spec: &str) -> Result<(String, Option<u16>)> {
+    let (cidr, port) = match spec.rsplit_once(':') {
+        Some((cidr, port)) => {
+            let port = port.parse::<u16>().ok().filter(|&port| port != 0)
+                .with_context(|| format!("Invalid port in --allow '{}'", spec))?;
+            (cidr, Some(port))
+        }
+        None => (spec, None),
+    };
+    Ok((parse_cidr(cidr).with_context(|| format!("Invalid --allow '{}'. Expected <cidr>[:<port>], e.g. 10.0.0.0/8:443.", spec))?, port))
+}
+
+/// Validates an IPv4 address or `<address>/<prefix>` network and returns it as a CIDR.
+pub fn parse_cidr(spec: &str) -> Result<String> {
+    let (addr, prefix) = spec.split_once('/').unwrap_or((spec, "32"));
+    let addr: Ipv4Addr = addr.parse().with_context(|| format!("Invalid IPv4 address '{}'", addr))?;
+    let prefix: u8 = prefix.parse().ok().filter(|&prefix| prefix <= 32)
+        .with_context(|| format!("Invalid prefix length in '{}'", spec))?;
+    Ok(format!("{}/{}", addr, prefix))
+}
+
+/// FORWARD rules confining traffic from `source` (the VM's subnet) to `--allow`/`--deny`, in
+/// chain order: denials first, then allowances, then a final drop when there is an allow-list.
+/// A port allows both TCP and UDP.
+pub fn egress_rules(source: &str, comment: &str, allow: &[String], deny: &[String]) -> Result<Vec<String>> {
+    let rule = |spec: String, target: &str| format!("-s {} {} -m comment --comment {} -j {}", source, spec, comment, target);
+    let mut rules = Vec::new();
+    for cidr in deny {
+        rules.push(rule(format!("-d {}", parse_cidr(cidr)?), "DROP"));
+    }
+    for spec in allow {
+        match parse_allow_rule(spec)? {
+            (cidr, Some(port)) => {
+                for protocol in ["tcp", "udp"] {
+                    rules.push(rule(format!("-d {} -p {} --dport {}", cidr, protocol, port), "ACCEPT"));
+                }
+            }
+            (cidr, None) => rules.push(rule(format!("-d {}", cidr), "ACCEPT")),
+        }
+    }
+    if !allow.is_empty() {
+        rules.push(format!("-s {} -m comment --comment {} -j DROP", source, comment));
+    }
+    Ok(rules)
+}
+
+/// The network `host_ip/prefix_len` belongs to, as a CIDR.
+fn subnet_cidr(host_ip: &str, prefix_len: u8) -> Result<String> {
+    let ip: Ipv4Addr = host_ip.parse()?;
+    let mask = if prefix_len == 0 { 0 } else { u32::MAX << (32 - prefix_len as u32) };
+    Ok(format!("{}/{}", Ipv4Addr::from(u32::from(ip) & mask), prefix_len))
+}
+
+/// Inserts the VM's `--allow`/`--deny` rules at the top of the FORWARD chain, ahead of any
+/// blanket ACCEPT rules and of the MASQUERADE done on the way out. Rules left by an earlier
+/// boot of the same VM are replaced.
+pub fn setup_egress_rules(vm_name: &str, host_ip: &str, prefix_len: u8, allow: &[String], deny: &[String]) -> Result<()> {
+    let rules = egress_rules(&subnet_cidr(host_ip, prefix_len)?, &format!("stoker-{}", vm_name), allow, deny)?;
+    if rules.is_empty() {
+        return Ok(());
+    }
+    let ipt = iptables::new(false).map_err(|e| anyhow::anyhow!("Failed to init iptables: {}", e))?;
+    for (position, rule) in rules.iter().enumerate() {
+        let _ = ipt.delete("filter", "FORWARD", rule);
+        ipt.insert("filter", "FORWARD", rule, position as i32 + 1)
+            .map_err(|e| anyhow::anyhow!("Failed to insert iptables rule '{}': {}", rule, e))?;
+    }
+    println!("Applied {} egress firewall rules for stoker-{}", rules.len(), vm_name);
+    Ok(())
+}
+
+/// Removes exactly the rules `setup_egress_rules` inserted for the same arguments.
+pub fn teardown_egress_rules(vm_name: &str, host_ip: &str, prefix_len: u8, allow: &[String], deny: &[String]) {
+    let Ok(source) = subnet_cidr(host_ip, prefix_len) else { return };
+    let Ok(rules) = egress_rules(&source, &format!("stoker-{}", vm_name), allow, deny) else { return };
+    let Ok(ipt) = iptables::new(false) else { return };
+    for rule in &rules {
+        let _ = ipt.delete("filter", "FORWARD", rule);
+    }
+}
+
+/// Lists every IPv4 address configured on the host as (interface, address, prefix length).
+pub fn host_ipv4_addresses() -> Vec<(String, Ipv4Addr, u8)> {
+    let mut result = Vec::new();
+    let mut addrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut addrs) } != 0 {
+        return result;
+    }
+
+    let mut cursor = addrs;
+    while !cursor.is_null() {
+        let entry = unsafe { &*cursor };
+        let addr = entry.ifa_addr;
+        if !addr.is_null() && unsafe { (*addr).sa_family } as i32 == libc::AF_INET {
+            let iface = unsafe { std::ffi::CStr::from_ptr(entry.ifa_name) };
+            let sin = unsafe { &*(addr as *const libc::sockaddr_in) };
+            let prefix = if entry.ifa_netmask.is_null() {
+                32
+            } else {
+                let mask = unsafe { &*(entry.ifa_netmask as *const libc::sockaddr_in) };
+                u32::from_be(mask.sin_addr.s_addr).count_ones() as u8
+            };
+            result.push((
+                iface.to_string_lossy().to_string(),
+                Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)),
+                prefix,
+            ));
+        }
+        cursor = entry.ifa_next;
+    }
+    unsafe { libc::freeifaddrs(addrs) };
+    result
+}
+
+/// The first IPv4 address and prefix length configured on interface `name`.
+pub fn interface_ipv4_address(name: &str) -> Option<(Ipv4Addr, u8)> {
+    host_ipv4_addresses().into_iter()
+        .find(|(iface, _, _)| iface == name)
+        .map(|(_, addr, prefix)| (addr, prefix))
+}
+
+/// Checks whether `ip` is already assigned to interface `name`, e.g. on a reused TAP.
+fn has_ipv4_address(name: &str, ip: Ipv4Addr) -> bool {
+    host_ipv4_addresses().iter().any(|(iface, addr, _)| iface == name && *addr == ip)
+}
+
+/// Whether the networks `a/a_prefix` and `b/b_prefix` share any address.
+pub fn subnets_overlap(a: Ipv4Addr, a_prefix: u8, b: Ipv4Addr, b_prefix: u8) -> bool {
+    let prefix = a_prefix.min(b_prefix) as u32;
+    let mask = if prefix == 0 { 0 } else { u32::MAX << (32 - prefix) };
+    u32::from(a) & mask == u32::from(b) & mask
+}
+
+/// Longest interface name the kernel accepts (IFNAMSIZ less the terminating NUL). Longer
+/// names make the TUNSETIFF ioctl fail.
+pub const MAX_IFNAME_LEN: usize = 15;
+
+/// Hex digits of the hash that replaces the end of a TAP name that would be too long.
+const TAP_HASH_LEN: usize = 6;
+
+/// Name for a TAP interface: `prefix` followed by `key` when that fits in `MAX_IFNAME_LEN`,
+/// otherwise the first characters of `prefix` and a hash of the full name. Names in `taken`
+/// are never returned; a clash is resolved by hashing again with a counter.
+pub fn tap_name(prefix: &str, key: &str, taken: &[String]) -> String {
+    let plain = format!("{}{}", prefix, key);
+    if plain.len() <= MAX_IFNAME_LEN && !taken.contains(&plain) {
+        return plain;
+    }
+    let head: String = prefix.chars().filter(char::is_ascii).take(MAX_IFNAME_LEN - TAP_HASH_LEN).collect();
+    (0u32..)
+        .map(|attempt| {
+            let digest = crate::signing::hex(&Sha256::digest(format!("{}#{}", plain, attempt).as_bytes()));
+            format!("{}{}", head, &digest[..MAX_IFNAME_LEN - head.len()])
+        })
+        .find(|name| !taken.contains(name))
+        .expect("some attempt yields an unused name")
+}
+
+/// Names of all network interfaces on the host.
+pub fn interface_names() -> Vec<String> {
+    std::fs::read_dir("/sys/class/net")
+        .map(|entries| entries.flatten().map(|entry| entry.file_name().to_string_lossy().to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// Interfaces stoker creates itself, whose addresses never count as conflicts.
+pub fn is_stoker_interface(name: &str) -> bool {
+    name.starts_with("tap-inet-") || name.starts_with("tap-x") || name.starts_with("stoker-bld")
+}
+
+/// Fails if `ip/prefix` overlaps an address on a host interface stoker doesn't manage.
+fn check_address_conflict(addresses: &[(String, Ipv4Addr, u8)], ip: Ipv4Addr, prefix: u8) -> Result<()> {
+    for (iface, addr, addr_prefix) in addresses {
+        if !is_stoker_interface(iface) && subnets_overlap(ip, prefix, *addr, *addr_prefix) {
+            bail!(
+                "{}/{} overlaps {}/{} on host interface {}; refusing to configure it to avoid breaking host networking",
+                ip, prefix, addr, addr_prefix, iface
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_address_conflict() {
+        let addresses = vec![
+            ("lo".to_string(), Ipv4Addr::new(127, 0, 0, 1), 8),
+            ("eth0".to_string(), Ipv4Addr::new(172, 16, 5, 10), 24),
+            ("tap-inet-3".to_string(), Ipv4Addr::new(172, 16, 3, 1), 30),
+        ];
+        assert!(check_address_conflict(&addresses, Ipv4Addr::new(172, 16, 4, 1), 30).is_ok());
+        // Inside eth0's /24
+        assert!(check_address_conflict(&addresses, Ipv4Addr::new(172, 16, 5, 1), 30).is_err());
+        // Stoker's own TAPs are ignored
+        assert!(check_address_conflict(&addresses, Ipv4Addr::new(172, 16, 3, 1), 30).is_ok());
+
+        let wide = vec![("br0".to_string(), Ipv4Addr::new(172, 16, 0, 1), 12)];
+        assert!(check_address_conflict(&wide, Ipv4Addr::new(172, 16, 200, 1), 30).is_err());
+    }
+
+    #[test]
+    fn test_parse_cni_result() {
+        let current = serde_json::json!({
+            "cniVersion": "1.0.0",
+            "ips": [
+                { "address": "fd00::5/64" },
+                { "address": "10.22.0.5/16", "gateway": "10.22.0.1" }
+            ]
+        });
+        assert_eq!(parse_cni_result(&current).unwrap(), (Ipv4Addr::new(10, 22, 0, 5), 16, Some("10.22.0.1".to_string())));
+
+        let legacy = serde_json::json!({ "cniVersion": "0.2.0", "ip4": { "ip": "10.1.1.2/24" } });
+        assert_eq!(parse_cni_result(&legacy).unwrap(), (Ipv4Addr::new(10, 1, 1, 2), 24, None));
+
+        assert!(parse_cni_result(&serde_json::json!({ "ips": [] })).is_err());
+    }
+
+    #[test]
+    fn test_find_cni_config() {
+        let dir = std::env::temp_dir().join(format!("stoker-cni-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("10-bridge.conf"), r#"{"cniVersion":"0.4.0","name":"br","type":"bridge"}"#).unwrap();
+        std::fs::write(dir.join("20-ptp.conf"), r#"{"cniVersion":"0.4.0","name":"p2p","type":"ptp"}"#).unwrap();
+        std::fs::write(dir.join("30-calico.conflist"), r#"{"cniVersion":"1.0.0","name":"k8s","plugins":[{"type":"calico"},{"type":"portmap"}]}"#).unwrap();
+        std::fs::write(dir.join("README"), "ptp").unwrap();
+
+        let dir_str = dir.to_str().unwrap();
+        assert!(find_cni_config(dir_str, "ptp").unwrap().contains("\"p2p\""));
+        assert!(find_cni_config(dir_str, "calico").unwrap().contains("\"k8s\""));
+        // Only the main plugin of a chain selects it
+        assert!(find_cni_config(dir_str, "portmap").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cni_plugin_chain() {
+        let chain = cni_plugin_chain(r#"{"cniVersion":"1.0.0","name":"k8s","plugins":[{"type":"bridge"},{"type":"firewall"}]}"#).unwrap();
+        assert_eq!(chain.len(), 2);
+        assert_eq!(chain[1], serde_json::json!({ "type": "firewall", "cniVersion": "1.0.0", "name": "k8s" }));
+        assert_eq!(cni_plugin_chain(r#"{"name":"br","type":"bridge"}"#).unwrap().len(), 1);
+        assert!(cni_plugin_chain(r#"{"name":"k8s","plugins":[]}"#).is_err());
+        assert!(cni_plugin_chain(r#"{"name":"k8s","plugins":[{"type":"../bin/sh"}]}"#).is_err());
+    }
+
+    #[test]
+    fn test_tap_name() {
+        assert_eq!(tap_name("tap-inet-", "42", &[]), "tap-inet-42");
+        // Too long: the prefix is kept and the rest replaced by a hash
+        let long = tap_name("tap-inet-", "1234567890", &[]);
+        assert_eq!(long.len(), MAX_IFNAME_LEN);
+        assert!(long.starts_with("tap-inet-") && is_stoker_interface(&long), "{}", long);
+        assert_eq!(tap_name("tap-inet-", "1234567890", &[]), long);
+        let long_prefix = tap_name("stoker-vm-tap-", "3", &[]);
+        assert_eq!(long_prefix.len(), MAX_IFNAME_LEN);
+        assert!(long_prefix.starts_with("stoker-vm"), "{}", long_prefix);
+
+        // Taken names are avoided
+        let taken = vec!["tap-inet-42".to_string()];
+        let renamed = tap_name("tap-inet-", "42", &taken);
+        assert_ne!(renamed, "tap-inet-42");
+        assert!(renamed.len() <= MAX_IFNAME_LEN);
+        let taken = vec!["tap-inet-42".to_string(), renamed.clone()];
+        assert!(!taken.contains(&tap_name("tap-inet-", "42", &taken)));
+    }
+
+    #[test]
+    fn test_is_reusable_tap() {
+        // IFF_TAP | IFF_NO_PI | IFF_PERSIST
+        assert!(is_reusable_tap("0x1802\n"));
+        // Firecracker also sets IFF_VNET_HDR while attached
+        assert!(is_reusable_tap("0x5802"));
+        // Not persistent
+        assert!(!is_reusable_tap("0x1002"));
+        // TUN rather than TAP
+        assert!(!is_reusable_tap("0x1801"));
+        assert!(!is_reusable_tap("garbage"));
+    }
+
+    #[test]
+    fn test_default_route_interface() {
+        let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+            tap-inet-0\t0010AC00\t00000000\t0001\t0\t0\t0\tFCFFFFFF\t0\t0\t0\n\
+            ens3\t00000000\t0100A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0\n";
+        assert_eq!(default_route_interface(routes), Some("ens3".to_string()));
+    }
+
+    #[test]
+    fn test_parse_allow_rule() {
+        assert_eq!(parse_allow_rule("10.0.0.0/8:443").unwrap(), ("10.0.0.0/8".to_string(), Some(443)));
+        assert_eq!(parse_allow_rule("1.1.1.1").unwrap(), ("1.1.1.1/32".to_string(), None));
+        assert!(parse_allow_rule("10.0.0.0/33").is_err());
+        assert!(parse_allow_rule("10.0.0.0/8:0").is_err());
+        assert!(parse_allow_rule("example.com:80").is_err());
+    }
+
+    #[test]
+    fn test_egress_rules() {
+        let allow = vec!["10.0.0.0/8:443".to_string()];
+        let deny = vec!["10.1.0.0/16".to_string()];
+        let rules = egress_rules("172.16.0.0/30", "stoker-web", &allow, &deny).unwrap();
+        assert_eq!(rules, vec![
+            "-s 172.16.0.0/30 -d 10.1.0.0/16 -m comment --comment stoker-web -j DROP",
+            "-s 172.16.0.0/30 -d 10.0.0.0/8 -p tcp --dport 443 -m comment --comment stoker-web -j ACCEPT",
+            "-s 172.16.0.0/30 -d 10.0.0.0/8 -p udp --dport 443 -m comment --comment stoker-web -j ACCEPT",
+            "-s 172.16.0.0/30 -m comment --comment stoker-web -j DROP",
+        ]);
+        // A deny-list alone leaves everything else open
+        assert_eq!(egress_rules("172.16.0.0/30", "stoker-web", &[], &deny).unwrap().len(), 1);
+        assert!(egress_rules("172.16.0.0/30", "stoker-web", &[], &[]).unwrap().is_empty());
+        assert_eq!(subnet_cidr("172.16.0.5", 30).unwrap(), "172.16.0.4/30");
+    }
+
+    #[test]
+    fn test_default_route_interface_missing() {
+        let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n";
+        assert_eq!(default_route_interface(routes), None);
+    }
+}
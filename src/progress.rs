@@ -0,0 +1,271 @@
+use anyhow::Result;
+use serde_json::json;
+use std::io::{IsTerminal, Write};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Build progress reporting for `stoker build --progress`, modelled on `docker build --progress`:
+// plain prints messages as they happen, tty animates the current step and json emits one event
+// per line so CI can parse stdout. Human-readable messages go to stderr in json mode.
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ProgressMode {
+    #[default]
+    Plain,
+    Tty,
+    Json,
+}
+
+impl std::str::FromStr for ProgressMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "plain" => Ok(ProgressMode::Plain),
+            "tty" => Ok(ProgressMode::Tty),
+            "json" => Ok(ProgressMode::Json),
+            other => anyhow::bail!("Unsupported progress mode '{}'. Use 'plain', 'tty' or 'json'.", other),
+        }
+    }
+}
+
+/// The spinner thread animating the current step in tty mode.
+struct Spinner {
+    stop: Arc<AtomicBool>,
+    thread: std::thread::JoinHandle<()>,
+}
+
+pub struct Progress {
+    mode: ProgressMode,
+    step: Option<(String, String, Instant)>,
+    spinner: Option<Spinner>,
+}
+
+const SPINNER_FRAMES: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+fn timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// Events are formatted by hand to keep `step` as the first key, as in Docker's output
+fn step_event(step: &str, status: &str, ts: u64) -> String {
+    format!("{{\"step\":{},\"status\":{},\"ts\":{}}}", json!(step), json!(status), ts)
+}
+
+fn output_event(step: &str, line: &str) -> String {
+    format!("{{\"step\":{},\"output\":{}}}", json!(step), json!(line))
+}
+
+impl Progress {
+    pub fn new(mode: ProgressMode) -> Self {
+        Progress { mode, step: None, spinner: None }
+    }
+
+    /// Begins build step `step`, finishing any step still in progress.
+    pub fn start(&mut self, step: &str, message: &str) {
+        self.done();
+        match self.mode {
+            ProgressMode::Plain => println!("{}", message),
+            ProgressMode::Json => println!("{}", step_event(step, "start", timestamp())),
+            // The run step streams the script's output, which a spinner would overwrite
+            ProgressMode::Tty if step == "run" => println!("▶ {}", message),
+            ProgressMode::Tty => self.spinner = Some(spin(message.to_string())),
+        }
+        self.step = Some((step.to_string(), message.to_string(), Instant::now()));
+    }
+
+    /// Marks the current step as completed.
+    pub fn done(&mut self) {
+        self.finish("done", '✓');
+    }
+
+    /// Marks the current step as failed with `err`.
+    pub fn fail(&mut self, err: &anyhow::Error) {
+        if self.mode == ProgressMode::Json {
+            if let Some((step, _, _)) = self.step.take() {
+                println!(
+                    "{{\"step\":{},\"status\":\"error\",\"error\":{},\"ts\":{}}}",
+                    json!(step), json!(format!("{:#}", err)), timestamp()
+                );
+            }
+            return;
+        }
+        self.finish("error", '✗');
+    }
+
+    fn finish(&mut self, status: &str, marker: char) {
+        self.stop_spinner();
+        let Some((step, message, started)) = self.step.take() else { return };
+        match self.mode {
+            ProgressMode::Plain => {}
+            ProgressMode::Json => println!("{}", step_event(&step, status, timestamp())),
+            ProgressMode::Tty => println!("\r\x1b[K{} {} ({:.1}s)", marker, message, started.elapsed().as_secs_f64()),
+        }
+    }
+
+    fn stop_spinner(&mut self) {
+        if let Some(spinner) = self.spinner.take() {
+            spinner.stop.store(true, Ordering::Relaxed);
+            let _ = spinner.thread.join();
+        }
+    }
+
+    /// Prints an informational message that isn't a step of its own.
+    pub fn message(&self, message: &str) {
+        match self.mode {
+            ProgressMode::Plain => println!("{}", message),
+            ProgressMode::Tty => println!("\r\x1b[K  {}", message),
+            ProgressMode::Json => eprintln!("{}", message),
+        }
+    }
+
+    /// Reports one line of the build script's output.
+    pub fn output(&self, line: &str) {
+        match self.mode {
+            ProgressMode::Json => println!("{}", output_event("run", line)),
+            _ => println!("{}", line),
+        }
+    }
+
+    /// Whether child output must be captured and passed through `output` rather than inherited.
+    pub fn captures_output(&self) -> bool {
+        self.mode == ProgressMode::Json
+    }
+
+    /// Stdout for helper tools (e2fsck, resize2fs, ...): inherited in plain mode, discarded
+    /// otherwise so it can't garble the spinner or the JSON stream.
+    pub fn tool_stdout(&self) -> Stdio {
+        match self.mode {
+            ProgressMode::Plain => Stdio::inherit(),
+            _ => Stdio::null(),
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.stop_spinner();
+    }
+}
+
+/// Renders `sent` of `total` bytes as `[#####.....]  50%  32.0/64.0 MiB`.
+pub fn render_bar(sent: u64, total: u64) -> String {
+    const WIDTH: u64 = 30;
+    let sent_clamped = sent.min(total);
+    let filled = (sent_clamped * WIDTH).checked_div(total).unwrap_or(WIDTH);
+    let percent = (sent_clamped * 100).checked_div(total).unwrap_or(100);
+    format!(
+        "[{}{}] {:>3}%  {:.1}/{:.1} MiB",
+        "#".repeat(filled as usize),
+        ".".repeat((WIDTH - filled) as usize),
+        percent,
+        sent as f64 / 1_048_576.0,
+        total as f64 / 1_048_576.0
+    )
+}
+
+/// `bytes` moved in `elapsed` as MiB/s.
+pub fn format_rate(bytes: u64, elapsed: Duration) -> String {
+    let secs = elapsed.as_secs_f64();
+    let rate = if secs > 0.0 { bytes as f64 / 1_048_576.0 / secs } else { 0.0 };
+    format!("{:.1} MiB/s", rate)
+}
+
+/// Byte progress of a transfer (downloads, image pushes, `stoker cp`), drawn on stderr with
+/// its rate. Nothing is drawn when stderr isn't a terminal, so logs don't fill with redraws.
+/// Safe to update from concurrent tasks.
+pub struct TransferProgress {
+    total: u64,
+    sent: AtomicU64,
+    started: Instant,
+    draw: bool,
+}
+
+impl TransferProgress {
+    pub fn new(total: u64) -> Self {
+        let progress = TransferProgress {
+            total,
+            sent: AtomicU64::new(0),
+            started: Instant::now(),
+            draw: std::io::stderr().is_terminal(),
+        };
+        progress.add(0);
+        progress
+    }
+
+    /// Records `bytes` more as transferred and redraws the bar.
+    pub fn add(&self, bytes: u64) {
+        let sent = self.sent.fetch_add(bytes, Ordering::SeqCst) + bytes;
+        if !self.draw {
+            return;
+        }
+        eprint!("\r\x1b[K{}  {}", render_bar(sent, self.total), format_rate(sent, self.started.elapsed()));
+        let _ = std::io::stderr().flush();
+    }
+
+    /// Bytes transferred so far.
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::SeqCst)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Ends the progress line.
+    pub fn finish(&self) {
+        if self.draw {
+            eprintln!();
+        }
+    }
+}
+
+fn spin(message: String) -> Spinner {
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    let thread = std::thread::spawn(move || {
+        for frame in SPINNER_FRAMES.iter().cycle() {
+            if stopped.load(Ordering::Relaxed) {
+                break;
+            }
+            print!("\r\x1b[K{} {}", frame, message);
+            let _ = std::io::stdout().flush();
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    });
+    Spinner { stop, thread }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_mode_from_str() {
+        assert_eq!("plain".parse::<ProgressMode>().unwrap(), ProgressMode::Plain);
+        assert_eq!("tty".parse::<ProgressMode>().unwrap(), ProgressMode::Tty);
+        assert_eq!("json".parse::<ProgressMode>().unwrap(), ProgressMode::Json);
+        assert!("fancy".parse::<ProgressMode>().is_err());
+    }
+
+    #[test]
+    fn test_render_bar() {
+        assert_eq!(render_bar(0, 2 * 1_048_576), format!("[{}]   0%  0.0/2.0 MiB", ".".repeat(30)));
+        assert_eq!(render_bar(1_048_576, 2 * 1_048_576), format!("[{}{}]  50%  1.0/2.0 MiB", "#".repeat(15), ".".repeat(15)));
+        assert_eq!(render_bar(0, 0), format!("[{}] 100%  0.0/0.0 MiB", "#".repeat(30)));
+    }
+
+    #[test]
+    fn test_format_rate() {
+        assert_eq!(format_rate(3 * 1_048_576, Duration::from_secs(2)), "1.5 MiB/s");
+        assert_eq!(format_rate(1_048_576, Duration::ZERO), "0.0 MiB/s");
+    }
+
+    #[test]
+    fn test_events() {
+        assert_eq!(step_event("mount", "start", 1234567890), r#"{"step":"mount","status":"start","ts":1234567890}"#);
+        assert_eq!(output_event("run", "Setting up nginx"), r#"{"step":"run","output":"Setting up nginx"}"#);
+    }
+}
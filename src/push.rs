@@ -0,0 +1,316 @@
+use anyhow::{Context, Result};
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+use reqwest::{Client, Method, Response};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::progress::TransferProgress;
+use crate::{assets, signing};
+use crate::errors::{ErrorKind, StokerError};
+
+// `stoker image push`: uploads an image's ext4 file to an S3 or GCS bucket so it can be shared
+// without running a registry. Images larger than one part go up in PART_SIZE pieces, as an S3
+// multipart upload or a GCS resumable upload.
+
+pub const PART_SIZE: u64 = 64 * 1024 * 1024;
+/// S3 parts uploaded at once; each is held in memory while it is sent
+const CONCURRENT_PARTS: usize = 4;
+const GCS_ENDPOINT: &str = "https://storage.googleapis.com";
+
+#[derive(Debug, PartialEq)]
+enum Destination {
+    S3 { bucket: String, key: String },
+    Gcs { bucket: String, key: String },
+}
+
+/// Parses `s3://<bucket>/<key>` or `gs://<bucket>/<key>`. A key that is empty or ends in `/`
+/// gets `<image>.ext4` appended.
+fn parse_destination(destination: &str, image: &str) -> Result<Destination> {
+    let invalid = || format!("Invalid destination '{}'. Expected s3://<bucket>/<key> or gs://<bucket>/<key>.", destination);
+    let (scheme, rest) = destination.split_once("://").with_context(invalid)?;
+    let (bucket, key) = rest.split_once('/').unwrap_or((rest, ""));
+    if bucket.is_empty() {
+        anyhow::bail!(invalid());
+    }
+    let key = if key.is_empty() || key.ends_with('/') { format!("{}{}.ext4", key, image) } else { key.to_string() };
+    match scheme {
+        "s3" => Ok(Destination::S3 { bucket: bucket.to_string(), key }),
+        "gs" => Ok(Destination::Gcs { bucket: bucket.to_string(), key }),
+        _ => anyhow::bail!(invalid()),
+    }
+}
+
+async fn read_part(path: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(std::io::SeekFrom::Start(offset)).await?;
+    let mut buf = vec![0u8; len as usize];
+    file.read_exact(&mut buf).await.with_context(|| format!("Failed to read {} at offset {}", path, offset))?;
+    Ok(buf)
+}
+
+/// Offsets and lengths of the parts of a `size`-byte file.
+fn parts(size: u64) -> Vec<(u64, u64)> {
+    (0..size.div_ceil(PART_SIZE)).map(|i| (i * PART_SIZE, PART_SIZE.min(size - i * PART_SIZE))).collect()
+}
+
+async fn check_status(response: Response, action: &str) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    anyhow::bail!("{} failed with HTTP {}: {}", action, status, body.trim())
+}
+
+/// The text of the first `<tag>` element in an S3 XML response.
+fn xml_value(xml: &str, tag: &str) -> Option<String> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = xml[start..].find(&format!("</{}>", tag))? + start;
+    Some(xml[start..end].to_string())
+}
+
+struct S3 {
+    client: Client,
+    credentials: signing::Credentials,
+    region: String,
+    /// Scheme and authority requests go to
+    endpoint: String,
+    host: String,
+    /// Unencoded object path on `endpoint`
+    path: String,
+}
+
+impl S3 {
+    /// Uses virtual-hosted AWS endpoints, or path-style requests to `STOKER_S3_ENDPOINT`
+    /// (e.g. `http://minio.local:9000`) when set.
+    fn new(bucket: &str, key: &str) -> Result<Self> {
+        let region = std::env::var("AWS_REGION")
+            .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|_| "us-east-1".to_string());
+        let (endpoint, path) = match std::env::var("STOKER_S3_ENDPOINT").ok().filter(|e| !e.is_empty()) {
+            Some(endpoint) => (endpoint.trim_end_matches('/').to_string(), format!("/{}/{}", bucket, key)),
+            None => (format!("https://{}.s3.{}.amazonaws.com", bucket, region), format!("/{}", key)),
+        };
+        let host = endpoint.split_once("://").map_or(endpoint.as_str(), |(_, host)| host).to_string();
+        Ok(S3 { client: Client::new(), credentials: signing::Credentials::from_env()?, region, endpoint, host, path })
+    }
+
+    async fn send(&self, method: Method, query: &[(String, String)], body: Vec<u8>) -> Result<Response> {
+        // Hashing a whole part takes a while; keep it off the runtime's worker threads
+        let (body, payload_hash) = tokio::task::spawn_blocking(move || {
+            let hash = signing::hex(&Sha256::digest(&body));
+            (body, hash)
+        }).await?;
+        let amz_date = signing::amz_date(crate::firecracker::unix_now());
+        let mut headers = vec![
+            ("host".to_string(), self.host.clone()),
+            ("x-amz-content-sha256".to_string(), payload_hash.clone()),
+            ("x-amz-date".to_string(), amz_date.clone()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token".to_string(), token.clone()));
+        }
+        let request = signing::Request { method: method.as_str(), path: &self.path, query, headers: &headers, payload_hash: &payload_hash };
+        let authorization = signing::authorization(&request, &self.credentials, &self.region, "s3", &amz_date);
+
+        let mut url = format!("{}{}", self.endpoint, signing::uri_encode(&self.path, true));
+        if !query.is_empty() {
+            let pairs: Vec<String> = query.iter()
+                .map(|(key, value)| format!("{}={}", signing::uri_encode(key, false), signing::uri_encode(value, false)))
+                .collect();
+            url = format!("{}?{}", url, pairs.join("&"));
+        }
+        // reqwest derives Host from the URL
+        let mut builder = self.client.request(method, &url).header("authorization", authorization);
+        for (name, value) in headers.iter().filter(|(name, _)| name != "host") {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        Ok(builder.body(body).send().await?)
+    }
+
+    async fn upload(&self, path: &str, size: u64, progress: &TransferProgress) -> Result<()> {
+        if size <= PART_SIZE {
+            let body = read_part(path, 0, size).await?;
+            check_status(self.send(Method::PUT, &[], body).await?, "Upload").await?;
+            progress.add(size);
+            return Ok(());
+        }
+
+        let response = self.send(Method::POST, &[("uploads".to_string(), String::new())], Vec::new()).await?;
+        let xml = check_status(response, "Starting the multipart upload").await?.text().await?;
+        let upload_id = xml_value(&xml, "UploadId").context("S3 did not return an UploadId")?;
+
+        let result = self.upload_parts(path, size, &upload_id, progress).await;
+        let etags = match result {
+            Ok(etags) => etags,
+            Err(e) => {
+                // Parts of an abandoned upload are billed until it is aborted
+                let _ = self.send(Method::DELETE, &[("uploadId".to_string(), upload_id)], Vec::new()).await;
+                return Err(e);
+            }
+        };
+
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for (number, etag) in etags.iter().enumerate() {
+            body.push_str(&format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", number + 1, etag));
+        }
+        body.push_str("</CompleteMultipartUpload>");
+        let response = self.send(Method::POST, &[("uploadId".to_string(), upload_id)], body.into_bytes()).await?;
+        let xml = check_status(response, "Completing the multipart upload").await?.text().await?;
+        // S3 may report a failed completion in the body of a 200 response
+        if let Some(message) = xml_value(&xml, "Message").filter(|_| xml.contains("<Error>")) {
+            anyhow::bail!("Completing the multipart upload failed: {}", message);
+        }
+        Ok(())
+    }
+
+    /// Uploads all parts, returning their ETags in part order.
+    async fn upload_parts(&self, path: &str, size: u64, upload_id: &str, progress: &TransferProgress) -> Result<Vec<String>> {
+        let mut etags: Vec<(usize, String)> = stream::iter(parts(size).into_iter().enumerate())
+            .map(|(index, (offset, len))| async move {
+                let body = read_part(path, offset, len).await?;
+                let query = [
+                    ("partNumber".to_string(), (index + 1).to_string()),
+                    ("uploadId".to_string(), upload_id.to_string()),
+                ];
+                let response = check_status(self.send(Method::PUT, &query, body).await?, "Uploading a part").await?;
+                let etag = response.headers().get("etag").and_then(|v| v.to_str().ok())
+                    .with_context(|| format!("S3 returned no ETag for part {}", index + 1))?
+                    .to_string();
+                progress.add(len);
+                Ok::<_, anyhow::Error>((index, etag))
+            })
+            .buffer_unordered(CONCURRENT_PARTS)
+            .try_collect()
+            .await?;
+        etags.sort();
+        Ok(etags.into_iter().map(|(_, etag)| etag).collect())
+    }
+}
+
+/// A GCS OAuth token from `GOOGLE_OAUTH_ACCESS_TOKEN`, or else `gcloud auth print-access-token`.
+async fn gcs_token() -> Result<String> {
+    if let Some(token) = std::env::var("GOOGLE_OAUTH_ACCESS_TOKEN").ok().filter(|t| !t.is_empty()) {
+        return Ok(token);
+    }
+    let output = tokio::process::Command::new("gcloud")
+        .args(["auth", "print-access-token"])
+        .output()
+        .await
+        .context("Set GOOGLE_OAUTH_ACCESS_TOKEN or install gcloud to push to GCS")?;
+    if !output.status.success() {
+        anyhow::bail!("gcloud auth print-access-token failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Uploads to GCS through its JSON API. Large images use a resumable upload, whose chunks
+/// must be sent in order.
+async fn upload_gcs(bucket: &str, key: &str, path: &str, size: u64, progress: &TransferProgress) -> Result<()> {
+    let client = Client::new();
+    let token = gcs_token().await?;
+    let url = format!("{}/upload/storage/v1/b/{}/o", GCS_ENDPOINT, signing::uri_encode(bucket, false));
+    let name = signing::uri_encode(key, false);
+
+    if size <= PART_SIZE {
+        let body = read_part(path, 0, size).await?;
+        let response = client.post(format!("{}?uploadType=media&name={}", url, name))
+            .bearer_auth(&token)
+            .header("content-type", "application/octet-stream")
+            .body(body)
+            .send()
+            .await?;
+        check_status(response, "Upload").await?;
+        progress.add(size);
+        return Ok(());
+    }
+
+    let response = client.post(format!("{}?uploadType=resumable&name={}", url, name))
+        .bearer_auth(&token)
+        .header("x-upload-content-type", "application/octet-stream")
+        .header("x-upload-content-length", size)
+        .header("content-length", 0)
+        .send()
+        .await?;
+    let response = check_status(response, "Starting the resumable upload").await?;
+    let session = response.headers().get("location").and_then(|v| v.to_str().ok())
+        .context("GCS did not return a resumable upload session")?
+        .to_string();
+
+    for (offset, len) in parts(size) {
+        let body = read_part(path, offset, len).await?;
+        let response = client.put(&session)
+            .header("content-range", format!("bytes {}-{}/{}", offset, offset + len - 1, size))
+            .body(body)
+            .send()
+            .await?;
+        // 308 Resume Incomplete acknowledges every chunk but the last
+        if response.status().as_u16() != 308 {
+            check_status(response, "Uploading a chunk").await?;
+        }
+        progress.add(len);
+    }
+    Ok(())
+}
+
+/// Uploads local image `name` to `destination` (`s3://<bucket>/<key>` or `gs://<bucket>/<key>`).
+pub async fn push_image(name: &str, destination: &str) -> Result<()> {
+    let path = assets::get_asset_path(&format!("{}.ext4", name));
+    let size = std::fs::metadata(&path)
+        .with_context(|| StokerError::new(ErrorKind::NotFound, format!("Image '{}' not found at {}", name, path)))?
+        .len();
+    let destination = parse_destination(destination, name)?;
+
+    let location = match &destination {
+        Destination::S3 { bucket, key } => format!("s3://{}/{}", bucket, key),
+        Destination::Gcs { bucket, key } => format!("gs://{}/{}", bucket, key),
+    };
+    println!("Pushing image '{}' ({:.1} MiB) to {}...", name, size as f64 / 1_048_576.0, location);
+    let progress = TransferProgress::new(size);
+    let result = match &destination {
+        Destination::S3 { bucket, key } => S3::new(bucket, key)?.upload(&path, size, &progress).await,
+        Destination::Gcs { bucket, key } => upload_gcs(bucket, key, &path, size, &progress).await,
+    };
+    progress.finish();
+    result?;
+    println!("Pushed image '{}' to {}", name, location);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_destination() {
+        assert_eq!(
+            parse_destination("s3://team-images/web/nginx.ext4", "nginx").unwrap(),
+            Destination::S3 { bucket: "team-images".to_string(), key: "web/nginx.ext4".to_string() }
+        );
+        assert_eq!(
+            parse_destination("gs://team-images/web/", "nginx").unwrap(),
+            Destination::Gcs { bucket: "team-images".to_string(), key: "web/nginx.ext4".to_string() }
+        );
+        assert_eq!(
+            parse_destination("s3://team-images", "nginx").unwrap(),
+            Destination::S3 { bucket: "team-images".to_string(), key: "nginx.ext4".to_string() }
+        );
+        assert!(parse_destination("https://team-images/nginx", "nginx").is_err());
+        assert!(parse_destination("s3:///nginx", "nginx").is_err());
+        assert!(parse_destination("team-images/nginx", "nginx").is_err());
+    }
+
+    #[test]
+    fn test_parts() {
+        assert_eq!(parts(10), vec![(0, 10)]);
+        assert_eq!(parts(PART_SIZE * 2 + 1), vec![(0, PART_SIZE), (PART_SIZE, PART_SIZE), (PART_SIZE * 2, 1)]);
+        assert_eq!(parts(PART_SIZE).len(), 1);
+    }
+
+    #[test]
+    fn test_xml_value() {
+        let xml = "<InitiateMultipartUploadResult><Bucket>b</Bucket><UploadId>abc.123</UploadId></InitiateMultipartUploadResult>";
+        assert_eq!(xml_value(xml, "UploadId").as_deref(), Some("abc.123"));
+        assert_eq!(xml_value(xml, "Key"), None);
+    }
+}
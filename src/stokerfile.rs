@@ -0,0 +1,207 @@
+use anyhow::{Context, Result};
+
+use crate::{assets, builder};
+
+// Stokerfiles describe a build as a sequence of stages, each a run of shell commands on top of
+// a base image:
+//
+//     FROM ubuntu-rootfs
+//     STAGE deps
+//     RUN apt-get update && apt-get install -y build-essential
+//     STAGE app
+//     RUN make -C /src install
+//
+// Every stage is built with `build_image` like a `--script-path` build. Stages start from the
+// previous stage's result unless they have their own FROM, and intermediate stages are kept as
+// `<image>-<stage>` images. `FROM <image>@<stage>` starts from stage `<stage>` of `<image>`.
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Stage {
+    /// None for directives before the first STAGE
+    pub name: Option<String>,
+    pub from: Option<String>,
+    pub run: Vec<String>,
+}
+
+/// Parses a Stokerfile into its stages. Blank lines and `#` comments are skipped and a trailing
+/// backslash continues a directive on the next line.
+pub fn parse(content: &str) -> Result<Vec<Stage>> {
+    let mut stages = vec![Stage::default()];
+    let mut pending = String::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if pending.is_empty() && (line.is_empty() || line.starts_with('#')) {
+            continue;
+        }
+        if let Some(continued) = line.strip_suffix('\\') {
+            pending.push_str(continued.trim_end());
+            pending.push(' ');
+            continue;
+        }
+        pending.push_str(line);
+        let directive = std::mem::take(&mut pending);
+        let (keyword, args) = directive.split_once(char::is_whitespace).unwrap_or((&directive, ""));
+        let args = args.trim();
+        let stage = stages.last_mut().expect("there is always a current stage");
+        match keyword.to_ascii_uppercase().as_str() {
+            "FROM" if args.is_empty() || args.contains(char::is_whitespace) => {
+                anyhow::bail!("Line {}: FROM takes one image, optionally as <image>@<stage>", number + 1)
+            }
+            "FROM" if stage.from.is_some() || !stage.run.is_empty() => {
+                anyhow::bail!("Line {}: FROM must come before the stage's RUN directives and only once per stage", number + 1)
+            }
+            "FROM" => stage.from = Some(args.to_string()),
+            "STAGE" => {
+                let valid = !args.is_empty() && args.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+                if !valid {
+                    anyhow::bail!("Line {}: invalid stage name '{}'. Use letters, digits, '-' and '_'.", number + 1, args);
+                }
+                if stages.iter().any(|s| s.name.as_deref() == Some(args)) {
+                    anyhow::bail!("Line {}: stage '{}' is defined twice", number + 1, args);
+                }
+                stages.push(Stage { name: Some(args.to_string()), ..Default::default() });
+            }
+            "RUN" if args.is_empty() => anyhow::bail!("Line {}: RUN needs a command", number + 1),
+            "RUN" => stage.run.push(args.to_string()),
+            other => anyhow::bail!("Line {}: unknown directive '{}'. Supported: FROM, STAGE, RUN", number + 1, other),
+        }
+    }
+    if !pending.is_empty() {
+        anyhow::bail!("The Stokerfile ends in a line continuation");
+    }
+    // In a staged file, a FROM before the first STAGE is that stage's base
+    if stages.len() > 1 {
+        let preamble = stages.remove(0);
+        if !preamble.run.is_empty() {
+            anyhow::bail!("RUN directives must follow a STAGE once the Stokerfile has stages");
+        }
+        if stages[0].from.is_none() {
+            stages[0].from = preamble.from;
+        }
+    }
+    Ok(stages)
+}
+
+/// Name of the image holding stage `stage` of `image`.
+pub fn stage_image(image: &str, stage: &str) -> String {
+    format!("{}-{}", image, stage)
+}
+
+/// Indexes of the stages needed to build stage `target`: the stages it chains from and those
+/// referenced as `FROM <image>@<stage>` by any of them, in build order.
+fn build_order(stages: &[Stage], image_name: &str, target: usize) -> Result<Vec<usize>> {
+    fn visit(stages: &[Stage], image_name: &str, index: usize, visiting: &mut Vec<usize>, order: &mut Vec<usize>) -> Result<()> {
+        if order.contains(&index) {
+            return Ok(());
+        }
+        if visiting.contains(&index) {
+            anyhow::bail!("Stage '{}' depends on itself", stages[index].name.as_deref().unwrap_or("<unnamed>"));
+        }
+        visiting.push(index);
+        match stages[index].from.as_deref().and_then(|from| from.split_once('@')) {
+            Some((image, stage)) if image == image_name => {
+                let dependency = stages.iter().position(|s| s.name.as_deref() == Some(stage))
+                    .with_context(|| format!("FROM {}@{}: no stage named '{}' in this Stokerfile", image, stage, stage))?;
+                visit(stages, image_name, dependency, visiting, order)?;
+            }
+            Some(_) => {}
+            None if stages[index].from.is_none() && index > 0 => visit(stages, image_name, index - 1, visiting, order)?,
+            None => {}
+        }
+        visiting.pop();
+        order.push(index);
+        Ok(())
+    }
+
+    let mut order = Vec::new();
+    visit(stages, image_name, target, &mut Vec::new(), &mut order)?;
+    Ok(order)
+}
+
+/// Builds the Stokerfile at `path` as `opts.image_name`. With `target`, only the stages up to and
+/// including it are built and the result is saved as `<image>-<target>`.
+pub fn build(opts: &builder::BuildOptions, path: &str, target: Option<&str>) -> Result<()> {
+    let content = std::fs::read_to_string(path).with_context(|| format!("Could not read Stokerfile: {}", path))?;
+    let stages = parse(&content)?;
+    let target_index = match target {
+        Some(target) => stages.iter().position(|s| s.name.as_deref() == Some(target))
+            .with_context(|| format!("Target stage '{}' not found in {}", target, path))?,
+        None => stages.len() - 1,
+    };
+    let image_name = opts.image_name.as_str();
+
+    for index in build_order(&stages, image_name, target_index)? {
+        let stage = &stages[index];
+        // The final stage of a full build produces the image itself
+        let output = match (&stage.name, index == target_index && target.is_none()) {
+            (Some(name), false) => stage_image(image_name, name),
+            _ => image_name.to_string(),
+        };
+        let from = match stage.from.as_deref() {
+            Some(from) => match from.split_once('@') {
+                Some((image, stage)) => {
+                    let base = stage_image(image, stage);
+                    if !std::path::Path::new(&assets::get_asset_path(&format!("{}.ext4", base))).exists() {
+                        anyhow::bail!("FROM {}: image '{}' not found. Build it first with `stoker build --image-name {} --file <Stokerfile> --target {}`.", from, base, image, stage);
+                    }
+                    Some(base)
+                }
+                None => Some(from.to_string()),
+            },
+            None if index > 0 => stages[index - 1].name.as_deref().map(|name| stage_image(image_name, name)),
+            None => opts.from.clone(),
+        };
+
+        let script_path = format!("/tmp/stoker-stage-{}-{}.sh", std::process::id(), index);
+        std::fs::write(&script_path, format!("#!/bin/bash\nset -e\n{}\n", stage.run.join("\n")))?;
+        println!("Building stage {} as image {}...", stage.name.as_deref().unwrap_or("<unnamed>"), output);
+        let result = builder::build_image(&builder::BuildOptions {
+            image_name: output,
+            script_path: script_path.clone(),
+            from,
+            ..opts.clone()
+        });
+        let _ = std::fs::remove_file(&script_path);
+        result?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STOKERFILE: &str = "# toolchain first\nFROM ubuntu-rootfs\nSTAGE deps\nRUN apt-get update && \\\n    apt-get install -y make\nSTAGE app\nRUN make install\nSTAGE test\nFROM web@deps\nRUN make check\n";
+
+    #[test]
+    fn test_parse() {
+        let stages = parse(STOKERFILE).unwrap();
+        assert_eq!(stages.len(), 3);
+        assert_eq!(stages[0].name.as_deref(), Some("deps"));
+        assert_eq!(stages[0].from.as_deref(), Some("ubuntu-rootfs"));
+        assert_eq!(stages[0].run, vec!["apt-get update && apt-get install -y make"]);
+        assert_eq!(stages[1].from, None);
+        assert_eq!(stages[2].from.as_deref(), Some("web@deps"));
+
+        assert!(parse("STAGE a\nSTAGE a\n").is_err());
+        assert!(parse("RUN true\nSTAGE a\n").is_err());
+        assert!(parse("COPY . /src\n").is_err());
+        assert!(parse("RUN true\nFROM base\n").is_err());
+
+        // A file without stages is a single unnamed one
+        assert_eq!(parse("RUN true\n").unwrap(), vec![Stage { run: vec!["true".to_string()], ..Default::default() }]);
+    }
+
+    #[test]
+    fn test_build_order() {
+        let stages = parse(STOKERFILE).unwrap();
+        assert_eq!(build_order(&stages, "web", 1).unwrap(), vec![0, 1]);
+        // `test` starts from web@deps rather than chaining from `app`
+        assert_eq!(build_order(&stages, "web", 2).unwrap(), vec![0, 2]);
+        // Stages of other images must already exist and are not built here
+        assert_eq!(build_order(&stages, "other", 2).unwrap(), vec![2]);
+
+        let cyclic = parse("STAGE a\nFROM web@b\nRUN true\nSTAGE b\nRUN true\n").unwrap();
+        assert!(build_order(&cyclic, "web", 1).is_err());
+    }
+}